@@ -1,39 +1,528 @@
 use std::{
+    cell::{Cell, OnceCell},
+    collections::HashSet,
     env,
     fs::File,
-    io::{self, BufReader, Read, ErrorKind as IoErrorKind},
-    path::Path,
+    io::{self, BufRead, BufReader, ErrorKind as IoErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
 };
 
 use age::{
-    armor::ArmoredReader,
+    armor::{ArmoredReader, ArmoredWriter, Format},
     plugin::{self, RecipientPluginV1},
-    Callbacks, DecryptError, Decryptor, Encryptor, Identity, IdentityFile, Recipient,
+    secrecy::{ExposeSecret, SecretString},
+    Callbacks, DecryptError, Decryptor, EncryptError, Encryptor, Identity, IdentityFile, Recipient,
 };
-use anyhow::{bail, Context, Result};
+use age_core::format::{FileKey, Stanza};
+use anyhow::{anyhow, bail, Context, Result};
+use thiserror::Error;
+
+use crate::{deterministic, tty};
 
 /// Environment variable name for providing passphrase to decrypt identity files
 const AGE_PASSPHRASE_ENV: &str = "AGE_PASSPHRASE";
 
+/// Errors of encrypting, decrypting and validating keys, for callers which need to
+/// tell them apart. Failures without a variant of their own keep their message in `Other`.
+#[derive(Error, Debug)]
+pub enum AgeError {
+    #[error("Invalid recipient at position {position}: {}", unsupported_recipient(.recipient))]
+    InvalidRecipient { recipient: String, position: usize },
+    /// A secret key is listed where a recipient is expected, it isn't repeated in the message
+    #[error(
+        "Invalid recipient at position {position}: that's an identity, not a recipient, \
+         list its public key instead (age-keygen -y prints it)"
+    )]
+    IdentityAsRecipient { position: usize },
+    #[error("No recipients configured, refusing to encrypt")]
+    NoRecipients,
+    /// The input starts like an age file, but ends before its header does
+    #[error("Failed to decrypt: the input ends within its age header, it may be truncated")]
+    TruncatedHeader,
+    /// A comment for the header isn't a single VCHAR word, see [`set_comment`]
+    #[error(
+        "Invalid comment {0:?}: it has to be printable ASCII without spaces, at most \
+         {MAX_COMMENT_LEN} characters"
+    )]
+    InvalidComment(String),
+    /// A recipient is of a type which isn't allowed, see [`check_recipient_types`]
+    #[error("Recipient {recipient} is of type {kind}, which isn't allowed, only {allowed} are")]
+    RecipientTypeNotAllowed {
+        recipient: String,
+        kind: RecipientType,
+        allowed: String,
+    },
+    /// The input is encrypted, but to none of the listed identities
+    #[error(
+        "Failed to decrypt: no matching identity found. Configured identities: [{identities}]"
+    )]
+    NoMatchingIdentity { identities: String },
+    /// Decrypting what is named needs a passphrase, but none was supplied. `None` is the
+    /// passphrase-encrypted input, see [`AgeError::for_input`].
+    #[error(
+        "AGE_PASSPHRASE environment variable not set, needed to decrypt {}",
+        .0.as_deref().unwrap_or("the passphrase-encrypted file")
+    )]
+    PassphraseRequired(Option<String>),
+    /// None of the supplied passphrases decrypts what the error names
+    #[error(transparent)]
+    IncorrectPassphrase(anyhow::Error),
+    /// An encrypted identity file is encrypted to recipients instead of a passphrase
+    #[error("Encrypted identity file {0:?} is not passphrase-encrypted")]
+    ScryptUnsupported(PathBuf),
+    /// An identity is neither an existing file nor recognized as an inline identity. A
+    /// mistyped key isn't named in the message.
+    #[error(
+        "Identity file {} doesn't exist, inline identities have a line starting with \
+         AGE-SECRET-KEY- or AGE-PLUGIN-",
+        quoted_identity_name(.0)
+    )]
+    IdentityNotFound(PathBuf),
+    /// An identity file holds a secret key in the clear, which
+    /// [`set_require_encrypted_identities`] forbids
+    #[error(
+        "Identity file {0:?} holds a secret key in the clear, which \
+         identity_files.require_encrypted forbids: encrypt it with `git-agecrypt identity \
+         rekey --encrypt`, or SSH keys with `ssh-keygen -p`"
+    )]
+    PlaintextIdentity(PathBuf),
+    /// An identity holds only recipients, e.g. a recipients file or an SSH public key
+    #[error(
+        "Identity {0:?} contains only recipients, not an identity: use the file with the \
+         secret key (AGE-SECRET-KEY-...) or the private SSH key instead"
+    )]
+    RecipientAsIdentity(PathBuf),
+    /// The cancellation flag of [`Streaming`] was set before the input was processed
+    #[error("Cancelled")]
+    Cancelled,
+    #[error(transparent)]
+    Encrypt(#[from] EncryptError),
+    #[error(transparent)]
+    Decrypt(#[from] DecryptError),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+impl AgeError {
+    /// Names `input` as the file needing a passphrase, which the decrypting functions only
+    /// know as a reader
+    pub fn for_input(self, input: &Path) -> Self {
+        match self {
+            Self::PassphraseRequired(None) => {
+                Self::PassphraseRequired(Some(format!("{:?}", input)))
+            }
+            err => err,
+        }
+    }
+}
+
+/// Passphrase obtained by the passphrase getter, see [`set_passphrase`]
+static PASSPHRASE: OnceLock<SecretString> = OnceLock::new();
+
+/// Supplies the passphrase for encrypted identity files and passphrase-encrypted files,
+/// taking precedence over AGE_PASSPHRASE.
+/// It is only kept in this process and never exported through the environment, so no
+/// child process sees it, neither the filters git starts for a `-g` command; those run
+/// the passphrase getter again themselves.
+pub fn set_passphrase(passphrase: &str) {
+    if PASSPHRASE.set(SecretString::from(passphrase)).is_err() {
+        log::warn!("Passphrase was already set, ignoring the new one");
+    }
+}
+
+/// Identity file contents obtained by an identity getter, see [`set_identity`]
+static IDENTITY: OnceLock<SecretString> = OnceLock::new();
+
+/// Supplies the contents of an identity file obtained by an identity getter, e.g. from
+/// a secrets manager, which is used like an inline identity before the configured
+/// ones. Like [`set_passphrase`], it stays within this process. See [`getter_identity`].
+pub fn set_identity(contents: &str) -> Result<(), AgeError> {
+    if IDENTITY.set(parse_getter_identity(contents)?).is_err() {
+        log::warn!("Identity was already set, ignoring the new one");
+    }
+    Ok(())
+}
+
+/// Checks that `contents` is an identity file, which is returned normalized like
+/// identity files on disk
+fn parse_getter_identity(contents: &str) -> Result<SecretString, AgeError> {
+    let contents = normalize_identity_text(contents.as_bytes());
+    let contents = std::str::from_utf8(&contents).context("Identity is not valid UTF-8")?;
+    if !is_inline_identity(Path::new(contents)) {
+        return Err(anyhow!("Identity getter output holds no age identity").into());
+    }
+    IdentityFile::from_buffer(contents.as_bytes())
+        .context("Failed to parse the identity from the identity getter")?;
+    Ok(SecretString::from(contents))
+}
+
+/// The identity set by [`set_identity`], as an inline identity
+pub fn getter_identity() -> Option<String> {
+    IDENTITY
+        .get()
+        .map(|contents| contents.expose_secret().to_string())
+}
+
+/// Runs every interaction on the terminal, like passphrase prompts and messages of
+/// plugins, inside of `hook`, e.g. to hide a progress bar while the user is asked
+pub fn set_terminal_hook(hook: fn(&mut dyn FnMut())) {
+    tty::set_hook(hook);
+}
+
+/// Comment written into the header of encrypted files, see [`set_comment`]
+static COMMENT: OnceLock<String> = OnceLock::new();
+
+/// Type of the stanza carrying the comment, which identities don't know and skip
+const COMMENT_STANZA: &str = "git-agecrypt-comment";
+
+const MAX_COMMENT_LEN: usize = 64;
+
+/// Writes `comment` into the header of every file encrypted to recipients by this
+/// process, e.g. the name of the repository for forensics. The comment isn't encrypted,
+/// so it must not contain anything secret. It is an extra stanza, which decryption
+/// ignores, as age's armor has no room for comments. See [`comment_of`].
+pub fn set_comment(comment: &str) -> Result<(), AgeError> {
+    validate_comment(comment)?;
+    if COMMENT.set(comment.to_string()).is_err() {
+        log::warn!("Comment was already set, ignoring the new one");
+    }
+    Ok(())
+}
+
+/// Checks that `comment` fits into a stanza argument: printable ASCII without spaces
+pub fn validate_comment(comment: &str) -> Result<(), AgeError> {
+    let valid = !comment.is_empty()
+        && comment.len() <= MAX_COMMENT_LEN
+        && comment.bytes().all(|b| b.is_ascii_graphic());
+    if !valid {
+        return Err(AgeError::InvalidComment(comment.to_string()));
+    }
+    Ok(())
+}
+
+/// Compression of the plaintext of files encrypted to recipients, see [`set_compression`]
+static COMPRESSION: OnceLock<Compression> = OnceLock::new();
+
+/// Type of the stanza naming the compression of the plaintext, which `age` skips
+const COMPRESSION_STANZA: &str = "git-agecrypt-compression";
+
+/// Level of zstd compression, its default
+const ZSTD_LEVEL: i32 = 3;
+
+/// Algorithm the plaintext is compressed with before it is encrypted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Zstd,
+}
+
+impl Compression {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Zstd => "zstd",
+        }
+    }
+}
+
+impl std::fmt::Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl std::str::FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "zstd" => Ok(Self::Zstd),
+            _ => bail!("Unknown compression '{}', expected zstd", name),
+        }
+    }
+}
+
+/// Compresses the plaintext of every file encrypted to recipients by this process.
+/// The algorithm is named by a stanza in the header, so that decryption knows to
+/// decompress, and files without it are read as they are. `age` skips the stanza and
+/// outputs the compressed plaintext.
+pub fn set_compression(compression: Compression) {
+    if COMPRESSION.set(compression).is_err() {
+        log::warn!("Compression was already set, ignoring the new one");
+    }
+}
+
+/// Stanzas which git-agecrypt adds to the header besides those of the recipients
+fn extra_stanzas(comment: Option<&str>, compression: Option<Compression>) -> Vec<Stanza> {
+    let annotation = |tag: &str, arg: &str| Stanza {
+        tag: tag.to_string(),
+        args: vec![arg.to_string()],
+        body: vec![],
+    };
+    let mut stanzas = vec![];
+    stanzas.extend(comment.map(|comment| annotation(COMMENT_STANZA, comment)));
+    stanzas.extend(compression.map(|c| annotation(COMPRESSION_STANZA, c.name())));
+    stanzas
+}
+
+/// Whether identity files have to be encrypted, see [`set_require_encrypted_identities`]
+static REQUIRE_ENCRYPTED_IDENTITIES: AtomicBool = AtomicBool::new(false);
+
+/// Rejects identity files holding a secret key in the clear for the rest of the
+/// process, when loading and validating them: age identity files have to be
+/// passphrase-encrypted, SSH keys protected by a passphrase. Inline identities, which
+/// aren't stored in a file, and plugin identity files, which hold no secret, are accepted.
+pub fn set_require_encrypted_identities() {
+    REQUIRE_ENCRYPTED_IDENTITIES.store(true, Ordering::Relaxed);
+}
+
+/// Fails if the `contents` of the identity file `path` hold a secret key in the clear
+/// while identity files are `required` to be encrypted
+fn check_encrypted_at_rest(path: &Path, contents: &[u8], required: bool) -> Result<(), AgeError> {
+    if !required || is_age_encrypted(contents) {
+        return Ok(());
+    }
+    let text = String::from_utf8_lossy(contents);
+    let age_key = text.lines().any(|line| {
+        let line = line.trim_start_matches('\u{feff}').trim();
+        line.starts_with("AGE-SECRET-KEY-")
+    });
+    let ssh_key = || {
+        let identity = age::ssh::Identity::from_buffer(contents, None);
+        matches!(identity, Ok(age::ssh::Identity::Unencrypted(_)))
+    };
+    if age_key || ssh_key() {
+        return Err(AgeError::PlaintextIdentity(path.to_path_buf()));
+    }
+    Ok(())
+}
+
+/// Key of deterministic encryption, or why it isn't available, see
+/// [`set_deterministic_key`]
+static DETERMINISTIC_KEY: OnceLock<std::result::Result<deterministic::Key, String>> =
+    OnceLock::new();
+
+/// Makes encryption to recipients by this process deterministic: the same plaintext
+/// yields the same ciphertext for the same recipients, as everything random is derived
+/// from `key` and the plaintext. This reveals which files are equal, and anyone with
+/// the key can confirm guesses of a plaintext. Only X25519 recipients are supported.
+/// When `key` is an error, e.g. it isn't set on this machine, encrypting fails with it
+/// rather than falling back to random keys. Unlike other encryption, the whole plaintext
+/// is read into memory first, as everything random depends on all of it.
+pub fn set_deterministic_key(key: Result<String>) {
+    let key = key
+        .and_then(|key| deterministic::Key::new(&zeroize::Zeroizing::new(key)))
+        .map_err(|err| format!("{:#}", err));
+    if DETERMINISTIC_KEY.set(key).is_err() {
+        log::warn!("Deterministic key was already set, ignoring the new one");
+    }
+}
+
+/// Environment variable name for a file holding the passphrase, preferred over AGE_PASSPHRASE
+const AGE_PASSPHRASE_FILE_ENV: &str = "AGE_PASSPHRASE_FILE";
+
+/// File given with `--passphrase-file`, see [`set_passphrase_file`]
+static PASSPHRASE_FILE: OnceLock<PathBuf> = OnceLock::new();
+
+/// Reads the passphrase from `path` when it is needed, taking precedence over
+/// AGE_PASSPHRASE_FILE. Unlike AGE_PASSPHRASE, the passphrase itself never appears in
+/// the environment of this or any child process.
+pub fn set_passphrase_file(path: &Path) {
+    if PASSPHRASE_FILE.set(path.to_path_buf()).is_err() {
+        log::warn!("Passphrase file was already set, ignoring the new one");
+    }
+}
+
+/// Gets the passphrases set by the passphrase getter, from the passphrase file or from
+/// AGE_PASSPHRASE, one per line, to be tried in order, e.g. for identity files encrypted
+/// with different passphrases over time
+fn configured_passphrases() -> Vec<SecretString> {
+    configured_secret().map_or_else(Vec::new, |configured| split_passphrases(&configured))
+}
+
+/// The non-empty lines of `configured`, without their line endings
+fn split_passphrases(configured: &SecretString) -> Vec<SecretString> {
+    configured
+        .expose_secret()
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(SecretString::from)
+        .collect()
+}
+
+/// The first of the [`configured_passphrases`], for what only takes one, like new
+/// identity files and PINs of plugins
+fn configured_passphrase() -> Option<SecretString> {
+    configured_passphrases().into_iter().next()
+}
+
+/// Everything the passphrase getter, the passphrase file or AGE_PASSPHRASE hold
+fn configured_secret() -> Option<SecretString> {
+    if let Some(passphrase) = PASSPHRASE.get() {
+        return Some(SecretString::from(passphrase.expose_secret()));
+    }
+    let file = PASSPHRASE_FILE
+        .get()
+        .cloned()
+        .or_else(|| env::var_os(AGE_PASSPHRASE_FILE_ENV).map(PathBuf::from));
+    if let Some(file) = file {
+        if env::var_os(AGE_PASSPHRASE_ENV).is_some() {
+            log::debug!(
+                "Both a passphrase file and {} are set, using the file",
+                AGE_PASSPHRASE_ENV
+            );
+        }
+        return match std::fs::read_to_string(&file) {
+            Ok(contents) => Some(SecretString::from(contents)),
+            Err(err) => {
+                log::warn!("Couldn't read passphrase file {:?}: {}", file, err);
+                None
+            }
+        };
+    }
+    env::var(AGE_PASSPHRASE_ENV).ok().map(SecretString::from)
+}
+
+/// Reads all of `file` without its final line ending. Each of its lines is a passphrase
+/// of its own, see [`configured_passphrases`].
+fn read_passphrase_file(file: &Path) -> Option<SecretString> {
+    match std::fs::read_to_string(file) {
+        Ok(contents) => {
+            let contents = zeroize::Zeroizing::new(contents);
+            let line = contents.as_str();
+            let passphrase = line.strip_suffix('\n').unwrap_or(line);
+            let passphrase = passphrase.strip_suffix('\r').unwrap_or(passphrase);
+            Some(SecretString::from(passphrase))
+        }
+        Err(err) => {
+            log::warn!("Couldn't read passphrase file {:?}: {}", file, err);
+            None
+        }
+    }
+}
+
+/// Gets the configured passphrase, or asks for it on the terminal.
+/// Returns `None` when neither is available, so non-interactive runs never block.
+fn request_passphrase(description: &str) -> Option<SecretString> {
+    configured_passphrase().or_else(|| tty::read_secret(&format!("{}: ", description)))
+}
+
+/// Like [`request_passphrase`], but gets all configured passphrases, to be tried in order.
+/// Empty when none is available.
+fn request_passphrases(description: &str) -> Vec<SecretString> {
+    let configured = configured_passphrases();
+    if !configured.is_empty() {
+        return configured;
+    }
+    tty::read_secret(&format!("{}: ", description))
+        .into_iter()
+        .collect()
+}
+
+/// Passphrases of which the first fitting one decrypts a passphrase-encrypted file.
+/// age stops at the first identity failing to unwrap the file key, so a wrong
+/// passphrase would end decryption if each was an identity of its own.
+struct Passphrases {
+    identities: Vec<age::scrypt::Identity>,
+    matched: Cell<Option<usize>>,
+}
+
+impl Passphrases {
+    fn new(passphrases: &[SecretString]) -> Self {
+        let identities = passphrases
+            .iter()
+            .map(|passphrase| {
+                age::scrypt::Identity::new(SecretString::from(passphrase.expose_secret()))
+            })
+            .collect();
+        Self {
+            identities,
+            matched: Cell::new(None),
+        }
+    }
+
+    /// Result of the first passphrase unwrapping the file key, else the last error
+    fn first_fitting(
+        &self,
+        unwrap: impl Fn(&dyn Identity) -> Option<std::result::Result<FileKey, DecryptError>>,
+    ) -> Option<std::result::Result<FileKey, DecryptError>> {
+        let mut failed = None;
+        for (i, identity) in self.identities.iter().enumerate() {
+            match unwrap(identity) {
+                Some(Ok(file_key)) => {
+                    self.matched.set(Some(i));
+                    return Some(Ok(file_key));
+                }
+                Some(Err(err)) => {
+                    log::debug!(
+                        "Passphrase {} of {} doesn't fit",
+                        i + 1,
+                        self.identities.len()
+                    );
+                    failed = Some(Err(err));
+                }
+                None => {}
+            }
+        }
+        failed
+    }
+}
+
+impl Identity for Passphrases {
+    fn unwrap_stanza(&self, stanza: &Stanza) -> Option<std::result::Result<FileKey, DecryptError>> {
+        self.first_fitting(|identity| identity.unwrap_stanza(stanza))
+    }
+
+    fn unwrap_stanzas(
+        &self,
+        stanzas: &[Stanza],
+    ) -> Option<std::result::Result<FileKey, DecryptError>> {
+        self.first_fitting(|identity| identity.unwrap_stanzas(stanzas))
+    }
+}
+
 /// Callbacks for identity file decryption.
 /// If AGE_PASSPHRASE environment variable is set, it will be used for decrypting
-/// passphrase-protected identity files in automated/non-interactive mode.
+/// passphrase-protected identity files in automated/non-interactive mode, otherwise
+/// the passphrase is asked for on the terminal.
+/// Plugins (e.g. age-plugin-yubikey) reach the user through the terminal when
+/// there is one.
 #[derive(Clone)]
 struct IdentityCallbacks;
 
 impl Callbacks for IdentityCallbacks {
-    fn display_message(&self, _message: &str) {}
-    
-    fn confirm(&self, _message: &str, _yes_string: &str, _no_string: Option<&str>) -> Option<bool> {
-        None
+    fn display_message(&self, message: &str) {
+        if !tty::message(message) {
+            log::info!("{}", message);
+        }
     }
-    
-    fn request_public_string(&self, _description: &str) -> Option<String> {
-        None
+
+    fn confirm(&self, message: &str, yes_string: &str, no_string: Option<&str>) -> Option<bool> {
+        let prompt = match no_string {
+            Some(no_string) => format!("{} [{}/{}]: ", message, yes_string, no_string),
+            None => format!("{} [{}]: ", message, yes_string),
+        };
+        let answer = tty::read_line(&prompt)?;
+        let answer = answer.trim();
+        if no_string.is_none() || answer == yes_string {
+            Some(true)
+        } else if Some(answer) == no_string {
+            Some(false)
+        } else {
+            None
+        }
     }
-    
-    fn request_passphrase(&self, _description: &str) -> Option<age::secrecy::SecretString> {
-        env::var(AGE_PASSPHRASE_ENV).ok().map(|p| p.into())
+
+    fn request_public_string(&self, description: &str) -> Option<String> {
+        tty::read_line(&format!("{}: ", description))
+    }
+
+    fn request_passphrase(&self, description: &str) -> Option<age::secrecy::SecretString> {
+        request_passphrase(description)
     }
 }
 
@@ -54,204 +543,1917 @@ impl Callbacks for NoOpCallbacks {
     }
 }
 
-pub(crate) fn decrypt(
+/// Result of decrypting a file, telling plaintext apart from files no identity matches
+#[derive(Debug, PartialEq, Eq)]
+pub enum DecryptOutcome<T = Vec<u8>> {
+    /// The input doesn't carry a valid age header, so it isn't encrypted
+    Plain,
+    Decrypted(T),
+    /// The input is encrypted, but to none of the identities
+    NoMatchingIdentity,
+}
+
+impl<T> DecryptOutcome<T> {
+    fn map<U>(self, f: impl FnOnce(T) -> U) -> DecryptOutcome<U> {
+        match self {
+            Self::Plain => DecryptOutcome::Plain,
+            Self::Decrypted(decrypted) => DecryptOutcome::Decrypted(f(decrypted)),
+            Self::NoMatchingIdentity => DecryptOutcome::NoMatchingIdentity,
+        }
+    }
+
+    /// Fails if none of the `identities` used for decrypting matches, like on other
+    /// decryption errors. Returns `None` for input which isn't encrypted.
+    pub fn required(self, identities: &[impl AsRef<Path>]) -> Result<Option<T>, AgeError> {
+        match self {
+            Self::Plain => Ok(None),
+            Self::Decrypted(decrypted) => Ok(Some(decrypted)),
+            Self::NoMatchingIdentity => Err(AgeError::NoMatchingIdentity {
+                identities: identity_names(identities),
+            }),
+        }
+    }
+}
+
+/// Decrypts `encrypted` with the given identities: paths of identity files, which
+/// may be passphrase-encrypted or SSH keys, or identities given inline.
+pub fn decrypt(
     identities: &[impl AsRef<Path>],
     encrypted: &mut impl Read,
-) -> Result<Option<Vec<u8>>> {
-    let id = load_identities(identities)?;
-    let id_refs = id.iter().map(|i| i.as_ref() as &dyn Identity);
+) -> Result<DecryptOutcome, AgeError> {
     let mut decrypted = vec![];
-    let decryptor = match Decryptor::new(ArmoredReader::new(encrypted)) {
-        Ok(d) => {
-            if d.is_scrypt() {
-                bail!("Passphrase encrypted files are not supported");
-            }
-            d
+    let outcome = decrypt_to(identities, encrypted, &mut decrypted)?;
+    Ok(outcome.map(|()| decrypted))
+}
+
+/// Like [`decrypt`], but also names the identity file which could decrypt the input.
+/// The path is `None` when it isn't determinable, e.g. for passphrase-encrypted files.
+pub fn decrypt_with_identity(
+    identities: &[impl AsRef<Path>],
+    encrypted: &mut impl Read,
+) -> Result<DecryptOutcome<(Vec<u8>, Option<PathBuf>)>, AgeError> {
+    let mut decrypted = vec![];
+    let outcome = decrypt_with_passphrase(identities, encrypted, &mut decrypted, file_passphrase)?;
+    Ok(outcome.map(|m| {
+        let path = match m {
+            Decrypted::Passphrase => None,
+            Decrypted::Identity(i) => i.map(|i| identities[i].as_ref().to_path_buf()),
+        };
+        (decrypted, path)
+    }))
+}
+
+/// Streams the plaintext of `encrypted` into `out` without buffering it.
+/// Unless the input is decrypted, nothing is written.
+pub fn decrypt_to<W: Write>(
+    identities: &[impl AsRef<Path>],
+    encrypted: &mut impl Read,
+    out: &mut W,
+) -> Result<DecryptOutcome<()>, AgeError> {
+    decrypt_streaming(identities, encrypted, out, Streaming::default())
+}
+
+/// Like [`decrypt_to`], reporting the progress of reading `encrypted` and stopping when
+/// cancelled, see [`Streaming`]
+pub fn decrypt_streaming<W: Write>(
+    identities: &[impl AsRef<Path>],
+    encrypted: &mut impl Read,
+    out: &mut W,
+    streaming: Streaming,
+) -> Result<DecryptOutcome<()>, AgeError> {
+    let mut encrypted = Monitored::new(encrypted, streaming);
+    let outcome = decrypt_with_passphrase(identities, &mut encrypted, out, file_passphrase);
+    Ok(streaming.checked(outcome)?.map(|_| ()))
+}
+
+/// Progress reporting and cancellation of streaming encryption and decryption, e.g. of
+/// files too large to wait for without feedback, see [`encrypt_streaming`] and
+/// [`decrypt_streaming`]
+#[derive(Clone, Copy, Default)]
+pub struct Streaming<'a> {
+    /// Called after every chunk read from the input with the number of bytes read so far
+    pub progress: Option<&'a dyn Fn(u64)>,
+    /// Checked before every chunk read from the input. Once it is set, nothing more is
+    /// read and the function fails with [`AgeError::Cancelled`]. What was already written
+    /// to the output is incomplete, the caller has to discard it, as on any error.
+    pub cancel: Option<&'a AtomicBool>,
+}
+
+impl Streaming<'_> {
+    fn cancelled(&self) -> bool {
+        self.cancel
+            .is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+    }
+
+    /// `result`, or [`AgeError::Cancelled`] if it failed because of the cancellation,
+    /// which may surface as any error, e.g. that of a truncated input
+    fn checked<T>(&self, result: Result<T, AgeError>) -> Result<T, AgeError> {
+        match result {
+            Err(_) if self.cancelled() => Err(AgeError::Cancelled),
+            result => result,
         }
-        Err(DecryptError::InvalidHeader) => return Ok(None),
-        Err(DecryptError::Io(e)) => {
-            match e.kind() {
-                // Age gives unexpected EOF when the file contains not enough data
-                IoErrorKind::UnexpectedEof => return Ok(None),
-                _ => bail!(e),
-            }
+    }
+}
+
+/// Input of streaming encryption or decryption, reporting what is read and failing once
+/// cancelled, see [`Streaming`]
+struct Monitored<'a, R> {
+    inner: R,
+    streaming: Streaming<'a>,
+    read: u64,
+}
+
+impl<'a, R: Read> Monitored<'a, R> {
+    fn new(inner: R, streaming: Streaming<'a>) -> Self {
+        Self {
+            inner,
+            streaming,
+            read: 0,
         }
-        Err(e) => {
-            log::error!("Decryption error: {:?}", e);
-            bail!(e)
+    }
+}
+
+impl<R: Read> Read for Monitored<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.streaming.cancelled() {
+            // Not Interrupted, which readers retry
+            return Err(io::Error::other("cancelled"));
         }
-    };
+        let read = self.inner.read(buf)?;
+        self.read += read as u64;
+        if let Some(progress) = self.streaming.progress {
+            progress(self.read);
+        }
+        Ok(read)
+    }
+}
 
-    let identity_paths: Vec<_> = identities.iter().map(|p| p.as_ref().display().to_string()).collect();
-    let mut reader = decryptor.decrypt(id_refs.into_iter())
-        .with_context(|| format!(
-            "Failed to decrypt: no matching identity found. Configured identities: [{}]",
-            identity_paths.join(", ")
-        ))?;
-    reader.read_to_end(&mut decrypted)?;
-    Ok(Some(decrypted))
-}
-
-fn load_identities(identities: &[impl AsRef<Path>]) -> Result<Vec<Box<dyn Identity + Send>>> {
-    let mut all_identities: Vec<Box<dyn Identity + Send>> = vec![];
-    
-    for path in identities {
-        let path = path.as_ref();
-        let path_str = path.to_string_lossy().to_string();
-        
-        // Try parsing as plaintext identity file first
-        match IdentityFile::from_file(path_str.clone()) {
-            Ok(identity_file) => {
-                let file_identities = identity_file
-                    .with_callbacks(IdentityCallbacks)
-                    .into_identities()
-                    .with_context(|| format!("Failed to parse identities from: {:?}", path))?;
-                // Convert from Box<dyn Identity + Send + Sync> to Box<dyn Identity + Send>
-                all_identities.extend(file_identities.into_iter().map(|i| i as Box<dyn Identity + Send>));
+/// Undoes what editors and line ending conversion do to armored files, which age
+/// rejects: carriage returns and other trailing whitespace of the lines, and blank lines
+/// after the footer. Binary files pass through unchanged.
+struct ArmorLines<R> {
+    inner: BufReader<R>,
+    armored: bool,
+    line: Vec<u8>,
+    pos: usize,
+    ended: bool,
+}
+
+impl<R: Read> ArmorLines<R> {
+    fn new(inner: R, armored: bool) -> Self {
+        Self {
+            inner: BufReader::new(inner),
+            armored,
+            line: vec![],
+            pos: 0,
+            ended: false,
+        }
+    }
+}
+
+impl<R: Read> Read for ArmorLines<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.armored {
+            return self.inner.read(buf);
+        }
+        while self.pos == self.line.len() {
+            self.line.clear();
+            self.pos = 0;
+            if self.inner.read_until(b'\n', &mut self.line)? == 0 {
+                return Ok(0);
             }
-            Err(_) => {
-                // Try as encrypted identity file - decrypt it first, then parse as plaintext
-                let file = File::open(path)
-                    .with_context(|| format!("Failed to open identity file: {:?}", path))?;
-                let reader = ArmoredReader::new(BufReader::new(file));
-                
-                // Check if it's a passphrase-encrypted file and decrypt it
-                let decryptor = match Decryptor::new(reader) {
-                    Ok(d) if d.is_scrypt() => d,
-                    Ok(_) => bail!("Encrypted identity file {:?} is not passphrase-encrypted", path),
-                    Err(e) => bail!("Failed to parse encrypted identity file {:?}: {}", path, e),
-                };
-                
-                // Get passphrase from environment
-                let passphrase = env::var(AGE_PASSPHRASE_ENV)
-                    .with_context(|| format!("AGE_PASSPHRASE environment variable not set, needed to decrypt {:?}", path))?;
-                
-                // Decrypt the identity file
-                let decrypted = {
-                    let mut reader = decryptor.decrypt(std::iter::once(
-                        &age::scrypt::Identity::new(passphrase.into()) as &dyn Identity
-                    ))?;
-                    let mut buf = Vec::new();
-                    reader.read_to_end(&mut buf)?;
-                    buf
-                };
-                
-                // Parse the decrypted content as a plaintext identity file
-                let decrypted_str = String::from_utf8(decrypted)
-                    .with_context(|| format!("Decrypted identity file {:?} is not valid UTF-8", path))?;
-                
-                let identity_file = IdentityFile::from_buffer(decrypted_str.as_bytes())
-                    .with_context(|| format!("Failed to parse decrypted identity file {:?}", path))?;
-                
-                let file_identities = identity_file
-                    .with_callbacks(IdentityCallbacks)
-                    .into_identities()
-                    .with_context(|| format!("Failed to load identities from decrypted {:?}", path))?;
-                
-                all_identities.extend(file_identities.into_iter().map(|i| i as Box<dyn Identity + Send>));
+            let len = self
+                .line
+                .iter()
+                .rposition(|b| !b.is_ascii_whitespace())
+                .map_or(0, |i| i + 1);
+            self.line.truncate(len);
+            if self.ended && len == 0 {
+                continue;
             }
+            self.ended |= self.line == ARMOR_END;
+            self.line.push(b'\n');
         }
+        let len = buf.len().min(self.line.len() - self.pos);
+        buf[..len].copy_from_slice(&self.line[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
     }
-    
-    Ok(all_identities)
 }
 
-pub(crate) fn encrypt(
-    public_keys: &[impl AsRef<str> + std::fmt::Debug],
-    cleartext: &mut impl Read,
-) -> Result<Vec<u8>> {
-    let recipients = load_public_keys(public_keys)?;
-    let recipient_refs: Vec<&dyn Recipient> = recipients.iter().map(|r| r.as_ref() as &dyn Recipient).collect();
+/// Reader of the age file `encrypted` starting with `magic`, read by [`read_magic`],
+/// with its armor normalized
+fn normalized<R: Read>(magic: Vec<u8>, encrypted: R) -> Normalized<R> {
+    let armored = is_age_encrypted(&magic) && !magic.starts_with(AGE_MAGIC);
+    ArmorLines::new(io::Cursor::new(magic).chain(encrypted), armored)
+}
+
+type Normalized<R> = ArmorLines<io::Chain<io::Cursor<Vec<u8>>, R>>;
+
+fn file_passphrase() -> Vec<SecretString> {
+    request_passphrases("Enter passphrase to decrypt the file")
+}
 
-    let encryptor = Encryptor::with_recipients(recipient_refs.into_iter()).with_context(|| {
+/// What unlocked a successfully decrypted file
+enum Decrypted {
+    /// The file was encrypted with a passphrase
+    Passphrase,
+    /// An identity file, with its index if known
+    Identity(Option<usize>),
+}
+
+/// Wraps an identity to record whether it was the one unwrapping the file key, and the
+/// compression named in the header, which every identity is shown
+struct TrackedIdentity<'a> {
+    inner: &'a dyn Identity,
+    source: usize,
+    matched: &'a Cell<Option<usize>>,
+    compression: &'a OnceCell<Option<String>>,
+}
+
+impl TrackedIdentity<'_> {
+    fn track(
+        &self,
+        result: Option<std::result::Result<FileKey, DecryptError>>,
+    ) -> Option<std::result::Result<FileKey, DecryptError>> {
+        if let Some(Ok(_)) = result {
+            self.matched.set(Some(self.source));
+        }
+        result
+    }
+}
+
+impl Identity for TrackedIdentity<'_> {
+    fn unwrap_stanza(&self, stanza: &Stanza) -> Option<std::result::Result<FileKey, DecryptError>> {
+        self.track(self.inner.unwrap_stanza(stanza))
+    }
+
+    fn unwrap_stanzas(
+        &self,
+        stanzas: &[Stanza],
+    ) -> Option<std::result::Result<FileKey, DecryptError>> {
+        self.compression.get_or_init(|| {
+            let stanza = stanzas
+                .iter()
+                .find(|stanza| stanza.tag == COMPRESSION_STANZA);
+            stanza.map(|stanza| stanza.args.join(" "))
+        });
+        self.track(self.inner.unwrap_stanzas(stanzas))
+    }
+}
+
+/// Decrypts `encrypted` into `out` using the given identity files.
+/// Files encrypted with `age -p` are decrypted with the first fitting one of
+/// `passphrases` instead, as those carry a single scrypt stanza which no identity file
+/// can unwrap. It is only called for such files.
+fn decrypt_with_passphrase<W: Write>(
+    identities: &[impl AsRef<Path>],
+    encrypted: &mut impl Read,
+    out: &mut W,
+    passphrases: impl FnOnce() -> Vec<SecretString>,
+) -> Result<DecryptOutcome<Decrypted>, AgeError> {
+    let Some(decryptor) = open_encrypted(encrypted)? else {
+        return Ok(DecryptOutcome::Plain);
+    };
+
+    if decryptor.is_scrypt() {
+        let passphrases = passphrases();
+        if passphrases.is_empty() {
+            return Err(AgeError::PassphraseRequired(None));
+        }
+        let identity = Passphrases::new(&passphrases);
+        let mut reader = decryptor
+            .decrypt(std::iter::once(&identity as &dyn Identity))
+            .with_context(|| {
+                format!(
+                    "Failed to decrypt passphrase-encrypted file with {}",
+                    AGE_PASSPHRASE_ENV
+                )
+            })
+            .map_err(AgeError::IncorrectPassphrase)?;
+        io::copy(&mut reader, out)?;
+        return Ok(DecryptOutcome::Decrypted(Decrypted::Passphrase));
+    }
+
+    let id = load_identities(identities)?;
+    let id_refs: Vec<_> = id
+        .iter()
+        .map(|(source, i)| (*source, i.as_ref() as &dyn Identity))
+        .collect();
+    let outcome = decrypt_tracked(decryptor, &id_refs, out, || {
         format!(
-            "Couldn't load keys for recepients; public_keys={:?}",
-            public_keys
+            "Failed to decrypt with the configured identities: [{}]",
+            identity_names(identities)
         )
     })?;
-    let mut encrypted = vec![];
 
-    let mut writer = encryptor.wrap_output(&mut encrypted)?;
-    io::copy(cleartext, &mut writer)?;
-    writer.finish()?;
-    Ok(encrypted)
+    if let DecryptOutcome::Decrypted(Some(source)) = outcome {
+        log::debug!(
+            "Decrypted with identity; identity={}",
+            identity_name(identities[source].as_ref())
+        );
+    }
+    Ok(outcome.map(Decrypted::Identity))
 }
 
-fn load_public_keys(public_keys: &[impl AsRef<str>]) -> Result<Vec<Box<dyn Recipient + Send>>> {
-    let mut recipients: Vec<Box<dyn Recipient + Send>> = vec![];
-    let mut plugin_recipients = vec![];
-
-    for pubk in public_keys {
-        if let Ok(pk) = pubk.as_ref().parse::<age::x25519::Recipient>() {
-            recipients.push(Box::new(pk));
-        } else if let Ok(pk) = pubk.as_ref().parse::<age::ssh::Recipient>() {
-            recipients.push(Box::new(pk));
-        } else if let Ok(recipient) = pubk.as_ref().parse::<plugin::Recipient>() {
-            plugin_recipients.push(recipient);
-        } else {
-            bail!("Invalid recipient");
+/// Decrypts `encrypted` into `out` with already loaded identities, e.g. when they are
+/// obtained by an application rather than from identity files. Passphrase-encrypted
+/// files need an [`age::scrypt::Identity`] among them.
+pub fn decrypt_with<W: Write>(
+    identities: &[Box<dyn Identity>],
+    encrypted: &mut impl Read,
+    out: &mut W,
+) -> Result<DecryptOutcome<()>, AgeError> {
+    let Some(decryptor) = open_encrypted(encrypted)? else {
+        return Ok(DecryptOutcome::Plain);
+    };
+    let id_refs: Vec<_> = identities
+        .iter()
+        .map(|i| i.as_ref() as &dyn Identity)
+        .enumerate()
+        .collect();
+    let outcome = decrypt_tracked(decryptor, &id_refs, out, || {
+        "Failed to decrypt with the given identities".to_string()
+    })?;
+    Ok(outcome.map(|_| ()))
+}
+
+/// Reads the header of `encrypted`, `None` if it isn't an age file
+fn open_encrypted<R: Read>(
+    mut encrypted: R,
+) -> Result<Option<Decryptor<ArmoredReader<Normalized<R>>>>, AgeError> {
+    let magic = read_magic(&mut encrypted)?;
+    if !is_age_encrypted(&magic) {
+        // Also covers empty files and those shorter than a header
+        return Ok(None);
+    }
+    match Decryptor::new(ArmoredReader::new(normalized(magic, encrypted))) {
+        Ok(d) => Ok(Some(d)),
+        Err(DecryptError::InvalidHeader) => Ok(None),
+        Err(DecryptError::Io(e)) => {
+            match e.kind() {
+                // Age gives unexpected EOF when the file ends before its header
+                IoErrorKind::UnexpectedEof => Err(AgeError::TruncatedHeader),
+                _ => Err(e.into()),
+            }
+        }
+        Err(e) => {
+            log::error!("Decryption error: {:?}", e);
+            Err(e.into())
         }
     }
+}
 
-    for plugin_name in plugin_recipients.iter().map(|r| r.plugin()) {
-        let recipient = RecipientPluginV1::new(plugin_name, &plugin_recipients, &[], NoOpCallbacks)?;
-        recipients.push(Box::new(recipient));
+/// Decrypts with `identities`, each with the index of its source, and tells which
+/// source unwrapped the file key. Failures other than not matching get `context`.
+fn decrypt_tracked<R: Read, W: Write>(
+    decryptor: Decryptor<R>,
+    identities: &[(usize, &dyn Identity)],
+    out: &mut W,
+    context: impl FnOnce() -> String,
+) -> Result<DecryptOutcome<Option<usize>>, AgeError> {
+    let matched = Cell::new(None);
+    let compression = OnceCell::new();
+    let tracked: Vec<_> = identities
+        .iter()
+        .map(|&(source, inner)| TrackedIdentity {
+            inner,
+            source,
+            matched: &matched,
+            compression: &compression,
+        })
+        .collect();
+    let id_refs = tracked.iter().map(|i| i as &dyn Identity);
+    let mut reader = match decryptor.decrypt(id_refs.into_iter()) {
+        Ok(reader) => reader,
+        Err(DecryptError::NoMatchingKeys) => return Ok(DecryptOutcome::NoMatchingIdentity),
+        Err(err) => return Err(anyhow!(err).context(context()).into()),
+    };
+    match compression.get().cloned().flatten() {
+        None => {
+            io::copy(&mut reader, out)?;
+        }
+        Some(name) => {
+            let compression: Compression = name.parse().with_context(|| {
+                "Couldn't decompress the decrypted file, it may need a newer git-agecrypt"
+            })?;
+            log::debug!("Decompressing decrypted file; compression={}", compression);
+            match compression {
+                Compression::Zstd => zstd::stream::copy_decode(reader, out)?,
+            }
+        }
     }
+    Ok(DecryptOutcome::Decrypted(matched.get()))
+}
 
-    Ok(recipients)
+/// Reads the start of `encrypted` which [`is_age_encrypted`] looks at: enough bytes for
+/// the magic of armored files after any leading whitespace, or all of a shorter input
+fn read_magic(encrypted: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut magic = vec![];
+    loop {
+        let start = magic.iter().position(|b| !b.is_ascii_whitespace());
+        let end = start.unwrap_or(magic.len()) + ARMOR_BEGIN.len();
+        let missing = end.saturating_sub(magic.len()) as u64;
+        if missing == 0 || encrypted.by_ref().take(missing).read_to_end(&mut magic)? == 0 {
+            return Ok(magic);
+        }
+    }
 }
 
-pub(crate) fn validate_public_keys(public_keys: &[impl AsRef<str>]) -> Result<()> {
-    load_public_keys(public_keys)?;
-    Ok(())
+/// Beginnings of identities given inline instead of as a path, e.g. in an environment variable
+const INLINE_IDENTITY_PREFIXES: [&str; 2] = ["AGE-SECRET-KEY-", "AGE-PLUGIN-"];
+
+/// Whether `identity` is the contents of an identity file rather than its path: a single
+/// identity, or several lines of which one is an identity, like in `age-keygen` output
+pub fn is_inline_identity(identity: &Path) -> bool {
+    identity.to_str().is_some_and(|identity| {
+        is_identity_line(identity)
+            || (identity.contains('\n') && identity.lines().any(is_identity_line))
+    })
 }
 
-/// Validates an identity file.
-/// Returns Ok(None) for valid plaintext identities or decrypted encrypted identities.
-/// Returns Ok(Some(note)) with a note for encrypted identities when AGE_PASSPHRASE is not set.
-pub(crate) fn validate_identity(identity: impl AsRef<Path>) -> Result<Option<String>> {
-    let path = identity.as_ref();
-    let path_str = path.to_string_lossy().to_string();
-    
-    // Try parsing as plaintext identity file first
-    match IdentityFile::from_file(path_str.clone()) {
-        Ok(identity_file) => {
-            identity_file
-                .with_callbacks(IdentityCallbacks)
-                .into_identities()
-                .with_context(|| format!("Failed to parse identity from: {:?}", path))?;
-            Ok(None)
+fn is_identity_line(line: &str) -> bool {
+    let line = line.trim_start();
+    INLINE_IDENTITY_PREFIXES
+        .iter()
+        .any(|prefix| line.starts_with(prefix))
+}
+
+/// Whether `identity` looks like an inline identity in the wrong case, e.g. a key typed
+/// in lowercase, which is taken for a path but must not be revealed like one
+fn is_mistyped_identity(identity: &Path) -> bool {
+    identity.to_string_lossy().lines().any(|line| {
+        let line = line.trim_start();
+        INLINE_IDENTITY_PREFIXES.iter().any(|prefix| {
+            let start = line.get(..prefix.len()).unwrap_or_default();
+            start.eq_ignore_ascii_case(prefix)
+        })
+    })
+}
+
+/// Whether `contents` holds recipients and nothing else, like a recipients file, which
+/// is mistaken for an identity file
+fn contains_only_recipients(contents: &str) -> bool {
+    let mut lines = contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .peekable();
+    lines.peek().is_some() && lines.all(|line| parse_recipient(line).is_some())
+}
+
+/// Whether the file at `path` isn't an identity file but lists recipients
+fn is_recipients_file(path: &Path) -> bool {
+    std::fs::read_to_string(path).is_ok_and(|contents| contains_only_recipients(&contents))
+}
+
+/// Names an identity in messages without revealing inline secrets
+pub fn identity_name(identity: &Path) -> String {
+    if is_inline_identity(identity) {
+        "<inline identity>".to_string()
+    } else if is_mistyped_identity(identity) {
+        "<mistyped inline identity>".to_string()
+    } else {
+        identity.display().to_string()
+    }
+}
+
+/// Like [`identity_name`], with the paths of identity files quoted
+fn quoted_identity_name(identity: &Path) -> String {
+    if is_inline_identity(identity) || is_mistyped_identity(identity) {
+        identity_name(identity)
+    } else {
+        format!("{:?}", identity)
+    }
+}
+
+fn identity_names(identities: &[impl AsRef<Path>]) -> String {
+    let names: Vec<_> = identities
+        .iter()
+        .map(|p| identity_name(p.as_ref()))
+        .collect();
+    names.join(", ")
+}
+
+/// Loads the identities of all `identities`, each with the position of the identity file
+/// it comes from. Identity files which can't be loaded, e.g. a key missing on this
+/// machine, are skipped with a warning, like age tolerates them as long as any other
+/// identity is usable. The first error is returned if none of them can be loaded.
+fn load_identities(
+    identities: &[impl AsRef<Path>],
+) -> Result<Vec<(usize, Box<dyn Identity + Send>)>, AgeError> {
+    let mut id = vec![];
+    let mut first_err = None;
+    for (source, path) in identities.iter().enumerate() {
+        match load_identity_file(path.as_ref()) {
+            Ok(loaded) => id.extend(loaded.into_iter().map(|i| (source, i))),
+            Err(err) => {
+                log::warn!(
+                    "Skipping identity which couldn't be loaded; identity={}, error={:#}",
+                    identity_name(path.as_ref()),
+                    err
+                );
+                first_err.get_or_insert(err);
+            }
+        }
+    }
+    match first_err {
+        Some(err) if id.is_empty() => Err(err),
+        _ => Ok(id),
+    }
+}
+
+fn load_identity_file(path: &Path) -> Result<Vec<Box<dyn Identity + Send>>, AgeError> {
+    let path_str = path.to_string_lossy().to_string();
+
+    if is_inline_identity(path) {
+        let file_identities = IdentityFile::from_buffer(path_str.as_bytes())
+            .context("Failed to parse inline identity")?
+            .with_callbacks(IdentityCallbacks)
+            .into_identities()
+            .context("Failed to load inline identity")?;
+        return Ok(file_identities
+            .into_iter()
+            .map(|i| i as Box<dyn Identity + Send>)
+            .collect());
+    }
+    if !path.exists() {
+        if contains_only_recipients(&path_str) {
+            return Err(AgeError::RecipientAsIdentity(path.to_path_buf()));
+        }
+        return Err(AgeError::IdentityNotFound(path.to_path_buf()));
+    }
+
+    // Try parsing as plaintext identity file first
+    let contents = read_identity_file(path)?;
+    check_encrypted_at_rest(
+        path,
+        &contents,
+        REQUIRE_ENCRYPTED_IDENTITIES.load(Ordering::Relaxed),
+    )?;
+    match IdentityFile::from_buffer(&contents[..]) {
+        Ok(identity_file) => {
+            let file_identities = identity_file
+                .with_callbacks(IdentityCallbacks)
+                .into_identities()
+                .with_context(|| format!("Failed to parse identities from: {:?}", path))?;
+            // Convert from Box<dyn Identity + Send + Sync> to Box<dyn Identity + Send>
+            Ok(file_identities
+                .into_iter()
+                .map(|i| i as Box<dyn Identity + Send>)
+                .collect())
+        }
+        Err(parse_err) => {
+            if is_recipients_file(path) {
+                return Err(AgeError::RecipientAsIdentity(path.to_path_buf()));
+            }
+            if let Some(identity) = load_ssh_identity(path, request_passphrase)? {
+                return Ok(vec![identity]);
+            }
+            if !is_age_encrypted(&contents) {
+                return Err(unparsable_identity_file(path, parse_err));
+            }
+
+            // Try as encrypted identity file - decrypt it first, then parse as plaintext
+            let reader = ArmoredReader::new(&contents[..]);
+
+            // Check if it's a passphrase-encrypted file and decrypt it
+            let decryptor = match Decryptor::new(reader) {
+                Ok(d) if d.is_scrypt() => d,
+                Ok(_) => return Err(AgeError::ScryptUnsupported(path.to_path_buf())),
+                Err(e) => {
+                    let err = anyhow!("Failed to parse encrypted identity file {:?}: {}", path, e);
+                    return Err(err.into());
+                }
+            };
+
+            // Decrypt the identity file with the passphrases from the environment or the terminal
+            let prompt = format!("Enter passphrase for identity file {:?}", path);
+            let (decrypted, _) =
+                decrypt_identity_file(path, decryptor, &prompt, request_passphrases)?;
+
+            // Parse the decrypted content as a plaintext identity file
+            let decrypted = normalize_identity_text(&decrypted);
+            let decrypted_str = std::str::from_utf8(&decrypted).with_context(|| {
+                format!("Decrypted identity file {:?} is not valid UTF-8", path)
+            })?;
+
+            let identity_file = IdentityFile::from_buffer(decrypted_str.as_bytes())
+                .with_context(|| format!("Failed to parse decrypted identity file {:?}", path))?;
+
+            let file_identities = identity_file
+                .with_callbacks(IdentityCallbacks)
+                .into_identities()
+                .with_context(|| format!("Failed to load identities from decrypted {:?}", path))?;
+
+            Ok(file_identities
+                .into_iter()
+                .map(|i| i as Box<dyn Identity + Send>)
+                .collect())
+        }
+    }
+}
+
+/// Start of files saved as UTF-8 by some Windows editors
+const UTF8_BOM: &[u8] = b"\xef\xbb\xbf";
+
+/// Removes what editors on Windows add to identity files, which age's parser doesn't
+/// expect: a leading UTF-8 BOM and the CR of CRLF line endings. Contents which aren't
+/// text, like binary encrypted identity files, are kept as they are.
+fn normalize_identity_text(contents: &[u8]) -> zeroize::Zeroizing<Vec<u8>> {
+    let contents = contents.strip_prefix(UTF8_BOM).unwrap_or(contents);
+    match std::str::from_utf8(contents) {
+        Ok(text) if text.contains('\r') => {
+            zeroize::Zeroizing::new(text.replace("\r\n", "\n").into_bytes())
+        }
+        _ => zeroize::Zeroizing::new(contents.to_vec()),
+    }
+}
+
+/// Reads the identity file at `path`, normalized to be parsed, see
+/// [`normalize_identity_text`]
+fn read_identity_file(path: &Path) -> Result<zeroize::Zeroizing<Vec<u8>>, AgeError> {
+    let contents = zeroize::Zeroizing::new(
+        std::fs::read(path).with_context(|| format!("Failed to read identity file: {:?}", path))?,
+    );
+    Ok(normalize_identity_text(&contents))
+}
+
+/// The error for a text file which is neither an identity file, an SSH key nor an
+/// encrypted identity file, with the reason age's parser gave
+fn unparsable_identity_file(path: &Path, parse_err: io::Error) -> AgeError {
+    anyhow!("Failed to parse identity file {:?}: {}", path, parse_err).into()
+}
+
+/// Generates an x25519 identity, returning the contents of its identity file and its
+/// public key. With a `passphrase`, the identity file is encrypted like by `age -p -a`.
+pub fn generate_identity(passphrase: Option<SecretString>) -> Result<(Vec<u8>, String)> {
+    let identity = age::x25519::Identity::generate();
+    let public_key = identity.to_public().to_string();
+    let contents = zeroize::Zeroizing::new(format!(
+        "# public key: {}\n{}\n",
+        public_key,
+        identity.to_string().expose_secret()
+    ));
+    match passphrase {
+        Some(passphrase) => {
+            let encrypted = encrypt_identity_file(contents.as_bytes(), passphrase)?;
+            Ok((encrypted, public_key))
+        }
+        None => Ok((contents.as_bytes().to_vec(), public_key)),
+    }
+}
+
+/// Encrypts the contents of an identity file with `passphrase`, like `age -p -a`
+fn encrypt_identity_file(contents: &[u8], passphrase: SecretString) -> Result<Vec<u8>> {
+    let mut encrypted = vec![];
+    let armored = ArmoredWriter::wrap_output(&mut encrypted, Format::AsciiArmor)?;
+    let mut writer = Encryptor::with_user_passphrase(passphrase).wrap_output(armored)?;
+    writer.write_all(contents)?;
+    writer.finish()?.finish()?;
+    Ok(encrypted)
+}
+
+/// Re-encrypts the `contents` of the passphrase-encrypted identity file at `path` with
+/// `new_passphrase`, e.g. when passphrases are rotated. The current passphrase is taken
+/// like for decryption. Plaintext identity files are only encrypted if `encrypt` is set.
+pub fn rekey_identity(
+    path: &Path,
+    contents: &[u8],
+    encrypt: bool,
+    new_passphrase: impl FnOnce() -> Result<SecretString>,
+) -> Result<Vec<u8>> {
+    rekey_identity_with(path, contents, encrypt, request_passphrases, new_passphrase)
+}
+
+/// Like [`rekey_identity`], trying the current passphrases from `current_passphrases`,
+/// called with the prompt to show
+fn rekey_identity_with(
+    path: &Path,
+    contents: &[u8],
+    encrypt: bool,
+    current_passphrases: impl FnOnce(&str) -> Vec<SecretString>,
+    new_passphrase: impl FnOnce() -> Result<SecretString>,
+) -> Result<Vec<u8>> {
+    let (plaintext, current) = match Decryptor::new(ArmoredReader::new(contents)) {
+        Ok(decryptor) if decryptor.is_scrypt() => {
+            let prompt = format!("Enter current passphrase for identity file {:?}", path);
+            let (plaintext, passphrase) =
+                decrypt_identity_file(path, decryptor, &prompt, current_passphrases)?;
+            (plaintext, Some(passphrase))
+        }
+        Ok(_) => return Err(AgeError::ScryptUnsupported(path.to_path_buf()).into()),
+        Err(_) if encrypt => (zeroize::Zeroizing::new(contents.to_vec()), None),
+        Err(_) => bail!(
+            "Identity file {:?} isn't passphrase-encrypted, use --encrypt to protect it with one",
+            path
+        ),
+    };
+
+    // Anything else, like SSH keys, couldn't be used once encrypted
+    let normalized = normalize_identity_text(&plaintext);
+    let has_identities = IdentityFile::from_buffer(&normalized[..]).is_ok_and(|file| {
+        file.with_callbacks(IdentityCallbacks)
+            .into_identities()
+            .is_ok_and(|identities| !identities.is_empty())
+    });
+    if !has_identities {
+        bail!(
+            "{:?} isn't an age identity file, only those can be re-encrypted",
+            path
+        );
+    }
+
+    let passphrase = new_passphrase()?;
+    if current.is_some_and(|current| current.expose_secret() == passphrase.expose_secret()) {
+        bail!("The new passphrase is the same as the current one");
+    }
+    encrypt_identity_file(&plaintext, passphrase)
+}
+
+/// Decrypts the passphrase-encrypted identity file at `path`, with the first fitting
+/// one of the passphrases from `passphrases`, called with `prompt`. Returns the
+/// plaintext and the passphrase. Fails only if none of them fits.
+fn decrypt_identity_file<R: Read>(
+    path: &Path,
+    decryptor: Decryptor<R>,
+    prompt: &str,
+    passphrases: impl FnOnce(&str) -> Vec<SecretString>,
+) -> Result<(zeroize::Zeroizing<Vec<u8>>, SecretString), AgeError> {
+    let mut passphrases = passphrases(prompt);
+    if passphrases.is_empty() {
+        return Err(AgeError::PassphraseRequired(Some(format!("{:?}", path))));
+    }
+    let identity = Passphrases::new(&passphrases);
+    let mut plaintext = zeroize::Zeroizing::new(vec![]);
+    decryptor
+        .decrypt(std::iter::once(&identity as &dyn Identity))
+        .with_context(|| {
+            format!(
+                "Failed to decrypt identity file {:?} with {} passphrase(s)",
+                path,
+                passphrases.len()
+            )
+        })
+        .map_err(AgeError::IncorrectPassphrase)?
+        .read_to_end(&mut plaintext)?;
+    let matched = identity.matched.get().unwrap_or_default();
+    Ok((plaintext, passphrases.swap_remove(matched)))
+}
+
+/// Recipients of the identities in identity files or inline identities, so that they
+/// can decrypt what is encrypted to them. Passphrase-encrypted identity files are
+/// decrypted first, the recipients of plugin identities are provided by their plugin.
+/// SSH keys aren't supported, their public keys have to be used instead.
+pub fn identity_recipients(
+    identities: &[impl AsRef<Path>],
+) -> Result<Vec<Box<dyn Recipient + Send>>, AgeError> {
+    let mut recipients = vec![];
+    for identity in identities {
+        let identity = identity.as_ref();
+        let contents = age_identity_file(identity)?;
+        let derived = IdentityFile::from_buffer(&contents[..])?
+            .with_callbacks(IdentityCallbacks)
+            .to_recipients()
+            .with_context(|| {
+                format!(
+                    "Couldn't derive recipients from {}",
+                    identity_name(identity)
+                )
+            })?;
+        log::info!(
+            "Encrypting to the recipients of identity; identity={}, recipients={}",
+            identity_name(identity),
+            derived.len()
+        );
+        recipients.extend(derived);
+    }
+    Ok(recipients)
+}
+
+/// Recipients of the identities in `identity`, an identity file or inline identity, as
+/// they are listed in the configuration. Passphrase-encrypted identity files are
+/// decrypted first. The recipients of plugin identities and SSH keys can't be derived
+/// here, which is an error explaining where to get them instead.
+pub fn recipients_of_identity(identity: &Path) -> Result<Vec<String>, AgeError> {
+    x25519_recipients(identity, &age_identity_file(identity)?)
+}
+
+/// Recipients of the identities in the plaintext `contents` of `identity`, failing
+/// unless all are x25519 ones
+fn x25519_recipients(identity: &Path, contents: &[u8]) -> Result<Vec<String>, AgeError> {
+    let text = std::str::from_utf8(contents).unwrap_or_default();
+    let mut recipients = vec![];
+    for line in identity_lines(text) {
+        if line.starts_with("AGE-PLUGIN-") {
+            return Err(underivable(identity, Underivable::Plugin));
+        }
+        let key = line
+            .parse::<age::x25519::Identity>()
+            .map_err(|err| anyhow!("Invalid identity in {}: {}", identity_name(identity), err))?;
+        recipients.push(key.to_public().to_string());
+    }
+    Ok(recipients)
+}
+
+/// The plaintext contents of `identity`, an identity file or inline identity, if it
+/// holds age identities, else the error telling what to use instead
+fn age_identity_file(identity: &Path) -> Result<zeroize::Zeroizing<Vec<u8>>, AgeError> {
+    let contents = identity_file_contents(identity)?;
+    let text = std::str::from_utf8(&contents).unwrap_or_default();
+    if identity_lines(text).next().is_some() && IdentityFile::from_buffer(&contents[..]).is_ok() {
+        return Ok(contents);
+    }
+    if !is_inline_identity(identity) && read_ssh_identity(identity)?.is_some() {
+        return Err(underivable(identity, Underivable::Ssh));
+    }
+    Err(underivable(identity, Underivable::NotAge))
+}
+
+/// The lines of an identity file holding identities, without comments
+fn identity_lines(contents: &str) -> impl Iterator<Item = &str> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+}
+
+/// Keys whose recipients can't be derived from an identity file
+enum Underivable {
+    Ssh,
+    Plugin,
+    NotAge,
+}
+
+/// Why the recipients of `identity` can't be derived from it, and where to get them
+/// instead. The one wording of everything deriving recipients.
+fn underivable(identity: &Path, keys: Underivable) -> AgeError {
+    match keys {
+        Underivable::Ssh => anyhow!(
+            "Can't derive the recipient of the SSH key {0}, encrypt to its public key \
+             instead, e.g. {0}.pub or as printed by ssh-keygen -y -f {0}",
+            identity.display()
+        ),
+        Underivable::Plugin => anyhow!(
+            "{} holds plugin identities, whose recipients only their plugin knows, e.g. \
+             age-plugin-yubikey --list prints them",
+            identity_name(identity)
+        ),
+        Underivable::NotAge => anyhow!("{} isn't an age identity file", identity_name(identity)),
+    }
+    .into()
+}
+
+/// The plaintext contents of an identity file, or of an inline identity
+fn identity_file_contents(identity: &Path) -> Result<zeroize::Zeroizing<Vec<u8>>, AgeError> {
+    if is_inline_identity(identity) {
+        return Ok(zeroize::Zeroizing::new(
+            identity.to_string_lossy().as_bytes().to_vec(),
+        ));
+    }
+    if !identity.exists() {
+        return Err(AgeError::IdentityNotFound(identity.to_path_buf()));
+    }
+    let contents = read_identity_file(identity)?;
+    match Decryptor::new(ArmoredReader::new(&contents[..])) {
+        Ok(decryptor) if decryptor.is_scrypt() => {
+            let prompt = format!("Enter passphrase for identity file {:?}", identity);
+            let decrypted =
+                decrypt_identity_file(identity, decryptor, &prompt, request_passphrases)?.0;
+            Ok(normalize_identity_text(&decrypted))
+        }
+        Ok(_) => Err(AgeError::ScryptUnsupported(identity.to_path_buf())),
+        Err(_) => Ok(contents),
+    }
+}
+
+/// Gets the passphrase for a new identity file from AGE_PASSPHRASE or the passphrase
+/// getter, or asks for it twice on the terminal
+pub fn new_passphrase() -> Result<SecretString> {
+    if let Some(passphrase) = configured_passphrase() {
+        return Ok(passphrase);
+    }
+    prompt_new_passphrase("Enter passphrase for the new identity: ")
+}
+
+/// Gets the passphrase to re-encrypt an identity file with from `file`, or asks for it
+/// twice on the terminal. AGE_PASSPHRASE and the passphrase getter give the current one.
+pub fn rotated_passphrase(file: Option<&Path>) -> Result<SecretString> {
+    let Some(file) = file else {
+        return prompt_new_passphrase("Enter new passphrase for the identity file: ");
+    };
+    let passphrase = read_passphrase_file(file)
+        .with_context(|| format!("Couldn't read the new passphrase from {:?}", file))?;
+    if passphrase.expose_secret().is_empty() {
+        bail!("Passphrase can't be empty");
+    }
+    Ok(passphrase)
+}
+
+/// Asks for a new passphrase on the terminal, and again to confirm it
+fn prompt_new_passphrase(prompt: &str) -> Result<SecretString> {
+    let passphrase = tty::read_secret(prompt).with_context(|| {
+        format!(
+            "No terminal to ask for a passphrase, set {}",
+            AGE_PASSPHRASE_ENV
+        )
+    })?;
+    let confirmed =
+        tty::read_secret("Confirm passphrase: ").context("Passphrase wasn't confirmed")?;
+    if passphrase.expose_secret() != confirmed.expose_secret() {
+        bail!("Passphrases didn't match");
+    }
+    if passphrase.expose_secret().is_empty() {
+        bail!("Passphrase can't be empty");
+    }
+    Ok(passphrase)
+}
+
+/// Recipients of `identity` which can be derived without plugins or passphrases: those
+/// of the x25519 identities of plaintext identity files and inline identities, and the
+/// public key of an SSH key, read from `<key>.pub` next to it. `None` if any identity in
+/// it can't be derived, e.g. of plugins or in passphrase-encrypted identity files.
+pub fn derivable_recipients(identity: &Path) -> Option<Vec<String>> {
+    let contents = if is_inline_identity(identity) {
+        zeroize::Zeroizing::new(identity.to_string_lossy().as_bytes().to_vec())
+    } else if read_ssh_identity(identity).ok()?.is_some() {
+        let public_key = std::fs::read_to_string(format!("{}.pub", identity.display())).ok()?;
+        let public_key = recipient_key(&public_key);
+        return parse_recipient(&public_key).map(|_| vec![public_key]);
+    } else {
+        read_identity_file(identity).ok()?
+    };
+    // Passphrase-encrypted identity files aren't x25519 identities
+    let recipients = x25519_recipients(identity, &contents).ok()?;
+    (!recipients.is_empty()).then_some(recipients)
+}
+
+/// The key of `recipient`, without its label and, for SSH keys, their comment
+fn recipient_key(recipient: &str) -> String {
+    let (key, _) = split_label(recipient);
+    key.split_whitespace().take(2).collect::<Vec<_>>().join(" ")
+}
+
+/// Whether `a` and `b` are the same recipient, regardless of labels and SSH comments
+pub fn same_recipient(a: &str, b: &str) -> bool {
+    recipient_key(a) == recipient_key(b)
+}
+
+/// Reads `path` as an OpenSSH private key, returning `None` when it isn't one
+fn read_ssh_identity(path: &Path) -> Result<Option<age::ssh::Identity>> {
+    let file =
+        File::open(path).with_context(|| format!("Failed to open identity file: {:?}", path))?;
+    let filename = Some(path.to_string_lossy().to_string());
+    Ok(age::ssh::Identity::from_buffer(BufReader::new(file), filename).ok())
+}
+
+/// Loads an SSH private key identity, returning `None` when `path` isn't one.
+/// Passphrase-protected keys are decrypted with the result of `passphrase`, called
+/// with the prompt to show, like passphrase-encrypted identity files.
+fn load_ssh_identity(
+    path: &Path,
+    passphrase: impl FnOnce(&str) -> Option<SecretString>,
+) -> Result<Option<Box<dyn Identity + Send>>, AgeError> {
+    let identity = match read_ssh_identity(path)? {
+        None => return Ok(None),
+        Some(age::ssh::Identity::Encrypted(key)) => {
+            let passphrase = passphrase(&format!("Enter passphrase for SSH key {:?}", path))
+                .ok_or_else(|| AgeError::PassphraseRequired(Some(format!("SSH key {:?}", path))))?;
+            let key = key.decrypt(passphrase).map_err(|_| {
+                AgeError::IncorrectPassphrase(anyhow!(
+                    "incorrect passphrase for SSH key {:?}",
+                    path
+                ))
+            })?;
+            age::ssh::Identity::Unencrypted(key)
+        }
+        Some(age::ssh::Identity::Unsupported(key)) => {
+            return Err(anyhow!("Unsupported SSH key {:?}: {:?}", path, key).into())
+        }
+        Some(identity) => identity,
+    };
+    Ok(Some(Box::new(identity.with_callbacks(IdentityCallbacks))))
+}
+
+/// Output format of encrypted files
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Encoding {
+    /// Raw age format
+    #[default]
+    Binary,
+    /// PEM-style ASCII armor
+    Armored,
+}
+
+impl From<Encoding> for Format {
+    fn from(value: Encoding) -> Self {
+        match value {
+            Encoding::Binary => Format::Binary,
+            Encoding::Armored => Format::AsciiArmor,
+        }
+    }
+}
+
+/// The encoding of the age file `encrypted`
+pub fn encoding_of(encrypted: &[u8]) -> Encoding {
+    if encrypted.starts_with(AGE_MAGIC) {
+        Encoding::Binary
+    } else {
+        Encoding::Armored
+    }
+}
+
+/// Converts the age file `encrypted` to `encoding` without decrypting it, as armor is
+/// just base64 framing of the binary format. `None` if it has that encoding already.
+pub fn reencode(encrypted: &[u8], encoding: Encoding) -> Result<Option<Vec<u8>>, AgeError> {
+    if !is_age_encrypted(encrypted) {
+        return Err(anyhow!("Content isn't age encrypted").into());
+    }
+    if encoding_of(encrypted) == encoding {
+        return Ok(None);
+    }
+    let mut binary = vec![];
+    ArmoredReader::new(encrypted).read_to_end(&mut binary)?;
+    if encoding == Encoding::Binary {
+        return Ok(Some(binary));
+    }
+    let mut armored = vec![];
+    let mut writer = ArmoredWriter::wrap_output(&mut armored, Format::AsciiArmor)?;
+    writer.write_all(&binary)?;
+    writer.finish()?;
+    Ok(Some(armored))
+}
+
+/// Encrypts `cleartext` to x25519, SSH or plugin public keys.
+pub fn encrypt(
+    public_keys: &[impl AsRef<str> + std::fmt::Debug],
+    cleartext: &mut impl Read,
+    encoding: Encoding,
+) -> Result<Vec<u8>, AgeError> {
+    let mut encrypted = vec![];
+    encrypt_to(public_keys, cleartext, &mut encrypted, encoding)?;
+    Ok(encrypted)
+}
+
+/// Streams the ciphertext of `cleartext` into `out` without buffering it.
+pub fn encrypt_to<W: Write>(
+    public_keys: &[impl AsRef<str> + std::fmt::Debug],
+    cleartext: &mut impl Read,
+    out: &mut W,
+    encoding: Encoding,
+) -> Result<(), AgeError> {
+    encrypt_to_identities(public_keys, &[] as &[&Path], cleartext, out, encoding)
+}
+
+/// Like [`encrypt_to`], also encrypting to the recipients of `identities`, so that
+/// they can decrypt the result, see [`identity_recipients`]
+pub fn encrypt_to_identities<W: Write>(
+    public_keys: &[impl AsRef<str> + std::fmt::Debug],
+    identities: &[impl AsRef<Path>],
+    cleartext: &mut impl Read,
+    out: &mut W,
+    encoding: Encoding,
+) -> Result<(), AgeError> {
+    let streaming = Streaming::default();
+    encrypt_streaming(public_keys, identities, cleartext, out, encoding, streaming)
+}
+
+/// Like [`encrypt_to_identities`], reporting the progress of reading `cleartext` and
+/// stopping when cancelled, see [`Streaming`]
+pub fn encrypt_streaming<W: Write>(
+    public_keys: &[impl AsRef<str> + std::fmt::Debug],
+    identities: &[impl AsRef<Path>],
+    cleartext: &mut impl Read,
+    out: &mut W,
+    encoding: Encoding,
+    streaming: Streaming,
+) -> Result<(), AgeError> {
+    let mut cleartext = Monitored::new(cleartext, streaming);
+    streaming.checked(encrypt_monitored(
+        public_keys,
+        identities,
+        &mut cleartext,
+        out,
+        encoding,
+    ))
+}
+
+fn encrypt_monitored<W: Write>(
+    public_keys: &[impl AsRef<str> + std::fmt::Debug],
+    identities: &[impl AsRef<Path>],
+    cleartext: &mut impl Read,
+    out: &mut W,
+    encoding: Encoding,
+) -> Result<(), AgeError> {
+    for public_key in public_keys {
+        let recipient = describe_recipient(public_key.as_ref());
+        log::info!("Encrypting to recipient; recipient={}", recipient);
+    }
+    if let Some(key) = DETERMINISTIC_KEY.get() {
+        let key = key
+            .as_ref()
+            .map_err(|err| anyhow!("output.deterministic is set, but {}", err))?;
+        return encrypt_deterministic(key, public_keys, identities, cleartext, out, encoding);
+    }
+    let mut recipients = load_public_keys(public_keys)?;
+    recipients.extend(identity_recipients(identities)?);
+    let recipients: Vec<_> = recipients
+        .into_iter()
+        .map(|r| r as Box<dyn Recipient>)
+        .collect();
+    encrypt_with(&recipients, cleartext, out, encoding)
+}
+
+/// Encrypts to the X25519 recipients among `public_keys` and those of `identities`
+/// with everything random derived from `key` and `cleartext`, see
+/// [`set_deterministic_key`]
+fn encrypt_deterministic<W: Write>(
+    key: &deterministic::Key,
+    public_keys: &[impl AsRef<str>],
+    identities: &[impl AsRef<Path>],
+    cleartext: &mut impl Read,
+    out: &mut W,
+    encoding: Encoding,
+) -> Result<(), AgeError> {
+    let mut recipients = vec![];
+    for public_key in public_keys {
+        match parse_recipient(public_key.as_ref()) {
+            Some(ParsedRecipient::X25519(pk)) => recipients.push(pk.to_string()),
+            _ => {
+                return Err(anyhow!(
+                    "output.deterministic only supports X25519 recipients, not {}",
+                    describe_recipient(public_key.as_ref())
+                )
+                .into())
+            }
+        }
+    }
+    for identity in identities {
+        recipients.extend(recipients_of_identity(identity.as_ref())?);
+    }
+    if recipients.is_empty() {
+        return Err(AgeError::NoRecipients);
+    }
+    // The order of the stanzas doesn't depend on how the recipients are configured
+    recipients.sort();
+    recipients.dedup();
+
+    let mut plaintext = zeroize::Zeroizing::new(vec![]);
+    cleartext.read_to_end(&mut plaintext)?;
+    let compression = COMPRESSION.get().copied();
+    if let Some(Compression::Zstd) = compression {
+        plaintext = zeroize::Zeroizing::new(zstd::stream::encode_all(&plaintext[..], ZSTD_LEVEL)?);
+    }
+    let extra = extra_stanzas(COMMENT.get().map(String::as_str), compression);
+    let mut armored = ArmoredWriter::wrap_output(out, encoding.into())?;
+    deterministic::encrypt(key, &recipients, &extra, &plaintext, &mut armored)?;
+    armored.finish()?;
+    Ok(())
+}
+
+/// Streams the ciphertext of `cleartext` into `out` for already parsed recipients, e.g.
+/// when they are obtained by an application rather than as strings
+pub fn encrypt_with<W: Write>(
+    recipients: &[Box<dyn Recipient>],
+    cleartext: &mut impl Read,
+    out: &mut W,
+    encoding: Encoding,
+) -> Result<(), AgeError> {
+    let comment = COMMENT.get().map(String::as_str);
+    encrypt_commented(
+        recipients,
+        comment,
+        COMPRESSION.get().copied(),
+        cleartext,
+        out,
+        encoding,
+    )
+}
+
+/// Like [`encrypt_with`], adding `comment` to the header and compressing the plaintext
+/// with `compression`
+fn encrypt_commented<W: Write>(
+    recipients: &[Box<dyn Recipient>],
+    comment: Option<&str>,
+    compression: Option<Compression>,
+    cleartext: &mut impl Read,
+    out: &mut W,
+    encoding: Encoding,
+) -> Result<(), AgeError> {
+    if recipients.is_empty() {
+        // Nobody could decrypt the result
+        return Err(AgeError::NoRecipients);
+    }
+    let stanzas = extra_stanzas(comment, compression);
+    let annotated = (!stanzas.is_empty()).then(|| Annotated {
+        inner: recipients[0].as_ref(),
+        stanzas,
+    });
+    let mut recipient_refs: Vec<&dyn Recipient> = recipients
+        .iter()
+        .map(|r| r.as_ref() as &dyn Recipient)
+        .collect();
+    if let Some(annotated) = &annotated {
+        recipient_refs[0] = annotated;
+    }
+
+    let encryptor = Encryptor::with_recipients(recipient_refs.into_iter())
+        .context("Couldn't encrypt to the recipients")?;
+
+    let armored = ArmoredWriter::wrap_output(out, encoding.into())?;
+    let mut writer = encryptor.wrap_output(armored)?;
+    match compression {
+        Some(Compression::Zstd) => {
+            let mut compressor = zstd::stream::write::Encoder::new(writer, ZSTD_LEVEL)?;
+            io::copy(cleartext, &mut compressor)?;
+            writer = compressor.finish()?;
+        }
+        None => {
+            io::copy(cleartext, &mut writer)?;
+        }
+    }
+    writer.finish()?.finish()?;
+    Ok(())
+}
+
+/// Adds the stanzas of the comment and the compression to the stanzas of `inner`.
+/// Wrapping a recipient keeps its labels, so that they are compatible with any
+/// recipients `inner` is.
+struct Annotated<'a> {
+    inner: &'a dyn Recipient,
+    stanzas: Vec<Stanza>,
+}
+
+impl Recipient for Annotated<'_> {
+    fn wrap_file_key(
+        &self,
+        file_key: &FileKey,
+    ) -> std::result::Result<(Vec<Stanza>, HashSet<String>), EncryptError> {
+        let (mut stanzas, labels) = self.inner.wrap_file_key(file_key)?;
+        stanzas.extend(self.stanzas.iter().cloned());
+        Ok((stanzas, labels))
+    }
+}
+
+/// A recipient stanza from the header of an age file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecipientStanzaInfo {
+    /// Stanza type, e.g. `X25519` or `ssh-ed25519`
+    pub kind: String,
+    /// The stanza's arguments, e.g. the tag identifying the key of SSH stanzas
+    pub args: Vec<String>,
+}
+
+/// Parses the recipient stanzas from the header of an age file, binary or armored,
+/// without decrypting it. The stanzas of the comment and the compression are left out.
+pub fn recipients_of(encrypted: &mut impl Read) -> Result<Vec<RecipientStanzaInfo>> {
+    Ok(header_stanzas(encrypted)?
+        .into_iter()
+        .filter(|stanza| ![COMMENT_STANZA, COMPRESSION_STANZA].contains(&stanza.kind.as_str()))
+        .collect())
+}
+
+/// The comment in the header of an age file, binary or armored, see [`set_comment`]
+pub fn comment_of(encrypted: &mut impl Read) -> Result<Option<String>> {
+    Ok(header_stanzas(encrypted)?
+        .into_iter()
+        .find(|stanza| stanza.kind == COMMENT_STANZA)
+        .and_then(|stanza| stanza.args.into_iter().next()))
+}
+
+/// Parses the stanzas from the header of an age file, except grease
+fn header_stanzas(encrypted: &mut impl Read) -> Result<Vec<RecipientStanzaInfo>> {
+    let magic = read_magic(encrypted)?;
+    let mut header = BufReader::new(ArmoredReader::new(normalized(magic, encrypted)));
+    let mut line = String::new();
+    header.read_line(&mut line)?;
+    if line != "age-encryption.org/v1\n" {
+        bail!("Not an age encrypted file");
+    }
+
+    let mut recipients = vec![];
+    loop {
+        line.clear();
+        if header.read_line(&mut line)? == 0 {
+            bail!("Age header is truncated");
+        }
+        if line.starts_with("---") {
+            return Ok(recipients);
+        }
+        let Some(stanza) = line.strip_prefix("-> ") else {
+            // Stanza body
+            continue;
+        };
+        let mut args = stanza.split_whitespace().map(String::from);
+        let Some(kind) = args.next() else {
+            bail!("Age header contains a stanza without type");
+        };
+        // Random stanzas age adds to keep clients from relying on the header layout
+        if !kind.ends_with("-grease") {
+            recipients.push(RecipientStanzaInfo {
+                kind,
+                args: args.collect(),
+            });
+        }
+    }
+}
+
+/// How a stanza names its recipient
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum StanzaRecipient {
+    /// SSH stanzas carry a tag derived from the recipient's key
+    Ssh { kind: String, tag: String },
+    /// X25519 stanzas don't reveal who they are for
+    X25519,
+    /// Neither do plugin stanzas, e.g. YubiKeys
+    Plugin,
+}
+
+impl From<&RecipientStanzaInfo> for StanzaRecipient {
+    fn from(info: &RecipientStanzaInfo) -> Self {
+        match info.kind.as_str() {
+            "ssh-ed25519" | "ssh-rsa" => Self::Ssh {
+                kind: info.kind.clone(),
+                tag: info.args.first().cloned().unwrap_or_default(),
+            },
+            "X25519" => Self::X25519,
+            _ => Self::Plugin,
+        }
+    }
+}
+
+impl StanzaRecipient {
+    fn is_anonymous(&self) -> bool {
+        !matches!(self, Self::Ssh { .. })
+    }
+}
+
+impl std::fmt::Display for StanzaRecipient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Ssh { kind, tag } => write!(f, "{} key tagged {}", kind, tag),
+            Self::X25519 => write!(f, "x25519 recipient"),
+            Self::Plugin => write!(f, "plugin recipient"),
         }
-        Err(_) => {
+    }
+}
+
+/// Differences between the recipients of an encrypted file and the configured ones
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RecipientsAudit {
+    /// Configured recipients the file isn't encrypted to
+    pub missing: Vec<String>,
+    /// Recipients the file is encrypted to which aren't configured
+    pub unknown: Vec<String>,
+    /// Whether x25519 or plugin recipients were only compared by their number
+    pub anonymous: bool,
+}
+
+impl RecipientsAudit {
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.unknown.is_empty()
+    }
+}
+
+/// Compares the recipients `encrypted` is encrypted to with `public_keys`.
+/// SSH recipients are matched by their key, x25519 and plugin recipients can only be
+/// counted, as age doesn't reveal who they are.
+pub fn audit_recipients(
+    public_keys: &[impl AsRef<str>],
+    encrypted: &[u8],
+) -> Result<RecipientsAudit> {
+    let mut existing: Vec<StanzaRecipient> = recipients_of(&mut &encrypted[..])?
+        .iter()
+        .map(StanzaRecipient::from)
+        .collect();
+    let expected = expected_recipients(public_keys)?;
+
+    let mut audit = RecipientsAudit {
+        anonymous: expected.iter().any(|(r, _)| r.is_anonymous()),
+        ..Default::default()
+    };
+    let mut missing_anonymous: Vec<StanzaRecipient> = vec![];
+    for (recipient, configured) in expected {
+        match existing.iter().position(|r| *r == recipient) {
+            Some(i) => {
+                existing.remove(i);
+            }
+            None if recipient.is_anonymous() => missing_anonymous.push(recipient),
+            None => audit.missing.push(configured),
+        }
+    }
+    audit.missing.extend(count_by_kind(&missing_anonymous));
+    audit.unknown = existing
+        .iter()
+        .filter(|r| !r.is_anonymous())
+        .map(|r| r.to_string())
+        .collect();
+    let unknown_anonymous: Vec<_> = existing.into_iter().filter(|r| r.is_anonymous()).collect();
+    audit.unknown.extend(count_by_kind(&unknown_anonymous));
+    Ok(audit)
+}
+
+/// Who a recipient stanza of an encrypted file is for, see [`identify_recipients`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StanzaOwner {
+    /// One of the given public keys, as it was given
+    Known(String),
+    /// An SSH key which isn't among the given ones
+    Unknown,
+    /// x25519 and plugin stanzas don't reveal their recipient, it may be any of the
+    /// given public keys of the same type
+    Anonymous(Vec<String>),
+    /// A stanza git-agecrypt doesn't encrypt to, e.g. of a passphrase-encrypted file
+    Unsupported,
+}
+
+/// A recipient stanza of an encrypted file and who it is for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdentifiedStanza {
+    /// The stanza, like "ssh-ed25519 key tagged dGFn" or "plugin recipient (piv-p256)"
+    pub description: String,
+    pub owner: StanzaOwner,
+}
+
+/// Tells for each recipient stanza of `encrypted` which of `public_keys` it is for, as
+/// far as the header reveals it, without decrypting anything
+pub fn identify_recipients(
+    public_keys: &[impl AsRef<str>],
+    encrypted: &[u8],
+) -> Result<Vec<IdentifiedStanza>> {
+    let expected = expected_recipients(public_keys)?;
+    let candidates = |kind: &StanzaRecipient| -> Vec<String> {
+        expected
+            .iter()
+            .filter(|(recipient, _)| recipient == kind)
+            .map(|(_, configured)| configured.clone())
+            .collect()
+    };
+    let mut identified = vec![];
+    for stanza in recipients_of(&mut &encrypted[..])? {
+        let recipient = StanzaRecipient::from(&stanza);
+        let (description, owner) = match &recipient {
+            _ if stanza.kind == "scrypt" => {
+                ("scrypt passphrase".to_string(), StanzaOwner::Unsupported)
+            }
+            StanzaRecipient::Ssh { .. } => {
+                let owner = match expected.iter().find(|(r, _)| *r == recipient) {
+                    Some((_, configured)) => StanzaOwner::Known(configured.clone()),
+                    None => StanzaOwner::Unknown,
+                };
+                (recipient.to_string(), owner)
+            }
+            StanzaRecipient::X25519 => (
+                recipient.to_string(),
+                StanzaOwner::Anonymous(candidates(&recipient)),
+            ),
+            StanzaRecipient::Plugin => (
+                format!("{} ({})", recipient, stanza.kind),
+                StanzaOwner::Anonymous(candidates(&recipient)),
+            ),
+        };
+        identified.push(IdentifiedStanza { description, owner });
+    }
+    Ok(identified)
+}
+
+/// Describes anonymous recipients like "2 x25519 recipients"
+fn count_by_kind(recipients: &[StanzaRecipient]) -> Vec<String> {
+    [StanzaRecipient::X25519, StanzaRecipient::Plugin]
+        .into_iter()
+        .filter_map(|kind| {
+            let count = recipients.iter().filter(|r| **r == kind).count();
+            (count > 0).then(|| format!("{} {}{}", count, kind, if count > 1 { "s" } else { "" }))
+        })
+        .collect()
+}
+
+/// How the recipients of an encrypted file relate to the currently configured ones
+#[derive(Debug, PartialEq, Eq)]
+pub enum RecipientsState {
+    /// Encrypted to exactly the current recipients
+    Current,
+    /// Encrypted to a different set of recipients
+    Stale,
+    /// Encrypted to as many x25519 and plugin recipients as configured, but age
+    /// doesn't reveal whether those are the same ones
+    Unverifiable,
+}
+
+/// Compares the recipients `encrypted` is encrypted to with `public_keys`
+pub fn recipients_state(
+    public_keys: &[impl AsRef<str>],
+    encrypted: &[u8],
+) -> Result<RecipientsState> {
+    let audit = audit_recipients(public_keys, encrypted)?;
+    Ok(if !audit.is_ok() {
+        RecipientsState::Stale
+    } else if audit.anonymous {
+        RecipientsState::Unverifiable
+    } else {
+        RecipientsState::Current
+    })
+}
+
+/// Whether `encrypted` is already encrypted to exactly `public_keys`.
+/// Only SSH recipients can be recognized in a header, so files encrypted to any
+/// x25519 or plugin recipient are never considered up to date.
+pub fn is_encrypted_to(public_keys: &[impl AsRef<str>], encrypted: &[u8]) -> Result<bool> {
+    Ok(recipients_state(public_keys, encrypted)? == RecipientsState::Current)
+}
+
+/// Whether `contents` starts with an age header, binary or armored
+pub fn is_encrypted(contents: &[u8]) -> bool {
+    is_age_encrypted(contents) && recipients_of(&mut &contents[..]).is_ok()
+}
+
+const AGE_MAGIC: &[u8] = b"age-encryption.org/v1";
+const ARMOR_BEGIN: &[u8] = b"-----BEGIN AGE ENCRYPTED FILE-----";
+const ARMOR_END: &[u8] = b"-----END AGE ENCRYPTED FILE-----";
+
+/// Whether `header` starts like an age file, binary or armored. Only looks at the magic
+/// bytes, so that plaintext can be told apart without parsing a header, but a `true`
+/// doesn't mean the header is valid, see [`is_encrypted`].
+pub fn is_age_encrypted(header: &[u8]) -> bool {
+    // Armored files may start with whitespace
+    let start = header
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(header.len());
+    header.starts_with(AGE_MAGIC) || header[start..].starts_with(ARMOR_BEGIN)
+}
+
+/// The stanzas encrypting to `public_keys` yields, after removing duplicates, with
+/// the recipient as configured
+fn expected_recipients(public_keys: &[impl AsRef<str>]) -> Result<Vec<(StanzaRecipient, String)>> {
+    let mut expected = vec![];
+    let mut seen = HashSet::new();
+    for (idx, pubk) in public_keys.iter().enumerate() {
+        let configured = pubk.as_ref().to_string();
+        match parse_recipient(pubk.as_ref()) {
+            Some(ParsedRecipient::X25519(pk)) => {
+                if seen.insert(pk.to_string()) {
+                    expected.push((StanzaRecipient::X25519, configured));
+                }
+            }
+            Some(ParsedRecipient::Ssh(pk)) => {
+                if seen.insert(pk.to_string()) {
+                    // The tag is most easily obtained from an actual header
+                    let header = ssh_probe_header(pk)?;
+                    for stanza in recipients_of(&mut &header[..])? {
+                        expected.push((StanzaRecipient::from(&stanza), configured.clone()));
+                    }
+                }
+            }
+            Some(ParsedRecipient::Plugin(_)) => {
+                if seen.insert(split_label(&configured).0.to_string()) {
+                    expected.push((StanzaRecipient::Plugin, configured));
+                }
+            }
+            None => return Err(invalid_recipient(pubk.as_ref(), idx + 1).into()),
+        }
+    }
+    Ok(expected)
+}
+
+/// An empty age file encrypted to just `pk`, whose stanza carries the tag of the key.
+/// Unlike [`encrypt`] it ignores the configured output options, like a deterministic
+/// key, which doesn't support SSH recipients, and doesn't log.
+fn ssh_probe_header(pk: age::ssh::Recipient) -> Result<Vec<u8>> {
+    let encryptor = Encryptor::with_recipients(std::iter::once(&pk as &dyn Recipient))
+        .context("Couldn't encrypt to the SSH recipient")?;
+    let mut header = vec![];
+    encryptor.wrap_output(&mut header)?.finish()?;
+    Ok(header)
+}
+
+/// A recipient string parsed into one of the supported key types
+enum ParsedRecipient {
+    X25519(age::x25519::Recipient),
+    Ssh(age::ssh::Recipient),
+    Plugin(plugin::Recipient),
+}
+
+/// Parses a recipient, ignoring its label, see [`split_label`]
+fn parse_recipient(pubk: &str) -> Option<ParsedRecipient> {
+    let (pubk, _) = split_label(pubk);
+    if let Ok(pk) = pubk.parse::<age::x25519::Recipient>() {
+        Some(ParsedRecipient::X25519(pk))
+    } else if let Ok(pk) = pubk.parse::<age::ssh::Recipient>() {
+        Some(ParsedRecipient::Ssh(pk))
+    } else if let Ok(pk) = pubk.parse::<plugin::Recipient>() {
+        Some(ParsedRecipient::Plugin(pk))
+    } else {
+        None
+    }
+}
+
+/// Type of key of a recipient, to restrict which ones may be encrypted to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecipientType {
+    X25519,
+    SshEd25519,
+    SshRsa,
+    /// Recipients of any plugin, like age-plugin-yubikey
+    Plugin,
+}
+
+impl RecipientType {
+    const ALL: [Self; 4] = [Self::X25519, Self::SshEd25519, Self::SshRsa, Self::Plugin];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::X25519 => "x25519",
+            Self::SshEd25519 => "ssh-ed25519",
+            Self::SshRsa => "ssh-rsa",
+            Self::Plugin => "plugin",
+        }
+    }
+}
+
+impl std::fmt::Display for RecipientType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl std::str::FromStr for RecipientType {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|kind| kind.name() == name)
+            .ok_or_else(|| {
+                anyhow!(
+                    "Unknown recipient type '{}', expected x25519, ssh-ed25519, ssh-rsa or plugin",
+                    name
+                )
+            })
+    }
+}
+
+/// The type of `recipient`, `None` if it isn't a valid recipient
+pub fn recipient_type(recipient: &str) -> Option<RecipientType> {
+    Some(match parse_recipient(recipient)? {
+        ParsedRecipient::X25519(_) => RecipientType::X25519,
+        // The algorithm is the first field of an SSH public key
+        ParsedRecipient::Ssh(_) if split_label(recipient).0.starts_with("ssh-rsa ") => {
+            RecipientType::SshRsa
+        }
+        ParsedRecipient::Ssh(_) => RecipientType::SshEd25519,
+        ParsedRecipient::Plugin(_) => RecipientType::Plugin,
+    })
+}
+
+/// Fails on the first of `public_keys` whose type isn't `allowed`, e.g. to forbid SSH
+/// RSA keys. Invalid recipients are left to [`validate_public_keys`].
+pub fn check_recipient_types(
+    public_keys: &[impl AsRef<str>],
+    allowed: &[RecipientType],
+) -> Result<(), AgeError> {
+    for public_key in public_keys {
+        let Some(kind) = recipient_type(public_key.as_ref()) else {
+            continue;
+        };
+        if !allowed.contains(&kind) {
+            let allowed: Vec<_> = allowed.iter().map(RecipientType::to_string).collect();
+            return Err(AgeError::RecipientTypeNotAllowed {
+                recipient: describe_recipient(public_key.as_ref()),
+                kind,
+                allowed: allowed.join(", "),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Splits a recipient written as `key # label` into its key and label, the label
+/// naming e.g. whose key it is. Neither keys nor keys URLs contain `#`.
+pub fn split_label(recipient: &str) -> (&str, Option<&str>) {
+    match recipient.split_once('#') {
+        Some((key, label)) => (key.trim(), Some(label.trim()).filter(|l| !l.is_empty())),
+        None => (recipient.trim(), None),
+    }
+}
+
+/// Names a recipient in logs. Plugin recipients are opaque, so only their plugin is named.
+fn describe_recipient(recipient: &str) -> String {
+    let (key, label) = split_label(recipient);
+    let described = match parse_recipient(key) {
+        Some(ParsedRecipient::Plugin(recipient)) => format!("plugin:{}", recipient.plugin()),
+        _ => key.to_string(),
+    };
+    match label {
+        Some(label) => format!("{}, label={}", described, label),
+        None => described,
+    }
+}
+
+/// The error for a `recipient` that can't be parsed, keeping secret keys out of it
+fn invalid_recipient(recipient: &str, position: usize) -> AgeError {
+    if is_identity_line(recipient) {
+        AgeError::IdentityAsRecipient { position }
+    } else {
+        AgeError::InvalidRecipient {
+            recipient: recipient.to_string(),
+            position,
+        }
+    }
+}
+
+fn unsupported_recipient(recipient: &str) -> String {
+    format!(
+        "{:?} is neither an x25519 key (age1...), an SSH public key (ssh-ed25519 or ssh-rsa) \
+         nor a plugin recipient (age1<plugin>1...)",
+        recipient
+    )
+}
+
+fn load_public_keys(
+    public_keys: &[impl AsRef<str>],
+) -> Result<Vec<Box<dyn Recipient + Send>>, AgeError> {
+    let mut recipients: Vec<Box<dyn Recipient + Send>> = vec![];
+    let mut plugin_recipients: Vec<(String, Vec<plugin::Recipient>)> = vec![];
+    // Canonical string forms of the recipients added so far, as the same key
+    // may be listed multiple times, e.g. directly and through an alias
+    let mut seen = HashSet::new();
+
+    for (idx, pubk) in public_keys.iter().enumerate() {
+        match parse_recipient(pubk.as_ref()) {
+            Some(ParsedRecipient::X25519(pk)) => {
+                if seen.insert(pk.to_string()) {
+                    recipients.push(Box::new(pk));
+                }
+            }
+            Some(ParsedRecipient::Ssh(pk)) => {
+                if seen.insert(pk.to_string()) {
+                    recipients.push(Box::new(pk));
+                }
+            }
+            Some(ParsedRecipient::Plugin(recipient)) => {
+                if seen.insert(split_label(pubk.as_ref()).0.to_string()) {
+                    let plugin_name = recipient.plugin().to_string();
+                    match plugin_recipients
+                        .iter()
+                        .position(|(name, _)| *name == plugin_name)
+                    {
+                        Some(i) => plugin_recipients[i].1.push(recipient),
+                        None => plugin_recipients.push((plugin_name, vec![recipient])),
+                    }
+                }
+            }
+            None => return Err(invalid_recipient(pubk.as_ref(), idx + 1)),
+        }
+    }
+
+    for (plugin_name, plugin_recipients) in &plugin_recipients {
+        let recipient = RecipientPluginV1::new(plugin_name, plugin_recipients, &[], NoOpCallbacks)?;
+        recipients.push(Box::new(recipient));
+    }
+
+    Ok(recipients)
+}
+
+/// Reads a recipients file in the format of `age -R`: one recipient per line,
+/// blank lines and lines starting with `#` are ignored.
+pub fn read_recipients_file(path: impl AsRef<Path>) -> Result<Vec<String>> {
+    let path = path.as_ref();
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Couldn't read recipients file {:?}", path))?;
+    parse_recipients(&contents, path)
+}
+
+/// Reads recipients in the format of a recipients file from `reader`, e.g. stdin,
+/// naming it `name` in errors
+pub fn read_recipients(mut reader: impl Read, name: &Path) -> Result<Vec<String>> {
+    let mut contents = String::new();
+    reader
+        .read_to_string(&mut contents)
+        .with_context(|| format!("Couldn't read recipients from {}", name.display()))?;
+    parse_recipients(&contents, name)
+}
+
+fn parse_recipients(contents: &str, path: &Path) -> Result<Vec<String>> {
+    let mut recipients = vec![];
+    for (idx, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if is_identity_line(line) {
+            bail!(
+                "Invalid recipient at {}:{}: that's an identity, not a recipient, list its \
+                 public key instead (age-keygen -y prints it)",
+                path.display(),
+                idx + 1
+            );
+        }
+        if parse_recipient(line).is_none() {
+            return Err(anyhow!(unsupported_recipient(line)).context(format!(
+                "Invalid recipient at {}:{}",
+                path.display(),
+                idx + 1
+            )));
+        }
+        recipients.push(line.to_string());
+    }
+    Ok(recipients)
+}
+
+/// Checks that each of `public_keys` is a recipient [`encrypt`] accepts.
+pub fn validate_public_keys(public_keys: &[impl AsRef<str>]) -> Result<(), AgeError> {
+    load_public_keys(public_keys)?;
+    Ok(())
+}
+
+/// Validates an identity file.
+/// Returns Ok(None) for valid plaintext identities or decrypted encrypted identities.
+/// Returns Ok(Some(note)) with a note for encrypted identities when AGE_PASSPHRASE is not set.
+pub fn validate_identity(identity: impl AsRef<Path>) -> Result<Option<String>, AgeError> {
+    let path = identity.as_ref();
+    if is_inline_identity(path) || !path.exists() {
+        load_identity_file(path)?;
+        return Ok(None);
+    }
+    // Try parsing as plaintext identity file first
+    let contents = read_identity_file(path)?;
+    check_encrypted_at_rest(
+        path,
+        &contents,
+        REQUIRE_ENCRYPTED_IDENTITIES.load(Ordering::Relaxed),
+    )?;
+    match IdentityFile::from_buffer(&contents[..]) {
+        Ok(identity_file) => {
+            identity_file
+                .with_callbacks(IdentityCallbacks)
+                .into_identities()
+                .with_context(|| format!("Failed to parse identity from: {:?}", path))?;
+            Ok(None)
+        }
+        Err(parse_err) => {
+            if is_recipients_file(path) {
+                return Err(AgeError::RecipientAsIdentity(path.to_path_buf()));
+            }
+            match read_ssh_identity(path)? {
+                Some(age::ssh::Identity::Encrypted(_)) if configured_passphrase().is_none() => {
+                    return Ok(Some(
+                        "SSH key with passphrase, AGE_PASSPHRASE not detected, decryption was not tested"
+                            .to_string(),
+                    ));
+                }
+                Some(_) => {
+                    load_ssh_identity(path, |_| configured_passphrase())?;
+                    return Ok(None);
+                }
+                None => {}
+            }
+
+            if !is_age_encrypted(&contents) {
+                return Err(unparsable_identity_file(path, parse_err));
+            }
+
             // Try as encrypted identity file
-            let file = File::open(path)
-                .with_context(|| format!("Failed to open identity file: {:?}", path))?;
-            let reader = ArmoredReader::new(BufReader::new(file));
+            let reader = ArmoredReader::new(&contents[..]);
             
             // Check if it's a valid encrypted file
             let decryptor = match Decryptor::new(reader) {
                 Ok(d) if d.is_scrypt() => d,
-                Ok(_) => bail!("Encrypted identity file {:?} is not passphrase-encrypted", path),
-                Err(e) => bail!("File {:?} is neither a valid plaintext nor encrypted identity file: {}", path, e),
+                Ok(_) => return Err(AgeError::ScryptUnsupported(path.to_path_buf())),
+                Err(e) => {
+                    return Err(anyhow!(
+                        "File {:?} is neither a valid plaintext nor encrypted identity file: {}",
+                        path,
+                        e
+                    )
+                    .into())
+                }
             };
             
             // If AGE_PASSPHRASE is set, try to decrypt and validate
             // Otherwise, just accept that it's a valid encrypted format
-            match env::var(AGE_PASSPHRASE_ENV) {
-                Ok(passphrase) => {
+            match configured_passphrase() {
+                Some(passphrase) => {
                     // Decrypt and validate the identity file
                     let decrypted = {
-                        let mut reader = decryptor.decrypt(std::iter::once(
-                            &age::scrypt::Identity::new(passphrase.into()) as &dyn Identity
-                        )).with_context(|| format!("Failed to decrypt {:?} with AGE_PASSPHRASE", path))?;
+                        let mut reader = decryptor
+                            .decrypt(std::iter::once(
+                                &age::scrypt::Identity::new(passphrase) as &dyn Identity
+                            ))
+                            .with_context(|| {
+                                format!("Failed to decrypt {:?} with AGE_PASSPHRASE", path)
+                            })?;
                         let mut buf = Vec::new();
                         reader.read_to_end(&mut buf)?;
                         buf
                     };
                     
-                    let decrypted_str = String::from_utf8(decrypted)
-                        .with_context(|| format!("Decrypted identity file {:?} is not valid UTF-8", path))?;
-                    
+                    let decrypted = normalize_identity_text(&decrypted);
+                    let decrypted_str =
+                        String::from_utf8(decrypted.to_vec()).with_context(|| {
+                            format!("Decrypted identity file {:?} is not valid UTF-8", path)
+                        })?;
+
                     let identity_file = IdentityFile::from_buffer(decrypted_str.as_bytes())
                         .with_context(|| format!("Failed to parse decrypted identity file {:?}", path))?;
                     
@@ -262,7 +2464,7 @@ pub(crate) fn validate_identity(identity: impl AsRef<Path>) -> Result<Option<Str
                     
                     Ok(None)
                 }
-                Err(_) => {
+                None => {
                     // No passphrase set - just validate format, don't require decryption
                     Ok(Some("encrypted, AGE_PASSPHRASE not detected, decryption was not tested".to_string()))
                 }
@@ -270,3 +2472,1518 @@ pub(crate) fn validate_identity(identity: impl AsRef<Path>) -> Result<Option<Str
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use age::secrecy::ExposeSecret;
+    use anyhow::Result;
+    use assert_fs::prelude::*;
+    use assert_fs::TempDir;
+    use assert_matches::assert_matches;
+    use rstest::rstest;
+
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    fn encrypt_with_passphrase(plaintext: &[u8], armor: bool) -> Result<Vec<u8>> {
+        let encryptor = Encryptor::with_user_passphrase(SecretString::from(PASSPHRASE.to_string()));
+        let mut encrypted = vec![];
+        if armor {
+            let armored = ArmoredWriter::wrap_output(&mut encrypted, Format::AsciiArmor)?;
+            let mut writer = encryptor.wrap_output(armored)?;
+            writer.write_all(plaintext)?;
+            writer.finish()?.finish()?;
+        } else {
+            let mut writer = encryptor.wrap_output(&mut encrypted)?;
+            writer.write_all(plaintext)?;
+            writer.finish()?;
+        }
+        Ok(encrypted)
+    }
+
+    #[rstest]
+    #[case::armored(true)]
+    #[case::binary(false)]
+    fn test_decrypt_passphrase_encrypted(#[case] armor: bool) -> Result<()> {
+        let encrypted = encrypt_with_passphrase(b"legacy secret", armor)?;
+        let identities: Vec<PathBuf> = vec![];
+
+        let mut decrypted = vec![];
+        let found =
+            decrypt_with_passphrase(&identities, &mut &encrypted[..], &mut decrypted, || {
+                vec![SecretString::from(PASSPHRASE.to_string())]
+            })?;
+
+        assert!(matches!(
+            found,
+            DecryptOutcome::Decrypted(Decrypted::Passphrase)
+        ));
+        assert_eq!(decrypted, b"legacy secret");
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::right_first(&[PASSPHRASE, "old"], true)]
+    #[case::right_last(&["ancient", "old", PASSPHRASE], true)]
+    #[case::all_wrong(&["ancient", "old"], false)]
+    fn test_passphrases_are_tried_in_order(
+        #[case] passphrases: &[&str],
+        #[case] decrypts: bool,
+    ) -> Result<()> {
+        let encrypted = encrypt_with_passphrase(b"legacy secret", true)?;
+        let identities: Vec<PathBuf> = vec![];
+        let passphrases =
+            || -> Vec<_> { passphrases.iter().map(|p| SecretString::from(*p)).collect() };
+
+        let mut decrypted = vec![];
+        let found = decrypt_with_passphrase(
+            &identities,
+            &mut &encrypted[..],
+            &mut decrypted,
+            passphrases,
+        );
+
+        assert_eq!(found.is_ok(), decrypts);
+        if decrypts {
+            assert_eq!(decrypted, b"legacy secret");
+        }
+
+        let (contents, _) = generate_identity(Some(SecretString::from(PASSPHRASE)))?;
+        let decryptor = Decryptor::new(ArmoredReader::new(&contents[..]))?;
+        let path = Path::new("identity.age");
+        let unlocked = decrypt_identity_file(path, decryptor, "", |_| passphrases());
+        assert_eq!(unlocked.is_ok(), decrypts);
+        if let Ok((plaintext, passphrase)) = unlocked {
+            assert!(plaintext.starts_with(b"# public key: "));
+            assert_eq!(
+                passphrase.expose_secret(),
+                PASSPHRASE,
+                "returns the fitting one"
+            );
+        }
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::single("hunter2", &["hunter2"])]
+    #[case::newline_separated("hunter2\ncorrect horse\n", &["hunter2", "correct horse"])]
+    #[case::crlf("hunter2\r\ncorrect horse\r\n", &["hunter2", "correct horse"])]
+    #[case::blank_lines("\nhunter2\n\n", &["hunter2"])]
+    #[case::empty("", &[])]
+    fn test_split_passphrases(#[case] configured: &str, #[case] expected: &[&str]) {
+        let passphrases = split_passphrases(&SecretString::from(configured));
+
+        let passphrases: Vec<_> = passphrases.iter().map(|p| p.expose_secret()).collect();
+        assert_eq!(passphrases, expected);
+    }
+
+    #[rstest]
+    #[case::armored(true)]
+    #[case::binary(false)]
+    fn test_decrypt_passphrase_encrypted_requires_passphrase(#[case] armor: bool) -> Result<()> {
+        let encrypted = encrypt_with_passphrase(b"legacy secret", armor)?;
+        let identities: Vec<PathBuf> = vec![];
+
+        let err = decrypt_with_passphrase(&identities, &mut &encrypted[..], &mut vec![], Vec::new)
+            .unwrap_err();
+
+        assert!(err.to_string().contains(AGE_PASSPHRASE_ENV));
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::newline("hunter2\n")]
+    #[case::crlf("hunter2\r\n")]
+    #[case::no_newline("hunter2")]
+    fn test_read_passphrase_file(#[case] contents: &str) -> Result<()> {
+        let dir = TempDir::new()?;
+        let file = dir.child("passphrase");
+        file.write_str(contents)?;
+
+        let passphrase = read_passphrase_file(file.path()).unwrap();
+
+        assert_eq!(passphrase.expose_secret(), "hunter2");
+        assert!(read_passphrase_file(&dir.path().join("missing")).is_none());
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_passphrase_is_only_requested_for_passphrase_encrypted_files() -> Result<()> {
+        let dir = TempDir::new()?;
+        let identity = age::x25519::Identity::generate();
+        let identity_file = dir.child("identity.txt");
+        identity_file.write_str(identity.to_string().expose_secret())?;
+        let encrypted = encrypt(
+            &[identity.to_public().to_string()],
+            &mut &b"no prompt"[..],
+            Encoding::Binary,
+        )?;
+
+        let mut decrypted = vec![];
+        decrypt_with_passphrase(
+            &[identity_file.path()],
+            &mut &encrypted[..],
+            &mut decrypted,
+            || panic!("passphrase requested for a file encrypted to recipients"),
+        )?;
+
+        assert_eq!(decrypted, b"no prompt");
+        Ok(())
+    }
+
+    /// Hands out `remaining` zero bytes in small chunks, counting what was read.
+    struct ChunkedReader {
+        remaining: usize,
+        read: Rc<Cell<usize>>,
+    }
+
+    impl Read for ChunkedReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.remaining).min(64 * 1024);
+            buf[..n].fill(0);
+            self.remaining -= n;
+            self.read.set(self.read.get() + n);
+            Ok(n)
+        }
+    }
+
+    /// Discards its input, tracking how far reading got ahead of writing.
+    struct LagWriter {
+        written: usize,
+        read: Rc<Cell<usize>>,
+        max_lag: usize,
+    }
+
+    impl Write for LagWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written += buf.len();
+            self.max_lag = self
+                .max_lag
+                .max(self.read.get().saturating_sub(self.written));
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[rstest]
+    fn test_encrypt_to_streams() -> Result<()> {
+        const PAYLOAD_SIZE: usize = 50 * 1024 * 1024;
+        let recipient = age::x25519::Identity::generate().to_public().to_string();
+        let read = Rc::new(Cell::new(0));
+        let mut reader = ChunkedReader {
+            remaining: PAYLOAD_SIZE,
+            read: read.clone(),
+        };
+        let mut writer = LagWriter {
+            written: 0,
+            read: read.clone(),
+            max_lag: 0,
+        };
+
+        encrypt_to(&[recipient], &mut reader, &mut writer, Encoding::Binary)?;
+
+        assert_eq!(read.get(), PAYLOAD_SIZE);
+        assert!(writer.written > PAYLOAD_SIZE);
+        assert!(
+            writer.max_lag < 1024 * 1024,
+            "ciphertext lagged {} bytes behind the cleartext",
+            writer.max_lag
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::binary(Encoding::Binary)]
+    #[case::armored(Encoding::Armored)]
+    fn test_encrypt_round_trip(#[case] encoding: Encoding) -> Result<()> {
+        let dir = TempDir::new()?;
+        let identity = age::x25519::Identity::generate();
+        let identity_file = dir.child("identity.txt");
+        identity_file.write_str(identity.to_string().expose_secret())?;
+        let recipient = identity.to_public().to_string();
+
+        let encrypted = encrypt(&[recipient], &mut &b"top secret"[..], encoding)?;
+        assert_eq!(
+            encrypted.starts_with(b"-----BEGIN AGE ENCRYPTED FILE-----"),
+            encoding == Encoding::Armored
+        );
+
+        let decrypted = decrypt(&[identity_file.path()], &mut &encrypted[..])?;
+        assert_eq!(decrypted, DecryptOutcome::Decrypted(b"top secret".to_vec()));
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_decrypt_with_identity_names_matching_file() -> Result<()> {
+        let dir = TempDir::new()?;
+        let unrelated = dir.child("unrelated.txt");
+        unrelated.write_str(
+            age::x25519::Identity::generate()
+                .to_string()
+                .expose_secret(),
+        )?;
+        let identity = age::x25519::Identity::generate();
+        let matching = dir.child("matching.txt");
+        matching.write_str(identity.to_string().expose_secret())?;
+
+        let encrypted = encrypt(
+            &[identity.to_public().to_string()],
+            &mut &b"audited"[..],
+            Encoding::Binary,
+        )?;
+        let decrypted =
+            decrypt_with_identity(&[unrelated.path(), matching.path()], &mut &encrypted[..])?;
+
+        assert_eq!(
+            decrypted,
+            DecryptOutcome::Decrypted((b"audited".to_vec(), Some(matching.path().to_path_buf())))
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_decrypt_outcomes() -> Result<()> {
+        let dir = TempDir::new()?;
+        let unrelated = dir.child("unrelated.txt");
+        unrelated.write_str(
+            age::x25519::Identity::generate()
+                .to_string()
+                .expose_secret(),
+        )?;
+        let recipient = age::x25519::Identity::generate().to_public().to_string();
+        let encrypted = encrypt(&[recipient], &mut &b"not for us"[..], Encoding::Binary)?;
+
+        let plain = decrypt(&[unrelated.path()], &mut &b"plain text"[..])?;
+        let not_ours = decrypt(&[unrelated.path()], &mut &encrypted[..])?;
+
+        assert_eq!(plain, DecryptOutcome::Plain);
+        assert_eq!(not_ours, DecryptOutcome::NoMatchingIdentity);
+        let err = not_ours.required(&[unrelated.path()]).unwrap_err();
+        assert!(err.to_string().contains("no matching identity"), "{}", err);
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::binary(Encoding::Binary)]
+    #[case::armored(Encoding::Armored)]
+    fn test_encrypt_with_parsed_keys(#[case] encoding: Encoding) -> Result<()> {
+        let identity = age::x25519::Identity::generate();
+        let recipients: Vec<Box<dyn Recipient>> = vec![Box::new(identity.to_public())];
+        let mut encrypted = vec![];
+        encrypt_with(
+            &recipients,
+            &mut &b"from memory"[..],
+            &mut encrypted,
+            encoding,
+        )?;
+
+        let unrelated: Box<dyn Identity> = Box::new(age::x25519::Identity::generate());
+        let identities: Vec<Box<dyn Identity>> = vec![unrelated, Box::new(identity)];
+        let mut decrypted = vec![];
+        let outcome = decrypt_with(&identities, &mut &encrypted[..], &mut decrypted)?;
+
+        assert_eq!(outcome, DecryptOutcome::Decrypted(()));
+        assert_eq!(decrypted, b"from memory");
+        let outcome = decrypt_with(&identities[..1], &mut &encrypted[..], &mut vec![])?;
+        assert_eq!(outcome, DecryptOutcome::NoMatchingIdentity);
+        let outcome = decrypt_with(&identities, &mut &b"plain text"[..], &mut vec![])?;
+        assert_eq!(outcome, DecryptOutcome::Plain);
+        assert_matches!(
+            encrypt_with(&[], &mut &b""[..], &mut vec![], encoding),
+            Err(AgeError::NoRecipients)
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::binary(Encoding::Binary)]
+    #[case::armored(Encoding::Armored)]
+    fn test_comment_in_header(#[case] encoding: Encoding) -> Result<()> {
+        let dir = TempDir::new()?;
+        let identity = age::x25519::Identity::generate();
+        let key = dir.child("key.txt");
+        key.write_str(identity.to_string().expose_secret())?;
+        let recipients: Vec<Box<dyn Recipient>> = vec![Box::new(identity.to_public())];
+        let mut encrypted = vec![];
+        encrypt_commented(
+            &recipients,
+            Some("repo:infra"),
+            None,
+            &mut &b"labelled"[..],
+            &mut encrypted,
+            encoding,
+        )?;
+
+        assert_eq!(
+            comment_of(&mut &encrypted[..])?.as_deref(),
+            Some("repo:infra")
+        );
+        assert_eq!(recipients_of(&mut &encrypted[..])?.len(), 1);
+        assert_eq!(
+            decrypt(&[key.path()], &mut &encrypted[..])?,
+            DecryptOutcome::Decrypted((b"labelled".to_vec(), Some(key.to_path_buf())))
+        );
+        let plain = encrypt(&[identity.to_public().to_string()], &mut &b""[..], encoding)?;
+        assert_eq!(comment_of(&mut &plain[..])?, None);
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::binary(Encoding::Binary)]
+    #[case::armored(Encoding::Armored)]
+    fn test_compressed_plaintext(#[case] encoding: Encoding) -> Result<()> {
+        let identity = age::x25519::Identity::generate();
+        let inline = identity.to_string().expose_secret().to_string();
+        let recipients: Vec<Box<dyn Recipient>> = vec![Box::new(identity.to_public())];
+        let plaintext = r#"{"name": "db", "password": "hunter2"}"#.repeat(1000);
+        let encrypt = |compression| -> Result<Vec<u8>, AgeError> {
+            let mut encrypted = vec![];
+            let cleartext = &mut plaintext.as_bytes();
+            encrypt_commented(
+                &recipients,
+                None,
+                compression,
+                cleartext,
+                &mut encrypted,
+                encoding,
+            )?;
+            Ok(encrypted)
+        };
+
+        let compressed = encrypt(Some(Compression::Zstd))?;
+        let legacy = encrypt(None)?;
+
+        assert!(compressed.len() < legacy.len() / 10, "{}", compressed.len());
+        for encrypted in [compressed, legacy] {
+            assert_eq!(
+                decrypt(&[&inline], &mut &encrypted[..])?,
+                DecryptOutcome::Decrypted(plaintext.as_bytes().to_vec())
+            );
+        }
+        assert_eq!(
+            recipients_of(&mut &encrypt(Some(Compression::Zstd))?[..])?.len(),
+            1
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_unknown_compression() -> Result<()> {
+        let identity = age::x25519::Identity::generate();
+        let inline = identity.to_string().expose_secret().to_string();
+        let recipient = identity.to_public();
+        let annotated = Annotated {
+            inner: &recipient,
+            stanzas: vec![Stanza {
+                tag: COMPRESSION_STANZA.to_string(),
+                args: vec!["brotli".to_string()],
+                body: vec![],
+            }],
+        };
+        let encryptor = Encryptor::with_recipients(std::iter::once(&annotated as &dyn Recipient))?;
+        let mut encrypted = vec![];
+        let mut writer = encryptor.wrap_output(&mut encrypted)?;
+        writer.write_all(b"compressed")?;
+        writer.finish()?;
+
+        let err = decrypt(&[&inline], &mut &encrypted[..]).unwrap_err();
+
+        assert!(
+            format!("{:#}", err).contains("Unknown compression 'brotli'"),
+            "{:#}",
+            err
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::space("repo foo", false)]
+    #[case::newline("repo\nfoo", false)]
+    #[case::non_ascii("dépôt", false)]
+    #[case::empty("", false)]
+    #[case::label("repo:foo/rule-1", true)]
+    fn test_validate_comment(#[case] comment: &str, #[case] valid: bool) {
+        assert_eq!(validate_comment(comment).is_ok(), valid);
+    }
+
+    #[rstest]
+    fn test_encrypt_requires_recipients() {
+        let no_recipients: [&str; 0] = [];
+        let err = encrypt(&no_recipients, &mut &b"for nobody"[..], Encoding::Binary).unwrap_err();
+        assert!(
+            err.to_string().contains("No recipients configured"),
+            "{}",
+            err
+        );
+    }
+
+    #[rstest]
+    fn test_errors_can_be_matched() -> Result<()> {
+        let no_recipients: [&str; 0] = [];
+        assert_matches!(
+            encrypt(&no_recipients, &mut &b""[..], Encoding::Binary),
+            Err(AgeError::NoRecipients)
+        );
+        assert_matches!(
+            validate_public_keys(&["age1typo"]),
+            Err(AgeError::InvalidRecipient { position: 1, recipient }) if recipient == "age1typo"
+        );
+
+        let encrypted = encrypt_with_passphrase(b"legacy secret", false)?;
+        let identities: Vec<PathBuf> = vec![];
+        let err = decrypt_with_passphrase(&identities, &mut &encrypted[..], &mut vec![], Vec::new)
+            .map(|_| ())
+            .unwrap_err()
+            .for_input(Path::new("secrets/legacy"));
+        assert_matches!(&err, AgeError::PassphraseRequired(Some(_)));
+        assert!(
+            err.to_string()
+                .ends_with("needed to decrypt \"secrets/legacy\""),
+            "{}",
+            err
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_decrypt_with_inline_identity() -> Result<()> {
+        let identity = age::x25519::Identity::generate();
+        let inline = identity.to_string().expose_secret().to_string();
+        let encrypted = encrypt(
+            &[identity.to_public().to_string()],
+            &mut &b"from env"[..],
+            Encoding::Binary,
+        )?;
+
+        let decrypted = decrypt(&[&inline], &mut &encrypted[..])?;
+        assert_eq!(decrypted, DecryptOutcome::Decrypted(b"from env".to_vec()));
+        assert_eq!(validate_identity(&inline)?, None);
+
+        let unrelated = age::x25519::Identity::generate();
+        let unrelated = unrelated.to_string().expose_secret().to_string();
+        let err = decrypt(&[&unrelated], &mut &encrypted[..])?
+            .required(&[&unrelated])
+            .unwrap_err();
+        assert!(!format!("{:#}", err).contains(&unrelated), "{:#}", err);
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_inline_identity_file_contents() -> Result<()> {
+        let identity = age::x25519::Identity::generate();
+        let public_key = identity.to_public().to_string();
+        let inline = format!(
+            "# created: 2024-01-01T00:00:00Z\n# public key: {}\n{}\n",
+            public_key,
+            identity.to_string().expose_secret()
+        );
+        let dir = TempDir::new()?;
+        let file = dir.child("identity.txt");
+        file.write_str(&inline)?;
+        let encrypted = encrypt(&[&public_key], &mut &b"inline"[..], Encoding::Binary)?;
+
+        assert!(is_inline_identity(Path::new(&inline)));
+        assert!(!is_inline_identity(file.path()));
+        let path = file.path().to_string_lossy().to_string();
+        for identity in [inline.as_str(), path.as_str()] {
+            let decrypted = decrypt(&[identity], &mut &encrypted[..])?;
+            assert_eq!(decrypted, DecryptOutcome::Decrypted(b"inline".to_vec()));
+            assert_eq!(validate_identity(identity)?, None);
+        }
+        assert_eq!(identity_name(Path::new(&inline)), "<inline identity>");
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_identity_as_recipient() {
+        let identity = age::x25519::Identity::generate();
+        let secret = identity.to_string().expose_secret().to_string();
+
+        assert_matches!(
+            validate_public_keys(&[identity.to_public().to_string(), secret.clone()]),
+            Err(AgeError::IdentityAsRecipient { position: 2 })
+        );
+        let err = parse_recipients(&format!("# ops\n{}\n", secret), Path::new("ops.txt"))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("ops.txt:2: that's an identity"), "{}", err);
+        assert!(!err.contains(&secret), "{}", err);
+    }
+
+    #[rstest]
+    fn test_recipients_as_identity() -> Result<()> {
+        let dir = TempDir::new()?;
+        let recipient = age::x25519::Identity::generate().to_public().to_string();
+        let recipients_file = dir.child("recipients.txt");
+        recipients_file.write_str(&format!("# ops\n{}\n", recipient))?;
+
+        assert_matches!(
+            validate_identity(recipients_file.path()),
+            Err(AgeError::RecipientAsIdentity(path)) if path == recipients_file.path()
+        );
+        assert_matches!(
+            validate_identity(&recipient),
+            Err(AgeError::RecipientAsIdentity(path)) if path == Path::new(&recipient)
+        );
+        let encrypted = encrypt(&[&recipient], &mut &b"secret"[..], Encoding::Binary)?;
+        assert_matches!(
+            decrypt(&[recipients_file.path()], &mut &encrypted[..]),
+            Err(AgeError::RecipientAsIdentity(_))
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_decrypt_skips_missing_identity_file() -> Result<()> {
+        let dir = TempDir::new()?;
+        let missing = dir.path().join("laptop.txt");
+        let identity = age::x25519::Identity::generate();
+        let matching = dir.child("server.txt");
+        matching.write_str(identity.to_string().expose_secret())?;
+        let encrypted = encrypt(
+            &[identity.to_public().to_string()],
+            &mut &b"tolerated"[..],
+            Encoding::Binary,
+        )?;
+
+        let decrypted =
+            decrypt_with_identity(&[missing.as_path(), matching.path()], &mut &encrypted[..])?;
+
+        assert_eq!(
+            decrypted,
+            DecryptOutcome::Decrypted((b"tolerated".to_vec(), Some(matching.to_path_buf())))
+        );
+        assert_matches!(
+            decrypt(&[&missing], &mut &encrypted[..]),
+            Err(AgeError::IdentityNotFound(path)) if path == missing
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::missing_file("/nonexistent/identity.txt", "\"/nonexistent/identity.txt\"")]
+    #[case::mistyped_key(
+        "age-secret-key-1qyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqs",
+        "<mistyped inline identity>"
+    )]
+    fn test_missing_identity_file(#[case] identity: &str, #[case] named: &str) {
+        assert!(!is_inline_identity(Path::new(identity)));
+        let err = validate_identity(identity).unwrap_err();
+        let message = err.to_string();
+        let expected = format!("Identity file {} ", named);
+        assert!(message.starts_with(&expected), "{}", message);
+        assert!(!message.contains("qyqszqgp"), "{}", message);
+        assert_eq!(identity_name(Path::new(identity)), named.trim_matches('"'));
+        assert_matches!(err, AgeError::IdentityNotFound(path) if path == Path::new(identity));
+    }
+
+    fn count_x25519_stanzas(encrypted: &[u8]) -> usize {
+        let header_end = encrypted
+            .windows(5)
+            .position(|w| w == b"\n--- ")
+            .expect("encrypted file has a header");
+        encrypted[..header_end]
+            .windows(10)
+            .filter(|w| *w == b"\n-> X25519")
+            .count()
+    }
+
+    #[rstest]
+    fn test_duplicate_recipients_produce_one_stanza() -> Result<()> {
+        let recipient = age::x25519::Identity::generate().to_public().to_string();
+
+        let encrypted = encrypt(
+            &[recipient.clone(), recipient],
+            &mut &b"once"[..],
+            Encoding::Binary,
+        )?;
+
+        assert_eq!(count_x25519_stanzas(&encrypted), 1);
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_labels_are_ignored_when_parsing() -> Result<()> {
+        let recipient = age::x25519::Identity::generate().to_public().to_string();
+
+        let encrypted = encrypt(
+            &[format!("{recipient} # alice"), recipient.clone()],
+            &mut &b"once"[..],
+            Encoding::Binary,
+        )?;
+
+        assert_eq!(count_x25519_stanzas(&encrypted), 1);
+        assert_eq!(
+            split_label(&format!("{recipient}  #  ")),
+            (recipient.as_str(), None)
+        );
+        assert_matches!(
+            validate_public_keys(&["age1typo # bob"]),
+            Err(AgeError::InvalidRecipient { position: 1, .. })
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_generate_identity() -> Result<()> {
+        let dir = TempDir::new()?;
+        let (contents, public_key) = generate_identity(None)?;
+        let identity_file = dir.child("identity.txt");
+        identity_file.write_binary(&contents)?;
+
+        assert_eq!(
+            recipients_of_identity(identity_file.path())?,
+            [public_key.clone()]
+        );
+        let encrypted = encrypt(&[public_key], &mut &b"onboarded"[..], Encoding::Binary)?;
+        let decrypted = decrypt(&[identity_file.path()], &mut &encrypted[..])?;
+        assert_eq!(decrypted, DecryptOutcome::Decrypted(b"onboarded".to_vec()));
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_generate_passphrase_protected_identity() -> Result<()> {
+        let passphrase = || vec![SecretString::from(PASSPHRASE.to_string())];
+        let (contents, public_key) = generate_identity(passphrase().pop())?;
+
+        assert!(contents.starts_with(b"-----BEGIN AGE ENCRYPTED FILE-----"));
+        let no_identities: Vec<PathBuf> = vec![];
+        let mut decrypted = vec![];
+        decrypt_with_passphrase(
+            &no_identities,
+            &mut &contents[..],
+            &mut decrypted,
+            passphrase,
+        )?;
+        let decrypted = String::from_utf8(decrypted)?;
+        assert!(decrypted.contains(&public_key));
+        assert!(decrypted.contains("AGE-SECRET-KEY-"));
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_rekey_identity() -> Result<()> {
+        let path = Path::new("identity.age");
+        let secret = |passphrase: &str| SecretString::from(passphrase.to_string());
+        let (contents, public_key) = generate_identity(Some(secret(PASSPHRASE)))?;
+        let current = |_: &str| vec![secret(PASSPHRASE)];
+        let rotated = || -> Result<SecretString> { Ok(secret("rotated")) };
+
+        let rekeyed = rekey_identity_with(path, &contents, false, current, rotated)?;
+
+        let no_identities: Vec<PathBuf> = vec![];
+        let mut decrypted = vec![];
+        decrypt_with_passphrase(&no_identities, &mut &rekeyed[..], &mut decrypted, || {
+            vec![secret("rotated")]
+        })?;
+        assert!(String::from_utf8(decrypted)?.contains(&public_key));
+        assert!(
+            decrypt_with_passphrase(&no_identities, &mut &rekeyed[..], &mut vec![], || {
+                vec![secret(PASSPHRASE)]
+            })
+            .is_err()
+        );
+        let unchanged = || -> Result<SecretString> { Ok(secret(PASSPHRASE)) };
+        assert!(rekey_identity_with(path, &contents, false, current, unchanged).is_err());
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_rekey_plaintext_identity() -> Result<()> {
+        let path = Path::new("identity.txt");
+        let (contents, _) = generate_identity(None)?;
+        let no_passphrase = |_: &str| -> Vec<SecretString> { panic!("passphrase requested") };
+        let new = || -> Result<SecretString> { Ok(SecretString::from(PASSPHRASE.to_string())) };
+
+        assert!(rekey_identity_with(path, &contents, false, no_passphrase, new).is_err());
+        let encrypted = rekey_identity_with(path, &contents, true, no_passphrase, new)?;
+        assert!(encrypted.starts_with(b"-----BEGIN AGE ENCRYPTED FILE-----"));
+        assert!(rekey_identity_with(path, b"not an identity\n", true, no_passphrase, new).is_err());
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_encrypt_to_identities() -> Result<()> {
+        let dir = TempDir::new()?;
+        let identity = age::x25519::Identity::generate();
+        let identity_file = dir.child("identity.txt");
+        identity_file.write_str(identity.to_string().expose_secret())?;
+        let inline = PathBuf::from(
+            age::x25519::Identity::generate()
+                .to_string()
+                .expose_secret(),
+        );
+        let no_public_keys: [&str; 0] = [];
+
+        let mut encrypted = vec![];
+        encrypt_to_identities(
+            &no_public_keys,
+            &[identity_file.path(), inline.as_path()],
+            &mut &b"to myself"[..],
+            &mut encrypted,
+            Encoding::Binary,
+        )?;
+
+        assert_eq!(count_x25519_stanzas(&encrypted), 2);
+        for identity in [identity_file.path(), inline.as_path()] {
+            let decrypted = decrypt(&[identity], &mut &encrypted[..])?;
+            assert_eq!(decrypted, DecryptOutcome::Decrypted(b"to myself".to_vec()));
+        }
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_no_recipients_from_ssh_keys() -> Result<()> {
+        let dir = TempDir::new()?;
+        let key = dir.child("id_ed25519");
+        key.write_str(SSH_ENCRYPTED_KEY)?;
+
+        let err = identity_recipients(&[key.path()])
+            .err()
+            .expect("SSH keys are rejected");
+
+        assert!(
+            err.to_string().contains("encrypt to its public key"),
+            "{}",
+            err
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_streaming_progress_and_cancellation() -> Result<()> {
+        let identity = age::x25519::Identity::generate();
+        let inline = identity.to_string().expose_secret().to_string();
+        let public_keys = [identity.to_public().to_string()];
+        let plaintext = vec![7; 1024 * 1024];
+        let no_identities: &[&Path] = &[];
+        let encrypt = |streaming: Streaming| -> Result<Vec<u8>, AgeError> {
+            let mut encrypted = vec![];
+            let cleartext = &mut &plaintext[..];
+            let (out, encoding) = (&mut encrypted, Encoding::Binary);
+            encrypt_streaming(
+                &public_keys,
+                no_identities,
+                cleartext,
+                out,
+                encoding,
+                streaming,
+            )?;
+            Ok(encrypted)
+        };
+        let reported = Cell::new(0);
+        let progress = |read: u64| reported.set(read);
+
+        let encrypted = encrypt(Streaming {
+            progress: Some(&progress),
+            cancel: None,
+        })?;
+        assert_eq!(reported.get(), plaintext.len() as u64);
+        let mut decrypted = vec![];
+        let streaming = Streaming {
+            progress: Some(&progress),
+            cancel: None,
+        };
+        decrypt_streaming(&[&inline], &mut &encrypted[..], &mut decrypted, streaming)?;
+        assert_eq!(reported.get(), encrypted.len() as u64);
+        assert_eq!(decrypted, plaintext);
+
+        // Cancelled by the progress callback, like a Ctrl-C handler in the middle
+        let cancel = AtomicBool::new(false);
+        let cancelling = |read: u64| {
+            reported.set(read);
+            if read >= 256 * 1024 {
+                cancel.store(true, Ordering::Relaxed);
+            }
+        };
+        let streaming = Streaming {
+            progress: Some(&cancelling),
+            cancel: Some(&cancel),
+        };
+        assert!(matches!(encrypt(streaming), Err(AgeError::Cancelled)));
+        assert!(
+            reported.get() < plaintext.len() as u64,
+            "{}",
+            reported.get()
+        );
+        cancel.store(false, Ordering::Relaxed);
+        let mut decrypted = vec![];
+        let outcome = decrypt_streaming(&[&inline], &mut &encrypted[..], &mut decrypted, streaming);
+        assert!(matches!(outcome, Err(AgeError::Cancelled)));
+        assert!(decrypted.len() < plaintext.len(), "{}", decrypted.len());
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_encrypt_deterministic() -> Result<()> {
+        let key = deterministic::Key::new("QmlsbHkgd2FzIGEgY2hhbXBpb24gb2YgYm93bGluZw==")?;
+        let (alice, bob) = (
+            age::x25519::Identity::generate(),
+            age::x25519::Identity::generate(),
+        );
+        let inline_alice = alice.to_string().expose_secret().to_string();
+        let inline_bob = bob.to_string().expose_secret().to_string();
+        let encrypt = |public_keys: &[String], encoding: Encoding| -> Result<Vec<u8>, AgeError> {
+            let mut encrypted = vec![];
+            let identities = [&inline_bob];
+            let cleartext = &mut &b"reproducible"[..];
+            let out = &mut encrypted;
+            encrypt_deterministic(&key, public_keys, &identities, cleartext, out, encoding)?;
+            Ok(encrypted)
+        };
+        let labeled = format!("{} # alice", alice.to_public());
+
+        let encrypted = encrypt(&[alice.to_public().to_string()], Encoding::Binary)?;
+
+        assert_eq!(encrypted, encrypt(&[labeled.clone()], Encoding::Binary)?);
+        for identity in [&inline_alice, &inline_bob] {
+            assert_eq!(
+                decrypt(&[identity], &mut &encrypted[..])?,
+                DecryptOutcome::Decrypted(b"reproducible".to_vec())
+            );
+        }
+        let armored = encrypt(&[labeled], Encoding::Armored)?;
+        assert_eq!(
+            reencode(&armored, Encoding::Binary)?.as_deref(),
+            Some(&encrypted[..])
+        );
+        let err = encrypt(&[SSH_ENCRYPTED_PUB.trim().to_string()], Encoding::Binary).unwrap_err();
+        assert!(
+            err.to_string().contains("only supports X25519 recipients"),
+            "{}",
+            err
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_recipients_of_identity() -> Result<()> {
+        let dir = TempDir::new()?;
+        let (alice, bob) = (
+            age::x25519::Identity::generate(),
+            age::x25519::Identity::generate(),
+        );
+        let identity_file = dir.child("team.txt");
+        identity_file.write_str(&format!(
+            "# created: 2024-01-01\r\n{}\r\n\r\n{}\r\n",
+            alice.to_string().expose_secret(),
+            bob.to_string().expose_secret()
+        ))?;
+        let inline = PathBuf::from(alice.to_string().expose_secret());
+
+        assert_eq!(
+            recipients_of_identity(identity_file.path())?,
+            [alice.to_public().to_string(), bob.to_public().to_string()]
+        );
+        assert_eq!(
+            recipients_of_identity(&inline)?,
+            [alice.to_public().to_string()]
+        );
+
+        let plugin = recipients_of_identity(Path::new(PLUGIN_IDENTITY)).unwrap_err();
+        assert!(
+            plugin.to_string().contains("only their plugin knows"),
+            "{}",
+            plugin
+        );
+        let key = dir.child("id_ed25519");
+        key.write_str(SSH_ENCRYPTED_KEY)?;
+        let ssh = recipients_of_identity(key.path()).unwrap_err();
+        assert!(ssh.to_string().contains("ssh-keygen -y"), "{}", ssh);
+        assert_matches!(
+            recipients_of_identity(&dir.path().join("missing.txt")),
+            Err(AgeError::IdentityNotFound(_))
+        );
+        Ok(())
+    }
+
+    /// Identity of the mock plugin, which unlike made up ones is valid bech32
+    const PLUGIN_IDENTITY: &str = "AGE-PLUGIN-MOCK-1D9JX2MN5D968J275AKZ";
+
+    /// ed25519 key protected with [`PASSPHRASE`], generated by `ssh-keygen -t ed25519`
+    const SSH_ENCRYPTED_KEY: &str = include_str!("../tests/fixtures/ssh_ed25519_encrypted");
+    const SSH_ENCRYPTED_PUB: &str = include_str!("../tests/fixtures/ssh_ed25519_encrypted.pub");
+    const SSH_KEY: &str = include_str!("../tests/fixtures/ssh_ed25519");
+
+    #[rstest]
+    #[case::required(true)]
+    #[case::not_required(false)]
+    fn test_check_encrypted_at_rest(#[case] required: bool) -> Result<()> {
+        let path = Path::new("identity.txt");
+        let identity = age::x25519::Identity::generate();
+        let plaintext = format!(
+            "# public key: {}\n{}\n",
+            identity.to_public(),
+            identity.to_string().expose_secret()
+        );
+        let passphrase = SecretString::from(PASSPHRASE);
+        let encrypted = encrypt_identity_file(plaintext.as_bytes(), passphrase)?;
+        let plugin = "# age-plugin-yubikey\nAGE-PLUGIN-YUBIKEY-1XYZ\n";
+
+        for accepted in [
+            &encrypted[..],
+            plugin.as_bytes(),
+            SSH_ENCRYPTED_KEY.as_bytes(),
+        ] {
+            check_encrypted_at_rest(path, accepted, required)?;
+        }
+        let bom = format!("\u{feff}{}", identity.to_string().expose_secret());
+        for rejected in [plaintext.as_str(), bom.as_str(), SSH_KEY] {
+            let checked = check_encrypted_at_rest(path, rejected.as_bytes(), required);
+            match required {
+                true => assert!(
+                    matches!(checked, Err(AgeError::PlaintextIdentity(_))),
+                    "{:?}",
+                    checked
+                ),
+                false => checked?,
+            }
+        }
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_decrypt_with_passphrase_protected_ssh_key() -> Result<()> {
+        let dir = TempDir::new()?;
+        let key = dir.child("id_ed25519");
+        key.write_str(SSH_ENCRYPTED_KEY)?;
+        let encrypted = encrypt(
+            &[SSH_ENCRYPTED_PUB.trim()],
+            &mut &b"behind two passphrases"[..],
+            Encoding::Binary,
+        )?;
+
+        let identity = load_ssh_identity(key.path(), |_| {
+            Some(SecretString::from(PASSPHRASE.to_string()))
+        })?
+        .unwrap();
+        let mut reader = Decryptor::new(&encrypted[..])?
+            .decrypt(std::iter::once(identity.as_ref() as &dyn Identity))?;
+        let mut decrypted = vec![];
+        reader.read_to_end(&mut decrypted)?;
+
+        assert_eq!(decrypted, b"behind two passphrases");
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_passphrase_protected_ssh_key_rejects_wrong_passphrase() -> Result<()> {
+        let dir = TempDir::new()?;
+        let key = dir.child("id_ed25519");
+        key.write_str(SSH_ENCRYPTED_KEY)?;
+
+        let wrong = load_ssh_identity(key.path(), |_| {
+            Some(SecretString::from("wrong".to_string()))
+        });
+        let missing = load_ssh_identity(key.path(), |_| None);
+
+        assert!(wrong
+            .unwrap_err()
+            .to_string()
+            .contains("incorrect passphrase for SSH key"));
+        assert!(missing
+            .unwrap_err()
+            .to_string()
+            .contains(AGE_PASSPHRASE_ENV));
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_derivable_recipients() -> Result<()> {
+        let dir = TempDir::new()?;
+        let identity = age::x25519::Identity::generate();
+        let identity_file = dir.child("identity.txt");
+        identity_file.write_str(&format!(
+            "# created: today\n{}\n",
+            identity.to_string().expose_secret()
+        ))?;
+        let ssh_key = dir.child("id_ed25519");
+        ssh_key.write_str(SSH_ENCRYPTED_KEY)?;
+        let ssh_without_pub = dir.child("id_other");
+        ssh_without_pub.write_str(SSH_ENCRYPTED_KEY)?;
+        dir.child("id_ed25519.pub").write_str(SSH_ENCRYPTED_PUB)?;
+        let encrypted = dir.child("encrypted.age");
+        encrypted.write_binary(&encrypt_with_passphrase(b"AGE-SECRET-KEY-1", true)?)?;
+
+        let public_key = identity.to_public().to_string();
+        assert_eq!(
+            derivable_recipients(identity_file.path()),
+            Some(vec![public_key])
+        );
+        let ssh = derivable_recipients(ssh_key.path()).expect("public key is next to it");
+        assert!(same_recipient(&ssh[0], SSH_ENCRYPTED_PUB), "{:?}", ssh);
+        assert_eq!(derivable_recipients(ssh_without_pub.path()), None);
+        assert_eq!(derivable_recipients(encrypted.path()), None);
+        assert_eq!(
+            derivable_recipients(Path::new("AGE-PLUGIN-YUBIKEY-1XYZ")),
+            None
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::label(SSH_ALICE, &format!("{} # alice", SSH_ALICE), true)]
+    #[case::comment(SSH_ALICE, &format!("{} alice@laptop", SSH_ALICE), true)]
+    #[case::other(SSH_ALICE, SSH_BOB, false)]
+    fn test_same_recipient(#[case] a: &str, #[case] b: &str, #[case] same: bool) {
+        assert_eq!(same_recipient(a, b), same);
+    }
+
+    #[rstest]
+    fn test_non_ssh_files_are_not_ssh_identities() -> Result<()> {
+        let dir = TempDir::new()?;
+        let identity = dir.child("identity.txt");
+        identity.write_str(
+            age::x25519::Identity::generate()
+                .to_string()
+                .expose_secret(),
+        )?;
+
+        assert!(load_ssh_identity(identity.path(), |_| panic!("passphrase requested"))?.is_none());
+        Ok(())
+    }
+
+    const FIXTURE_PUBLIC_KEY: &str =
+        "age19p9nfga0xjpqeljnhhxk3hwug0wve772svmvy45995u6kfephcvqj7wps7";
+
+    /// Identity files as saved by editors on Windows
+    #[rstest]
+    #[case::crlf(&include_bytes!("../tests/fixtures/identity_crlf.txt")[..])]
+    #[case::bom(&include_bytes!("../tests/fixtures/identity_bom.txt")[..])]
+    fn test_identity_file_from_windows_editor(#[case] contents: &[u8]) -> Result<()> {
+        let dir = TempDir::new()?;
+        let identity = dir.child("identity.txt");
+        identity.write_binary(contents)?;
+
+        assert_matches!(validate_identity(identity.path()), Ok(None));
+        assert_eq!(
+            recipients_of_identity(identity.path())?,
+            [FIXTURE_PUBLIC_KEY]
+        );
+        let encrypted = encrypt(
+            &[FIXTURE_PUBLIC_KEY],
+            &mut &b"from windows"[..],
+            Encoding::Armored,
+        )?;
+        let decrypted = decrypt(&[identity.path()], &mut &encrypted[..])?;
+        assert_eq!(
+            decrypted,
+            DecryptOutcome::Decrypted(b"from windows".to_vec())
+        );
+        Ok(())
+    }
+
+    /// Armored files after editors or line ending conversion got to them
+    #[rstest]
+    #[case::trailing_newlines(
+        &include_bytes!("../tests/fixtures/armored_trailing_newline.age")[..]
+    )]
+    #[case::crlf(&include_bytes!("../tests/fixtures/armored_crlf.age")[..])]
+    fn test_decrypt_mangled_armor(#[case] contents: &[u8]) -> Result<()> {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+        let identity = fixtures.join("identity_crlf.txt");
+
+        assert!(is_encrypted(contents));
+        assert_eq!(recipients_of(&mut &contents[..])?.len(), 1);
+        assert_eq!(
+            decrypt(&[identity], &mut &contents[..])?,
+            DecryptOutcome::Decrypted(b"DB_PASSWORD=hunter2\n".to_vec())
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_parse_getter_identity() -> Result<()> {
+        let contents = std::str::from_utf8(include_bytes!("../tests/fixtures/identity_crlf.txt"))?;
+
+        let identity = parse_getter_identity(contents)?;
+        let encrypted = encrypt(
+            &[FIXTURE_PUBLIC_KEY],
+            &mut &b"from the vault"[..],
+            Encoding::Binary,
+        )?;
+        let inline = PathBuf::from(identity.expose_secret());
+
+        assert!(!identity.expose_secret().contains('\r'));
+        assert_eq!(
+            decrypt(&[inline], &mut &encrypted[..])?,
+            DecryptOutcome::Decrypted(b"from the vault".to_vec())
+        );
+        assert!(parse_getter_identity("hunter2").is_err());
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_unparsable_identity_file_isnt_encrypted() -> Result<()> {
+        let dir = TempDir::new()?;
+        let identity = dir.child("identity.txt");
+        identity.write_str("AGE-SECRET-KEY-NOT-A-KEY\r\n")?;
+
+        let err = validate_identity(identity.path()).unwrap_err();
+
+        assert!(
+            err.to_string().contains("Failed to parse identity file"),
+            "{}",
+            err
+        );
+        Ok(())
+    }
+
+    const SSH_ALICE: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIBTqOL6QsCymbYvOXkxghyHqxYg8kPtoNAg84+05YHDs";
+    const SSH_BOB: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIHJhPwqcFkDwPw6rlyRZT8xsJDoLT+RxAigl8LZ/uLi/";
+    const SSH_RSA: &str = concat!(
+        "ssh-rsa ",
+        "AAAAB3NzaC1yc2EAAAADAQABAAABAQCG1bXHSMkXtjaXMPIMetDgsxkmj1pJeqtoitJR8tq0TGSFHttM",
+        "Ln/rAtLISgUx37mUWl2oMUk0IjjfxBuxDJLkGvT9y5RIGbs1cF7WSLlo4gyRb0vkDPcSDfmmO08dJu21",
+        "GCaVl5UDj5oVh2hlqXcoJXxA7dVo4JA3BzV6H+a/FGwD0fDHgWI9kHKVTTXqNrut8H87iAexI5JOgCL/",
+        "EZ9ZPLFU9nn1ukJIAAT+qxyA5n2cCBywYqJuV5FXq/GPlvLLF7+jDHDUoHyQwKfpymVEKWQvofbPYoc4",
+        "dTsg6/UlylAnS8KdeozMvDAJZdrIcunhE0nEHX4Zv1NEcj0qX5jr",
+    );
+
+    #[rstest]
+    fn test_recipient_type() {
+        let x25519 = age::x25519::Identity::generate().to_public().to_string();
+        assert_eq!(recipient_type(&x25519), Some(RecipientType::X25519));
+        assert_eq!(recipient_type(SSH_ALICE), Some(RecipientType::SshEd25519));
+        assert_eq!(
+            recipient_type(&format!("{} # alice", SSH_RSA)),
+            Some(RecipientType::SshRsa)
+        );
+        assert_eq!(recipient_type("age1yubikey1not-a-key"), None);
+        assert_eq!(
+            "ssh-rsa".parse::<RecipientType>().ok(),
+            Some(RecipientType::SshRsa)
+        );
+        assert!("rsa".parse::<RecipientType>().is_err());
+    }
+
+    #[rstest]
+    fn test_check_recipient_types() {
+        let allowed = [RecipientType::X25519, RecipientType::SshEd25519];
+        let x25519 = age::x25519::Identity::generate().to_public().to_string();
+
+        assert!(check_recipient_types(&[x25519.as_str(), SSH_ALICE], &allowed).is_ok());
+        let rsa = format!("{} # legacy", SSH_RSA);
+        assert_matches!(
+            check_recipient_types(&[SSH_ALICE, rsa.as_str()], &allowed),
+            Err(AgeError::RecipientTypeNotAllowed {
+                recipient,
+                kind: RecipientType::SshRsa,
+                allowed,
+            }) if recipient.ends_with("label=legacy") && allowed == "x25519, ssh-ed25519"
+        );
+    }
+
+    #[rstest]
+    #[case::same(&[SSH_ALICE, SSH_BOB], &[SSH_BOB, SSH_ALICE], true)]
+    #[case::removed(&[SSH_ALICE, SSH_BOB], &[SSH_ALICE], false)]
+    #[case::added(&[SSH_ALICE], &[SSH_ALICE, SSH_BOB], false)]
+    #[case::replaced(&[SSH_ALICE], &[SSH_BOB], false)]
+    fn test_is_encrypted_to_ssh_recipients(
+        #[case] encrypted_to: &[&str],
+        #[case] current: &[&str],
+        #[case] expected: bool,
+    ) -> Result<()> {
+        let encrypted = encrypt(encrypted_to, &mut &b"rekey"[..], Encoding::Armored)?;
+        assert_eq!(is_encrypted_to(current, &encrypted)?, expected);
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_audit_recipients() -> Result<()> {
+        let x25519 = age::x25519::Identity::generate().to_public().to_string();
+        let encrypted = encrypt(
+            &[SSH_ALICE, x25519.as_str()],
+            &mut &b"audit"[..],
+            Encoding::Armored,
+        )?;
+
+        let mut kinds: Vec<_> = recipients_of(&mut &encrypted[..])?
+            .into_iter()
+            .map(|stanza| stanza.kind)
+            .collect();
+        kinds.sort();
+        assert_eq!(kinds, ["X25519", "ssh-ed25519"]);
+
+        let audit = audit_recipients(&[SSH_BOB], &encrypted)?;
+        assert_eq!(audit.missing, [SSH_BOB]);
+        assert_eq!(audit.unknown.len(), 2);
+        assert!(audit.unknown[0].starts_with("ssh-ed25519 key tagged "));
+        assert_eq!(audit.unknown[1], "1 x25519 recipient");
+
+        assert!(audit_recipients(&[SSH_ALICE, x25519.as_str()], &encrypted)?.is_ok());
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_identify_recipients() -> Result<()> {
+        let bob = age::x25519::Identity::generate().to_public().to_string();
+        let public_keys = [SSH_ALICE, SSH_BOB, bob.as_str()];
+        let encrypted = encrypt(&public_keys, &mut &b"who"[..], Encoding::Binary)?;
+        let alice = format!("{} # alice", SSH_ALICE);
+
+        let mut identified = identify_recipients(&[alice.as_str(), bob.as_str()], &encrypted)?;
+        identified.sort_by_key(|stanza| matches!(stanza.owner, StanzaOwner::Anonymous(_)));
+
+        let owners: Vec<_> = identified
+            .iter()
+            .map(|stanza| stanza.owner.clone())
+            .collect();
+        assert_eq!(owners.len(), 3);
+        assert!(owners.contains(&StanzaOwner::Known(alice.clone())));
+        assert!(owners.contains(&StanzaOwner::Unknown));
+        assert_eq!(owners[2], StanzaOwner::Anonymous(vec![bob]));
+        assert_eq!(identified[2].description, "x25519 recipient");
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_recipients_state_of_x25519_recipients() -> Result<()> {
+        let alice = age::x25519::Identity::generate().to_public().to_string();
+        let bob = age::x25519::Identity::generate().to_public().to_string();
+        let encrypted = encrypt(&[&alice], &mut &b"rekey"[..], Encoding::Binary)?;
+
+        assert_eq!(
+            recipients_state(&[&alice], &encrypted)?,
+            RecipientsState::Unverifiable
+        );
+        assert_eq!(
+            recipients_state(&[&alice, &bob], &encrypted)?,
+            RecipientsState::Stale
+        );
+        assert_eq!(
+            recipients_state(&[SSH_ALICE], &encrypted)?,
+            RecipientsState::Stale
+        );
+        assert!(is_encrypted(&encrypted));
+        assert!(!is_encrypted(b"age-encryption.org/v1 but not really"));
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::binary(Encoding::Binary)]
+    #[case::armored(Encoding::Armored)]
+    fn test_is_age_encrypted(#[case] encoding: Encoding) -> Result<()> {
+        let recipient = age::x25519::Identity::generate().to_public().to_string();
+        let encrypted = encrypt(&[&recipient], &mut &b"secret"[..], encoding)?;
+
+        assert!(is_age_encrypted(&encrypted));
+        assert!(
+            is_age_encrypted(&encrypted[..ARMOR_BEGIN.len()]),
+            "only the magic is read"
+        );
+        assert!(!is_age_encrypted(b"DATABASE_URL=postgres://localhost"));
+        assert!(!is_age_encrypted(b""));
+        assert!(!is_age_encrypted(b"  age-encryption.org/v1"));
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::binary(Encoding::Binary)]
+    #[case::armored(Encoding::Armored)]
+    fn test_empty_file_round_trip(#[case] encoding: Encoding) -> Result<()> {
+        let dir = TempDir::new()?;
+        let identity = age::x25519::Identity::generate();
+        let identity_file = dir.child("identity.txt");
+        identity_file.write_str(identity.to_string().expose_secret())?;
+
+        let encrypted = encrypt(&[identity.to_public().to_string()], &mut &b""[..], encoding)?;
+        assert!(is_encrypted(&encrypted));
+
+        let decrypted = decrypt(&[identity_file.path()], &mut &encrypted[..])?;
+        assert_eq!(decrypted, DecryptOutcome::Decrypted(vec![]));
+        assert_eq!(
+            decrypt(&[identity_file.path()], &mut &b""[..])?,
+            DecryptOutcome::Plain
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_reencode() -> Result<()> {
+        let identity = age::x25519::Identity::generate();
+        let public_keys = [identity.to_public().to_string()];
+        let binary = encrypt(&public_keys, &mut &b"same ciphertext"[..], Encoding::Binary)?;
+
+        let armored = reencode(&binary, Encoding::Armored)?.expect("binary is converted");
+        assert_eq!(encoding_of(&armored), Encoding::Armored);
+        assert!(reencode(&armored, Encoding::Armored)?.is_none());
+        assert_eq!(reencode(&armored, Encoding::Binary)?, Some(binary.clone()));
+
+        let mut decrypted = vec![];
+        let outcome = decrypt_with(&[Box::new(identity)], &mut &armored[..], &mut decrypted)?;
+        assert_eq!(outcome, DecryptOutcome::Decrypted(()));
+        assert_eq!(decrypted, b"same ciphertext");
+        assert!(reencode(b"plaintext", Encoding::Binary).is_err());
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_truncated_header_isnt_plain() -> Result<()> {
+        let dir = TempDir::new()?;
+        let identity = age::x25519::Identity::generate();
+        let identity_file = dir.child("identity.txt");
+        identity_file.write_str(identity.to_string().expose_secret())?;
+        let recipients = [identity.to_public().to_string()];
+        let binary = encrypt(&recipients, &mut &b""[..], Encoding::Binary)?;
+        let armored = encrypt(&recipients, &mut &b""[..], Encoding::Armored)?;
+
+        let truncated = &binary[..AGE_MAGIC.len() + 20];
+        let err = decrypt(&[identity_file.path()], &mut &truncated[..]).unwrap_err();
+        assert!(matches!(err, AgeError::TruncatedHeader), "{}", err);
+        let truncated = &armored[..ARMOR_BEGIN.len() + 20];
+        assert!(decrypt(&[identity_file.path()], &mut &truncated[..]).is_err());
+        assert_eq!(
+            decrypt(&[identity_file.path()], &mut &b"age"[..])?,
+            DecryptOutcome::Plain
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_x25519_recipients_are_never_up_to_date() -> Result<()> {
+        let recipient = age::x25519::Identity::generate().to_public().to_string();
+        let encrypted = encrypt(&[&recipient], &mut &b"rekey"[..], Encoding::Binary)?;
+
+        assert!(!is_encrypted_to(&[&recipient], &encrypted)?);
+        assert!(is_encrypted_to(&[SSH_ALICE], b"plaintext").is_err());
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_read_recipients_file() -> Result<()> {
+        let dir = TempDir::new()?;
+        let alice = age::x25519::Identity::generate().to_public().to_string();
+        let bob = age::x25519::Identity::generate().to_public().to_string();
+        let file = dir.child("team.recipients");
+        file.write_str(&format!("# alice\n{alice}\n\n  {bob}  \n"))?;
+
+        assert_eq!(read_recipients_file(file.path())?, [alice, bob]);
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_read_recipients_file_reports_line() -> Result<()> {
+        let dir = TempDir::new()?;
+        let alice = age::x25519::Identity::generate().to_public().to_string();
+        let file = dir.child("team.recipients");
+        file.write_str(&format!("# alice\n{alice}\nnot-a-key\n"))?;
+
+        let err = read_recipients_file(file.path()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("Invalid recipient at {}:3", file.path().display())
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::x25519(
+        "age1ql3z7hjy54pw3hyww5ayyfg7zqgvc7w3j2elw8zmrj2kg5sfn9aqmcac8p",
+        "age1ql3z7hjy54pw3hyww5ayyfg7zqgvc7w3j2elw8zmrj2kg5sfn9aqmcac8p"
+    )]
+    #[case::ssh(SSH_ALICE, SSH_ALICE)]
+    #[case::plugin("age1mock1wfjkx6tsd9jkuaqpduv4h", "plugin:mock")]
+    #[case::labeled("age1mock1wfjkx6tsd9jkuaqpduv4h # alice", "plugin:mock, label=alice")]
+    fn test_describe_recipient(#[case] recipient: &str, #[case] expected: &str) {
+        assert_eq!(describe_recipient(recipient), expected);
+    }
+
+    #[rstest]
+    fn test_invalid_recipients_are_named() -> Result<()> {
+        let dir = TempDir::new()?;
+        let alice = age::x25519::Identity::generate().to_public().to_string();
+        let file = dir.child("team.recipients");
+        file.write_str(&format!("{alice}\nage1typo\n"))?;
+
+        let err = read_recipients_file(file.path()).unwrap_err();
+        let err = format!("{:#}", err);
+        assert!(err.contains("team.recipients:2"), "{}", err);
+        assert!(err.contains("\"age1typo\""), "{}", err);
+        assert!(err.contains("x25519"), "{}", err);
+
+        let err = validate_public_keys(&[alice.as_str(), "ssh-ed25519 AAAA"]).unwrap_err();
+        let err = format!("{:#}", err);
+        assert!(err.contains("position 2"), "{}", err);
+        assert!(err.contains("\"ssh-ed25519 AAAA\""), "{}", err);
+        Ok(())
+    }
+
+    /// Insecure stand-in for an age plugin, the "wrapped" file key is the file key itself
+    #[cfg(feature = "plugin-tests")]
+    const MOCK_PLUGIN: &str = r#"#!/usr/bin/env bash
+set -eu
+
+# Reads a stanza into $cmd, $args and $body
+read_stanza() {
+    read -r _ cmd args || true
+    body=""
+    while IFS= read -r line; do
+        body="$body$line"
+        [ "${#line}" -lt 64 ] && break
+    done
+}
+
+key=""
+case "$1" in
+--age-plugin=recipient-v1)
+    while read_stanza; do
+        [ "$cmd" = wrap-file-key ] && key="$body"
+        [ "$cmd" = done ] && break
+    done
+    printf -- '-> recipient-stanza 0 mock\n%s\n' "$key"
+    read_stanza
+    ;;
+--age-plugin=identity-v1)
+    while read_stanza; do
+        [ "$cmd" = recipient-stanza ] && [ "$args" = "0 mock" ] && key="$body"
+        [ "$cmd" = done ] && break
+    done
+    # "touch"
+    printf -- '-> msg\ndG91Y2g\n'
+    read_stanza
+    printf -- '-> file-key 0\n%s\n' "$key"
+    read_stanza
+    ;;
+esac
+printf -- '-> done\n\n'
+"#;
+
+    #[cfg(feature = "plugin-tests")]
+    #[rstest]
+    fn test_decrypt_with_plugin_identity() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new()?;
+        let plugin = dir.child("age-plugin-mock");
+        plugin.write_str(MOCK_PLUGIN)?;
+        std::fs::set_permissions(plugin.path(), std::fs::Permissions::from_mode(0o755))?;
+        let mut paths = vec![dir.path().to_path_buf()];
+        paths.extend(env::split_paths(&env::var_os("PATH").unwrap_or_default()));
+        env::set_var("PATH", env::join_paths(paths)?);
+
+        let identity_file = dir.child("identity.txt");
+        identity_file.write_str("AGE-PLUGIN-MOCK-1D9JX2MN5D968J275AKZ\n")?;
+
+        let encrypted = encrypt(
+            &["age1mock1wfjkx6tsd9jkuaqpduv4h"],
+            &mut &b"hardware backed"[..],
+            Encoding::Binary,
+        )?;
+        let decrypted = decrypt(&[identity_file.path()], &mut &encrypted[..])?;
+
+        assert_eq!(
+            decrypted,
+            DecryptOutcome::Decrypted(b"hardware backed".to_vec())
+        );
+        Ok(())
+    }
+}
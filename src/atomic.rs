@@ -0,0 +1,259 @@
+//! Crash-safe replacement of files, like the working tree files of `lock` and `unlock`.
+//!
+//! The new contents are written to a temporary file next to the original, which is
+//! renamed over it once complete, so that a crash or error leaves either the old or the
+//! new contents, but never a mix of both.
+
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+/// Replaces the contents of `path` with `contents`, see [`write_with`]
+pub(crate) fn write(path: &Path, contents: &[u8]) -> Result<()> {
+    write_with(path, |file| file.write_all(contents))
+}
+
+/// Like [`write`], but the file is only accessible by the current user, also when it
+/// existed with other permissions, e.g. for decrypted contents
+pub(crate) fn write_private(path: &Path, contents: &[u8]) -> Result<()> {
+    replace(path, true, |file| file.write_all(contents))
+}
+
+/// Replaces the contents of `path` with what `write` writes to the file it is given.
+/// An existing file keeps its permissions and, if possible, its owner. Links to it are
+/// followed, so that they keep pointing to it.
+pub(crate) fn write_with(
+    path: &Path,
+    write: impl FnOnce(&mut File) -> io::Result<()>,
+) -> Result<()> {
+    replace(path, false, write)
+}
+
+/// Like [`write_with`], for contents which may fail while they are produced, e.g. while
+/// they are decrypted, and are too large to buffer. The error of `write` is returned as
+/// it is, and the file is left alone. `private` is that of [`write_private`].
+pub(crate) fn write_streamed(
+    path: &Path,
+    private: bool,
+    write: impl FnOnce(&mut File) -> Result<()>,
+) -> Result<()> {
+    let mut failed = None;
+    let written = replace(path, private, |file| {
+        write(file).map_err(|err| {
+            failed = Some(err);
+            io::Error::other("producing the contents failed")
+        })
+    });
+    match failed {
+        Some(err) => Err(err),
+        None => written,
+    }
+}
+
+/// Writes and renames the temporary file, which is left `private` or gets the
+/// permissions of the file it replaces
+fn replace(
+    path: &Path,
+    private: bool,
+    write: impl FnOnce(&mut File) -> io::Result<()>,
+) -> Result<()> {
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let original = fs::metadata(&path).ok();
+    let tmp = tmp_path(&path);
+
+    let written = create(&tmp, private || original.is_some()).and_then(|mut file| {
+        write(&mut file)?;
+        file.sync_all()?;
+        if let Some(original) = &original {
+            if !private {
+                fs::set_permissions(&tmp, original.permissions())?;
+            }
+            keep_owner(&tmp, original);
+        }
+        fs::rename(&tmp, &path)
+    });
+    if let Err(err) = written {
+        let _ = fs::remove_file(&tmp);
+        return Err(err).with_context(|| format!("Couldn't write {:?}", path));
+    }
+    sync_parent(&path);
+    Ok(())
+}
+
+/// Hidden file next to `path`, unique to this process
+fn tmp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{}.tmp{}", name, std::process::id()))
+}
+
+/// Creates the temporary file, only accessible by the current user when it replaces a
+/// file, whose permissions are only applied once it is written. It is also readable, so
+/// that what was written can be checked before it replaces the file.
+fn create(tmp: &Path, private: bool) -> io::Result<File> {
+    let mut options = fs::OpenOptions::new();
+    options.read(true).write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        if private {
+            options.mode(0o600);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+    options.open(tmp)
+}
+
+#[cfg(unix)]
+fn keep_owner(tmp: &Path, original: &fs::Metadata) {
+    use std::os::unix::fs::MetadataExt;
+
+    let Ok(created) = fs::metadata(tmp) else {
+        return;
+    };
+    if (created.uid(), created.gid()) == (original.uid(), original.gid()) {
+        return;
+    }
+    // Only possible with privileges, e.g. when run as root on the files of others
+    if let Err(err) = std::os::unix::fs::chown(tmp, Some(original.uid()), Some(original.gid())) {
+        log::debug!(
+            "Couldn't keep the owner of the file; file={:?}, error={}",
+            tmp,
+            err
+        );
+    }
+}
+
+#[cfg(not(unix))]
+fn keep_owner(_tmp: &Path, _original: &fs::Metadata) {}
+
+/// Makes the rename durable, which needs syncing the directory on unix
+fn sync_parent(path: &Path) {
+    #[cfg(unix)]
+    {
+        let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+        let synced = File::open(dir.unwrap_or(Path::new("."))).and_then(|dir| dir.sync_all());
+        if let Err(err) = synced {
+            log::debug!(
+                "Couldn't sync the directory of the file; file={:?}, error={}",
+                path,
+                err
+            );
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_fs::{prelude::*, TempDir};
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    fn test_write_replaces_contents() -> Result<()> {
+        let dir = TempDir::new()?;
+        let file = dir.child("secret.env");
+        file.write_str("ciphertext")?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(file.path(), fs::Permissions::from_mode(0o640))?;
+        }
+
+        write(file.path(), b"plaintext")?;
+
+        file.assert("plaintext");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(
+                fs::metadata(file.path())?.permissions().mode() & 0o777,
+                0o640
+            );
+        }
+        assert_eq!(fs::read_dir(dir.path())?.count(), 1);
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_failed_write_keeps_original() -> Result<()> {
+        let dir = TempDir::new()?;
+        let file = dir.child("secret.env");
+        file.write_str("ciphertext")?;
+
+        let result = write_with(file.path(), |tmp| {
+            tmp.write_all(b"half of the plain")?;
+            Err(io::Error::other("crashed"))
+        });
+
+        assert!(result.is_err());
+        file.assert("ciphertext");
+        assert_eq!(
+            fs::read_dir(dir.path())?.count(),
+            1,
+            "temporary file is left"
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_failed_streamed_write_keeps_original() -> Result<()> {
+        let dir = TempDir::new()?;
+        let file = dir.child("secret.env");
+        file.write_str("ciphertext")?;
+
+        let result = write_streamed(file.path(), true, |tmp| {
+            tmp.write_all(b"half of the plain")?;
+            anyhow::bail!("Cancelled")
+        });
+
+        assert_eq!(result.unwrap_err().to_string(), "Cancelled");
+        file.assert("ciphertext");
+        assert_eq!(
+            fs::read_dir(dir.path())?.count(),
+            1,
+            "temporary file is left"
+        );
+        write_streamed(file.path(), false, |tmp| Ok(tmp.write_all(b"plaintext")?))?;
+        file.assert("plaintext");
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[rstest]
+    fn test_write_private() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new()?;
+        let file = dir.child("secret.env");
+        file.write_str("ciphertext")?;
+        fs::set_permissions(file.path(), fs::Permissions::from_mode(0o644))?;
+
+        write_private(file.path(), b"plaintext")?;
+
+        file.assert("plaintext");
+        assert_eq!(
+            fs::metadata(file.path())?.permissions().mode() & 0o777,
+            0o600
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_write_creates_missing_file() -> Result<()> {
+        let dir = TempDir::new()?;
+        let file = dir.child(".gitattributes");
+
+        write(file.path(), b"secrets/** filter=git-agecrypt\n")?;
+
+        file.assert("secrets/** filter=git-agecrypt\n");
+        Ok(())
+    }
+}
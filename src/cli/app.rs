@@ -1,38 +1,212 @@
-use anyhow::Result;
+use std::num::NonZeroUsize;
 
-use crate::ctx::Context;
+use anyhow::{bail, Context as _, Result};
 
-use super::{internal, public};
+use crate::{ctx::Context, git::Repository, parallel};
 
-use super::args::{Args, Commands, InternalCommands, ModifyConfig, PublicCommands, QueryConfig};
+use super::{
+    internal, public,
+    report::{self, Format},
+    standalone,
+};
 
-pub(crate) fn run(args: Args, ctx: impl Context) -> Result<()> {
+use super::args::{
+    Args, Commands, IdentityCommands, InternalCommands, ModifyConfig, PublicCommands, QueryConfig,
+    StandaloneCommands,
+};
+
+/// Runs the command of `args` in the repository of `ctx`. `getter` is the outcome of
+/// running the passphrase getter, with its key when one is used, which only doctor
+/// reports instead of failing right away.
+pub(crate) fn run(args: Args, ctx: impl Context, getter: Result<Option<String>>) -> Result<()> {
+    let progress = !args.quiet && args.format == Format::Human;
     match args.command {
-        Commands::Public(c) => run_public_command(c, ctx),
+        Commands::Public(c) => {
+            let jobs = args.jobs.unwrap_or_else(parallel::default_jobs);
+            run_public_command(c, ctx, getter, jobs, args.format, progress)
+        }
         Commands::Internal(c) => run_internal_command(c, ctx),
+        Commands::Standalone(c) => run_standalone_command(c, Some(&ctx), progress),
+        Commands::Completions { shell } => {
+            super::write_completions(shell, &mut std::io::stdout());
+            Ok(())
+        }
     }
 }
 
-fn run_internal_command(commands: InternalCommands, ctx: impl Context) -> Result<()> {
-    let cmd = internal::CommandContext { ctx };
+/// Runs commands which work outside of repositories, with `ctx` of the current one, if any
+pub(crate) fn run_standalone(args: Args, ctx: Option<impl Context>) -> Result<()> {
+    let progress = !args.quiet && args.format == Format::Human;
+    match args.command {
+        Commands::Standalone(c) => run_standalone_command(c, ctx.as_ref(), progress),
+        _ => run(args, ctx.context("Not inside a git repository")?, Ok(None)),
+    }
+}
+
+/// Runs `commands`, with progress bars of the encrypted or decrypted file if `progress`
+fn run_standalone_command(
+    commands: StandaloneCommands,
+    ctx: Option<&impl Context>,
+    progress: bool,
+) -> Result<()> {
     match commands {
-        InternalCommands::Clean { file } => cmd.clean(file),
-        InternalCommands::Smudge { file } => cmd.smudge(file),
+        StandaloneCommands::Encrypt {
+            to,
+            armor,
+            output,
+            file,
+        } => standalone::encrypt(ctx, to, armor.armor(), output, file, progress),
+        StandaloneCommands::Decrypt {
+            identities,
+            output,
+            private,
+            file,
+        } => standalone::decrypt(ctx, identities, output, private, file, progress),
+        StandaloneCommands::Identity(IdentityCommands::Rekey {
+            path,
+            encrypt,
+            new_passphrase_file,
+        }) => standalone::rekey_identity(path, encrypt, new_passphrase_file),
+        StandaloneCommands::Identity(IdentityCommands::Pubkey { path }) => {
+            standalone::identity_pubkey(path)
+        }
+    }
+}
+
+/// Whether `commands` is a filter which GIT_AGECRYPT_DISABLE turns into a pass-through
+pub(crate) fn passes_through(commands: &Commands) -> bool {
+    matches!(
+        commands,
+        Commands::Internal(InternalCommands::Clean { .. } | InternalCommands::Smudge { .. })
+    ) && internal::filters_disabled()
+}
+
+fn run_internal_command(commands: InternalCommands, ctx: impl Context) -> Result<()> {
+    let cmd = internal::CommandContext {
+        ctx,
+        disabled: internal::filters_disabled(),
+    };
+    let result = match commands {
+        InternalCommands::Clean {
+            file,
+            dry_run,
+            output,
+            input,
+        } => cmd.clean(file, dry_run, input, output),
+        InternalCommands::Smudge {
+            file,
+            no_cache,
+            output,
+            input,
+        } => cmd.smudge(file, no_cache, input, output),
         InternalCommands::Textconv { path } => cmd.textconv(path),
+        InternalCommands::Merge {
+            base,
+            current,
+            other,
+            path,
+        } => cmd.merge(&base, &current, &other, path),
+    };
+    internal::exit_on_broken_pipe(result)
+}
+
+/// Names the commands working on the index or the checked out files, which bare
+/// repositories don't have
+fn worktree_command(commands: &PublicCommands) -> Option<&'static str> {
+    match commands {
+        PublicCommands::Status { .. } => Some("status"),
+        PublicCommands::Audit { .. } => Some("audit"),
+        PublicCommands::Info { .. } => Some("info"),
+        PublicCommands::AssertRecipients { .. } => Some("assert-recipients"),
+        PublicCommands::Rekey { .. } => Some("rekey"),
+        PublicCommands::Reencode { .. } => Some("reencode"),
+        PublicCommands::Unlock => Some("unlock"),
+        PublicCommands::Lock => Some("lock"),
+        _ => None,
     }
 }
 
-fn run_public_command(commands: PublicCommands, ctx: impl Context) -> Result<()> {
-    let cmd = public::CommandContext::new(ctx);
+fn run_public_command(
+    commands: PublicCommands,
+    ctx: impl Context,
+    getter: Result<Option<String>>,
+    jobs: NonZeroUsize,
+    format: Format,
+    progress: bool,
+) -> Result<()> {
+    if internal::filters_disabled() {
+        eprintln!(
+            "git-agecrypt: warning: {} is set, clean and smudge pass contents through \
+             without encrypting or decrypting them",
+            internal::DISABLE_ENV
+        );
+    }
+    if let Some(name) = worktree_command(&commands).filter(|_| ctx.repo().is_bare()) {
+        bail!(
+            "`git-agecrypt {}` needs a working tree, {} is a bare repository",
+            name,
+            ctx.repo().path().display()
+        );
+    }
+    let cmd = public::CommandContext::new(ctx, progress);
     match commands {
-        PublicCommands::Init => {
-            cmd.init()?;
+        PublicCommands::Init { patterns, dry_run } => {
+            cmd.init(patterns, dry_run)?;
         }
         PublicCommands::Deinit => {
             cmd.deinit()?;
         }
-        PublicCommands::Status => {
-            cmd.status()?;
+        PublicCommands::Status {
+            porcelain,
+            pathspecs,
+        } => {
+            report::json_errors(format, cmd.status(porcelain, &pathspecs, jobs, format))?;
+        }
+        PublicCommands::Audit { pathspecs } => {
+            report::json_errors(format, cmd.audit(&pathspecs, format))?;
+        }
+        PublicCommands::Info { path } => {
+            report::json_errors(format, cmd.info(&path, format))?;
+        }
+        PublicCommands::AssertRecipients {
+            path,
+            recipients,
+            strict,
+        } => {
+            let asserted = cmd.assert_recipients(&path, &recipients, strict, format);
+            report::json_errors(format, asserted)?;
+        }
+        PublicCommands::Doctor => {
+            report::json_errors(format, cmd.doctor(getter, format))?;
+        }
+        PublicCommands::Rekey {
+            dry_run,
+            check,
+            strict,
+            since,
+            pathspecs,
+        } => {
+            if check {
+                cmd.check_rekeyed(strict, since.as_deref(), &pathspecs, jobs)?;
+            } else {
+                cmd.rekey(dry_run, since.as_deref(), &pathspecs, jobs)?;
+            }
+        }
+        PublicCommands::Reencode { to, dry_run } => {
+            cmd.reencode(to.into(), dry_run)?;
+        }
+        PublicCommands::GenIdentity {
+            output,
+            passphrase,
+            add,
+        } => {
+            cmd.gen_identity(output, passphrase, add)?;
+        }
+        PublicCommands::Unlock => {
+            cmd.unlock(jobs)?;
+        }
+        PublicCommands::Lock => {
+            cmd.lock(jobs)?;
         }
         PublicCommands::Config(cfg) => match cfg {
             super::args::ConfigCommands::Add(what) => match ModifyConfig::from(what) {
@@ -51,6 +225,9 @@ fn run_public_command(commands: PublicCommands, ctx: impl Context) -> Result<()>
                 QueryConfig::Identities => cmd.list_identities()?,
                 QueryConfig::Recipients => cmd.list_recipients()?,
             },
+            super::args::ConfigCommands::Validate => {
+                report::json_errors(format, cmd.validate_config(format))?
+            }
         },
     }
     Ok(())
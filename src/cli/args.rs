@@ -1,6 +1,10 @@
-use std::path::PathBuf;
+use std::{io, num::NonZeroUsize, path::PathBuf};
 
-use clap::{ArgGroup, Parser, Subcommand};
+use clap::{ArgAction, ArgGroup, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+
+use super::report::Format;
+use crate::age;
 
 /// Transparently encrypt/decrypt age secrets
 #[derive(Parser)]
@@ -10,6 +14,30 @@ pub struct Args {
     #[arg(short = 'g', long = "getter")]
     pub passphrase_getter: Option<String>,
 
+    /// File with the passphrase of encrypted identities, also set by AGE_PASSPHRASE_FILE
+    #[arg(long = "passphrase-file")]
+    pub passphrase_file: Option<PathBuf>,
+
+    /// Configuration file to use instead of git-agecrypt.toml, also set by GIT_AGECRYPT_CONFIG
+    #[arg(long = "config")]
+    pub config_file: Option<PathBuf>,
+
+    /// Number of files to process in parallel, defaults to the number of CPUs
+    #[arg(short = 'j', long = "jobs")]
+    pub jobs: Option<NonZeroUsize>,
+
+    /// Output format of status, audit, config validate and doctor
+    #[arg(long, value_enum, global = true, default_value = "human")]
+    pub format: Format,
+
+    /// Only log errors, and don't show progress bars while walking the encrypted files
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    pub quiet: bool,
+
+    /// Log more, -v for info and -vv for debug messages; RUST_LOG takes precedence
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    pub verbose: u8,
+
     #[clap(subcommand)]
     pub command: Commands,
 }
@@ -17,22 +45,46 @@ pub struct Args {
 #[derive(Subcommand)]
 #[clap(
     after_help = "In addition to the above, The following subcommands are used from git filters:
-    clean, smudge, textconv"
+    clean, smudge, textconv, merge"
 )]
 pub enum Commands {
     #[command(flatten)]
     Public(PublicCommands),
     #[command(flatten)]
     Internal(InternalCommands),
+    #[command(flatten)]
+    Standalone(StandaloneCommands),
+
+    /// Print the completion script for a shell
+    #[command(hide = true)]
+    Completions {
+        /// Shell to complete for
+        #[arg(value_enum)]
+        shell: Shell,
+    },
 }
 
 #[derive(Subcommand)]
 pub enum PublicCommands {
     /// Set-up repository for use with git-agecrypt
-    Init,
+    Init {
+        /// Pattern of files to encrypt, added to .gitattributes
+        #[arg(short, long = "pattern", num_args = 1..)]
+        patterns: Vec<String>,
+        /// Only print the git configuration and .gitattributes lines which would be added
+        #[arg(long)]
+        dry_run: bool,
+    },
 
-    /// Display configuration status information
-    Status,
+    /// Display configuration and the encryption state of tracked files
+    Status {
+        /// Only list the files with their state, in a format for scripts
+        #[arg(long)]
+        porcelain: bool,
+        /// Only the files matching these pathspecs, relative to the root of the repository
+        #[arg(value_name = "PATHSPEC")]
+        pathspecs: Vec<String>,
+    },
 
     /// Configure encryption settings
     #[command(subcommand)]
@@ -40,6 +92,250 @@ pub enum PublicCommands {
 
     /// Remove repository specific configuration
     Deinit,
+
+    /// Check that tracked files are encrypted to exactly the configured recipients
+    ///
+    /// Only reads the headers of the encrypted files, so no identity is needed. Fails
+    /// when any file is missing a recipient or is encrypted to an unknown one.
+    Audit {
+        /// Only the files matching these pathspecs, relative to the root of the repository
+        #[arg(value_name = "PATHSPEC")]
+        pathspecs: Vec<String>,
+    },
+
+    /// Show who a file is encrypted to, without decrypting it
+    ///
+    /// Reads the recipients from the header of the encrypted file, the staged one unless
+    /// the file itself is encrypted, and names them by the labels and aliases of the
+    /// configuration. x25519 and plugin recipients aren't named in the header.
+    Info {
+        /// The file to inspect
+        path: PathBuf,
+    },
+
+    /// Check that a file is encrypted to exactly the given recipients, e.g. in CI
+    ///
+    /// Compares the recipients in the header of the encrypted file, read like `info` does,
+    /// with the given ones, so no identity is needed. Fails naming the missing and extra
+    /// recipients. x25519 and plugin recipients aren't named in the header, so they can
+    /// only be compared by their number, unless `--strict` fails on them.
+    AssertRecipients {
+        /// The file to check
+        path: PathBuf,
+
+        /// Recipient the file has to be encrypted to, also an alias, group or keys URL
+        #[arg(short, long = "recipient", required = true)]
+        recipients: Vec<String>,
+
+        /// Fail when recipients can only be compared by their number
+        #[arg(long)]
+        strict: bool,
+    },
+
+    /// Re-encrypt tracked files to the currently configured recipients
+    ///
+    /// The re-encrypted files are staged, so that they can be committed.
+    Rekey {
+        /// Only list the files which would be re-encrypted
+        #[arg(long)]
+        dry_run: bool,
+        /// Fail listing the files which aren't encrypted to the current recipients,
+        /// without changing anything
+        #[arg(long, conflicts_with = "dry_run")]
+        check: bool,
+        /// With --check, also fail on the files whose x25519 and plugin recipients can
+        /// only be compared by their number
+        #[arg(long, requires = "check")]
+        strict: bool,
+        /// Only process the files changed between this commit and HEAD. A change of
+        /// the recipients still needs a full rekey.
+        #[arg(long, value_name = "REF")]
+        since: Option<String>,
+        /// Only the files matching these pathspecs, relative to the root of the repository
+        #[arg(value_name = "PATHSPEC")]
+        pathspecs: Vec<String>,
+    },
+
+    /// Convert tracked files to another encoding, keeping their recipients
+    ///
+    /// The ciphertext isn't decrypted, only its armor is added or removed. The converted
+    /// files are staged, so that they can be committed.
+    Reencode {
+        /// Encoding to convert the files to
+        #[arg(long, value_enum)]
+        to: TargetEncoding,
+        /// Only list the files which would be converted
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Diagnose the set-up of the repository, printing how to fix each problem
+    ///
+    /// Checks the git configuration written by `init`, the patterns of .gitattributes,
+    /// the identities, the recipients and the passphrase getter. Fails when any problem
+    /// keeps the filters from working.
+    Doctor,
+
+    /// Generate an x25519 identity and print its public key
+    ///
+    /// The identity file is only readable by the current user. It is never overwritten.
+    GenIdentity {
+        /// Identity file to write, defaults to ~/.config/git-agecrypt/identity.txt
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Encrypt the identity file with a passphrase, from AGE_PASSPHRASE or the terminal
+        #[arg(long)]
+        passphrase: bool,
+
+        /// Add the identity to the configuration of the repository, like `config add -i`
+        #[arg(long)]
+        add: bool,
+    },
+
+    /// Decrypt the encrypted files of the working tree in place
+    ///
+    /// Files checked out without a matching identity become readable. Files which
+    /// can't be decrypted are reported and skipped.
+    Unlock,
+
+    /// Replace the decrypted files of the working tree with their encrypted version
+    ///
+    /// Refuses to change anything when a file has changes which aren't staged, as
+    /// those would be lost. Use `git-agecrypt unlock` to decrypt them again.
+    Lock,
+}
+
+/// Commands which also work outside of repositories
+#[derive(Subcommand)]
+pub enum StandaloneCommands {
+    /// Encrypt a file or stdin to stdout, like `age -e`
+    ///
+    /// Inside a repository, the recipients configured for the file are added to the
+    /// given ones.
+    Encrypt {
+        #[command(flatten)]
+        to: EncryptRecipients,
+
+        #[command(flatten)]
+        armor: ArmorFlags,
+
+        #[command(flatten)]
+        output: OutputFile,
+
+        /// File to encrypt, stdin if not given
+        file: Option<PathBuf>,
+    },
+
+    /// Decrypt a file or stdin to stdout, like `age -d`
+    ///
+    /// Inside a repository, the configured identities are tried after the given ones.
+    Decrypt {
+        /// Identity file to decrypt with
+        #[arg(short, long = "identity")]
+        identities: Vec<PathBuf>,
+
+        #[command(flatten)]
+        output: OutputFile,
+
+        /// Make the output file only readable by the current user (0600)
+        #[arg(long, requires = "output")]
+        private: bool,
+
+        /// File to decrypt, stdin if not given
+        file: Option<PathBuf>,
+    },
+
+    /// Manage identity files
+    #[command(subcommand)]
+    Identity(IdentityCommands),
+}
+
+/// Who `encrypt` encrypts to
+#[derive(clap::Args)]
+pub struct EncryptRecipients {
+    /// Recipient to encrypt to
+    #[arg(short, long = "recipient")]
+    pub recipients: Vec<String>,
+
+    /// File with recipients to encrypt to, one per line, `-` for stdin
+    #[arg(short = 'R', long = "recipients-file")]
+    pub recipients_files: Vec<PathBuf>,
+
+    /// Identity file whose X25519 identities to encrypt to
+    #[arg(short, long = "identity")]
+    pub identities: Vec<PathBuf>,
+
+    /// Also encrypt to the configured identities, so that they can decrypt the result
+    ///
+    /// Passphrase-encrypted identity files are decrypted to derive the recipients,
+    /// plugin identities get theirs from the plugin.
+    #[arg(long)]
+    pub recipient_from_identity: bool,
+}
+
+/// Whether `encrypt` armors its output, overriding armor in git-agecrypt.toml
+#[derive(clap::Args)]
+pub struct ArmorFlags {
+    /// Emit ASCII armored output, the default if armor is set in git-agecrypt.toml
+    #[arg(short, long, overrides_with = "no_armor")]
+    armor: bool,
+
+    /// Emit binary output, even if armor is set in git-agecrypt.toml
+    #[arg(long, overrides_with = "armor")]
+    no_armor: bool,
+}
+
+impl ArmorFlags {
+    /// The given choice, `None` to use the configured encoding. The last flag wins.
+    pub fn armor(&self) -> Option<bool> {
+        match (self.armor, self.no_armor) {
+            (true, _) => Some(true),
+            (_, true) => Some(false),
+            _ => None,
+        }
+    }
+}
+
+/// Where `encrypt` and `decrypt` write their result
+#[derive(clap::Args)]
+pub struct OutputFile {
+    /// File to write to instead of stdout, `-` for stdout. It is replaced atomically.
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// Overwrite the output file if it exists
+    #[arg(long, requires = "output")]
+    pub force: bool,
+}
+
+#[derive(Subcommand)]
+pub enum IdentityCommands {
+    /// Re-encrypt a passphrase-encrypted identity file with a new passphrase
+    ///
+    /// The current passphrase is taken from the passphrase getter, the passphrase file or
+    /// AGE_PASSPHRASE, or asked for. The file is replaced atomically, keeping its permissions.
+    Rekey {
+        /// Identity file to re-encrypt
+        path: PathBuf,
+
+        /// Encrypt an identity file which isn't passphrase-encrypted yet
+        #[arg(long)]
+        encrypt: bool,
+
+        /// File with the new passphrase, asked for twice on the terminal if not given
+        #[arg(long = "new-passphrase-file")]
+        new_passphrase_file: Option<PathBuf>,
+    },
+
+    /// Print the recipients of an identity, to add them to the configuration
+    ///
+    /// Passphrase-encrypted identity files are decrypted like for decrypting files. The
+    /// recipients of plugin identities and SSH keys can't be derived from them.
+    Pubkey {
+        /// Identity file or inline identity
+        path: PathBuf,
+    },
 }
 
 #[derive(Subcommand)]
@@ -52,6 +348,11 @@ pub enum ConfigCommands {
 
     /// List configuration entries
     List(ConfigType),
+
+    /// Check identities, recipients and recipients files without encrypting anything
+    ///
+    /// Fails when any entry is invalid, so it can be run in CI.
+    Validate,
 }
 
 #[derive(clap::Args)]
@@ -170,6 +471,18 @@ pub enum InternalCommands {
         /// File to clean
         #[clap(short, long)]
         file: PathBuf,
+
+        /// Only report what would be encrypted to stderr, passing the input through.
+        /// Also enabled by setting GIT_AGECRYPT_DRY_RUN.
+        #[clap(long)]
+        dry_run: bool,
+
+        /// File to write the result to instead of stdout
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+
+        /// File to read instead of stdin
+        input: Option<PathBuf>,
     },
 
     /// Decrypt files from checkout
@@ -178,6 +491,18 @@ pub enum InternalCommands {
         /// File to smudge
         #[clap(short, long)]
         file: PathBuf,
+
+        /// Decrypt the file even if it is in the smudge cache, without caching it.
+        /// Also enabled by setting GIT_AGECRYPT_NO_CACHE.
+        #[clap(long)]
+        no_cache: bool,
+
+        /// File to write the result to instead of stdout
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+
+        /// File to read instead of stdin
+        input: Option<PathBuf>,
     },
 
     // Decrypt files for diff
@@ -186,8 +511,152 @@ pub enum InternalCommands {
         /// File to show
         path: PathBuf,
     },
+
+    /// Merge encrypted files
+    #[command(hide = true)]
+    Merge {
+        /// Version of the common ancestor (%O)
+        base: PathBuf,
+        /// Current version, replaced with the merge result (%A)
+        current: PathBuf,
+        /// Version from the other branch (%B)
+        other: PathBuf,
+        /// Path of the file in the repository, to find its recipients (%P)
+        path: PathBuf,
+    },
+}
+
+/// Builds the command line interface, for parsing and generating completions
+pub fn command() -> clap::Command {
+    Args::command()
+}
+
+impl Args {
+    /// Log level selected by `--quiet` and `--verbose`, warnings by default
+    pub fn log_level(&self) -> log::LevelFilter {
+        match (self.quiet, self.verbose) {
+            (true, _) => log::LevelFilter::Error,
+            (false, 0) => log::LevelFilter::Warn,
+            (false, 1) => log::LevelFilter::Info,
+            (false, _) => log::LevelFilter::Debug,
+        }
+    }
 }
 
 pub fn parse_args() -> Args {
-    Args::parse()
+    let matches = command().get_matches();
+    Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit())
+}
+
+pub fn write_completions(shell: Shell, out: &mut impl io::Write) {
+    let mut cmd = command();
+    let name = cmd.get_name().to_string();
+    clap_complete::generate(shell, &mut cmd, name, out);
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    fn test_command_is_valid() {
+        command().debug_assert();
+    }
+
+    #[rstest]
+    #[case::default(&[], log::LevelFilter::Warn)]
+    #[case::quiet(&["-q"], log::LevelFilter::Error)]
+    #[case::verbose(&["-v"], log::LevelFilter::Info)]
+    #[case::very_verbose(&["-vv"], log::LevelFilter::Debug)]
+    #[case::after_subcommand(&["status", "-v", "--verbose"], log::LevelFilter::Debug)]
+    fn test_log_level(#[case] flags: &[&str], #[case] expected: log::LevelFilter) {
+        let mut argv = vec!["git-agecrypt"];
+        argv.extend(flags);
+        if !argv.contains(&"status") {
+            argv.push("status");
+        }
+
+        let args = Args::try_parse_from(argv).unwrap();
+
+        assert_eq!(args.log_level(), expected);
+    }
+
+    #[rstest]
+    fn test_quiet_conflicts_with_verbose() {
+        assert!(Args::try_parse_from(["git-agecrypt", "-q", "-v", "status"]).is_err());
+    }
+
+    #[rstest]
+    #[case::stdio(&[], None, None)]
+    #[case::path(&["db.env"], Some("db.env"), None)]
+    #[case::path_and_output(&["db.env", "-o", "db.age"], Some("db.env"), Some("db.age"))]
+    fn test_clean_input_and_output(
+        #[case] extra: &[&str],
+        #[case] expected_input: Option<&str>,
+        #[case] expected_output: Option<&str>,
+    ) {
+        let mut argv = vec!["git-agecrypt", "clean", "-f", "secrets/db.env"];
+        argv.extend(extra);
+
+        let args = Args::try_parse_from(argv).unwrap();
+
+        let Commands::Internal(InternalCommands::Clean { input, output, .. }) = args.command else {
+            panic!("not parsed as clean");
+        };
+        assert_eq!(input, expected_input.map(PathBuf::from));
+        assert_eq!(output, expected_output.map(PathBuf::from));
+    }
+
+    #[rstest]
+    #[case::configured(&[], None)]
+    #[case::armor(&["--armor"], Some(true))]
+    #[case::short(&["-a"], Some(true))]
+    #[case::no_armor(&["--no-armor"], Some(false))]
+    #[case::last_wins(&["--armor", "--no-armor"], Some(false))]
+    #[case::last_wins_again(&["--no-armor", "-a"], Some(true))]
+    fn test_armor_flags(#[case] flags: &[&str], #[case] expected: Option<bool>) {
+        let mut argv = vec!["git-agecrypt", "encrypt", "-r", "age1..."];
+        argv.extend(flags);
+
+        let args = Args::try_parse_from(argv).unwrap();
+
+        let Commands::Standalone(StandaloneCommands::Encrypt { armor, .. }) = args.command else {
+            panic!("not parsed as encrypt");
+        };
+        assert_eq!(armor.armor(), expected);
+    }
+
+    #[rstest]
+    #[case::bash(Shell::Bash)]
+    #[case::zsh(Shell::Zsh)]
+    #[case::fish(Shell::Fish)]
+    #[case::powershell(Shell::PowerShell)]
+    fn test_completions_cover_subcommands(#[case] shell: Shell) {
+        let mut out = vec![];
+        write_completions(shell, &mut out);
+
+        let script = String::from_utf8(out).unwrap();
+        assert!(script.contains("git-agecrypt"));
+        assert!(script.contains("rekey"));
+    }
+}
+
+/// Encoding of encrypted files, see `output.armor`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum TargetEncoding {
+    /// Raw age format
+    Binary,
+    /// PEM-style ASCII armor
+    Armor,
+}
+
+impl From<TargetEncoding> for age::Encoding {
+    fn from(value: TargetEncoding) -> Self {
+        match value {
+            TargetEncoding::Binary => age::Encoding::Binary,
+            TargetEncoding::Armor => age::Encoding::Armored,
+        }
+    }
 }
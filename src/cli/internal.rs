@@ -1,22 +1,79 @@
 use std::{
-    fs::File,
+    collections::HashMap,
+    fs,
     io::{self, Read, Seek, Write},
     path::{Path, PathBuf},
 };
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context as _, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
 use blake3::Hash;
 
-use crate::{age, ctx::Context, git::Error as GitError, git::Repository};
+use crate::{
+    age, atomic,
+    config::{AgeIdentity, AppConfig, EncryptionScope, Envelope, IgnoreFile, IGNORE_FILE},
+    ctx::Context,
+    git::{self, Error as GitError, Repository},
+    partial, smudge_cache,
+};
 
 pub(crate) struct CommandContext<C: Context> {
     pub ctx: C,
+    /// Whether clean and smudge pass the contents through, see [`filters_disabled`]
+    pub disabled: bool,
 }
 
 impl<C: Context> CommandContext<C> {
-    pub(crate) fn clean(&self, file: impl AsRef<Path>) -> Result<()> {
-        log::info!("Encrypting file");
+    /// Encrypts the contents of `file`, read from `input` or stdin, to `output` or stdout
+    pub(crate) fn clean(
+        &self,
+        file: impl AsRef<Path>,
+        dry_run: bool,
+        input: Option<PathBuf>,
+        output: Option<PathBuf>,
+    ) -> Result<()> {
+        let timing = Timing::start("clean", file.as_ref());
         let file = self.ctx.repo().workdir().join(file);
+        let contents = read_input(input.as_deref())?;
+        let input_bytes = contents.len();
+        let mut out = Output::new(output);
+        if self.disabled {
+            pass_through("clean", &contents, &mut out)?;
+            timing.finish(input_bytes, out.written());
+            return out.finish();
+        }
+        let dry_run = dry_run || env_flag(DRY_RUN_ENV);
+        if IgnoreFile::load(self.ctx.repo().workdir())?.is_ignored(&file) {
+            log::info!(
+                "File is listed in {}, passing it through unencrypted",
+                IGNORE_FILE
+            );
+            if dry_run {
+                let relpath = file
+                    .strip_prefix(self.ctx.repo().workdir())
+                    .unwrap_or(&file);
+                eprintln!(
+                    "git-agecrypt dry run: {} is listed in {}; passed through unencrypted",
+                    relpath.display(),
+                    IGNORE_FILE
+                );
+            }
+            out.write_all(&contents)?;
+        } else if dry_run {
+            self.clean_dry_run(&file, contents, &mut out)?;
+        } else if let Some(envelope) = self.ctx.config()?.envelope() {
+            let mut encrypted = vec![];
+            self.clean_contents(file, contents, &mut encrypted)?;
+            out.write_all(&seal_envelope(envelope, encrypted))?;
+        } else {
+            self.clean_contents(file, contents, &mut out)?;
+        }
+        timing.finish(input_bytes, out.written());
+        out.finish()
+    }
+
+    fn clean_contents(&self, file: PathBuf, contents: Vec<u8>, out: &mut impl Write) -> Result<()> {
+        log::info!("Encrypting file");
 
         log::debug!("Looking for saved has information. target={:?}", file,);
         let mut existing_hash = [0u8; 32];
@@ -27,8 +84,29 @@ impl<C: Context> CommandContext<C> {
         }
 
         let mut hasher = blake3::Hasher::new();
-        let mut contents = vec![];
-        io::stdin().read_to_end(&mut contents)?;
+        let mut contents = contents;
+        if age::is_encrypted(&contents) {
+            // Encrypting it again would nest age layers which smudge only removes one of
+            let public_keys = self.ctx.config()?.get_public_keys(&file)?;
+            if check_already_encrypted(&file, &public_keys, &contents)? {
+                log::info!("File is already encrypted, passing it through");
+                return Ok(out.write_all(&contents)?);
+            }
+            let identities = self.get_identities()?;
+            let decrypted = age::decrypt(&identities, &mut &contents[..])
+                .and_then(|outcome| outcome.required(&identities))
+                .with_context(|| {
+                    format!(
+                        "{} is already age encrypted, and its x25519 or plugin recipients can \
+                         only be checked by decrypting it",
+                        file.display()
+                    )
+                })?;
+            if let Some(decrypted) = decrypted {
+                contents = decrypted;
+            }
+        }
+
         let hash = hasher.update(&contents).finalize();
 
         let old_hash = Hash::from(existing_hash);
@@ -44,8 +122,23 @@ impl<C: Context> CommandContext<C> {
             None
         };
 
-        let result = self.get_content(contents, hash, file, saved)?;
-        Ok(io::stdout().write_all(&result)?)
+        if self.ctx.config()?.encryption_scope(&file)? == EncryptionScope::MarkedLines {
+            return self.get_marked_lines(contents, hash, file, saved, out);
+        }
+        self.get_content(contents, hash, file, saved, out)
+    }
+
+    /// Runs the encryption without storing anything and passes the input through
+    fn clean_dry_run(&self, file: &Path, contents: Vec<u8>, out: &mut Output) -> Result<()> {
+        log::info!("Checking encryption of file, dry run");
+        let cfg = self.ctx.config()?;
+        let public_keys = cfg.get_public_keys(file)?;
+        let relpath = file.strip_prefix(self.ctx.repo().workdir()).unwrap_or(file);
+        eprintln!(
+            "{}",
+            dry_run_report(relpath, &public_keys, cfg.encoding(), &contents)?
+        );
+        Ok(out.write_all(&contents)?)
     }
 
     fn get_content(
@@ -54,31 +147,41 @@ impl<C: Context> CommandContext<C> {
         hash: Hash,
         file: PathBuf,
         saved_content: Option<Vec<u8>>,
-    ) -> Result<Vec<u8>> {
+        out: &mut impl Write,
+    ) -> Result<()> {
         if let Some(saved_content) = saved_content {
             log::debug!("File didn't change since last encryption, loading from git HEAD");
-            return Ok(saved_content);
+            return Ok(out.write_all(&saved_content)?);
         }
 
-        log::debug!("Encrypted content changed, checking decrypted version");
-        let repo_contents = match self.ctx.repo().get_file_contents(&file) {
-            Ok(v) => Some(v),
-            Err(GitError::NotExist(s)) => {
-                log::debug!("{}", s);
-                None
+        log::debug!("Encrypted content changed, checking the staged and committed versions");
+        // Reusing their ciphertext keeps git from seeing a change when only the random
+        // file key of a new encryption would differ
+        let mut versions = vec![];
+        let repo = self.ctx.repo();
+        let envelope = self.ctx.config()?.envelope();
+        for version in [
+            repo.get_staged_contents(&file),
+            repo.get_file_contents(&file),
+        ] {
+            match version.map(|v| open_envelope(envelope, v)) {
+                Ok(v) => {
+                    if !versions.contains(&v) {
+                        versions.push(v);
+                    }
+                }
+                Err(GitError::NotExist(s)) => log::debug!("{}", s),
+                Err(e) => return Err(e.into()),
             }
-            Err(e) => return Err(e.into()),
-        };
+        }
 
-        if let Some(repo_contents) = repo_contents {
+        if !versions.is_empty() {
             let identities = self.get_identities()?;
-            let mut cur = io::Cursor::new(repo_contents);
-            let decrypted = age::decrypt(&identities, &mut cur)?.unwrap_or_default();
-            if decrypted == contents {
-                log::debug!("Decrypted content matches, using from working copy");
-                self.ctx.store_sidecar(&file, "hash", hash.as_bytes())?;
-                self.ctx.store_sidecar(&file, "age", cur.get_ref())?;
-                return Ok(cur.into_inner());
+            if let Some(encrypted) = reusable_ciphertext(versions, &contents, &identities)? {
+                log::debug!("Decrypted content matches, reusing its ciphertext");
+                self.ctx
+                    .store_sidecars(&file, hash.as_bytes(), &encrypted)?;
+                return Ok(out.write_all(&encrypted)?);
             }
         }
 
@@ -87,10 +190,111 @@ impl<C: Context> CommandContext<C> {
         let cfg = self.ctx.config()?;
         let public_keys = cfg.get_public_keys(&file)?;
 
-        let res = age::encrypt(&public_keys, &mut &contents[..])?;
-        self.ctx.store_sidecar(&file, "hash", hash.as_bytes())?;
-        self.ctx.store_sidecar(&file, "age", &res)?;
-        Ok(res)
+        // The ciphertext is streamed into the new sidecar, which only replaces the
+        // previous one once encrypting succeeded
+        self.ctx
+            .store_sidecars_with(&file, hash.as_bytes(), |sidecar| {
+                if !cfg.require_self_decrypt() {
+                    return Ok(age::encrypt_to(
+                        &public_keys,
+                        &mut &contents[..],
+                        &mut Tee(out, sidecar),
+                        cfg.encoding(),
+                    )?);
+                }
+                // Nothing is written before the check, so that git doesn't store the result
+                age::encrypt_to(&public_keys, &mut &contents[..], sidecar, cfg.encoding())?;
+                sidecar.rewind()?;
+                check_self_decrypt(&file, sidecar, &self.get_identities()?)?;
+                sidecar.rewind()?;
+                io::copy(sidecar, out)?;
+                Ok(())
+            })
+    }
+
+    /// Like [`Self::get_content`], for files of which only the marked lines are encrypted.
+    /// Lines unchanged since the committed version keep their ciphertext, so that they
+    /// don't show up in diffs.
+    fn get_marked_lines(
+        &self,
+        contents: Vec<u8>,
+        hash: Hash,
+        file: PathBuf,
+        saved_content: Option<Vec<u8>>,
+        out: &mut impl Write,
+    ) -> Result<()> {
+        if let Some(saved_content) = saved_content {
+            log::debug!("File didn't change since last encryption, loading saved lines");
+            return Ok(out.write_all(&saved_content)?);
+        }
+
+        log::info!("Encrypting marked lines of file");
+        let contents = String::from_utf8(contents).with_context(|| {
+            format!("{} isn't UTF-8, can't encrypt marked lines", file.display())
+        })?;
+        let committed = self.committed_lines(&file)?;
+        let cfg = self.ctx.config()?;
+        let public_keys = cfg.get_public_keys(&file)?;
+        let identities = match cfg.require_self_decrypt() {
+            true => Some(self.get_identities()?),
+            false => None,
+        };
+        let encrypted = partial::encrypt_lines(&contents, |content| {
+            let reused = committed.get(content).filter(|ciphertext| {
+                matches!(
+                    age::recipients_state(&public_keys, ciphertext),
+                    Ok(age::RecipientsState::Current | age::RecipientsState::Unverifiable)
+                )
+            });
+            if let Some(ciphertext) = reused {
+                return Ok(ciphertext.clone());
+            }
+            let mut ciphertext = vec![];
+            age::encrypt_to(
+                &public_keys,
+                &mut content.as_bytes(),
+                &mut ciphertext,
+                age::Encoding::Binary,
+            )?;
+            if let Some(identities) = &identities {
+                check_self_decrypt(&file, &ciphertext, identities)?;
+            }
+            Ok(ciphertext)
+        })?;
+        self.ctx
+            .store_sidecars(&file, hash.as_bytes(), encrypted.as_bytes())?;
+        Ok(out.write_all(encrypted.as_bytes())?)
+    }
+
+    /// The decrypted lines of the committed version of `file`, with their ciphertexts
+    fn committed_lines(&self, file: &Path) -> Result<HashMap<String, Vec<u8>>> {
+        let committed = match self.ctx.repo().get_file_contents(file) {
+            Ok(v) => v,
+            Err(GitError::NotExist(s)) => {
+                log::debug!("{}", s);
+                return Ok(HashMap::new());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let committed = String::from_utf8_lossy(&committed);
+        let mut lines = HashMap::new();
+        let ciphertexts = partial::ciphertexts(&committed);
+        if ciphertexts.is_empty() {
+            return Ok(lines);
+        }
+        let identities = self.get_identities()?;
+        for ciphertext in ciphertexts {
+            match age::decrypt(&identities, &mut &ciphertext[..]) {
+                Ok(age::DecryptOutcome::Decrypted(plaintext)) => {
+                    if let Ok(plaintext) = String::from_utf8(plaintext) {
+                        lines.insert(plaintext, ciphertext);
+                    }
+                }
+                Ok(_) => {}
+                Err(err) => log::debug!("Couldn't decrypt committed line; error={:#}", err),
+            }
+        }
+        Ok(lines)
     }
 
     fn get_identities(&self) -> Result<Vec<String>> {
@@ -100,54 +304,1053 @@ impl<C: Context> CommandContext<C> {
             "Loaded identities from config; identities='{:?}'",
             all_identities
         );
-        Ok(all_identities)
+        let mut expanded = vec![];
+        for path in all_identities {
+            expanded.push(AgeIdentity { path }.expanded_path()?);
+        }
+        Ok(self.ctx.config()?.merge_identities(expanded)?)
     }
 
-    pub(crate) fn smudge(&self, file: impl AsRef<Path>) -> Result<()> {
-        log::info!("Decrypting file");
+    /// Decrypts the contents of `file`, read from `input` or stdin, to `output` or stdout
+    pub(crate) fn smudge(
+        &self,
+        file: impl AsRef<Path>,
+        no_cache: bool,
+        input: Option<PathBuf>,
+        output: Option<PathBuf>,
+    ) -> Result<()> {
+        let timing = Timing::start("smudge", file.as_ref());
         let file = self.ctx.repo().workdir().join(file);
+        let encrypted = read_input(input.as_deref())?;
+        let mut out = Output::new(output);
+        if self.disabled {
+            pass_through("smudge", &encrypted, &mut out)?;
+        } else {
+            let encrypted = open_envelope(self.ctx.config()?.envelope(), encrypted);
+            self.smudge_contents(&file, no_cache, &encrypted, &mut out)?;
+        }
+        timing.finish(encrypted.len(), out.written());
+        out.finish()
+    }
 
-        let mut encrypted = vec![];
-        io::stdin().read_to_end(&mut encrypted)?;
-        let mut cur = io::Cursor::new(encrypted);
+    fn smudge_contents(
+        &self,
+        file: &Path,
+        no_cache: bool,
+        encrypted: &[u8],
+        out: &mut Output,
+    ) -> Result<()> {
+        log::info!("Decrypting file");
         let all_identities = self.get_identities()?;
-        if let Some(rv) = age::decrypt(&all_identities, &mut cur)? {
-            log::info!("Decrypted file");
-            let mut hasher = blake3::Hasher::new();
-            let hash = hasher.update(&rv).finalize();
+        let cfg = self.ctx.config()?;
 
-            log::debug!("Storing hash for file; hash={:?}", hash.to_hex().as_str(),);
-            self.ctx.store_sidecar(&file, "hash", hash.as_bytes())?;
-            self.ctx.store_sidecar(&file, "age", cur.get_ref())?;
+        if partial::has_encrypted_lines(encrypted) {
+            return self.smudge_marked_lines(file, encrypted, &all_identities, &cfg, out);
+        }
+        let require_decrypt = cfg.require_decrypt();
+        if !age::is_age_encrypted(encrypted) {
+            // Added without the filter, which needs neither the cache nor a decryptor
+            let plain = age::DecryptOutcome::Plain;
+            if let Some(reason) = undecrypted_reason(plain, require_decrypt, &all_identities)? {
+                return self.check_out_as_is(file, encrypted, reason, out);
+            }
+        }
+
+        let cache = if no_cache || env_flag(NO_CACHE_ENV) {
+            None
+        } else {
+            self.smudge_cache(&cfg, file, encrypted, &all_identities)
+        };
+        if let Some(cache) = &cache {
+            if let Some(plaintext) = smudge_cache::load(&cache.dir, &cache.key) {
+                log::info!("Decrypted file from the smudge cache");
+                out.write_all(&plaintext)?;
+                return self.store_smudged(file, blake3::hash(&plaintext), encrypted);
+            }
+        }
 
-            Ok(io::stdout().write_all(&rv)?)
+        let mut hasher = blake3::Hasher::new();
+        let (mut plaintext, mut sink) = (vec![], io::sink());
+        let copy: &mut dyn Write = if cache.is_some() {
+            &mut plaintext
         } else {
-            bail!("Input isn't encrypted")
+            &mut sink
+        };
+        let decrypted = {
+            let mut out = Tee(&mut *out, Tee(&mut hasher, copy));
+            age::decrypt_to(&all_identities, &mut &encrypted[..], &mut out)
+                .map_err(|err| err.for_input(file))?
+        };
+        if let Some(reason) = undecrypted_reason(decrypted, require_decrypt, &all_identities)? {
+            return self.check_out_as_is(file, encrypted, reason, out);
         }
+
+        log::info!("Decrypted file");
+        if let Some(cache) = cache {
+            let stored = smudge_cache::store(&cache.dir, &cache.key, &plaintext, cache.max_bytes);
+            if let Err(err) = stored {
+                log::warn!("Couldn't cache decrypted file; error={:#}", err);
+            }
+        }
+        self.store_smudged(file, hasher.finalize(), encrypted)
+    }
+
+    /// Passes a file smudge couldn't decrypt through, warning why
+    fn check_out_as_is(
+        &self,
+        file: &Path,
+        encrypted: &[u8],
+        reason: &str,
+        out: &mut Output,
+    ) -> Result<()> {
+        let relpath = file.strip_prefix(self.ctx.repo().workdir()).unwrap_or(file);
+        eprintln!(
+            "git-agecrypt: warning: {} {}, checking it out as it is",
+            relpath.display(),
+            reason
+        );
+        Ok(out.write_all(encrypted)?)
+    }
+
+    /// Like [`Self::smudge`], for files of which only the marked lines are encrypted.
+    /// These aren't cached, as most of their contents are in git anyway.
+    fn smudge_marked_lines(
+        &self,
+        file: &Path,
+        encrypted: &[u8],
+        identities: &[String],
+        cfg: &AppConfig,
+        out: &mut Output,
+    ) -> Result<()> {
+        let plaintext = match decrypt_marked_lines(identities, encrypted) {
+            Ok(plaintext) => plaintext,
+            Err(err) if !cfg.require_decrypt() && is_no_matching_identity(&err) => {
+                let relpath = file.strip_prefix(self.ctx.repo().workdir()).unwrap_or(file);
+                eprintln!(
+                    "git-agecrypt: warning: {} has lines which can't be decrypted with the \
+                     configured identities, checking it out as it is",
+                    relpath.display()
+                );
+                return Ok(out.write_all(encrypted)?);
+            }
+            Err(err) => return Err(err),
+        };
+
+        log::info!("Decrypted marked lines of file");
+        out.write_all(plaintext.as_bytes())?;
+        self.store_smudged(file, blake3::hash(plaintext.as_bytes()), encrypted)
+    }
+
+    /// Locates the cache entry of a file, if caching is enabled and its recipients are known
+    fn smudge_cache(
+        &self,
+        cfg: &AppConfig,
+        file: &Path,
+        encrypted: &[u8],
+        identities: &[String],
+    ) -> Option<SmudgeCache> {
+        let max_bytes = cfg.smudge_cache_max_bytes()?;
+        let public_keys = cfg
+            .get_public_keys(file)
+            .map_err(|err| log::debug!("Not caching file without recipients; error={:#}", err))
+            .ok()?;
+        Some(SmudgeCache {
+            dir: self.ctx.repo().path().join("git-agecrypt"),
+            key: smudge_cache::key(encrypted, &public_keys, identities),
+            max_bytes,
+        })
+    }
+
+    /// Lets the clean filter reuse the ciphertext while the checked out file is unchanged
+    fn store_smudged(&self, file: &Path, hash: Hash, encrypted: &[u8]) -> Result<()> {
+        log::debug!("Storing hash for file; hash={:?}", hash.to_hex().as_str(),);
+        self.ctx.store_sidecars(file, hash.as_bytes(), encrypted)
     }
 
     pub(crate) fn textconv(&self, path: impl AsRef<Path>) -> Result<()> {
         log::info!("Decrypting file to show in diff");
+        let path = path.as_ref();
 
-        let all_identities: Vec<String> = self
-            .ctx
-            .age_identities()
-            .list()?
-            .into_iter()
-            .map(|i| i.path)
-            .collect();
+        let all_identities = self.get_identities()?;
 
-        let mut f = File::open(path)?;
-        let result = if let Some(rv) = age::decrypt(&all_identities, &mut f)? {
+        let contents =
+            fs::read(path).with_context(|| format!("Couldn't open {:?} for diff", path))?;
+        let contents = open_envelope(self.ctx.config()?.envelope(), contents);
+        let mut stdout = io::stdout().lock();
+        let decrypted = age::decrypt_to(&all_identities, &mut &contents[..], &mut stdout)
+            .and_then(|outcome| outcome.required(&all_identities))
+            .map_err(|err| err.for_input(path))
+            .with_context(|| format!("Couldn't decrypt {:?} for diff", path))?;
+        if decrypted.is_some() {
             log::info!("Decrypted file to show in diff");
-            rv
+        } else if partial::has_encrypted_lines(&contents) {
+            log::info!("Decrypting marked lines of file to show in diff");
+            let decrypted = decrypt_marked_lines(&all_identities, &contents)
+                .with_context(|| format!("Couldn't decrypt {:?} for diff", path))?;
+            stdout.write_all(decrypted.as_bytes())?;
         } else {
             log::info!("File isn't encrypted, probably a working copy; showing as is.");
-            f.rewind()?;
-            let mut buff = vec![];
-            f.read_to_end(&mut buff)?;
-            buff
+            stdout.write_all(&contents)?;
+        }
+        Ok(stdout.flush()?)
+    }
+
+    pub(crate) fn merge(
+        &self,
+        base: &Path,
+        current: &Path,
+        other: &Path,
+        path: impl AsRef<Path>,
+    ) -> Result<()> {
+        log::info!("Merging encrypted file");
+        let file = self.ctx.repo().workdir().join(&path);
+
+        let identities = self.get_identities()?;
+        let cfg = self.ctx.config()?;
+        let public_keys = cfg.get_public_keys(&file)?;
+        let envelope = cfg.envelope();
+        let read = |p: &Path| {
+            let contents = fs::read(p).with_context(|| format!("Couldn't read {:?}", p));
+            contents.map(|contents| open_envelope(envelope, contents))
+        };
+        let mut merged = merge_encrypted(
+            &identities,
+            &public_keys,
+            cfg.encoding(),
+            [&read(base)?, &read(current)?, &read(other)?],
+            self.ctx.repo().path(),
+        )?;
+
+        if let Some(envelope) = envelope {
+            merged.contents = seal_envelope(envelope, merged.contents);
+        }
+        // Git takes the result from the current version's file, also when conflicting
+        atomic::write(current, &merged.contents)
+            .with_context(|| format!("Couldn't write merge result to {:?}", current))?;
+        if merged.conflicts > 0 {
+            bail!(
+                "{} merge conflicts in {}",
+                merged.conflicts,
+                path.as_ref().display()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Merges the decrypted `[base, current, other]` versions of a file and encrypts the
+/// result, conflict markers included. Versions which aren't encrypted, like the empty
+/// base of a file added on both sides, are merged as they are.
+fn merge_encrypted(
+    identities: &[impl AsRef<Path>],
+    public_keys: &[String],
+    encoding: age::Encoding,
+    versions: [&[u8]; 3],
+    scratch_dir: &Path,
+) -> Result<git::MergedFile> {
+    let mut decrypted = vec![];
+    for version in versions {
+        let plaintext = age::decrypt(identities, &mut &version[..])?.required(identities)?;
+        decrypted.push(plaintext.unwrap_or_else(|| version.to_vec()));
+    }
+
+    let merged = git::merge_file(&decrypted[0], &decrypted[1], &decrypted[2], scratch_dir)?;
+    Ok(git::MergedFile {
+        contents: age::encrypt(public_keys, &mut &merged.contents[..], encoding)?,
+        conflicts: merged.conflicts,
+    })
+}
+
+/// Removes the `envelope` of `input.decode` from the `stored` contents of a file. Only
+/// contents which decode to an age file are decoded, anything else like age files or
+/// plaintext which happens to be valid base64 is returned as it is.
+pub(crate) fn open_envelope(envelope: Option<Envelope>, stored: Vec<u8>) -> Vec<u8> {
+    let Some(Envelope::Base64) = envelope else {
+        return stored;
+    };
+    if age::is_age_encrypted(&stored) {
+        return stored;
+    }
+    let encoded: Vec<u8> = stored
+        .iter()
+        .copied()
+        .filter(|b| !b.is_ascii_whitespace())
+        .collect();
+    match STANDARD.decode(&encoded) {
+        Ok(decoded) if age::is_age_encrypted(&decoded) => decoded,
+        _ => {
+            log::debug!("Contents don't decode to an age file, passing them on as they are");
+            stored
+        }
+    }
+}
+
+/// Wraps the age file `encrypted` into `envelope`, leaving anything else like files of
+/// which only marked lines are encrypted as it is
+pub(crate) fn seal_envelope(envelope: Envelope, encrypted: Vec<u8>) -> Vec<u8> {
+    if !age::is_age_encrypted(&encrypted) {
+        return encrypted;
+    }
+    match envelope {
+        Envelope::Base64 => format!("{}\n", STANDARD.encode(&encrypted)).into_bytes(),
+    }
+}
+
+/// Decrypts the lines of `contents` encrypted by the clean filter of marked-lines rules
+fn decrypt_marked_lines(identities: &[impl AsRef<Path>], contents: &[u8]) -> Result<String> {
+    let contents =
+        std::str::from_utf8(contents).context("File with encrypted lines isn't UTF-8")?;
+    partial::decrypt_lines(contents, |ciphertext| {
+        let decrypted = age::decrypt(identities, &mut &ciphertext[..])?.required(identities)?;
+        decrypted.context("Line isn't encrypted")
+    })
+}
+
+/// Exit status of a process killed by SIGPIPE, as shells report it
+const BROKEN_PIPE_STATUS: i32 = 141;
+
+/// Exits quietly when the reader of stdout went away, e.g. git aborting a checkout, as
+/// there is nobody to report the broken pipe to. Rust ignores SIGPIPE, so writes fail
+/// with `BrokenPipe` instead of killing the process.
+pub(crate) fn exit_on_broken_pipe(result: Result<()>) -> Result<()> {
+    match result {
+        Err(err) if err.chain().any(is_broken_pipe) => {
+            log::debug!("Output was closed by its reader, stopping; error={:#}", err);
+            std::process::exit(BROKEN_PIPE_STATUS)
+        }
+        result => result,
+    }
+}
+
+/// Whether `err` is a broken pipe, also inside of [`age::AgeError`], whose transparent
+/// variants leave the cause out of the chain
+fn is_broken_pipe(err: &(dyn std::error::Error + 'static)) -> bool {
+    if let Some(err) = err.downcast_ref::<io::Error>() {
+        return err.kind() == io::ErrorKind::BrokenPipe;
+    }
+    match err.downcast_ref::<age::AgeError>() {
+        Some(age::AgeError::Io(err)) => err.kind() == io::ErrorKind::BrokenPipe,
+        Some(age::AgeError::Other(err)) => err.chain().any(is_broken_pipe),
+        _ => false,
+    }
+}
+
+fn is_no_matching_identity(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<age::AgeError>(),
+        Some(age::AgeError::NoMatchingIdentity { .. })
+    )
+}
+
+/// The first of the encrypted `versions` of a file which decrypts to `contents`, so that
+/// cleaning unchanged contents yields the same ciphertext. Versions which aren't
+/// encrypted are skipped, those which `identities` can't decrypt fail.
+fn reusable_ciphertext(
+    versions: Vec<Vec<u8>>,
+    contents: &[u8],
+    identities: &[String],
+) -> Result<Option<Vec<u8>>> {
+    for encrypted in versions {
+        let decrypted = age::decrypt(identities, &mut &encrypted[..])?.required(identities)?;
+        if decrypted.as_deref() == Some(contents) {
+            return Ok(Some(encrypted));
+        }
+    }
+    Ok(None)
+}
+
+/// Fails unless one of `identities` decrypts `encrypted`, the encrypted contents of
+/// `file`, see `clean.require_self_decrypt`
+fn check_self_decrypt(file: &Path, encrypted: &mut impl Read, identities: &[String]) -> Result<()> {
+    age::decrypt_to(identities, encrypted, &mut io::sink())
+        .and_then(|outcome| outcome.required(identities))
+        .with_context(|| {
+            format!(
+                "Refusing to encrypt {}: the configured identities can't decrypt it, so it \
+                 couldn't be read back (clean.require_self_decrypt is set)",
+                file.display()
+            )
+        })?;
+    Ok(())
+}
+
+/// Why smudge should check out a file as it is, given the result of decrypting it.
+/// `None` if it was decrypted. Fails if it wasn't and `require_decrypt` is set, or
+/// if decryption failed for other reasons than a missing identity.
+fn undecrypted_reason(
+    decrypted: age::DecryptOutcome<()>,
+    require_decrypt: bool,
+    identities: &[impl AsRef<Path>],
+) -> Result<Option<&'static str>> {
+    if require_decrypt {
+        decrypted
+            .required(identities)?
+            .context("Input isn't encrypted")?;
+        return Ok(None);
+    }
+    Ok(match decrypted {
+        age::DecryptOutcome::Decrypted(()) => None,
+        age::DecryptOutcome::Plain => Some("isn't encrypted"),
+        age::DecryptOutcome::NoMatchingIdentity => {
+            Some("can't be decrypted with the configured identities")
+        }
+    })
+}
+
+/// Whether the already encrypted `contents` can be passed through. It can't when they
+/// are encrypted to x25519 or plugin recipients, as only their number can be compared:
+/// they have to be decrypted and encrypted to the configured recipients again, so that
+/// a rotation to as many other recipients isn't skipped.
+fn check_already_encrypted(file: &Path, public_keys: &[String], contents: &[u8]) -> Result<bool> {
+    match age::recipients_state(public_keys, contents)? {
+        age::RecipientsState::Current => Ok(true),
+        age::RecipientsState::Unverifiable => {
+            log::info!(
+                "{} is already encrypted, to x25519 or plugin recipients which can't be \
+                 compared with the configured ones; encrypting it again",
+                file.display()
+            );
+            Ok(false)
+        }
+        age::RecipientsState::Stale => bail!(
+            "{} is already age encrypted, to different recipients than configured. \
+             Refusing to encrypt it again; replace it with its decrypted content first",
+            file.display()
+        ),
+    }
+}
+
+const DRY_RUN_ENV: &str = "GIT_AGECRYPT_DRY_RUN";
+
+/// Disables the smudge cache like `smudge --no-cache`, e.g. for a single checkout
+const NO_CACHE_ENV: &str = "GIT_AGECRYPT_NO_CACHE";
+
+/// Reports the duration and sizes of every filter run on stderr, see [`Timing`]
+const TIMING_ENV: &str = "GIT_AGECRYPT_TIMING";
+
+/// Where a decrypted file is cached, see [`smudge_cache`]
+struct SmudgeCache {
+    dir: PathBuf,
+    key: Hash,
+    max_bytes: u64,
+}
+
+/// Turns clean and smudge into pass-throughs, e.g. while rewriting history with
+/// git filter-repo, with the filters still configured
+pub(crate) const DISABLE_ENV: &str = "GIT_AGECRYPT_DISABLE";
+
+/// Whether the filters are disabled by GIT_AGECRYPT_DISABLE
+pub(crate) fn filters_disabled() -> bool {
+    env_flag(DISABLE_ENV)
+}
+
+/// Copies `contents` to `out` unchanged for the disabled `filter`
+fn pass_through(filter: &str, contents: &[u8], out: &mut impl Write) -> Result<()> {
+    log::warn!(
+        "{} is set, {} passes the contents through unchanged",
+        DISABLE_ENV,
+        filter
+    );
+    Ok(out.write_all(contents)?)
+}
+
+/// Whether the variable `name` is set to something other than empty or `0`
+fn env_flag(name: &str) -> bool {
+    std::env::var_os(name).is_some_and(|value| !value.is_empty() && value != "0")
+}
+
+/// Encrypts `contents` like the clean filter and describes the result
+fn dry_run_report(
+    path: &Path,
+    public_keys: &[String],
+    encoding: age::Encoding,
+    contents: &[u8],
+) -> Result<String> {
+    let encrypted = age::encrypt(public_keys, &mut &contents[..], encoding)?;
+    let format = match encoding {
+        age::Encoding::Binary => "binary",
+        age::Encoding::Armored => "armored",
+    };
+    Ok(format!(
+        "git-agecrypt dry run: {} would be encrypted to {} recipients, {} bytes {} instead of {} bytes plaintext; passed through unencrypted",
+        path.display(),
+        public_keys.len(),
+        encrypted.len(),
+        format,
+        contents.len()
+    ))
+}
+
+/// Reads the input of a filter from `path`, or from stdin as git passes it
+fn read_input(path: Option<&Path>) -> Result<Vec<u8>> {
+    match path {
+        Some(path) => fs::read(path).with_context(|| format!("Couldn't read {:?}", path)),
+        None => {
+            let mut contents = vec![];
+            io::stdin().read_to_end(&mut contents)?;
+            Ok(contents)
+        }
+    }
+}
+
+/// Where a filter writes its result: stdout as git expects, or the file given with
+/// `--output`, which is only replaced once the filter succeeded
+enum Output {
+    /// Standard output, with the number of bytes written to it
+    Stdout(io::StdoutLock<'static>, usize),
+    File(PathBuf, Vec<u8>),
+}
+
+impl Output {
+    fn new(path: Option<PathBuf>) -> Self {
+        match path {
+            Some(path) => Self::File(path, vec![]),
+            None => Self::Stdout(io::stdout().lock(), 0),
+        }
+    }
+
+    /// Number of bytes written so far
+    fn written(&self) -> usize {
+        match self {
+            Self::Stdout(_, written) => *written,
+            Self::File(_, contents) => contents.len(),
+        }
+    }
+
+    fn finish(self) -> Result<()> {
+        match self {
+            Self::Stdout(mut stdout, _) => Ok(stdout.flush()?),
+            Self::File(path, contents) => atomic::write(&path, &contents),
+        }
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Stdout(stdout, written) => {
+                let n = stdout.write(buf)?;
+                *written += n;
+                Ok(n)
+            }
+            Self::File(_, contents) => contents.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Stdout(stdout, _) => stdout.flush(),
+            Self::File(..) => Ok(()),
+        }
+    }
+}
+
+/// Measures a filter run when GIT_AGECRYPT_TIMING is set, to spot files which are slow
+/// to encrypt or decrypt. git starts a filter process per file, so each run is reported
+/// on its own.
+struct Timing {
+    operation: &'static str,
+    path: PathBuf,
+    /// `None` unless the run is measured
+    started: Option<std::time::Instant>,
+}
+
+impl Timing {
+    /// Starts measuring `operation` on `path`, as git passes it
+    fn start(operation: &'static str, path: &Path) -> Self {
+        Self {
+            operation,
+            path: path.to_path_buf(),
+            started: env_flag(TIMING_ENV).then(std::time::Instant::now),
+        }
+    }
+
+    /// Reports the run on stderr, if it is measured
+    fn finish(self, input_bytes: usize, output_bytes: usize) {
+        if let Some(started) = self.started {
+            let elapsed = started.elapsed();
+            eprintln!(
+                "{}",
+                timing_report(
+                    self.operation,
+                    &self.path,
+                    input_bytes,
+                    output_bytes,
+                    elapsed
+                )
+            );
+        }
+    }
+}
+
+fn timing_report(
+    operation: &str,
+    path: &Path,
+    input_bytes: usize,
+    output_bytes: usize,
+    elapsed: std::time::Duration,
+) -> String {
+    format!(
+        "git-agecrypt timing: {}; path={}, input_bytes={}, output_bytes={}, elapsed_ms={}",
+        operation,
+        path.display(),
+        input_bytes,
+        output_bytes,
+        elapsed.as_millis()
+    )
+}
+
+/// Writes everything into both writers, e.g. to hash the output while streaming it.
+struct Tee<A: Write, B: Write>(A, B);
+
+impl<A: Write, B: Write> Write for Tee<A, B> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.0.write(buf)?;
+        self.1.write_all(&buf[..written])?;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()?;
+        self.1.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::age::secrecy::ExposeSecret;
+    use assert_fs::{prelude::*, TempDir};
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case::clean("a\nB\nc\n", "a\nb\nC\n", "a\nB\nC\n", 0)]
+    #[case::conflict(
+        "a\nX\nc\n",
+        "a\nY\nc\n",
+        "a\n<<<<<<< ours\nX\n=======\nY\n>>>>>>> theirs\nc\n",
+        1
+    )]
+    fn test_merge_encrypted(
+        #[case] current: &str,
+        #[case] other: &str,
+        #[case] expected: &str,
+        #[case] conflicts: usize,
+    ) -> Result<()> {
+        let dir = TempDir::new()?;
+        let identity = ::age::x25519::Identity::generate();
+        let identity_file = dir.child("identity.txt");
+        identity_file.write_str(identity.to_string().expose_secret())?;
+        let identities = [identity_file.path()];
+        let public_keys = [identity.to_public().to_string()];
+        let encrypt = |plaintext: &str| {
+            age::encrypt(
+                &public_keys,
+                &mut plaintext.as_bytes(),
+                age::Encoding::Binary,
+            )
         };
-        Ok(io::stdout().write_all(&result)?)
+
+        let merged = merge_encrypted(
+            &identities,
+            &public_keys,
+            age::Encoding::Binary,
+            [&encrypt("a\nb\nc\n")?, &encrypt(current)?, &encrypt(other)?],
+            dir.path(),
+        )?;
+
+        assert_eq!(merged.conflicts, conflicts);
+        let decrypted = age::decrypt(&identities, &mut &merged.contents[..])?;
+        assert_eq!(
+            decrypted,
+            age::DecryptOutcome::Decrypted(expected.as_bytes().to_vec())
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::binary(age::Encoding::Binary, "binary")]
+    #[case::armored(age::Encoding::Armored, "armored")]
+    fn test_dry_run_report(#[case] encoding: age::Encoding, #[case] format: &str) -> Result<()> {
+        let public_keys = [
+            ::age::x25519::Identity::generate().to_public().to_string(),
+            ::age::x25519::Identity::generate().to_public().to_string(),
+        ];
+
+        let report = dry_run_report(
+            Path::new("secrets/token"),
+            &public_keys,
+            encoding,
+            b"s3cr3t",
+        )?;
+
+        assert!(report.contains("secrets/token"), "{}", report);
+        assert!(report.contains("to 2 recipients"), "{}", report);
+        assert!(
+            report.contains(&format!(" bytes {} ", format)),
+            "{}",
+            report
+        );
+        assert!(report.contains("instead of 6 bytes"), "{}", report);
+        Ok(())
+    }
+
+    /// Stdout of a filter after git closed its end of the pipe
+    struct ClosedPipe;
+
+    impl Write for ClosedPipe {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+    }
+
+    #[rstest]
+    fn test_closed_reader_is_a_broken_pipe() -> Result<()> {
+        let dir = TempDir::new()?;
+        let identity = ::age::x25519::Identity::generate();
+        let key = dir.child("key.txt");
+        key.write_str(identity.to_string().expose_secret())?;
+        let public_keys = [identity.to_public().to_string()];
+        let plaintext = vec![b'x'; 256 * 1024];
+
+        let cleaned = age::encrypt_to(
+            &public_keys,
+            &mut &plaintext[..],
+            &mut ClosedPipe,
+            age::Encoding::Binary,
+        )
+        .map_err(|err| anyhow::Error::from(err).context("Couldn't encrypt"));
+        let encrypted = age::encrypt(&public_keys, &mut &plaintext[..], age::Encoding::Binary)?;
+        let smudged = age::decrypt_to(&[key.path()], &mut &encrypted[..], &mut ClosedPipe)
+            .map(|_| ())
+            .map_err(|err| anyhow::Error::from(err).context("Couldn't decrypt"));
+
+        for result in [cleaned, smudged] {
+            let err = result.unwrap_err();
+            assert!(err.chain().any(is_broken_pipe), "{:?}", err);
+        }
+        let other = anyhow::Error::from(io::Error::from(io::ErrorKind::PermissionDenied));
+        assert!(!other.chain().any(is_broken_pipe));
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::clean("clean", b"DB_PASSWORD=hunter2\n")]
+    #[case::smudge("smudge", b"age-encryption.org/v1\n-> X25519 ...\n\x00\xff")]
+    fn test_pass_through(#[case] filter: &str, #[case] contents: &[u8]) -> Result<()> {
+        let dir = TempDir::new()?;
+        let output = dir.child("out");
+        let mut out = Output::new(Some(output.to_path_buf()));
+
+        pass_through(filter, contents, &mut out)?;
+        out.finish()?;
+
+        assert_eq!(fs::read(output.path())?, contents);
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_envelope_round_trip() -> Result<()> {
+        let identity = ::age::x25519::Identity::generate();
+        let public_keys = [identity.to_public().to_string()];
+        let encrypted = age::encrypt(
+            &public_keys,
+            &mut &b"DB_PASSWORD=hunter2"[..],
+            age::Encoding::Binary,
+        )?;
+
+        let stored = seal_envelope(Envelope::Base64, encrypted.clone());
+        assert!(!age::is_age_encrypted(&stored));
+        // Line breaks like those of YAML block scalars are ignored
+        let wrapped: Vec<u8> = stored
+            .chunks(60)
+            .flat_map(|c| [c, b"\n  "].concat())
+            .collect();
+
+        for stored in [stored, wrapped, encrypted.clone()] {
+            assert_eq!(open_envelope(Some(Envelope::Base64), stored), encrypted);
+        }
+        assert_eq!(
+            open_envelope(Some(Envelope::Base64), b"plain text".to_vec()),
+            b"plain text"
+        );
+        assert_eq!(
+            seal_envelope(Envelope::Base64, b"key: value\n".to_vec()),
+            b"key: value\n"
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::encoded(STANDARD.encode("not age").into_bytes())]
+    // Plaintext which happens to be valid base64
+    #[case::plaintext(b"DBPASSWORDhunter\n".to_vec())]
+    fn test_envelope_of_no_age_file(#[case] stored: Vec<u8>) {
+        let opened = open_envelope(Some(Envelope::Base64), stored.clone());
+        assert_eq!(opened, stored);
+    }
+
+    #[rstest]
+    fn test_timing_report() {
+        let elapsed = std::time::Duration::from_micros(12_345);
+
+        let report = timing_report("smudge", Path::new("secrets/db.env"), 512, 300, elapsed);
+
+        assert_eq!(
+            report,
+            "git-agecrypt timing: smudge; path=secrets/db.env, input_bytes=512, output_bytes=300, \
+             elapsed_ms=12"
+        );
+    }
+
+    #[rstest]
+    fn test_dry_run_report_rejects_invalid_recipients() {
+        let public_keys = ["age1notakey".to_string()];
+        assert!(dry_run_report(Path::new("a"), &public_keys, age::Encoding::Binary, b"").is_err());
+    }
+
+    #[rstest]
+    fn test_check_self_decrypt() -> Result<()> {
+        let dir = TempDir::new()?;
+        let identity = ::age::x25519::Identity::generate();
+        let identity_file = dir.child("identity.txt");
+        identity_file.write_str(identity.to_string().expose_secret())?;
+        let identities = [identity_file.path().to_string_lossy().to_string()];
+        let others = ::age::x25519::Identity::generate().to_public().to_string();
+        let encrypt = |public_key: String| {
+            age::encrypt(&[public_key], &mut &b"secret"[..], age::Encoding::Binary)
+        };
+
+        let own = encrypt(identity.to_public().to_string())?;
+        check_self_decrypt(Path::new("a"), &mut &own[..], &identities)?;
+        let err = check_self_decrypt(Path::new("a"), &mut &encrypt(others)?[..], &identities)
+            .unwrap_err();
+
+        assert!(
+            err.to_string().starts_with("Refusing to encrypt a"),
+            "{}",
+            err
+        );
+        assert!(is_no_matching_identity(&err), "{:#}", err);
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::tee(false)]
+    #[case::require_self_decrypt(true)]
+    fn test_clean_streams_into_sidecar(#[case] require_self_decrypt: bool) -> Result<()> {
+        let dir = TempDir::new()?;
+        duct::cmd!("git", "init").dir(dir.path()).run()?;
+        let identity = ::age::x25519::Identity::generate();
+        let identity_file = dir.child("identity.txt");
+        identity_file.write_str(identity.to_string().expose_secret())?;
+        duct::cmd!(
+            "git",
+            "config",
+            "--add",
+            "git-agecrypt.config.identity",
+            identity_file.path()
+        )
+        .dir(dir.path())
+        .run()?;
+        dir.child("git-agecrypt.toml").write_str(&format!(
+            "[config]\n\"secret\" = [\"{}\"]\n\n[clean]\nrequire_self_decrypt = {}\n",
+            identity.to_public(),
+            require_self_decrypt
+        ))?;
+        let repo = git::LibGit2Repository::from_dir(dir.to_path_buf())?;
+        let cmd = CommandContext {
+            ctx: crate::ctx::new(repo, dir.join("git-agecrypt.toml")),
+            disabled: false,
+        };
+        // Several age chunks of 64 KiB
+        let contents: Vec<u8> = (0..3 << 20).map(|i: u32| (i % 251) as u8).collect();
+        let (input, output) = (dir.child("input"), dir.child("output"));
+        input.write_binary(&contents)?;
+
+        let (input, output) = (Some(input.to_path_buf()), Some(output.to_path_buf()));
+        cmd.clean("secret", false, input, output)?;
+
+        let encrypted = fs::read(dir.child("output"))?;
+        let identities = [identity_file.path().to_string_lossy().to_string()];
+        assert_eq!(
+            age::decrypt(&identities, &mut &encrypted[..])?.required(&identities)?,
+            Some(contents.clone())
+        );
+        let file = dir.join("secret");
+        assert_eq!(cmd.ctx.load_sidecar(&file, "age")?, Some(encrypted));
+        assert_eq!(
+            cmd.ctx.load_sidecar(&file, "hash")?.as_deref(),
+            Some(&blake3::hash(&contents).as_bytes()[..])
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_unchanged_contents_reuse_ciphertext() -> Result<()> {
+        let dir = TempDir::new()?;
+        let identity = ::age::x25519::Identity::generate();
+        let identity_file = dir.child("identity.txt");
+        identity_file.write_str(identity.to_string().expose_secret())?;
+        let identities = [identity_file.path().to_string_lossy().to_string()];
+        let encrypt = |plaintext: &[u8]| {
+            let public_key = identity.to_public().to_string();
+            age::encrypt(&[public_key], &mut &plaintext[..], age::Encoding::Binary)
+        };
+        let (staged, committed) = (encrypt(b"staged")?, encrypt(b"committed")?);
+        let versions = || vec![staged.clone(), committed.clone()];
+
+        // Cleaning the same contents twice yields identical blobs, so git sees no change
+        let first = reusable_ciphertext(versions(), b"staged", &identities)?;
+        let second = reusable_ciphertext(versions(), b"staged", &identities)?;
+        assert_eq!(first.as_ref(), Some(&staged));
+        assert_eq!(first, second);
+        assert_eq!(
+            reusable_ciphertext(versions(), b"committed", &identities)?,
+            Some(committed.clone())
+        );
+        assert_eq!(
+            reusable_ciphertext(versions(), b"changed", &identities)?,
+            None
+        );
+        assert_eq!(
+            reusable_ciphertext(vec![b"".to_vec()], b"", &identities)?,
+            None
+        );
+        Ok(())
+    }
+
+    const SSH_ALICE: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIBTqOL6QsCymbYvOXkxghyHqxYg8kPtoNAg84+05YHDs";
+    const SSH_BOB: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIHJhPwqcFkDwPw6rlyRZT8xsJDoLT+RxAigl8LZ/uLi/";
+    const X25519_ALICE: &str = "age1ql3z7hjy54pw3hyww5ayyfg7zqgvc7w3j2elw8zmrj2kg5sfn9aqmcac8p";
+    const X25519_BOB: &str = "age19p9nfga0xjpqeljnhhxk3hwug0wve772svmvy45995u6kfephcvqj7wps7";
+
+    #[rstest]
+    #[case::same_recipients(&[SSH_ALICE, SSH_BOB], &[SSH_BOB, SSH_ALICE], Some(true))]
+    #[case::recipient_added(&[SSH_ALICE], &[SSH_ALICE, SSH_BOB], None)]
+    #[case::recipient_replaced(&[SSH_ALICE], &[SSH_BOB], None)]
+    #[case::x25519_rotated(&[X25519_ALICE], &[X25519_BOB], Some(false))]
+    fn test_already_encrypted_input(
+        #[case] encrypted_to: &[&str],
+        #[case] configured: &[&str],
+        #[case] passed_through: Option<bool>,
+    ) -> Result<()> {
+        let encrypted = age::encrypt(encrypted_to, &mut &b"once"[..], age::Encoding::Binary)?;
+        let configured: Vec<_> = configured.iter().map(|r| r.to_string()).collect();
+
+        let result = check_already_encrypted(Path::new("secret"), &configured, &encrypted);
+
+        assert_eq!(result.as_ref().ok().copied(), passed_through);
+        if let Err(err) = result {
+            assert!(err.to_string().contains("already age encrypted"), "{}", err);
+        }
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::decrypted(true, false, Some(None))]
+    #[case::decrypted_required(true, true, Some(None))]
+    #[case::not_encrypted(false, false, Some(Some("isn't encrypted")))]
+    #[case::not_encrypted_required(false, true, None)]
+    fn test_undecrypted_reason_of_results(
+        #[case] decrypted: bool,
+        #[case] require_decrypt: bool,
+        #[case] expected: Option<Option<&str>>,
+    ) {
+        let decrypted = if decrypted {
+            age::DecryptOutcome::Decrypted(())
+        } else {
+            age::DecryptOutcome::Plain
+        };
+        let no_identities: [&str; 0] = [];
+        let reason = undecrypted_reason(decrypted, require_decrypt, &no_identities).ok();
+        assert_eq!(reason, expected);
+    }
+
+    #[rstest]
+    #[case::passed_through(false, true)]
+    #[case::required(true, false)]
+    fn test_undecrypted_reason_without_identity(
+        #[case] require_decrypt: bool,
+        #[case] passed_through: bool,
+    ) -> Result<()> {
+        let dir = TempDir::new()?;
+        let unrelated = dir.child("unrelated.txt");
+        unrelated.write_str(
+            ::age::x25519::Identity::generate()
+                .to_string()
+                .expose_secret(),
+        )?;
+        let recipient = ::age::x25519::Identity::generate().to_public().to_string();
+        let encrypted = age::encrypt(&[recipient], &mut &b"not for us"[..], age::Encoding::Binary)?;
+
+        let identities = [unrelated.path()];
+        let decrypted = age::decrypt_to(&identities, &mut &encrypted[..], &mut io::sink())?;
+        let reason = undecrypted_reason(decrypted, require_decrypt, &identities);
+
+        assert_eq!(reason.is_ok(), passed_through);
+        if passed_through {
+            assert!(reason?.is_some());
+        }
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_merge_file_added_on_both_sides() -> Result<()> {
+        let dir = TempDir::new()?;
+        let identity = ::age::x25519::Identity::generate();
+        let identity_file = dir.child("identity.txt");
+        identity_file.write_str(identity.to_string().expose_secret())?;
+        let identities = [identity_file.path()];
+        let public_keys = [identity.to_public().to_string()];
+        let added = age::encrypt(&public_keys, &mut &b"same\n"[..], age::Encoding::Binary)?;
+
+        let merged = merge_encrypted(
+            &identities,
+            &public_keys,
+            age::Encoding::Binary,
+            [b"", &added, &added],
+            dir.path(),
+        )?;
+
+        assert_eq!(merged.conflicts, 0);
+        let decrypted = age::decrypt(&identities, &mut &merged.contents[..])?;
+        assert_eq!(
+            decrypted,
+            age::DecryptOutcome::Decrypted(b"same\n".to_vec())
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::clean("clean")]
+    #[case::smudge("smudge")]
+    fn test_disabled_filter_passes_through(#[case] filter: &str) -> Result<()> {
+        let dir = TempDir::new()?;
+        duct::cmd!("git", "init").dir(dir.path()).run()?;
+        let repo = git::LibGit2Repository::from_dir(dir.to_path_buf())?;
+        let cmd = CommandContext {
+            ctx: crate::ctx::new(repo, dir.join("git-agecrypt.toml")),
+            disabled: true,
+        };
+        let contents = b"not age encrypted\n\0\xff";
+        let (input, output) = (dir.child("input"), dir.child("output"));
+        input.write_binary(contents)?;
+
+        let (input, output) = (Some(input.to_path_buf()), Some(output.to_path_buf()));
+        match filter {
+            "clean" => cmd.clean("secret", false, input, output)?,
+            _ => cmd.smudge("secret", false, input, output)?,
+        }
+
+        assert_eq!(fs::read(dir.child("output"))?, contents);
+        Ok(())
     }
 }
@@ -2,5 +2,7 @@ mod app;
 mod args;
 mod internal;
 mod public;
-pub(crate) use app::run;
-pub(crate) use args::{parse_args, Args};
+mod report;
+mod standalone;
+pub(crate) use app::{passes_through, run, run_standalone};
+pub(crate) use args::{parse_args, write_completions, Args, Commands, PublicCommands};
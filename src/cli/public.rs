@@ -1,41 +1,111 @@
-use std::path::PathBuf;
+use std::{
+    collections::HashSet,
+    fs,
+    io::{self, Write},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+};
 
-use crate::{git, Result};
+use anyhow::Context as _;
 
-use crate::config::Validated;
+use crate::{age, atomic, git, parallel, partial, progress::Progress, Result};
+
+use crate::config::{
+    recipient_sections_changed, user_config_dir, AppConfig, Envelope, IgnoreFile, Validated,
+    IGNORE_FILE,
+};
 use crate::git::Repository;
 use crate::{config::AgeIdentity, ctx::Context};
 
+use super::internal::{open_envelope, seal_envelope};
+use super::report::{self, Entry, Format};
+
 pub(crate) struct CommandContext<C: Context> {
     ctx: C,
+    /// Whether to show progress bars for walks over the encrypted files
+    progress: bool,
 }
 
 impl<C: Context> CommandContext<C> {
-    pub fn new(ctx: C) -> Self {
-        Self { ctx }
+    pub fn new(ctx: C, progress: bool) -> Self {
+        Self { ctx, progress }
+    }
+
+    fn progress(&self, len: usize, message: &'static str) -> Progress {
+        Progress::new(len, message, self.progress)
     }
 
-    pub(crate) fn init(&self) -> Result<()> {
+    /// Sets up the filters and adds `patterns` to .gitattributes, printing the changes
+    /// as a diff. With `dry_run`, only the diff is printed.
+    pub(crate) fn init(&self, patterns: Vec<String>, dry_run: bool) -> Result<()> {
         let exe = self.ctx.current_exe()?;
         let repo = self.ctx.repo();
-        ensure_state(repo.set_config("filter.git-agecrypt.required", "true"))?;
-        ensure_state(repo.set_config(
-            "filter.git-agecrypt.smudge",
-            &format!("{} smudge -f %f", exe),
-        ))?;
-        ensure_state(
-            repo.set_config("filter.git-agecrypt.clean", &format!("{} clean -f %f", exe)),
-        )?;
-        ensure_state(repo.set_config("diff.git-agecrypt.textconv", &format!("{} textconv", exe)))?;
+
+        let mut settings = vec![];
+        for (key, value) in filter_settings(&exe) {
+            let current = repo.get_config(key).ok();
+            if current.as_ref() != Some(&value) {
+                settings.push(ConfigChange {
+                    key,
+                    current,
+                    value,
+                });
+            }
+        }
+
+        if repo.is_bare() && !patterns.is_empty() {
+            anyhow::bail!(
+                "Bare repositories have no {}, add the patterns in a clone",
+                GIT_ATTRIBUTES
+            );
+        }
+        let attributes_path = repo.workdir().join(GIT_ATTRIBUTES);
+        let mut attributes = match fs::read_to_string(&attributes_path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => {
+                return Err(err).with_context(|| format!("Couldn't read {:?}", attributes_path))
+            }
+        };
+        let mut lines = vec![];
+        for pattern in &patterns {
+            lines.extend(add_attributes(&mut attributes, pattern)?);
+        }
+
+        if settings.is_empty() && lines.is_empty() {
+            println!("Repository is already set up for git-agecrypt");
+        } else {
+            print!("{}", init_diff(&settings, &lines));
+        }
+        if dry_run {
+            println!("Dry run, nothing was written");
+            return Ok(());
+        }
+        for setting in &settings {
+            ensure_state(repo.set_config(setting.key, &setting.value))?;
+        }
+        if !lines.is_empty() {
+            atomic::write(&attributes_path, attributes.as_bytes())?;
+        }
+        self.warn_about_attributes();
         Ok(())
     }
 
     pub(crate) fn deinit(&self) -> Result<()> {
         let repo = self.ctx.repo();
-        ensure_state(repo.remove_config_section("fiter.git-agecrypt"))?;
-        ensure_state(repo.remove_config_section("diff.git-agecrypt"))?;
+        for section in [
+            "filter.git-agecrypt",
+            "diff.git-agecrypt",
+            "merge.git-agecrypt",
+        ] {
+            match repo.remove_config_section(section) {
+                Ok(()) => println!("Removed {}", section),
+                result => ensure_state(result)?,
+            }
+        }
 
         self.ctx.remove_sidecar_files()?;
+        println!("{} is left as it is", GIT_ATTRIBUTES);
         Ok(())
     }
 
@@ -43,10 +113,771 @@ impl<C: Context> CommandContext<C> {
         self.print_identities()
     }
 
-    pub(crate) fn status(&self) -> Result<()> {
+    pub(crate) fn status(
+        &self,
+        porcelain: bool,
+        pathspecs: &[String],
+        jobs: NonZeroUsize,
+        format: Format,
+    ) -> Result<()> {
+        let entries: Vec<_> = self
+            .file_states(pathspecs, jobs)?
+            .into_iter()
+            .map(|(path, recipients, state)| Entry {
+                path: path.display().to_string(),
+                state: state.as_ref().map_or("error", FileState::as_str),
+                recipients,
+                notes: state
+                    .err()
+                    .map(|err| format!("{:#}", err))
+                    .into_iter()
+                    .collect(),
+            })
+            .collect();
+        if format == Format::Json {
+            return report::finish(format, &entries, None);
+        }
+        if porcelain {
+            for entry in &entries {
+                println!("{} {}", entry.state, entry.path);
+            }
+            return Ok(());
+        }
+
         self.list_identities()?;
         println!();
         self.list_recipients()?;
+        println!();
+
+        let padding = entries.iter().map(|e| e.state.len()).max().unwrap_or(0);
+        println!("The following files are managed by git-agecrypt:");
+        for entry in &entries {
+            match entry.notes.first() {
+                None => println!(
+                    "    {:padding$} {}",
+                    entry.state,
+                    entry.path,
+                    padding = padding
+                ),
+                Some(err) => println!(
+                    "    {:padding$} {} -- {}",
+                    entry.state,
+                    entry.path,
+                    err,
+                    padding = padding
+                ),
+            }
+        }
+        self.warn_about_attributes();
+        Ok(())
+    }
+
+    /// Prints who `path` is encrypted to, as far as its header reveals it
+    pub(crate) fn info(&self, path: &Path, format: Format) -> Result<()> {
+        let cfg = self.ctx.config()?;
+        let (file, relpath, encrypted) = self.encrypted_version(path)?;
+        let (public_keys, mut notes) = match cfg.get_public_keys(&file) {
+            Ok(public_keys) => (public_keys, vec![]),
+            Err(err) => (vec![], vec![format!("no configured recipients: {:#}", err)]),
+        };
+
+        let mut recipients = vec![];
+        for stanza in age::identify_recipients(&public_keys, &encrypted)? {
+            let description = stanza.description;
+            let note = match stanza.owner {
+                age::StanzaOwner::Known(recipient) => {
+                    recipients.push(cfg.recipient_name(&recipient));
+                    continue;
+                }
+                age::StanzaOwner::Unknown => {
+                    format!("unknown: {}, not configured for the path", description)
+                }
+                age::StanzaOwner::Anonymous(candidates) if candidates.is_empty() => {
+                    format!("anonymous: {}, none of its type is configured", description)
+                }
+                age::StanzaOwner::Anonymous(candidates) => {
+                    let names: Vec<_> = candidates.iter().map(|r| cfg.recipient_name(r)).collect();
+                    format!("anonymous: {}, one of {}", description, names.join(", "))
+                }
+                age::StanzaOwner::Unsupported => format!("unsupported: {}", description),
+            };
+            notes.push(note);
+        }
+        let comment = age::comment_of(&mut &encrypted[..])?;
+
+        if format == Format::Human {
+            println!("Recipients of {}:", relpath.display());
+            for recipient in &recipients {
+                println!("    ✓ {}", recipient);
+            }
+            for note in &notes {
+                println!("    ? {}", note);
+            }
+            if let Some(comment) = &comment {
+                println!();
+                println!("Comment: {}", comment);
+            }
+        }
+        notes.extend(comment.map(|comment| format!("comment: {}", comment)));
+        let entry = Entry {
+            path: relpath.display().to_string(),
+            state: "encrypted",
+            recipients,
+            notes,
+        };
+        report::finish(format, &[entry], None)
+    }
+
+    /// Fails unless `path` is encrypted to exactly `recipients`, as far as its header
+    /// reveals it. With `strict`, recipients which can only be counted fail too.
+    pub(crate) fn assert_recipients(
+        &self,
+        path: &Path,
+        recipients: &[String],
+        strict: bool,
+        format: Format,
+    ) -> Result<()> {
+        let cfg = self.ctx.config()?;
+        let (_, relpath, encrypted) = self.encrypted_version(path)?;
+        let public_keys = cfg.resolve_recipients(recipients)?;
+        let (matches, notes) = assert_header_recipients(&public_keys, &encrypted, strict)?;
+
+        if format == Format::Human {
+            let mark = if matches { "✓" } else { "⨯" };
+            println!("    {} {}", mark, relpath.display());
+            for note in &notes {
+                println!("        {}", note);
+            }
+        }
+        let error = (!matches).then(|| {
+            format!(
+                "{} isn't encrypted to exactly the given recipients",
+                relpath.display()
+            )
+        });
+        let entry = Entry {
+            path: relpath.display().to_string(),
+            state: if matches { "ok" } else { "flagged" },
+            recipients: public_keys,
+            notes,
+        };
+        report::finish(format, &[entry], error)
+    }
+
+    /// The encrypted version of `path` with its absolute and relative path: the working
+    /// tree file, which is decrypted unless it was locked or never checked out, else the
+    /// staged one. The envelope of `input.decode` is removed from either.
+    fn encrypted_version(&self, path: &Path) -> Result<(PathBuf, PathBuf, Vec<u8>)> {
+        let repo = self.ctx.repo();
+        let envelope = self.ctx.config()?.envelope();
+        let file = std::env::current_dir()?.join(path);
+        let relpath = file
+            .strip_prefix(repo.workdir())
+            .unwrap_or(&file)
+            .to_path_buf();
+        let working = fs::read(&file).map(|contents| open_envelope(envelope, contents));
+        let encrypted = match working {
+            Ok(contents) if age::is_encrypted(&contents) => contents,
+            _ => repo
+                .get_staged_contents(&file)
+                .map(|contents| open_envelope(envelope, contents))
+                .with_context(|| format!("Couldn't read {}", relpath.display()))?,
+        };
+        if !age::is_encrypted(&encrypted) {
+            anyhow::bail!("{} isn't encrypted", relpath.display());
+        }
+        Ok((file, relpath, encrypted))
+    }
+
+    pub(crate) fn audit(&self, pathspecs: &[String], format: Format) -> Result<()> {
+        let repo = self.ctx.repo();
+        let cfg = self.ctx.config()?;
+
+        let (mut entries, mut flagged, mut anonymous) = (vec![], 0, false);
+        for file in repo.list_filtered_files("git-agecrypt", pathspecs)? {
+            let relpath = file.strip_prefix(repo.workdir()).unwrap_or(&file);
+            let staged = load_staged(repo, &cfg, &file);
+            let recipients = staged_recipients(&staged);
+            let (state, notes) = match audit_file(staged) {
+                Ok(audit) => {
+                    anonymous |= audit.anonymous;
+                    let missing = audit.missing.iter().map(|r| format!("missing: {}", r));
+                    let unknown = audit.unknown.iter().map(|r| format!("unknown: {}", r));
+                    let notes = missing.chain(unknown).collect();
+                    if audit.is_ok() {
+                        ("ok", notes)
+                    } else {
+                        flagged += 1;
+                        ("flagged", notes)
+                    }
+                }
+                Err(err) => {
+                    flagged += 1;
+                    ("error", vec![format!("{:#}", err)])
+                }
+            };
+            entries.push(Entry {
+                path: relpath.display().to_string(),
+                state,
+                recipients,
+                notes,
+            });
+        }
+
+        if format == Format::Human {
+            println!("Checking recipients of encrypted files:");
+            for entry in &entries {
+                match entry.state {
+                    "ok" => println!("    ✓ {}", entry.path),
+                    "flagged" => {
+                        println!("    ⨯ {}", entry.path);
+                        for note in &entry.notes {
+                            println!("        {}", note);
+                        }
+                    }
+                    _ => println!("    ⨯ {} -- {}", entry.path, entry.notes.join("; ")),
+                }
+            }
+            if anonymous {
+                println!();
+                println!("Note: x25519 and plugin recipients can only be compared by their number");
+            }
+        }
+        let error = (flagged > 0).then(|| {
+            format!(
+                "{} files aren't encrypted to exactly the configured recipients",
+                flagged
+            )
+        });
+        report::finish(format, &entries, error)
+    }
+
+    /// Determines the state of each file handled by the filter and matching `pathspecs`,
+    /// by relative path, with the recipients configured for it
+    fn file_states(
+        &self,
+        pathspecs: &[String],
+        jobs: NonZeroUsize,
+    ) -> Result<Vec<(PathBuf, Vec<String>, Result<FileState>)>> {
+        let repo = self.ctx.repo();
+        let cfg = self.ctx.config()?;
+        let identities = self.identity_paths()?;
+
+        let files = repo.list_filtered_files("git-agecrypt", pathspecs)?;
+        let staged: Vec<_> = files
+            .iter()
+            .map(|file| load_staged(repo, &cfg, file))
+            .collect();
+        let recipients: Vec<_> = staged.iter().map(staged_recipients).collect();
+        let states = parallel::map(
+            jobs,
+            staged,
+            self.progress(files.len(), "Checking"),
+            |staged| file_state(staged?, &identities),
+        );
+
+        let relpaths = files.iter().map(|file| {
+            file.strip_prefix(repo.workdir())
+                .unwrap_or(file)
+                .to_path_buf()
+        });
+        Ok(relpaths
+            .zip(recipients)
+            .zip(states)
+            .map(|((path, recipients), state)| (path, recipients, state))
+            .collect())
+    }
+
+    fn identity_paths(&self) -> Result<Vec<String>> {
+        let mut paths = vec![];
+        for identity in self.ctx.age_identities().list()? {
+            paths.push(identity.expanded_path()?);
+        }
+        Ok(self.ctx.config()?.merge_identities(paths)?)
+    }
+
+    pub(crate) fn rekey(
+        &self,
+        dry_run: bool,
+        since: Option<&str>,
+        pathspecs: &[String],
+        jobs: NonZeroUsize,
+    ) -> Result<()> {
+        let repo = self.ctx.repo();
+        let cfg = self.ctx.config()?;
+        let identities = self.identity_paths()?;
+        let encoding = cfg.encoding();
+
+        // The repository is only accessed from this thread, just the cryptography is
+        // done in parallel
+        let files = self.rekey_files(&cfg, since, pathspecs)?;
+        let staged: Vec<_> = files
+            .iter()
+            .map(|file| load_staged(repo, &cfg, file))
+            .collect();
+        let results = parallel::map(
+            jobs,
+            staged,
+            self.progress(files.len(), "Rekeying"),
+            |staged| rekey_contents(staged?, &identities, encoding, dry_run),
+        );
+
+        if dry_run {
+            println!("The following files would be re-encrypted:");
+        } else {
+            println!("Re-encrypting files:");
+        }
+        let (mut rekeyed, mut failed) = (0, 0);
+        for (file, result) in files.iter().zip(results) {
+            let relpath = file.strip_prefix(repo.workdir()).unwrap_or(file);
+            match result.and_then(|result| self.store_rekeyed(file, result)) {
+                Ok(true) => {
+                    rekeyed += 1;
+                    println!("    ✓ {}", relpath.display());
+                }
+                Ok(false) => {
+                    log::debug!("Already encrypted to current recipients; file={:?}", file);
+                }
+                Err(err) => {
+                    failed += 1;
+                    println!("    ⨯ {} -- {:#}", relpath.display(), err);
+                }
+            }
+        }
+
+        println!();
+        if dry_run {
+            println!("{} files would be re-encrypted, {} failed", rekeyed, failed);
+        } else {
+            println!(
+                "{} files re-encrypted and staged, {} failed",
+                rekeyed, failed
+            );
+        }
+        Ok(())
+    }
+
+    /// Fails listing the staged files which aren't encrypted to their current recipients.
+    /// Only the headers are compared, so unlike `rekey` it needs no identity. With
+    /// `strict`, files whose recipients can only be counted fail too.
+    pub(crate) fn check_rekeyed(
+        &self,
+        strict: bool,
+        since: Option<&str>,
+        pathspecs: &[String],
+        jobs: NonZeroUsize,
+    ) -> Result<()> {
+        let repo = self.ctx.repo();
+        let cfg = self.ctx.config()?;
+
+        let files = self.rekey_files(&cfg, since, pathspecs)?;
+        let staged: Vec<_> = files
+            .iter()
+            .map(|file| load_staged(repo, &cfg, file))
+            .collect();
+        let results = parallel::map(
+            jobs,
+            staged,
+            self.progress(files.len(), "Checking"),
+            |staged| {
+                let staged = staged?;
+                age::recipients_state(&staged.public_keys?, &staged.encrypted)
+            },
+        );
+
+        println!("Checking files for stale recipients:");
+        let (mut stale, mut unverifiable) = (0, 0);
+        for (file, result) in files.iter().zip(results) {
+            let relpath = file.strip_prefix(repo.workdir()).unwrap_or(file);
+            match result {
+                Ok(age::RecipientsState::Current) => {
+                    log::debug!("Encrypted to current recipients; file={:?}", file);
+                }
+                Ok(age::RecipientsState::Unverifiable) => {
+                    unverifiable += 1;
+                    if strict {
+                        let note = "unverifiable, failing with --strict";
+                        println!("    ⨯ {} -- {}", relpath.display(), note);
+                    }
+                }
+                Ok(age::RecipientsState::Stale) => {
+                    stale += 1;
+                    println!("    ⨯ {}", relpath.display());
+                }
+                Err(err) => {
+                    stale += 1;
+                    println!("    ⨯ {} -- {:#}", relpath.display(), err);
+                }
+            }
+        }
+
+        println!();
+        if unverifiable > 0 && !strict {
+            println!("Note: x25519 and plugin recipients can only be compared by their number");
+        }
+        if stale > 0 {
+            anyhow::bail!(
+                "{} files need to be re-encrypted, run `git-agecrypt rekey`",
+                stale
+            );
+        }
+        if strict && unverifiable > 0 {
+            anyhow::bail!(
+                "{} files have x25519 or plugin recipients, which can only be compared by \
+                 their number",
+                unverifiable
+            );
+        }
+        println!("All files are encrypted to the current recipients");
+        Ok(())
+    }
+
+    /// The encrypted files matching `pathspecs` to rekey, with `since` only those changed
+    /// between that commit and HEAD
+    fn rekey_files(
+        &self,
+        cfg: &AppConfig,
+        since: Option<&str>,
+        pathspecs: &[String],
+    ) -> Result<Vec<PathBuf>> {
+        let repo = self.ctx.repo();
+        let files = repo.list_filtered_files("git-agecrypt", pathspecs)?;
+        let Some(since) = since else {
+            return Ok(files);
+        };
+        self.warn_recipients_changed(cfg, since);
+        let changed: HashSet<_> = repo.list_changed_files(since)?.into_iter().collect();
+        let files: Vec<_> = files
+            .into_iter()
+            .filter(|file| changed.contains(file))
+            .collect();
+        log::debug!(
+            "Rekeying changed files; since={}, files={}",
+            since,
+            files.len()
+        );
+        Ok(files)
+    }
+
+    /// Warns when the recipient sections of the configuration file changed since the
+    /// commit `since`, as the files which didn't change are still encrypted to the old
+    /// recipients
+    fn warn_recipients_changed(&self, cfg: &AppConfig, since: &str) {
+        let repo = self.ctx.repo();
+        let contents = |rev: &str| match repo.get_revision_contents(rev, cfg.path()) {
+            Ok(contents) => Ok(String::from_utf8_lossy(&contents).into_owned()),
+            Err(git::Error::NotExist(_)) => Ok(String::new()),
+            Err(err) => Err(anyhow::Error::from(err)),
+        };
+        let changed = contents(since).and_then(|old| {
+            let new = contents("HEAD")?;
+            Ok(recipient_sections_changed(&old, &new)?)
+        });
+        match changed {
+            Ok(false) => {}
+            Ok(true) => eprintln!(
+                "git-agecrypt: warning: the recipients in {} changed since {}, run \
+                 `git-agecrypt rekey` without --since to re-encrypt all files to them",
+                cfg.path().display(),
+                since
+            ),
+            Err(err) => log::debug!(
+                "Couldn't compare the configured recipients; since={}, error={:#}",
+                since,
+                err
+            ),
+        }
+    }
+
+    /// Stages a re-encrypted file, returns `false` if it was already up to date
+    fn store_rekeyed(&self, file: &Path, rekeyed: Rekeyed) -> Result<bool> {
+        match rekeyed {
+            Rekeyed::UpToDate => Ok(false),
+            Rekeyed::Pending => Ok(true),
+            Rekeyed::Encrypted { hash, encrypted } => {
+                let envelope = self.ctx.config()?.envelope();
+                let sealed = envelope.map(|envelope| seal_envelope(envelope, encrypted.clone()));
+                let stored = sealed.as_deref().unwrap_or(&encrypted);
+                self.ctx.repo().stage_contents(file, stored)?;
+                // Lets the clean filter reuse the new ciphertext while the file is unchanged
+                self.ctx.store_sidecars(file, hash.as_bytes(), &encrypted)?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Converts the staged encrypted files to `encoding` without decrypting them, and
+    /// reports the space saved
+    pub(crate) fn reencode(&self, encoding: age::Encoding, dry_run: bool) -> Result<()> {
+        let repo = self.ctx.repo();
+        let files = repo.list_filtered_files("git-agecrypt", &[])?;
+        let ignored = IgnoreFile::load(repo.workdir())?;
+
+        if dry_run {
+            println!("The following files would be converted:");
+        } else {
+            println!("Converting files:");
+        }
+        let (mut converted, mut skipped, mut failed, mut saved) = (0, 0, 0, 0i64);
+        for file in &files {
+            let relpath = file.strip_prefix(repo.workdir()).unwrap_or(file);
+            match self.reencode_file(file, &ignored, encoding, dry_run) {
+                Ok(Reencoded::Converted(size_change)) => {
+                    converted += 1;
+                    saved += size_change;
+                    println!("    ✓ {}", relpath.display());
+                }
+                Ok(Reencoded::Unchanged) => {
+                    log::debug!("Already in the requested encoding; file={:?}", file);
+                }
+                Ok(Reencoded::Skipped(reason)) => {
+                    skipped += 1;
+                    println!("    - {} -- skipped, {}", relpath.display(), reason);
+                }
+                Err(err) => {
+                    failed += 1;
+                    println!("    ⨯ {} -- {:#}", relpath.display(), err);
+                }
+            }
+        }
+
+        println!();
+        let verb = if dry_run {
+            "would be converted"
+        } else {
+            "converted and staged"
+        };
+        let size = if saved >= 0 {
+            format!("{} bytes saved", saved)
+        } else {
+            format!("{} bytes added", -saved)
+        };
+        println!(
+            "{} files {}, {} skipped, {} failed, {}",
+            converted, verb, skipped, failed, size
+        );
+        if self.ctx.config()?.encoding() != encoding {
+            eprintln!(
+                "git-agecrypt: warning: files changed from now on are still encrypted as \
+                 configured, set output.armor = {} to keep them in this encoding",
+                encoding == age::Encoding::Armored
+            );
+        }
+        Ok(())
+    }
+
+    /// Converts the staged contents of `file`, unless it isn't a whole age file, e.g.
+    /// one listed in `ignored`
+    fn reencode_file(
+        &self,
+        file: &Path,
+        ignored: &IgnoreFile,
+        encoding: age::Encoding,
+        dry_run: bool,
+    ) -> Result<Reencoded> {
+        let stored = self.ctx.repo().get_staged_contents(file)?;
+        let envelope = self.ctx.config()?.envelope();
+        let staged = open_envelope(envelope, stored.clone());
+        if !age::is_encrypted(&staged) {
+            return Ok(Reencoded::Skipped(if ignored.is_ignored(file) {
+                format!("it is listed in {}", IGNORE_FILE)
+            } else if partial::has_encrypted_lines(&staged) {
+                "only its marked lines are encrypted".to_string()
+            } else {
+                "it isn't age encrypted".to_string()
+            }));
+        }
+        let Some(converted) = age::reencode(&staged, encoding)? else {
+            return Ok(Reencoded::Unchanged);
+        };
+        let sealed = envelope.map(|envelope| seal_envelope(envelope, converted.clone()));
+        let sealed = sealed.as_deref().unwrap_or(&converted);
+        if !dry_run {
+            self.ctx.repo().stage_contents(file, sealed)?;
+            // Keeps the clean filter from restoring the old encoding of unchanged files
+            if self.ctx.load_sidecar(file, "age")?.as_deref() == Some(&staged[..]) {
+                self.ctx.store_sidecar(file, "age", &converted)?;
+            }
+        }
+        let saved = stored.len() as i64 - sealed.len() as i64;
+        Ok(Reencoded::Converted(saved))
+    }
+
+    /// Decrypts the encrypted files of the working tree in place
+    pub(crate) fn unlock(&self, jobs: NonZeroUsize) -> Result<()> {
+        let repo = self.ctx.repo();
+        let identities = self.identity_paths()?;
+        if identities.is_empty() {
+            anyhow::bail!(
+                "No identity is configured to unlock files with, add one with `git-agecrypt config add -i`"
+            );
+        }
+
+        let cfg = self.ctx.config()?;
+        let file_mode = cfg.smudge_file_mode()?;
+
+        let files = repo.list_filtered_files("git-agecrypt", &[])?;
+        let read = |file: &PathBuf| read_working_copy(file, cfg.envelope());
+        let contents: Vec<_> = files.iter().map(read).collect();
+        let results = parallel::map(
+            jobs,
+            contents,
+            self.progress(files.len(), "Unlocking"),
+            |contents| {
+                let encrypted = contents?;
+                if !age::is_encrypted(&encrypted) {
+                    return Ok(None);
+                }
+                let decrypted = age::decrypt(&identities, &mut &encrypted[..])?
+                    .required(&identities)?
+                    .context("Working copy isn't encrypted")?;
+                Ok(Some((encrypted, decrypted)))
+            },
+        );
+
+        println!("Unlocking files:");
+        let (mut unlocked, mut skipped) = (0, 0);
+        for (file, result) in files.iter().zip(results) {
+            let relpath = file.strip_prefix(repo.workdir()).unwrap_or(file);
+            let stored = result.and_then(|result| match result {
+                None => Ok(false),
+                Some((encrypted, decrypted)) => {
+                    // Before writing, so that the plaintext is never readable by others
+                    set_file_mode(file, file_mode)?;
+                    atomic::write(file, &decrypted)?;
+                    // Lets the clean filter reuse the ciphertext while the file is unchanged
+                    let hash = blake3::hash(&decrypted);
+                    self.ctx.store_sidecars(file, hash.as_bytes(), &encrypted)?;
+                    Ok(true)
+                }
+            });
+            match stored {
+                Ok(true) => {
+                    unlocked += 1;
+                    println!("    ✓ {}", relpath.display());
+                }
+                Ok(false) => log::debug!("Already decrypted; file={:?}", file),
+                Err(err) => {
+                    skipped += 1;
+                    println!("    ⨯ {} -- {:#}", relpath.display(), err);
+                }
+            }
+        }
+
+        println!();
+        println!("{} files unlocked, {} skipped", unlocked, skipped);
+        Ok(())
+    }
+
+    /// Replaces the decrypted files of the working tree with their staged ciphertext.
+    /// Refuses to change anything when a file has changes which would be lost.
+    pub(crate) fn lock(&self, jobs: NonZeroUsize) -> Result<()> {
+        let repo = self.ctx.repo();
+        let cfg = self.ctx.config()?;
+        let identities = self.identity_paths()?;
+        let encoding = cfg.encoding();
+
+        let files = repo.list_filtered_files("git-agecrypt", &[])?;
+        let mut inputs = vec![];
+        for file in &files {
+            let saved = match (
+                self.ctx.load_sidecar(file, "hash")?,
+                self.ctx.load_sidecar(file, "age")?,
+            ) {
+                (Some(hash), Some(encrypted)) => Some(Saved { hash, encrypted }),
+                _ => None,
+            };
+            let working = read_working_copy(file, cfg.envelope());
+            inputs.push((working, load_staged(repo, &cfg, file), saved));
+        }
+        let results = parallel::map(
+            jobs,
+            inputs,
+            self.progress(files.len(), "Locking"),
+            |(working, staged, saved)| {
+                lock_contents(&working?, staged?, saved, &identities, encoding)
+            },
+        );
+
+        let relpath = |file: &PathBuf| {
+            file.strip_prefix(repo.workdir())
+                .unwrap_or(file)
+                .to_path_buf()
+        };
+        let changed: Vec<_> = files
+            .iter()
+            .zip(&results)
+            .filter(|(_, result)| matches!(result, Ok(Locked::Changed)))
+            .map(|(file, _)| format!("    {}", relpath(file).display()))
+            .collect();
+        if !changed.is_empty() {
+            anyhow::bail!(
+                "Refusing to lock, these files have changes which aren't staged and would be lost:\n{}",
+                changed.join("\n")
+            );
+        }
+
+        println!("Locking files:");
+        let (mut locked, mut skipped) = (0, 0);
+        for (file, result) in files.iter().zip(results) {
+            let path = relpath(file);
+            let stored = result.and_then(|result| match result {
+                Locked::Encrypted(encrypted) => {
+                    atomic::write(file, &encrypted)?;
+                    Ok(true)
+                }
+                Locked::AlreadyLocked => Ok(false),
+                Locked::Changed => unreachable!("changed files are refused above"),
+            });
+            match stored {
+                Ok(true) => {
+                    locked += 1;
+                    println!("    ✓ {}", path.display());
+                }
+                Ok(false) => log::debug!("Already encrypted; file={:?}", file),
+                Err(err) => {
+                    skipped += 1;
+                    println!("    ⨯ {} -- {:#}", path.display(), err);
+                }
+            }
+        }
+
+        println!();
+        println!("{} files locked, {} skipped", locked, skipped);
+        Ok(())
+    }
+
+    /// Writes a new identity file and prints its public key
+    pub(crate) fn gen_identity(
+        &self,
+        output: Option<PathBuf>,
+        passphrase: bool,
+        add: bool,
+    ) -> Result<()> {
+        let output = match output {
+            Some(output) => output,
+            None => user_config_dir()
+                .context("Can't determine the configuration directory, use --output")?
+                .join("identity.txt"),
+        };
+        let passphrase = passphrase.then(age::new_passphrase).transpose()?;
+        let (contents, public_key) = age::generate_identity(passphrase)?;
+
+        if let Some(parent) = output.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Couldn't create directory {:?}", parent))?;
+        }
+        create_identity_file(&output)
+            .and_then(|mut file| Ok(file.write_all(&contents)?))
+            .with_context(|| format!("Couldn't write identity file {:?}", output))?;
+        eprintln!("Identity written to {}", output.display());
+
+        if add {
+            // Filters run from the repository root, so relative paths wouldn't do
+            self.add_identity(fs::canonicalize(&output)?)?;
+            eprintln!("Identity added to the repository configuration");
+        }
+        println!("{}", public_key);
         Ok(())
     }
 
@@ -67,18 +898,19 @@ impl<C: Context> CommandContext<C> {
     fn print_identities(&self) -> Result<()> {
         let identities = self.ctx.age_identities().list()?;
 
-        let padding = identities.iter().map(|i| i.path.len()).max().unwrap_or(0);
+        let names: Vec<_> = identities.iter().map(|i| i.to_string()).collect();
+        let padding = names.iter().map(String::len).max().unwrap_or(0);
         println!("The following identities are currently configured:");
-        for i in &identities {
+        for (i, name) in identities.iter().zip(&names) {
             match i.validate() {
                 Err(err) => {
-                    println!("    ⨯ {:padding$} -- {:?}", i.path, err, padding = padding);
+                    println!("    ⨯ {:padding$} -- {:?}", name, err, padding = padding);
                 }
                 Ok(Some(note)) => {
-                    println!("    ✓ {:padding$} -- {}", i.path, note, padding = padding);
+                    println!("    ✓ {:padding$} -- {}", name, note, padding = padding);
                 }
                 Ok(None) => {
-                    println!("    ✓ {}", i.path);
+                    println!("    ✓ {}", name);
                 }
             }
         }
@@ -86,7 +918,7 @@ impl<C: Context> CommandContext<C> {
     }
 
     pub fn add_recipients(&self, recipients: Vec<String>, paths: Vec<PathBuf>) -> Result<()> {
-        let mut cfg = self.ctx.config()?;
+        let mut cfg = self.ctx.config_file()?;
 
         cfg.add(recipients, paths)?;
 
@@ -95,7 +927,7 @@ impl<C: Context> CommandContext<C> {
     }
 
     pub fn remove_recipients(&self, recipients: Vec<String>, paths: Vec<PathBuf>) -> Result<()> {
-        let mut cfg = self.ctx.config()?;
+        let mut cfg = self.ctx.config_file()?;
         cfg.remove(recipients, paths)?;
         cfg.save()?;
         Ok(())
@@ -111,7 +943,744 @@ impl<C: Context> CommandContext<C> {
         }
         Ok(())
     }
+
+    /// Cross-checks the patterns of .gitattributes using the filter with the configured
+    /// recipients, and names the configured patterns matching no file using the filter.
+    /// Returns what was checked, with a warning if it looks misconfigured.
+    fn check_attributes(&self) -> Result<Vec<(String, Option<String>)>> {
+        let repo = self.ctx.repo();
+        let cfg = self.ctx.config()?;
+        let attributes_path = repo.workdir().join(GIT_ATTRIBUTES);
+        let attributes = match fs::read_to_string(&attributes_path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => {
+                return Err(err).with_context(|| format!("Couldn't read {:?}", attributes_path))
+            }
+        };
+        let files = if repo.is_bare() {
+            vec![]
+        } else {
+            repo.list_filtered_files("git-agecrypt", &[])?
+        };
+
+        let mut checked = vec![];
+        for pattern in filter_patterns(&attributes) {
+            let mut samples: Vec<_> = files
+                .iter()
+                .filter(|file| {
+                    let relpath = file.strip_prefix(repo.workdir()).unwrap_or(file);
+                    attribute_matches(pattern, relpath)
+                })
+                .cloned()
+                .collect();
+            if samples.is_empty() {
+                // Nothing is tracked yet, e.g. right after init, so check the pattern itself
+                samples.push(repo.workdir().join(pattern.trim_start_matches('/')));
+            }
+            let warning = samples
+                .iter()
+                .find_map(|file| cfg.get_public_keys(file).err())
+                .map(|err| format!("has no recipients: {:#}", err));
+            checked.push((format!("{} {}", GIT_ATTRIBUTES, pattern), warning));
+        }
+        // Without any tracked files yet, all of them would be reported
+        if !files.is_empty() {
+            for entry in cfg.unmatched_entries(&files) {
+                let warning = "matches no tracked file using the filter".to_string();
+                checked.push((entry, Some(warning)));
+            }
+        }
+        Ok(checked)
+    }
+
+    /// Prints the warnings of [`Self::check_attributes`], which only `config validate`
+    /// fails on
+    fn warn_about_attributes(&self) {
+        match self.check_attributes() {
+            Ok(checked) => {
+                for (name, warning) in checked {
+                    if let Some(warning) = warning {
+                        eprintln!("git-agecrypt: warning: {} {}", name, warning);
+                    }
+                }
+            }
+            Err(err) => log::debug!("Couldn't check {}; error={:#}", GIT_ATTRIBUTES, err),
+        }
+    }
+
+    pub fn validate_config(&self, format: Format) -> Result<()> {
+        let cfg = self.ctx.config()?;
+        let identities = self.checked_identities(&cfg)?;
+        let recipients = checked_recipients(&cfg);
+
+        if format == Format::Human {
+            println!("Checking identities:");
+            for entry in &identities {
+                print_checked(entry, &entry.path);
+            }
+            println!();
+            println!("Checking recipients:");
+            for entry in &recipients {
+                print_checked(
+                    entry,
+                    &format!("{}: {}", entry.path, entry.recipients.join(", ")),
+                );
+            }
+        }
+
+        let patterns: Vec<_> = self
+            .check_attributes()?
+            .into_iter()
+            .map(|(name, warning)| checked_entry(name, vec![], Ok::<_, anyhow::Error>(warning)))
+            .collect();
+        if format == Format::Human && !patterns.is_empty() {
+            println!();
+            println!("Checking patterns:");
+            for entry in &patterns {
+                print_checked(entry, &entry.path);
+            }
+        }
+
+        let own = self.own_recipients(&cfg)?;
+        if format == Format::Human {
+            if let Some(entry) = &own {
+                println!();
+                println!("Checking identities against recipients:");
+                print_checked(entry, &entry.path);
+            }
+        }
+
+        let entries: Vec<_> = identities
+            .into_iter()
+            .chain(recipients)
+            .chain(patterns)
+            .chain(own)
+            .collect();
+        let errors = entries.iter().filter(|e| e.state == "error").count();
+        let error = (errors > 0).then(|| format!("{} invalid configuration entries", errors));
+        report::finish(format, &entries, error)
+    }
+
+    /// Warns when none of the identities corresponds to a configured recipient, so that
+    /// files encrypted here likely can't be decrypted here, see [`decrypts_none`]
+    fn own_recipients(&self, cfg: &AppConfig) -> Result<Option<Entry>> {
+        let identities = self.identity_paths()?;
+        let derived: Vec<_> = identities
+            .iter()
+            .map(|identity| age::derivable_recipients(Path::new(identity)))
+            .collect();
+        let Some(recipients) = cfg.all_recipients() else {
+            return Ok(None);
+        };
+        if !decrypts_none(&derived, &recipients) {
+            return Ok(None);
+        }
+        let note = "none of the identities corresponds to a configured recipient, so files \
+                    encrypted to them likely can't be decrypted here, e.g. only SSH \
+                    recipients are configured for an x25519 identity";
+        Ok(Some(Entry {
+            path: "identities and recipients".to_string(),
+            state: "warning",
+            recipients: derived.into_iter().flatten().flatten().collect(),
+            notes: vec![note.to_string()],
+        }))
+    }
+
+    /// Checks the identities of the git config and the configuration file
+    fn checked_identities(&self, cfg: &AppConfig) -> Result<Vec<Entry>> {
+        let file_identities = cfg
+            .identities()
+            .iter()
+            .map(|path| AgeIdentity { path: path.clone() });
+        Ok(self
+            .ctx
+            .age_identities()
+            .list()?
+            .into_iter()
+            .chain(file_identities)
+            .map(|i| {
+                let result = i.validate();
+                checked_entry(i.to_string(), vec![], result)
+            })
+            .collect())
+    }
+
+    /// Runs the checks of `config validate` along with those of the set-up of the
+    /// repository, printing how each problem can be fixed. `getter` is the outcome of
+    /// running the passphrase getter, with its key when one is used. Fails when any
+    /// problem keeps the filters from working, warnings don't.
+    pub fn doctor(&self, getter: Result<Option<String>>, format: Format) -> Result<()> {
+        let mut sections = vec![("git configuration", self.diagnose_filter_config()?)];
+        match self.ctx.config() {
+            Ok(cfg) => {
+                sections.push((GIT_ATTRIBUTES, self.diagnose_attributes()?));
+                sections.push(("identities", self.diagnose_identities(&cfg)?));
+                sections.push(("recipients", diagnose_recipients(&cfg)));
+                // Problems of the identities themselves are diagnosed above
+                if let Ok(Some(entry)) = self.own_recipients(&cfg) {
+                    let fix = "add the recipient of one of the identities, e.g. with \
+                               `git-agecrypt config add -r <public key> -p <path>`";
+                    sections.push(("identities against recipients", vec![diagnosed(entry, fix)]));
+                }
+            }
+            Err(err) => {
+                let entry = checked_entry("git-agecrypt.toml".to_string(), vec![], Err(err));
+                let fix = "fix the file, then run `git-agecrypt doctor` again";
+                sections.push(("configuration", vec![diagnosed(entry, fix)]));
+            }
+        }
+        sections.push(("passphrase getter", vec![diagnose_getter(getter)]));
+
+        if format == Format::Human {
+            for (idx, (name, entries)) in sections.iter().enumerate() {
+                if idx > 0 {
+                    println!();
+                }
+                println!("Checking {}:", name);
+                for entry in entries {
+                    print_checked(entry, &entry.path);
+                    for fix in entry.notes.iter().skip(1) {
+                        println!("        → {}", fix);
+                    }
+                }
+            }
+        }
+
+        let entries: Vec<_> = sections
+            .into_iter()
+            .flat_map(|(_, entries)| entries)
+            .collect();
+        let problems = entries.iter().filter(|e| e.state == "error").count();
+        let error = (problems > 0).then(|| format!("Found {} problems", problems));
+        report::finish(format, &entries, error)
+    }
+
+    /// Checks that the filter, diff and merge drivers are configured, and run this
+    /// git-agecrypt
+    fn diagnose_filter_config(&self) -> Result<Vec<Entry>> {
+        let exe = self.ctx.current_exe()?;
+        let repo = self.ctx.repo();
+        let fix = "run `git-agecrypt init` to set up the repository";
+        Ok(filter_settings(&exe)
+            .into_iter()
+            .map(|(key, expected)| {
+                // Without these, files are committed or checked out without the filter
+                let required = key.starts_with("filter.");
+                let result = match repo.get_config(key) {
+                    Ok(value) if value == expected => Ok(None),
+                    Ok(value) => Ok(Some(format!("is {:?} rather than {:?}", value, expected))),
+                    Err(_) if required => Err(anyhow::anyhow!("isn't set")),
+                    Err(_) => Ok(Some("isn't set".to_string())),
+                };
+                diagnosed(checked_entry(key.to_string(), vec![], result), fix)
+            })
+            .collect())
+    }
+
+    /// Checks that files use the filter and have recipients, see [`Self::check_attributes`]
+    fn diagnose_attributes(&self) -> Result<Vec<Entry>> {
+        let checked = self.check_attributes()?;
+        if !checked
+            .iter()
+            .any(|(name, _)| name.starts_with(GIT_ATTRIBUTES))
+        {
+            let entry = checked_entry(
+                GIT_ATTRIBUTES.to_string(),
+                vec![],
+                Err(anyhow::anyhow!("no pattern uses the git-agecrypt filter")),
+            );
+            let fix = "add one with `git-agecrypt init -p <pattern>`";
+            return Ok(vec![diagnosed(entry, fix)]);
+        }
+        Ok(checked
+            .into_iter()
+            .map(|(name, warning)| {
+                let fix = if name.starts_with(GIT_ATTRIBUTES) {
+                    "add recipients with `git-agecrypt config add -r <recipient> -p <path>`"
+                } else {
+                    "fix or remove the entry in git-agecrypt.toml"
+                };
+                let entry = checked_entry(name, vec![], Ok::<_, anyhow::Error>(warning));
+                diagnosed(entry, fix)
+            })
+            .collect())
+    }
+
+    fn diagnose_identities(&self, cfg: &AppConfig) -> Result<Vec<Entry>> {
+        let identities = self.checked_identities(cfg)?;
+        if identities.is_empty() {
+            let entry = checked_entry(
+                "identities".to_string(),
+                vec![],
+                Err(anyhow::anyhow!(
+                    "none configured, so nothing can be decrypted"
+                )),
+            );
+            let fix = "add one with `git-agecrypt config add -i <path>`, or create one with \
+                       `git-agecrypt gen-identity --add`";
+            return Ok(vec![diagnosed(entry, fix)]);
+        }
+        Ok(identities
+            .into_iter()
+            .map(|entry| {
+                let fix = format!(
+                    "fix the path, or remove it with `git-agecrypt config remove -i {}` or \
+                     from git-agecrypt.toml",
+                    entry.path
+                );
+                diagnosed(entry, &fix)
+            })
+            .collect())
+    }
+}
+
+/// Git configuration written by `init`, for git-agecrypt at `exe`
+fn filter_settings(exe: &str) -> [(&'static str, String); 6] {
+    [
+        ("filter.git-agecrypt.required", "true".to_string()),
+        (
+            "filter.git-agecrypt.smudge",
+            format!("{} smudge -f %f", exe),
+        ),
+        ("filter.git-agecrypt.clean", format!("{} clean -f %f", exe)),
+        ("diff.git-agecrypt.textconv", format!("{} textconv", exe)),
+        (
+            "merge.git-agecrypt.name",
+            "git-agecrypt merge driver".to_string(),
+        ),
+        (
+            "merge.git-agecrypt.driver",
+            format!("{} merge %O %A %B %P", exe),
+        ),
+    ]
+}
+
+/// Setting of the repository configuration which `init` changes
+struct ConfigChange {
+    key: &'static str,
+    current: Option<String>,
+    value: String,
+}
+
+/// The changes of `init` to the git configuration and the `lines` it appends to
+/// .gitattributes, in the format of a diff
+fn init_diff(settings: &[ConfigChange], lines: &[String]) -> String {
+    let mut diff = String::new();
+    if !settings.is_empty() {
+        diff.push_str("git config:\n");
+        for setting in settings {
+            if let Some(current) = &setting.current {
+                diff.push_str(&format!("-   {} = {}\n", setting.key, current));
+            }
+            diff.push_str(&format!("+   {} = {}\n", setting.key, setting.value));
+        }
+    }
+    if !lines.is_empty() {
+        diff.push_str(&format!("{}:\n", GIT_ATTRIBUTES));
+        for line in lines {
+            diff.push_str(&format!("+   {}\n", line));
+        }
+    }
+    diff
+}
+
+/// Whether none of the `derived` recipients of the identities is among the configured
+/// `recipients`. As a heuristic, it is only claimed when the recipients of every
+/// identity could be derived, and there are identities and recipients.
+fn decrypts_none(derived: &[Option<Vec<String>>], recipients: &[String]) -> bool {
+    if derived.is_empty() || recipients.is_empty() {
+        return false;
+    }
+    let Some(derived) = derived.iter().cloned().collect::<Option<Vec<_>>>() else {
+        return false;
+    };
+    !derived.iter().flatten().any(|own| {
+        recipients
+            .iter()
+            .any(|recipient| age::same_recipient(own, recipient))
+    })
+}
+
+/// Checks the recipients and recipients files of the configuration file
+fn checked_recipients(cfg: &AppConfig) -> Vec<Entry> {
+    cfg.validate()
+        .into_iter()
+        .map(|(entry, recipient, result)| checked_entry(entry, vec![recipient], result))
+        .collect()
+}
+
+fn diagnose_recipients(cfg: &AppConfig) -> Vec<Entry> {
+    let recipients = checked_recipients(cfg);
+    if recipients.is_empty() {
+        let entry = checked_entry(
+            "recipients".to_string(),
+            vec![],
+            Err(anyhow::anyhow!(
+                "none configured, so nothing can be encrypted"
+            )),
+        );
+        let fix = "add some with `git-agecrypt config add -r <recipient> -p <path>`";
+        return vec![diagnosed(entry, fix)];
+    }
+    let fix = "fix or remove the recipient in git-agecrypt.toml";
+    recipients
+        .into_iter()
+        .map(|entry| diagnosed(entry, fix))
+        .collect()
+}
+
+fn diagnose_getter(getter: Result<Option<String>>) -> Entry {
+    let (name, result) = match getter {
+        Ok(Some(key)) => (format!("getter '{}'", key), Ok(None)),
+        Ok(None) => (
+            "none used, passphrases come from AGE_PASSPHRASE or the terminal".to_string(),
+            Ok(None),
+        ),
+        Err(err) => ("passphrase getter".to_string(), Err(err)),
+    };
+    let fix = "fix the getter in the [passphrase] section of git-agecrypt.toml";
+    diagnosed(checked_entry(name, vec![], result), fix)
+}
+
+/// Adds how to fix a problem of `entry` to its notes, after the description of the problem
+fn diagnosed(mut entry: Entry, fix: &str) -> Entry {
+    if entry.state != "ok" {
+        entry.notes.push(fix.to_string());
+    }
+    entry
+}
+
+/// Entry of `config validate`, with a note on what couldn't be checked as a warning
+fn checked_entry<E: Into<anyhow::Error>>(
+    path: String,
+    recipients: Vec<String>,
+    result: std::result::Result<Option<String>, E>,
+) -> Entry {
+    let (state, notes) = match result {
+        Ok(None) => ("ok", vec![]),
+        Ok(Some(note)) => ("warning", vec![note]),
+        Err(err) => ("error", vec![format!("{:#}", err.into())]),
+    };
+    Entry {
+        path,
+        state,
+        recipients,
+        notes,
+    }
+}
+
+fn print_checked(entry: &Entry, name: &str) {
+    let mark = match entry.state {
+        "ok" => "✓",
+        "warning" => "⚠",
+        _ => "⨯",
+    };
+    match entry.notes.first() {
+        None => println!("    {} {}", mark, name),
+        Some(note) => println!("    {} {} -- {}", mark, name, note),
+    }
+}
+
+/// The staged version of a file with the recipients it should be encrypted to
+struct Staged {
+    encrypted: Vec<u8>,
+    /// Only needed for encrypted files, so failing to find them isn't fatal yet
+    public_keys: Result<Vec<String>>,
+}
+
+/// Encryption state of a file handled by the filter
+enum FileState {
+    /// Encrypted to the current recipients, or as many x25519 and plugin recipients
+    Encrypted,
+    /// Staged as plaintext, despite the filter
+    Plaintext,
+    /// Encrypted to a different set of recipients than configured
+    StaleRecipients,
+    /// None of the configured identities can decrypt it
+    Undecryptable,
+}
+
+impl FileState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            FileState::Encrypted => "encrypted",
+            FileState::Plaintext => "plaintext",
+            FileState::StaleRecipients => "stale-recipients",
+            FileState::Undecryptable => "undecryptable",
+        }
+    }
+}
+
+enum Reencoded {
+    /// Converted, shrinking by this many bytes
+    Converted(i64),
+    /// In the requested encoding already
+    Unchanged,
+    /// Not a whole age file, so there is nothing to convert, with why
+    Skipped(String),
+}
+
+enum Rekeyed {
+    /// Already encrypted to the current recipients
+    UpToDate,
+    /// Would be re-encrypted, but this is a dry run
+    Pending,
+    Encrypted {
+        /// Hash of the decrypted content
+        hash: blake3::Hash,
+        encrypted: Vec<u8>,
+    },
+}
+
+/// What `lock` does with a working tree file
+enum Locked {
+    /// The working tree file is encrypted already
+    AlreadyLocked,
+    /// The plaintext differs from the staged content, so locking would lose changes
+    Changed,
+    /// Ciphertext of the unchanged plaintext, to write to the working tree
+    Encrypted(Vec<u8>),
+}
+
+/// Sidecars stored for a file when it was last encrypted or decrypted
+struct Saved {
+    /// Hash of the plaintext
+    hash: Vec<u8>,
+    encrypted: Vec<u8>,
+}
+
+/// The working tree contents of `file`, without the envelope of `input.decode`
+fn read_working_copy(file: &Path, envelope: Option<Envelope>) -> Result<Vec<u8>> {
+    let contents = fs::read(file).with_context(|| format!("Couldn't read {:?}", file))?;
+    Ok(open_envelope(envelope, contents))
+}
+
+/// Applies the permissions of `smudge.file_mode` to a working tree file
+#[cfg(unix)]
+fn set_file_mode(file: &Path, mode: Option<u32>) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    if let Some(mode) = mode {
+        fs::set_permissions(file, fs::Permissions::from_mode(mode))
+            .with_context(|| format!("Couldn't set the mode of {:?} to {:o}", file, mode))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_file_mode(file: &Path, mode: Option<u32>) -> Result<()> {
+    if mode.is_some() {
+        log::debug!(
+            "Ignoring smudge.file_mode, file modes are only supported on unix; file={:?}",
+            file
+        );
+    }
+    Ok(())
+}
+
+fn lock_contents(
+    working: &[u8],
+    staged: Staged,
+    saved: Option<Saved>,
+    identities: &[String],
+    encoding: age::Encoding,
+) -> Result<Locked> {
+    if age::is_encrypted(working) {
+        return Ok(Locked::AlreadyLocked);
+    }
+    if !age::is_encrypted(&staged.encrypted) {
+        // Staged as plaintext, so there is no ciphertext to restore
+        if staged.encrypted != working {
+            return Ok(Locked::Changed);
+        }
+        let encrypted = age::encrypt(&staged.public_keys?, &mut &working[..], encoding)?;
+        return Ok(Locked::Encrypted(encrypted));
+    }
+
+    // The sidecars spare decrypting files which didn't change since checkout
+    let unchanged = match saved {
+        Some(saved) if saved.encrypted == staged.encrypted => {
+            saved.hash == blake3::hash(working).as_bytes()
+        }
+        _ => {
+            let decrypted = age::decrypt(identities, &mut &staged.encrypted[..])?;
+            decrypted.required(identities)?.as_deref() == Some(working)
+        }
+    };
+    Ok(if unchanged {
+        Locked::Encrypted(staged.encrypted)
+    } else {
+        Locked::Changed
+    })
+}
+
+/// The staged contents of `file`, without the envelope of `input.decode`, and its
+/// recipients
+fn load_staged(repo: &impl Repository, cfg: &AppConfig, file: &Path) -> Result<Staged> {
+    Ok(Staged {
+        encrypted: open_envelope(cfg.envelope(), repo.get_staged_contents(file)?),
+        public_keys: cfg.get_public_keys(file).map_err(Into::into),
+    })
+}
+
+/// The recipients a staged file should be encrypted to, if they can be found
+fn staged_recipients(staged: &Result<Staged>) -> Vec<String> {
+    staged
+        .as_ref()
+        .ok()
+        .and_then(|staged| staged.public_keys.as_ref().ok())
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Whether `encrypted` is encrypted to exactly `public_keys`, with notes on the missing
+/// and extra recipients. Recipients which can only be counted are noted, and fail with
+/// `strict`.
+fn assert_header_recipients(
+    public_keys: &[String],
+    encrypted: &[u8],
+    strict: bool,
+) -> Result<(bool, Vec<String>)> {
+    let audit = age::audit_recipients(public_keys, encrypted)?;
+    let missing = audit.missing.iter().map(|r| format!("missing: {}", r));
+    let extra = audit.unknown.iter().map(|r| format!("extra: {}", r));
+    let mut notes: Vec<_> = missing.chain(extra).collect();
+    if audit.anonymous {
+        let note = "x25519 and plugin recipients can only be compared by their number";
+        notes.push(match strict {
+            true => format!("unverifiable: {}, failing with --strict", note),
+            false => format!("unverifiable: {}", note),
+        });
+    }
+    Ok((audit.is_ok() && !(strict && audit.anonymous), notes))
+}
+
+fn audit_file(staged: Result<Staged>) -> Result<age::RecipientsAudit> {
+    let staged = staged?;
+    if !age::is_encrypted(&staged.encrypted) {
+        anyhow::bail!("Staged content isn't encrypted");
+    }
+    age::audit_recipients(&staged.public_keys?, &staged.encrypted)
+}
+
+fn file_state(staged: Staged, identities: &[String]) -> Result<FileState> {
+    if !age::is_encrypted(&staged.encrypted) {
+        return Ok(FileState::Plaintext);
+    }
+    match age::decrypt_to(identities, &mut &staged.encrypted[..], &mut io::sink()) {
+        Ok(age::DecryptOutcome::Decrypted(())) => {}
+        Ok(_) => return Ok(FileState::Undecryptable),
+        Err(err) => {
+            log::debug!("Couldn't decrypt staged file; error={:#}", err);
+            return Ok(FileState::Undecryptable);
+        }
+    }
+    Ok(
+        match age::recipients_state(&staged.public_keys?, &staged.encrypted)? {
+            age::RecipientsState::Stale => FileState::StaleRecipients,
+            age::RecipientsState::Current | age::RecipientsState::Unverifiable => {
+                FileState::Encrypted
+            }
+        },
+    )
+}
+
+fn rekey_contents(
+    staged: Staged,
+    identities: &[String],
+    encoding: age::Encoding,
+    dry_run: bool,
+) -> Result<Rekeyed> {
+    let public_keys = staged.public_keys?;
+    if age::is_encrypted_to(&public_keys, &staged.encrypted)? {
+        return Ok(Rekeyed::UpToDate);
+    }
+
+    let decrypted = age::decrypt(identities, &mut &staged.encrypted[..])?
+        .required(identities)?
+        .context("Staged content isn't encrypted")?;
+    if dry_run {
+        return Ok(Rekeyed::Pending);
+    }
+
+    Ok(Rekeyed::Encrypted {
+        hash: blake3::hash(&decrypted),
+        encrypted: age::encrypt(&public_keys, &mut &decrypted[..], encoding)?,
+    })
+}
+
+/// Creates a new file only accessible by the current user, failing if it exists
+fn create_identity_file(path: &Path) -> Result<fs::File> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    Ok(options.open(path)?)
+}
+
+const GIT_ATTRIBUTES: &str = ".gitattributes";
+
+/// Assigns the git-agecrypt filters to `pattern` in the `.gitattributes` contents.
+/// Returns the added line, or `None` if the pattern already has the filter assigned.
+fn add_attributes(attributes: &mut String, pattern: &str) -> Result<Option<String>> {
+    if pattern.is_empty() || pattern.contains(char::is_whitespace) {
+        anyhow::bail!("Invalid pattern for {}: {:?}", GIT_ATTRIBUTES, pattern);
+    }
+
+    let configured = attributes.lines().any(|line| {
+        let mut words = line.split_whitespace();
+        words.next() == Some(pattern) && words.any(|attr| attr == "filter=git-agecrypt")
+    });
+    if configured {
+        return Ok(None);
+    }
+
+    let line = format!(
+        "{} filter=git-agecrypt diff=git-agecrypt merge=git-agecrypt",
+        pattern
+    );
+    if !attributes.is_empty() && !attributes.ends_with('\n') {
+        attributes.push('\n');
+    }
+    attributes.push_str(&line);
+    attributes.push('\n');
+    Ok(Some(line))
+}
+
+/// Patterns of the .gitattributes lines which set the filter
+fn filter_patterns(attributes: &str) -> Vec<&str> {
+    attributes
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            let pattern = words.next().filter(|pattern| !pattern.starts_with('#'))?;
+            words
+                .any(|attr| attr == "filter=git-agecrypt")
+                .then_some(pattern)
+        })
+        .collect()
 }
+
+/// Whether the .gitattributes `pattern` matches `relpath`. Like in git, patterns without
+/// a slash match file names in any directory, others the path from the root.
+fn attribute_matches(pattern: &str, relpath: &Path) -> bool {
+    let relpath = relpath.to_string_lossy();
+    let (pattern, target) = match pattern.strip_prefix('/') {
+        Some(anchored) => (anchored, &*relpath),
+        None if !pattern.contains('/') => (pattern, relpath.rsplit('/').next().unwrap_or("")),
+        None => (pattern, &*relpath),
+    };
+    let options = glob::MatchOptions {
+        require_literal_separator: true,
+        ..glob::MatchOptions::new()
+    };
+    glob::Pattern::new(pattern).is_ok_and(|glob| glob.matches_with(target, options))
+}
+
 fn ensure_state(result: git::Result<()>) -> Result<()> {
     match result {
         Ok(()) => Ok(()),
@@ -122,3 +1691,337 @@ fn ensure_state(result: git::Result<()>) -> Result<()> {
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use ::age::secrecy::ExposeSecret;
+    use assert_fs::prelude::*;
+    use assert_fs::TempDir;
+    use assert_matches::assert_matches;
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    fn test_diagnose_getter() {
+        let used = diagnose_getter(Ok(Some("sops".to_string())));
+        assert_eq!((used.path.as_str(), used.state), ("getter 'sops'", "ok"));
+        assert!(used.notes.is_empty());
+
+        let failed = diagnose_getter(Err(anyhow::anyhow!("Passphrase command failed")));
+        assert_eq!(failed.state, "error");
+        assert_eq!(
+            failed.notes,
+            [
+                "Passphrase command failed",
+                "fix the getter in the [passphrase] section of git-agecrypt.toml"
+            ]
+        );
+    }
+
+    #[rstest]
+    #[case::empty(
+        "",
+        "*.secret filter=git-agecrypt diff=git-agecrypt merge=git-agecrypt\n"
+    )]
+    #[case::no_trailing_newline(
+        "*.png binary",
+        "*.png binary\n*.secret filter=git-agecrypt diff=git-agecrypt merge=git-agecrypt\n"
+    )]
+    fn test_add_attributes(#[case] existing: &str, #[case] expected: &str) -> Result<()> {
+        let mut attributes = existing.to_string();
+
+        assert!(add_attributes(&mut attributes, "*.secret")?.is_some());
+        assert_eq!(attributes, expected);
+
+        // Running it again changes nothing
+        assert!(add_attributes(&mut attributes, "*.secret")?.is_none());
+        assert_eq!(attributes, expected);
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::matching(&[Some(&["age1alice", "age1bob"][..])], &["age1bob # Bob"], false)]
+    #[case::other_type(&[Some(&["age1alice"][..])], &["ssh-ed25519 AAAAalice"], true)]
+    #[case::underivable(&[Some(&["age1alice"][..]), None], &["ssh-ed25519 AAAAalice"], false)]
+    #[case::no_recipients(&[Some(&["age1alice"][..])], &[], false)]
+    #[case::no_identities(&[], &["age1bob"], false)]
+    fn test_decrypts_none(
+        #[case] derived: &[Option<&[&str]>],
+        #[case] recipients: &[&str],
+        #[case] expected: bool,
+    ) {
+        let derived: Vec<_> = derived
+            .iter()
+            .map(|keys| keys.map(|keys| keys.iter().map(|k| k.to_string()).collect()))
+            .collect();
+        let recipients: Vec<_> = recipients.iter().map(|r| r.to_string()).collect();
+
+        assert_eq!(decrypts_none(&derived, &recipients), expected);
+    }
+
+    #[rstest]
+    fn test_init_diff() {
+        let settings = [
+            ConfigChange {
+                key: "filter.git-agecrypt.required",
+                current: None,
+                value: "true".into(),
+            },
+            ConfigChange {
+                key: "diff.git-agecrypt.textconv",
+                current: Some("/old/git-agecrypt textconv".into()),
+                value: "/new/git-agecrypt textconv".into(),
+            },
+        ];
+        let lines = ["*.secret filter=git-agecrypt".to_string()];
+
+        assert_eq!(
+            init_diff(&settings, &lines),
+            "git config:\n\
+             +   filter.git-agecrypt.required = true\n\
+             -   diff.git-agecrypt.textconv = /old/git-agecrypt textconv\n\
+             +   diff.git-agecrypt.textconv = /new/git-agecrypt textconv\n\
+             .gitattributes:\n\
+             +   *.secret filter=git-agecrypt\n"
+        );
+        assert_eq!(
+            init_diff(&settings[..0], &lines),
+            ".gitattributes:\n+   *.secret filter=git-agecrypt\n"
+        );
+    }
+
+    fn encrypted_staged(identity: &::age::x25519::Identity, plaintext: &[u8]) -> Result<Staged> {
+        let public_keys = vec![identity.to_public().to_string()];
+        Ok(Staged {
+            encrypted: age::encrypt(&public_keys, &mut &plaintext[..], age::Encoding::Binary)?,
+            public_keys: Ok(public_keys),
+        })
+    }
+
+    #[rstest]
+    fn test_lock_contents() -> Result<()> {
+        let dir = TempDir::new()?;
+        let identity = ::age::x25519::Identity::generate();
+        let identity_file = dir.child("identity.txt");
+        identity_file.write_str(identity.to_string().expose_secret())?;
+        let identities = vec![identity_file.path().to_string_lossy().to_string()];
+        let lock = |working: &[u8], staged| {
+            lock_contents(working, staged, None, &identities, age::Encoding::Binary)
+        };
+
+        let staged = encrypted_staged(&identity, b"secret")?;
+        let encrypted = staged.encrypted.clone();
+        assert_matches!(lock(b"secret", staged)?, Locked::Encrypted(e) if e == encrypted);
+        assert_matches!(
+            lock(b"changed", encrypted_staged(&identity, b"secret")?)?,
+            Locked::Changed
+        );
+        assert_matches!(
+            lock(&encrypted, encrypted_staged(&identity, b"secret")?)?,
+            Locked::AlreadyLocked
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_assert_header_recipients() -> Result<()> {
+        let ssh = include_str!("../../tests/fixtures/ssh_ed25519.pub")
+            .trim()
+            .to_string();
+        let x25519 = ::age::x25519::Identity::generate().to_public().to_string();
+        let encrypt = |public_keys: &[String]| {
+            age::encrypt(public_keys, &mut &b"secret"[..], age::Encoding::Binary)
+        };
+        let ssh_encrypted = encrypt(&[ssh.clone()])?;
+        let x25519_encrypted = encrypt(&[x25519.clone()])?;
+        let check = assert_header_recipients;
+
+        assert_eq!(check(&[ssh.clone()], &ssh_encrypted, true)?, (true, vec![]));
+        let (matches, notes) = check(&[], &ssh_encrypted, true)?;
+        assert!(!matches);
+        assert_matches!(&notes[..], [extra] if extra.starts_with("extra: ssh-ed25519 key tagged"));
+        let (matches, notes) = check(&[ssh, x25519.clone()], &ssh_encrypted, false)?;
+        assert!(!matches);
+        assert_eq!(notes[0], "missing: 1 x25519 recipient");
+
+        let (matches, notes) = check(&[x25519.clone()], &x25519_encrypted, false)?;
+        assert!(matches);
+        assert_matches!(&notes[..], [note] if note.starts_with("unverifiable: "));
+        assert!(!check(&[x25519], &x25519_encrypted, true)?.0);
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_lock_contents_trusts_matching_sidecars() -> Result<()> {
+        let identity = ::age::x25519::Identity::generate();
+        let staged = encrypted_staged(&identity, b"secret")?;
+        let saved = Saved {
+            hash: blake3::hash(b"secret").as_bytes().to_vec(),
+            encrypted: staged.encrypted.clone(),
+        };
+
+        // Without identities, only the sidecars tell that the file is unchanged
+        let locked = lock_contents(b"secret", staged, Some(saved), &[], age::Encoding::Binary)?;
+
+        assert_matches!(locked, Locked::Encrypted(_));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[rstest]
+    fn test_set_file_mode() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new()?;
+        let path = dir.path().join("secret.env");
+        fs::write(&path, b"ciphertext")?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644))?;
+
+        set_file_mode(&path, None)?;
+        assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o777, 0o644);
+        set_file_mode(&path, Some(0o600))?;
+        assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o777, 0o600);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[rstest]
+    fn test_identity_file_is_private_and_never_overwritten() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new()?;
+        let path = dir.path().join("identity.txt");
+        create_identity_file(&path)?;
+
+        assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o777, 0o600);
+        assert!(create_identity_file(&path).is_err());
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_add_attributes_keeps_existing_assignment() -> Result<()> {
+        let mut attributes = "secrets/** filter=git-agecrypt diff=git-agecrypt\n".to_string();
+
+        assert!(add_attributes(&mut attributes, "secrets/**")?.is_none());
+        assert!(add_attributes(&mut attributes, "two words").is_err());
+        assert_eq!(
+            attributes,
+            "secrets/** filter=git-agecrypt diff=git-agecrypt\n"
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_filter_patterns() {
+        let attributes = "# *.key filter=git-agecrypt\n*.png binary\n\n\
+                          *.env filter=git-agecrypt diff=git-agecrypt\n\
+                          /secrets/** diff=git-agecrypt filter=git-agecrypt\n";
+
+        assert_eq!(filter_patterns(attributes), ["*.env", "/secrets/**"]);
+    }
+
+    #[rstest]
+    #[case::any_directory("*.env", "deploy/prod.env", true)]
+    #[case::not_across_directories("deploy/*.env", "deploy/eu/prod.env", false)]
+    #[case::anchored("/prod.env", "deploy/prod.env", false)]
+    #[case::recursive("secrets/**", "secrets/eu/token", true)]
+    #[case::other_directory("secrets/**", "docs/secrets", false)]
+    fn test_attribute_matches(
+        #[case] pattern: &str,
+        #[case] relpath: &str,
+        #[case] expected: bool,
+    ) {
+        assert_eq!(attribute_matches(pattern, Path::new(relpath)), expected);
+    }
+
+    /// Commands for a new repository in `dir` with `config` as its git-agecrypt.toml,
+    /// whose files below secrets/ are handled by the filter. `files` are staged as they
+    /// are, as the filter isn't configured.
+    fn staged_repo(
+        dir: &TempDir,
+        config: &str,
+        files: &[(&str, &[u8])],
+    ) -> Result<CommandContext<impl Context>> {
+        duct::cmd!("git", "init").dir(dir.path()).run()?;
+        dir.child(".gitattributes")
+            .write_str("secrets/** filter=git-agecrypt\n")?;
+        dir.child("git-agecrypt.toml").write_str(config)?;
+        for (path, contents) in files {
+            dir.child(path).write_binary(contents)?;
+        }
+        duct::cmd!("git", "add", ".").dir(dir.path()).run()?;
+        let repo = git::LibGit2Repository::from_dir(dir.to_path_buf())?;
+        let ctx = crate::ctx::new(repo, dir.join("git-agecrypt.toml"));
+        Ok(CommandContext::new(ctx, false))
+    }
+
+    #[rstest]
+    fn test_check_rekeyed_strict() -> Result<()> {
+        let dir = TempDir::new()?;
+        let recipient = ::age::x25519::Identity::generate().to_public().to_string();
+        let config = format!("[config]\n\"secrets/**\" = ['{}']\n", recipient);
+        let encrypted = age::encrypt(&[recipient], &mut &b"secret"[..], age::Encoding::Binary)?;
+        let cmd = staged_repo(&dir, &config, &[("secrets/token", &encrypted)])?;
+        let jobs = NonZeroUsize::new(1).unwrap();
+
+        assert!(cmd.check_rekeyed(false, None, &[], jobs).is_ok());
+        let err = cmd
+            .check_rekeyed(true, None, &[], jobs)
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with("1 files have x25519"), "{}", err);
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_reencode_file_skips_other_files() -> Result<()> {
+        let dir = TempDir::new()?;
+        let recipient = ::age::x25519::Identity::generate().to_public().to_string();
+        let encrypted = age::encrypt(&[recipient], &mut &b"secret"[..], age::Encoding::Binary)?;
+        let files: [(&str, &[u8]); 5] = [
+            (IGNORE_FILE, b"secrets/ignored\n"),
+            ("secrets/binary", &encrypted),
+            ("secrets/partial", b"token:\n  ENC[age:YWdl] # agecrypt\n"),
+            ("secrets/ignored", b"public"),
+            ("secrets/plain", b"public"),
+        ];
+        let cmd = staged_repo(&dir, "", &files)?;
+        let ignored = IgnoreFile::load(dir.path())?;
+        let reencode = |path: &str| {
+            let file = dir.path().join(path);
+            cmd.reencode_file(&file, &ignored, age::Encoding::Armored, true)
+        };
+
+        assert_matches!(reencode("secrets/binary")?, Reencoded::Converted(saved) if saved < 0);
+        assert_matches!(reencode("secrets/partial")?, Reencoded::Skipped(r) if r.contains("lines"));
+        assert_matches!(reencode("secrets/ignored")?, Reencoded::Skipped(r) if r.contains(".git"));
+        assert_matches!(reencode("secrets/plain")?, Reencoded::Skipped(r) if r.contains("age"));
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_base64_envelope() -> Result<()> {
+        let dir = TempDir::new()?;
+        let recipient = ::age::x25519::Identity::generate().to_public().to_string();
+        let config = format!(
+            "[input]\ndecode = \"base64\"\n[config]\n\"secrets/**\" = ['{}']\n",
+            recipient
+        );
+        let encrypted = age::encrypt(&[recipient], &mut &b"secret"[..], age::Encoding::Binary)?;
+        let stored = seal_envelope(Envelope::Base64, encrypted);
+        let cmd = staged_repo(&dir, &config, &[("secrets/token", &stored)])?;
+        let file = dir.path().join("secrets/token");
+        let ignored = IgnoreFile::load(dir.path())?;
+
+        cmd.check_rekeyed(false, None, &[], NonZeroUsize::new(1).unwrap())?;
+        let reencoded = cmd.reencode_file(&file, &ignored, age::Encoding::Armored, false)?;
+        assert_matches!(reencoded, Reencoded::Converted(_));
+        // Staged encoded again, around the armored file
+        let staged = cmd.ctx.repo().get_staged_contents(&file)?;
+        assert!(!age::is_age_encrypted(&staged));
+        let armored = open_envelope(Some(Envelope::Base64), staged);
+        assert!(armored.starts_with(b"-----BEGIN AGE ENCRYPTED FILE-----"));
+        Ok(())
+    }
+}
@@ -0,0 +1,103 @@
+//! Output of the commands checking files or configuration entries, as text or JSON.
+
+use std::io::{self, Write};
+
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::Serialize;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// Text for people
+    Human,
+    /// A JSON document for scripts
+    Json,
+}
+
+/// A checked file or configuration entry
+#[derive(Serialize)]
+pub(crate) struct Entry {
+    pub path: String,
+    pub state: &'static str,
+    pub recipients: Vec<String>,
+    pub notes: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct Document<'a> {
+    entries: &'a [Entry],
+    error: Option<&'a str>,
+}
+
+/// Error which is already part of the printed JSON document
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+struct Reported(String);
+
+/// Prints the JSON document of `entries` if requested, and fails with `error` if any.
+/// Text output is left to the commands.
+pub(crate) fn finish(format: Format, entries: &[Entry], error: Option<String>) -> Result<()> {
+    if format == Format::Json {
+        print(entries, error.as_deref())?;
+        if let Some(error) = error {
+            return Err(Reported(error).into());
+        }
+    } else if let Some(error) = error {
+        anyhow::bail!(error);
+    }
+    Ok(())
+}
+
+/// Includes the error of a command failing before `finish` in a JSON document, so that
+/// scripts don't need to parse stderr.
+pub(crate) fn json_errors(format: Format, result: Result<()>) -> Result<()> {
+    if let (Format::Json, Err(err)) = (format, &result) {
+        if err.downcast_ref::<Reported>().is_none() {
+            print(&[], Some(&format!("{:#}", err)))?;
+        }
+    }
+    result
+}
+
+fn print(entries: &[Entry], error: Option<&str>) -> Result<()> {
+    let mut stdout = io::stdout().lock();
+    serde_json::to_writer_pretty(&mut stdout, &Document { entries, error })?;
+    writeln!(stdout)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    fn test_document_format() -> Result<()> {
+        let entries = [Entry {
+            path: "secrets/db".to_string(),
+            state: "stale-recipients",
+            recipients: vec!["age1example".to_string()],
+            notes: vec![],
+        }];
+
+        let json = serde_json::to_value(Document {
+            entries: &entries,
+            error: Some("1 files aren't encrypted to exactly the configured recipients"),
+        })?;
+
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "entries": [{
+                    "path": "secrets/db",
+                    "state": "stale-recipients",
+                    "recipients": ["age1example"],
+                    "notes": [],
+                }],
+                "error": "1 files aren't encrypted to exactly the configured recipients",
+            })
+        );
+        Ok(())
+    }
+}
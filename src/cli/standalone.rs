@@ -0,0 +1,308 @@
+use std::{
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use anyhow::{bail, Context as _, Result};
+use zeroize::Zeroizing;
+
+use crate::{age, atomic, ctx::Context, exit_code::Failure, progress::Progress};
+
+use super::args::{EncryptRecipients, OutputFile};
+
+/// Encrypts `file` or stdin to `output` or stdout for the given recipients, and those
+/// configured for the file in the current repository. `armor` overrides the configured
+/// encoding.
+pub(crate) fn encrypt(
+    ctx: Option<&impl Context>,
+    to: EncryptRecipients,
+    armor: Option<bool>,
+    output: OutputFile,
+    file: Option<PathBuf>,
+    progress: bool,
+) -> Result<()> {
+    let EncryptRecipients {
+        mut recipients,
+        recipients_files,
+        identities,
+        recipient_from_identity,
+    } = to;
+    check_stdin_use(&recipients_files, &file)?;
+    for recipients_file in &recipients_files {
+        recipients.extend(if recipients_file == Path::new(STDIN) {
+            age::read_recipients(io::stdin().lock(), Path::new("<stdin>"))?
+        } else {
+            age::read_recipients_file(recipients_file)?
+        });
+    }
+
+    let cfg = ctx.map(|ctx| ctx.config()).transpose()?;
+    let mut public_keys = match &cfg {
+        Some(cfg) => cfg.resolve_recipients(&recipients)?,
+        None => recipients,
+    };
+    for identity in &identities {
+        public_keys.extend(age::recipients_of_identity(identity)?);
+    }
+    if let (Some(cfg), Some(file)) = (&cfg, &file) {
+        let path = std::env::current_dir()?.join(file);
+        match cfg.get_public_keys(&path) {
+            Ok(configured) => public_keys.extend(configured),
+            Err(err) => log::debug!("No configured recipients for the file; error={:#}", err),
+        }
+    }
+    if let Some(cfg) = &cfg {
+        cfg.check_recipient_types(&public_keys)?;
+    }
+    let own_identities = match ctx {
+        Some(ctx) if recipient_from_identity => configured_identities(ctx)?,
+        _ => vec![],
+    };
+    if recipient_from_identity && own_identities.is_empty() {
+        bail!("No configured identities to encrypt to, add one with `git-agecrypt config add -i`");
+    }
+    if public_keys.is_empty() && own_identities.is_empty() {
+        bail!("No recipients to encrypt to, add some with -r, -R or -i");
+    }
+
+    let encoding = encoding(armor, cfg.as_ref().map(|cfg| cfg.encoding()));
+    let mut input = input(&file)?;
+    let progress = input_progress(&file, "Encrypting", progress);
+    let report = |read| progress.set_position(read);
+    write_output(&output, false, |mut out| {
+        age::encrypt_streaming(
+            &public_keys,
+            &own_identities,
+            &mut input,
+            &mut out,
+            encoding,
+            streaming(&report),
+        )?;
+        Ok(())
+    })
+}
+
+/// The encoding chosen on the command line, else the configured one, else binary
+fn encoding(armor: Option<bool>, configured: Option<age::Encoding>) -> age::Encoding {
+    match (armor, configured) {
+        (Some(true), _) => age::Encoding::Armored,
+        (Some(false), _) => age::Encoding::Binary,
+        (None, configured) => configured.unwrap_or_default(),
+    }
+}
+
+/// Decrypts `file` or stdin to `output` or stdout with the given identities, and those
+/// configured for the current repository. With `private`, the output file is only
+/// readable by the current user.
+pub(crate) fn decrypt(
+    ctx: Option<&impl Context>,
+    identities: Vec<PathBuf>,
+    output: OutputFile,
+    private: bool,
+    file: Option<PathBuf>,
+    progress: bool,
+) -> Result<()> {
+    let mut all_identities: Vec<_> = identities
+        .iter()
+        .map(|identity| identity.to_string_lossy().to_string())
+        .collect();
+    if let Some(ctx) = ctx {
+        all_identities.extend(configured_identities(ctx)?);
+    }
+    if all_identities.is_empty() {
+        bail!("No identity to decrypt with, add one with -i");
+    }
+
+    let mut input = input(&file)?;
+    let progress = input_progress(&file, "Decrypting", progress);
+    let report = |read| progress.set_position(read);
+    write_output(&output, private, |mut out| {
+        let outcome =
+            age::decrypt_streaming(&all_identities, &mut input, &mut out, streaming(&report))
+                .map_err(|err| match &file {
+                    Some(file) => err.for_input(file),
+                    None => err,
+                })?;
+        if outcome.required(&all_identities)?.is_none() {
+            bail!("Input isn't age encrypted");
+        }
+        Ok(())
+    })
+}
+
+/// Runs `write` on stdout, or on a temporary file which replaces the output file once it
+/// succeeded. Existing files are only replaced with `--force`. Ctrl-C cancels writing
+/// the file, which is then left alone.
+fn write_output(
+    output: &OutputFile,
+    private: bool,
+    write: impl FnOnce(&mut dyn Write) -> Result<()>,
+) -> Result<()> {
+    let Some(path) = output
+        .output
+        .as_deref()
+        .filter(|path| *path != Path::new(STDOUT))
+    else {
+        let mut stdout = io::stdout().lock();
+        write(&mut stdout)?;
+        return Ok(stdout.flush()?);
+    };
+    if path.exists() && !output.force {
+        bail!(
+            "{} already exists, pass --force to overwrite it",
+            path.display()
+        );
+    }
+    cancel_on_ctrl_c();
+    atomic::write_streamed(path, private, |file| {
+        let mut out = io::BufWriter::new(file);
+        write(&mut out)?;
+        Ok(out.flush()?)
+    })
+}
+
+/// Set by Ctrl-C while an output file is written, see [`cancel_on_ctrl_c`]
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+/// Makes Ctrl-C cancel encrypting or decrypting into an output file at the next chunk of
+/// the input, so that the half-written temporary file is removed. A second Ctrl-C exits
+/// right away, e.g. when waiting for stdin or at a passphrase prompt.
+fn cancel_on_ctrl_c() {
+    let installed = ctrlc::set_handler(|| {
+        if CANCELLED.swap(true, Ordering::Relaxed) {
+            std::process::exit(Failure::Cancelled as i32);
+        }
+        eprintln!("git-agecrypt: cancelling, press Ctrl-C again to exit right away");
+    });
+    if let Err(err) = installed {
+        log::debug!("Couldn't handle Ctrl-C, it exits right away; error={}", err);
+    }
+}
+
+/// Progress of reading `file`, hidden for stdin, whose length is unknown
+fn input_progress(file: &Option<PathBuf>, message: &'static str, enabled: bool) -> Progress {
+    match file.as_ref().and_then(|file| fs::metadata(file).ok()) {
+        Some(metadata) => Progress::bytes(metadata.len(), message, enabled),
+        None => Progress::hidden(),
+    }
+}
+
+/// Streaming which reports the bytes read to `report` and stops on Ctrl-C
+fn streaming(report: &dyn Fn(u64)) -> age::Streaming<'_> {
+    age::Streaming {
+        progress: Some(report),
+        cancel: Some(&CANCELLED),
+    }
+}
+
+/// Identities of the repository, from the git config and the configuration file
+fn configured_identities(ctx: &impl Context) -> Result<Vec<String>> {
+    let mut configured = vec![];
+    for identity in ctx.age_identities().list()? {
+        configured.push(identity.expanded_path()?);
+    }
+    Ok(ctx.config()?.merge_identities(configured)?)
+}
+
+/// Re-encrypts the identity file at `path` with a new passphrase, read from
+/// `new_passphrase_file` or asked for
+pub(crate) fn rekey_identity(
+    path: PathBuf,
+    encrypt: bool,
+    new_passphrase_file: Option<PathBuf>,
+) -> Result<()> {
+    // The file is replaced rather than a link to it
+    let path = fs::canonicalize(&path)
+        .with_context(|| format!("Couldn't find identity file {:?}", path))?;
+    let contents = Zeroizing::new(
+        fs::read(&path).with_context(|| format!("Couldn't read identity file {:?}", path))?,
+    );
+    let rekeyed = age::rekey_identity(&path, &contents, encrypt, || {
+        age::rotated_passphrase(new_passphrase_file.as_deref())
+    })?;
+    atomic::write(&path, &rekeyed)?;
+    eprintln!("Identity file {} re-encrypted", path.display());
+    Ok(())
+}
+
+/// Prints the recipients of the identity `path` on stdout, one per line
+pub(crate) fn identity_pubkey(path: PathBuf) -> Result<()> {
+    let mut stdout = io::stdout().lock();
+    for recipient in age::recipients_of_identity(&path)? {
+        writeln!(stdout, "{}", recipient)?;
+    }
+    Ok(stdout.flush()?)
+}
+
+/// Name of stdin among recipients files
+const STDIN: &str = "-";
+/// Name of stdout as output file
+const STDOUT: &str = "-";
+
+/// Ensures that stdin is read only once, either for recipients or for the input
+fn check_stdin_use(recipients_files: &[PathBuf], file: &Option<PathBuf>) -> Result<()> {
+    let from_stdin = recipients_files
+        .iter()
+        .filter(|f| *f == Path::new(STDIN))
+        .count();
+    if from_stdin > 1 {
+        bail!("Recipients can only be read from stdin once");
+    }
+    if from_stdin == 1 && file.is_none() {
+        bail!(
+            "Can't read both recipients (-R -) and the input from stdin, pass the file to encrypt"
+        );
+    }
+    Ok(())
+}
+
+fn input(file: &Option<PathBuf>) -> Result<Box<dyn Read>> {
+    Ok(match file {
+        Some(file) => {
+            Box::new(File::open(file).with_context(|| format!("Couldn't open {:?}", file))?)
+        }
+        None => Box::new(io::stdin().lock()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case::default(None, None, age::Encoding::Binary)]
+    #[case::configured_binary(None, Some(age::Encoding::Binary), age::Encoding::Binary)]
+    #[case::configured_armor(None, Some(age::Encoding::Armored), age::Encoding::Armored)]
+    #[case::armor(Some(true), None, age::Encoding::Armored)]
+    #[case::no_armor(Some(false), None, age::Encoding::Binary)]
+    #[case::armor_over_config(Some(true), Some(age::Encoding::Binary), age::Encoding::Armored)]
+    #[case::no_armor_over_config(Some(false), Some(age::Encoding::Armored), age::Encoding::Binary)]
+    fn test_armor_precedence(
+        #[case] armor: Option<bool>,
+        #[case] configured: Option<age::Encoding>,
+        #[case] expected: age::Encoding,
+    ) {
+        assert_eq!(encoding(armor, configured), expected);
+    }
+
+    #[rstest]
+    #[case::file_and_stdin(&["team.txt", "-"], Some("secret.txt"), true)]
+    #[case::files_only(&["team.txt"], None, true)]
+    #[case::input_from_stdin(&["-"], None, false)]
+    #[case::stdin_twice(&["-", "-"], Some("secret.txt"), false)]
+    fn test_stdin_is_read_once(
+        #[case] recipients_files: &[&str],
+        #[case] file: Option<&str>,
+        #[case] allowed: bool,
+    ) {
+        let recipients_files: Vec<_> = recipients_files.iter().map(PathBuf::from).collect();
+
+        let checked = check_stdin_use(&recipients_files, &file.map(PathBuf::from));
+
+        assert_eq!(checked.is_ok(), allowed);
+    }
+}
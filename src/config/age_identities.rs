@@ -1,13 +1,52 @@
-use std::{fmt::Display, path::PathBuf};
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+};
 
 use anyhow::Context as AnyhowContext;
 
-use super::{git::GitConfigEntry, Container, Result, Validated};
+use super::{env, git::GitConfigEntry, Container, Result, Validated};
 
+/// Path of an identity file, or an inline identity
 pub(crate) struct AgeIdentity {
     pub path: String,
 }
 
+impl AgeIdentity {
+    /// The path with environment variables and `~` expanded, inline identities as they are
+    pub fn expanded_path(&self) -> Result<String> {
+        if crate::age::is_inline_identity(Path::new(&self.path)) {
+            return Ok(self.path.clone());
+        }
+        env::expand(&self.path, "git-agecrypt.config.identity")
+    }
+}
+
+/// Whether `identity` is a glob pattern naming several identity files
+fn is_glob(identity: &str) -> bool {
+    !crate::age::is_inline_identity(Path::new(identity)) && identity.contains(['*', '?', '['])
+}
+
+/// Expands an identity given as a glob pattern into the files it matches, sorted by path.
+/// Other identities are kept as they are. A pattern which matches nothing only warns, as
+/// some keys may be absent on some machines.
+pub(super) fn expand_glob(identity: String) -> Result<Vec<String>> {
+    if !is_glob(&identity) {
+        return Ok(vec![identity]);
+    }
+    let mut paths: Vec<_> = glob::glob(&identity)
+        .with_context(|| format!("Invalid identity pattern '{}'", identity))?
+        .filter_map(|path| path.ok())
+        .filter(|path| path.is_file())
+        .map(|path| path.to_string_lossy().to_string())
+        .collect();
+    paths.sort();
+    if paths.is_empty() {
+        log::warn!("Identity pattern matches no files; pattern={}", identity);
+    }
+    Ok(paths)
+}
+
 impl TryFrom<PathBuf> for AgeIdentity {
     type Error = anyhow::Error;
 
@@ -24,14 +63,32 @@ impl TryFrom<PathBuf> for AgeIdentity {
 
 impl Display for AgeIdentity {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        self.path.fmt(f)
+        // Never reveals inline identities
+        crate::age::identity_name(Path::new(&self.path)).fmt(f)
     }
 }
 
 impl Validated for AgeIdentity {
     fn validate(&self) -> Result<Option<String>> {
-        Ok(crate::age::validate_identity(&self.path)
-            .with_context(|| format!("The file '{}' is not a valid age identity", self.path))?)
+        let path = self.expanded_path()?;
+        if !is_glob(&path) {
+            return Ok(crate::age::validate_identity(path)
+                .with_context(|| format!("The file '{}' is not a valid age identity", self))?);
+        }
+        let paths = expand_glob(path)?;
+        if paths.is_empty() {
+            return Ok(Some("matches no identity files".to_string()));
+        }
+        let mut notes = vec![];
+        for path in &paths {
+            let note = crate::age::validate_identity(path)
+                .with_context(|| format!("The file '{}' is not a valid age identity", path))?;
+            notes.extend(note.map(|note| format!("{}: {}", path, note)));
+        }
+        match notes.is_empty() {
+            true => Ok(None),
+            false => Ok(Some(notes.join("; "))),
+        }
     }
 }
 
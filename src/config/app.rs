@@ -1,17 +1,20 @@
 use std::{
-    collections::HashMap,
-    fs, io,
+    collections::{HashMap, HashSet},
+    fs,
+    hash::Hash,
+    io,
     path::{Path, PathBuf},
+    time::Duration,
 };
 
 use glob::Pattern;
 
 use anyhow::{anyhow, Context};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 
-use crate::age;
+use crate::{age, atomic};
 
-use super::Result;
+use super::{age_identities::expand_glob, env, remote, Error, Result};
 
 #[derive(Serialize, Deserialize, PartialEq, Eq)]
 pub struct RecipientEntry {
@@ -19,36 +22,531 @@ pub struct RecipientEntry {
     recipients: Vec<String>,
 }
 
+/// Recipients of the files matching `path`, checked in the order of the `[[rules]]` array
+#[derive(Serialize, Deserialize)]
+pub struct Rule {
+    path: PathBuf,
+    /// Glob pattern of the branches the rule applies to, e.g. "release/*". Without it,
+    /// the rule applies on every branch and on a detached HEAD, with it never on the latter.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    branch: Option<String>,
+    #[serde(
+        default,
+        skip_serializing_if = "Vec::is_empty",
+        deserialize_with = "labeled_recipients"
+    )]
+    recipients: Vec<String>,
+    /// Recipients file in `age -R` format, used in addition to `recipients`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    recipients_file: Option<PathBuf>,
+    /// Directory or glob pattern of key files, used in addition to `recipients`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    recipients_dir: Option<PathBuf>,
+    /// Which parts of the matching files are encrypted
+    #[serde(default, skip_serializing_if = "EncryptionScope::is_file")]
+    encrypt: EncryptionScope,
+}
+
+/// Binary of an age plugin which isn't installed in PATH, e.g. one shipped with the
+/// repository, from the `[[plugins]]` array
+#[derive(Serialize, Deserialize)]
+struct PluginConfig {
+    /// Name of the plugin, as in `age1name1...` recipients and `AGE-PLUGIN-NAME-...`
+    /// identities
+    name: String,
+    /// Path of the binary, relative to the repository root
+    path: PathBuf,
+    /// Arguments passed to the binary before those of age
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    args: Vec<String>,
+}
+
+/// A plugin binary from `[[plugins]]`, with its path resolved
+#[derive(Debug, PartialEq, Eq)]
+pub struct Plugin {
+    pub name: String,
+    pub path: PathBuf,
+    pub args: Vec<String>,
+}
+
+/// Which parts of a file the clean filter encrypts
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum EncryptionScope {
+    /// The whole file
+    #[default]
+    File,
+    /// Only the lines ending with `# agecrypt`
+    MarkedLines,
+}
+
+impl EncryptionScope {
+    fn is_file(&self) -> bool {
+        *self == Self::File
+    }
+}
+
+/// A recipient as written in the configuration, either a string with an optional
+/// trailing `# label` or a table like `{ key = "age1...", label = "alice" }`.
+/// Tables are kept as `key # label` strings, see [`age::split_label`].
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LabeledRecipient {
+    Plain(String),
+    Table { key: String, label: Option<String> },
+}
+
+impl From<LabeledRecipient> for String {
+    fn from(recipient: LabeledRecipient) -> Self {
+        match recipient {
+            LabeledRecipient::Plain(recipient) => recipient,
+            LabeledRecipient::Table { key, label } => with_label(key, label.as_deref()),
+        }
+    }
+}
+
+fn labeled_recipients<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Vec<String>, D::Error> {
+    let recipients = Vec::<LabeledRecipient>::deserialize(deserializer)?;
+    Ok(recipients.into_iter().map(String::from).collect())
+}
+
+fn labeled_recipients_by_key<'de, D: Deserializer<'de>, K: Deserialize<'de> + Eq + Hash>(
+    deserializer: D,
+) -> std::result::Result<HashMap<K, Vec<String>>, D::Error> {
+    let entries = HashMap::<K, Vec<LabeledRecipient>>::deserialize(deserializer)?;
+    Ok(entries
+        .into_iter()
+        .map(|(key, recipients)| (key, recipients.into_iter().map(String::from).collect()))
+        .collect())
+}
+
+/// Whether `recipient` is among `recipients`, regardless of labels
+fn is_listed(recipient: &str, recipients: &[String]) -> bool {
+    let (key, _) = age::split_label(recipient);
+    recipients.iter().any(|r| age::split_label(r).0 == key)
+}
+
+fn with_label(key: String, label: Option<&str>) -> String {
+    match label {
+        Some(label) => format!("{} # {}", key, label),
+        None => key,
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct OutputConfig {
+    /// Emit ASCII armored instead of binary age files
+    #[serde(default)]
+    armor: bool,
+    /// Non-secret label written into the header of encrypted files, e.g. "repo:infra"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    comment: Option<String>,
+    /// Derive the randomness of encrypted files from `deterministic_key` and the
+    /// plaintext, so that equal plaintext yields equal ciphertext. Each file is held in
+    /// memory as a whole while it is encrypted.
+    #[serde(default)]
+    deterministic: bool,
+    /// Secret of deterministic encryption, typically `${VAR}` of an environment variable
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    deterministic_key: Option<String>,
+    /// Compress the plaintext before encrypting it, currently only with "zstd"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    compress: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct InputConfig {
+    /// Envelope other tools stored the age files in, removed before decrypting
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    decode: Option<Envelope>,
+}
+
+/// Encoding wrapped around age files, e.g. by tools storing them in YAML fields
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Envelope {
+    /// Standard base64, of which line breaks are ignored
+    Base64,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct RecipientsConfig {
+    /// Types of recipients which may be encrypted to, e.g. `["x25519", "ssh-ed25519"]`,
+    /// all if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    allowed_types: Option<Vec<String>>,
+    /// Fail on malformed key files of `recipients_dir` instead of skipping them
+    #[serde(default)]
+    strict: bool,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct IdentityFilesConfig {
+    /// Reject identity files holding a secret key in the clear
+    #[serde(default)]
+    require_encrypted: bool,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct CleanConfig {
+    /// Fail when none of the configured identities can decrypt what was just encrypted
+    #[serde(default)]
+    require_self_decrypt: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SmudgeConfig {
+    /// Fail when a file can't be decrypted, instead of checking out its ciphertext
+    #[serde(default)]
+    require_decrypt: bool,
+    /// Keep decrypted contents in the git directory, to reuse them on later checkouts
+    #[serde(default)]
+    cache: bool,
+    /// Size limit of the cache of decrypted contents
+    #[serde(default = "SmudgeConfig::default_cache_max_bytes")]
+    cache_max_bytes: u64,
+    /// Octal permissions of decrypted files written by git-agecrypt, e.g. "0600"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    file_mode: Option<String>,
+}
+
+impl SmudgeConfig {
+    fn default_cache_max_bytes() -> u64 {
+        64 * 1024 * 1024
+    }
+}
+
+impl Default for SmudgeConfig {
+    fn default() -> Self {
+        Self {
+            require_decrypt: false,
+            cache: false,
+            cache_max_bytes: Self::default_cache_max_bytes(),
+            file_mode: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RemoteKeysConfig {
+    /// How long keys fetched from URLs are reused before fetching them again
+    #[serde(default = "RemoteKeysConfig::default_ttl_secs")]
+    ttl_secs: u64,
+}
+
+impl RemoteKeysConfig {
+    fn default_ttl_secs() -> u64 {
+        24 * 60 * 60
+    }
+}
+
+impl Default for RemoteKeysConfig {
+    fn default() -> Self {
+        Self {
+            ttl_secs: Self::default_ttl_secs(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PassphraseConfig {
+    /// Interpreter running the getter commands, e.g. `pwsh -c`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    shell: Option<String>,
+    /// How long a getter command may run before it is killed, 0 waits forever
+    #[serde(default = "PassphraseConfig::default_timeout_secs")]
+    timeout_secs: u64,
+    /// Getters by key
+    #[serde(flatten)]
+    getters: HashMap<String, Getter>,
+}
+
+impl PassphraseConfig {
+    fn default_timeout_secs() -> u64 {
+        30
+    }
+}
+
+impl IdentityGetterConfig {
+    fn is_empty(&self) -> bool {
+        self.getters.is_empty()
+    }
+}
+
+impl Default for PassphraseConfig {
+    fn default() -> Self {
+        Self {
+            shell: None,
+            timeout_secs: Self::default_timeout_secs(),
+            getters: HashMap::new(),
+        }
+    }
+}
+
+/// Getters of the contents of an identity file, by key. Commands run with the
+/// interpreter and timeout of the [passphrase] section.
+#[derive(Serialize, Deserialize, Default)]
+pub struct IdentityGetterConfig {
+    #[serde(flatten)]
+    getters: HashMap<String, Getter>,
+}
+
+/// A getter of the [passphrase] or [identity] section: a command printing the secret,
+/// or a table naming a provider which reads it itself
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Getter {
+    Command(String),
+    Provider(GetterProvider),
+}
+
+/// Tells a command from a provider's table, so that a table naming an unknown provider
+/// fails naming the known ones rather than matching neither
+impl<'de> Deserialize<'de> for Getter {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        use serde::de::Error as _;
+
+        match toml::Value::deserialize(deserializer)? {
+            toml::Value::String(command) => Ok(Self::Command(command)),
+            toml::Value::Table(table) => {
+                let provider = table.get("provider").and_then(toml::Value::as_str);
+                if let Some(name) = provider.filter(|name| !PROVIDERS.contains(name)) {
+                    return Err(D::Error::custom(format!(
+                        "unknown getter provider '{}', known are {}",
+                        name,
+                        PROVIDERS.join(", ")
+                    )));
+                }
+                let provider = toml::Value::Table(table)
+                    .try_into()
+                    .map_err(D::Error::custom)?;
+                Ok(Self::Provider(provider))
+            }
+            _ => Err(D::Error::custom(
+                "a getter is either a command or a table naming its provider",
+            )),
+        }
+    }
+}
+
+impl Getter {
+    /// The getter with environment variables expanded in its command or path, which is
+    /// named `key` in errors
+    fn expanded(&self, key: &str) -> Result<Self> {
+        Ok(match self {
+            Self::Command(command) => Self::Command(env::expand(command, key)?),
+            Self::Provider(GetterProvider::File { path }) => Self::Provider(GetterProvider::File {
+                path: env::expand(path, key)?,
+            }),
+            Self::Provider(provider) => Self::Provider(provider.clone()),
+        })
+    }
+}
+
+/// Names of the variants of [`GetterProvider`]
+const PROVIDERS: [&str; 3] = ["env", "file", "env-json"];
+
+/// Providers reading the secret without a command, e.g. `{ provider = "env", var = "X" }`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "provider", rename_all = "kebab-case")]
+pub enum GetterProvider {
+    /// The environment variable `var`
+    Env { var: String },
+    /// The file at `path`, e.g. a secret mounted by the CI system
+    File { path: String },
+    /// The string `field` of the JSON object in the environment variable `var`, like
+    /// secrets managers inject all secrets of an application at once
+    EnvJson { var: String, field: String },
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct PassphraseCacheConfig {
+    /// How long a passphrase obtained by a getter is reused, 0 disables caching
+    #[serde(default)]
+    ttl_secs: u64,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct AppConfig {
+    /// Identity files, in addition to the ones in the git configuration
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    identities: Vec<String>,
     #[serde(default)]
-    passphrase: HashMap<String, String>,
+    passphrase: PassphraseConfig,
+    #[serde(default)]
+    passphrase_cache: PassphraseCacheConfig,
+    #[serde(default, skip_serializing_if = "IdentityGetterConfig::is_empty")]
+    identity: IdentityGetterConfig,
+    /// Policy for the identity files, `identities` being taken by their list
+    #[serde(default)]
+    identity_files: IdentityFilesConfig,
     #[serde(default)]
     aliases: HashMap<String, String>,
+    /// Lists of recipients referenced as `@name`, which may include other groups
+    #[serde(default, deserialize_with = "labeled_recipients_by_key")]
+    groups: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    output: OutputConfig,
+    #[serde(default)]
+    input: InputConfig,
+    #[serde(default)]
+    recipients: RecipientsConfig,
+    #[serde(default)]
+    clean: CleanConfig,
+    #[serde(default)]
+    smudge: SmudgeConfig,
+    #[serde(default)]
+    remote_keys: RemoteKeysConfig,
+    #[serde(default, deserialize_with = "labeled_recipients_by_key")]
     config: HashMap<PathBuf, Vec<String>>,
+    /// Recipients files in `age -R` format, matched against paths like `config`
+    #[serde(default)]
+    recipients_file: HashMap<PathBuf, PathBuf>,
+    /// Directories or glob patterns of key files with a recipient each, matched like `config`
+    #[serde(default)]
+    recipients_dir: HashMap<PathBuf, PathBuf>,
+    /// Ordered recipient rules, taking precedence over `config` and `recipients_file`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    rules: Vec<Rule>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    plugins: Vec<PluginConfig>,
     #[serde(skip)]
     path: PathBuf,
     #[serde(skip)]
     prefix: PathBuf,
+    #[serde(skip)]
+    cache_dir: Option<PathBuf>,
+    /// The checked out branch, which rules with a `branch` are matched against
+    #[serde(skip)]
+    current_branch: Option<String>,
+    #[serde(skip)]
+    overrides: EnvOverrides,
+}
+
+/// Identity and recipients given through the environment, e.g. in CI
+#[derive(Default)]
+struct EnvOverrides {
+    /// Path or inline identity, tried before the configured identities
+    identity: Option<String>,
+    /// Recipients replacing the configured ones for all paths
+    recipients: Option<Vec<String>>,
 }
 
+impl EnvOverrides {
+    fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Self {
+        let identity = lookup(IDENTITY_ENV).filter(|identity| !identity.trim().is_empty());
+        let recipients = lookup(RECIPIENTS_ENV).map(|recipients| {
+            recipients
+                .split(',')
+                .map(str::trim)
+                .filter(|r| !r.is_empty())
+                .map(String::from)
+                .collect::<Vec<_>>()
+        });
+        let recipients = recipients.filter(|recipients| !recipients.is_empty());
+        Self {
+            identity,
+            recipients,
+        }
+    }
+}
+
+const CONFIG_FILE: &str = "git-agecrypt.toml";
+const LOCAL_CONFIG_FILE: &str = "git-agecrypt.local.toml";
+
+const CONFIG_ENV: &str = "GIT_AGECRYPT_CONFIG";
+const IDENTITY_ENV: &str = "GIT_AGECRYPT_IDENTITY";
+const RECIPIENTS_ENV: &str = "GIT_AGECRYPT_RECIPIENTS";
+
 impl AppConfig {
+    /// Loads and merges the user's, the repository's and the local configuration file,
+    /// with the overrides from `GIT_AGECRYPT_IDENTITY` and `GIT_AGECRYPT_RECIPIENTS`
+    /// applied. `path` is the repository's file, the local one is next to it.
     pub fn load(path: &Path, repo_prefix: &Path) -> Result<Self> {
+        let mut files: Vec<PathBuf> = user_config_file().into_iter().collect();
+        files.push(path.into());
+        files.push(path.with_file_name(LOCAL_CONFIG_FILE));
+        let mut cfg =
+            Self::load_merged(&files, path, repo_prefix).map_err(|err| Error::Load(err.into()))?;
+        cfg.overrides = EnvOverrides::from_lookup(|name| std::env::var(name).ok());
+        Ok(cfg)
+    }
+
+    /// Deep merges the existing `files`, later ones taking precedence
+    fn load_merged(files: &[PathBuf], path: &Path, repo_prefix: &Path) -> Result<Self> {
+        let mut merged = toml::Value::Table(toml::Table::new());
+        let mut loaded = vec![];
+        for file in files {
+            let contents = match fs::read_to_string(file) {
+                Ok(contents) => contents,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => Err(err).with_context(|| {
+                    format!("Couldn't read configuration file '{}'", file.display())
+                })?,
+            };
+            let table: toml::Table = toml::from_str(&contents).with_context(|| {
+                format!("Couldn't load configuration file '{}'", file.display())
+            })?;
+            log::debug!("Loaded configuration file '{}'", file.display());
+            merge_values(&mut merged, toml::Value::Table(table));
+            loaded.push(format!("'{}'", file.display()));
+        }
+
+        let mut cfg: AppConfig = merged
+            .try_into()
+            .with_context(|| format!("Couldn't load configuration from {}", loaded.join(", ")))?;
+        cfg.path = path.into();
+        cfg.prefix = repo_prefix.into();
+        cfg.validate_rules()?;
+        cfg.validate_plugins()?;
+        cfg.smudge_file_mode()?;
+        cfg.comment()?;
+        cfg.compression()?;
+        cfg.allowed_recipient_types()?;
+        Ok(cfg)
+    }
+
+    /// Loads only the file at `path`, for changing and saving it
+    pub fn load_file(path: &Path, repo_prefix: &Path) -> Result<Self> {
         match fs::read_to_string(path) {
             Ok(contents) => {
-                let mut cfg: AppConfig = toml::from_str(&contents).with_context(|| {
-                    format!("Couldn't load configuration file '{}'", path.display())
-                })?;
+                let mut cfg: AppConfig = toml::from_str(&contents)
+                    .with_context(|| {
+                        format!("Couldn't load configuration file '{}'", path.display())
+                    })
+                    .map_err(Error::Load)?;
                 cfg.path = path.into();
                 cfg.prefix = repo_prefix.into();
+                cfg.validate_rules()
+                    .map_err(|err| Error::Load(err.into()))?;
                 Ok(cfg)
             }
             Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self {
-                passphrase: HashMap::new(),
+                passphrase: PassphraseConfig::default(),
+                passphrase_cache: PassphraseCacheConfig::default(),
+                identity: IdentityGetterConfig::default(),
+                identity_files: IdentityFilesConfig::default(),
                 aliases: HashMap::new(),
+                groups: HashMap::new(),
+                output: OutputConfig::default(),
+                input: InputConfig::default(),
+                recipients: RecipientsConfig::default(),
+                clean: CleanConfig::default(),
+                smudge: SmudgeConfig::default(),
+                remote_keys: RemoteKeysConfig::default(),
                 config: HashMap::new(),
+                recipients_file: HashMap::new(),
+                recipients_dir: HashMap::new(),
+                rules: vec![],
+                plugins: vec![],
+                identities: vec![],
                 path: path.into(),
                 prefix: repo_prefix.into(),
+                cache_dir: None,
+                current_branch: None,
+                overrides: EnvOverrides::default(),
             }),
             Err(err) => Ok(Err(err).with_context(|| {
                 format!("Couldn't read configuration file '{}'", path.display())
@@ -56,16 +554,126 @@ impl AppConfig {
         }
     }
 
+    fn validate_rules(&self) -> Result<()> {
+        for (idx, rule) in self.rules.iter().enumerate() {
+            if rule.recipients.is_empty()
+                && rule.recipients_file.is_none()
+                && rule.recipients_dir.is_none()
+            {
+                return Err(anyhow!(
+                    "Rule {} for '{}' has neither recipients nor a recipients_file or \
+                     recipients_dir",
+                    idx + 1,
+                    rule.path.display()
+                )
+                .into());
+            }
+            Pattern::new(&rule.path.to_string_lossy()).with_context(|| {
+                format!(
+                    "Rule {} has an invalid path pattern '{}'",
+                    idx + 1,
+                    rule.path.display()
+                )
+            })?;
+            if let Some(branch) = &rule.branch {
+                Pattern::new(branch).with_context(|| {
+                    format!(
+                        "Rule {} has an invalid branch pattern '{}'",
+                        idx + 1,
+                        branch
+                    )
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `rule` applies to `relpath` on the current branch
+    fn rule_matches(&self, rule: &Rule, relpath: &Path) -> bool {
+        if !matches(&rule.path, relpath) {
+            return false;
+        }
+        let Some(branch) = &rule.branch else {
+            return true;
+        };
+        // Nothing is checked out under a name on a detached HEAD
+        self.current_branch.as_deref().is_some_and(|current| {
+            Pattern::new(branch).is_ok_and(|pattern| pattern.matches(current))
+        })
+    }
+
+    fn validate_plugins(&self) -> Result<()> {
+        for (idx, plugin) in self.plugins.iter().enumerate() {
+            // The name becomes part of the binary's file name
+            let valid = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.');
+            if plugin.name.is_empty() || !plugin.name.chars().all(valid) {
+                return Err(anyhow!(
+                    "Plugin {} has an invalid name '{}', like 'yubikey' of age-plugin-yubikey",
+                    idx + 1,
+                    plugin.name
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Plugin binaries from `[[plugins]]`, with their paths resolved against the
+    /// repository root. Fails if a binary doesn't exist.
+    pub fn plugins(&self) -> Result<Vec<Plugin>> {
+        let mut plugins = vec![];
+        for (idx, plugin) in self.plugins.iter().enumerate() {
+            let key = format!("plugins[{}].path", idx + 1);
+            let path = self
+                .prefix
+                .join(env::expand(&plugin.path.to_string_lossy(), &key)?);
+            if !path.is_file() {
+                return Err(anyhow!(
+                    "Binary of plugin '{}' doesn't exist: {} (from {} in git-agecrypt.toml)",
+                    plugin.name,
+                    path.display(),
+                    key
+                )
+                .into());
+            }
+            plugins.push(Plugin {
+                name: plugin.name.clone(),
+                path,
+                args: plugin.args.clone(),
+            });
+        }
+        Ok(plugins)
+    }
+
+    /// Sets the directory where keys fetched from URLs are cached
+    pub fn with_cache_dir(mut self, cache_dir: PathBuf) -> Self {
+        self.cache_dir = Some(cache_dir);
+        self
+    }
+
+    /// Sets the checked out branch, `None` on a detached HEAD, for rules with a `branch`
+    pub fn with_branch(mut self, branch: Option<String>) -> Self {
+        self.current_branch = branch;
+        self
+    }
+
+    /// The repository's configuration file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
     pub fn save(&self) -> Result<()> {
         let cfg = toml::to_string_pretty(self).context("Coldn't format configuration as TOML")?;
-        fs::write(&self.path, cfg).with_context(|| {
+        atomic::write(&self.path, cfg.as_bytes()).with_context(|| {
             format!("Couldn't save configuration file '{}'", self.path.display())
         })?;
         Ok(())
     }
 
     pub fn add(&mut self, recipients: Vec<String>, paths: Vec<PathBuf>) -> Result<()> {
-        age::validate_public_keys(&recipients)?;
+        for recipient in &recipients {
+            self.validate_recipient(recipient)?;
+        }
         
         for path in paths {
             let path_str = path.to_string_lossy();
@@ -91,7 +699,7 @@ impl AppConfig {
     pub fn remove(&mut self, recipients: Vec<String>, paths: Vec<PathBuf>) -> Result<()> {
         if paths.is_empty() {
             for rs in self.config.values_mut() {
-                rs.retain(|r| !recipients.contains(r));
+                rs.retain(|r| !is_listed(r, &recipients));
             }
         } else {
             for path in paths {
@@ -101,7 +709,7 @@ impl AppConfig {
                 if recipients.is_empty() {
                     rs.clear();
                 } else {
-                    rs.retain(|r| !recipients.contains(r));
+                    rs.retain(|r| !is_listed(r, &recipients));
                 }
             }
         }
@@ -118,56 +726,1523 @@ impl AppConfig {
                 rv.push((p.to_string_lossy().to_string(), r.clone()));
             }
         }
+        for (p, f) in &self.recipients_file {
+            rv.push((
+                p.to_string_lossy().to_string(),
+                format!("recipients file {}", f.display()),
+            ));
+        }
+        for (p, d) in &self.recipients_dir {
+            rv.push((
+                p.to_string_lossy().to_string(),
+                format!("key files {}", d.display()),
+            ));
+        }
+        for (idx, rule) in self.rules.iter().enumerate() {
+            let p = match &rule.branch {
+                Some(branch) => {
+                    format!(
+                        "{} (rule {}, branch {})",
+                        rule.path.display(),
+                        idx + 1,
+                        branch
+                    )
+                }
+                None => format!("{} (rule {})", rule.path.display(), idx + 1),
+            };
+            for r in &rule.recipients {
+                rv.push((p.clone(), r.clone()));
+            }
+            if let Some(f) = &rule.recipients_file {
+                rv.push((p.clone(), format!("recipients file {}", f.display())));
+            }
+            if let Some(d) = &rule.recipients_dir {
+                rv.push((p, format!("key files {}", d.display())));
+            }
+        }
+        rv
+    }
+
+    /// Checks every configured recipient and recipients file without encrypting anything.
+    /// Returns `(entry, recipient, result)` for each, where like with
+    /// `age::validate_identity` the result may hold a note about what wasn't checked.
+    pub fn validate(&self) -> Vec<(String, String, Result<Option<String>>)> {
+        let mut rv = vec![];
+        let mut config: Vec<_> = self.config.iter().collect();
+        config.sort();
+        for (p, rs) in config {
+            let entry = format!("[config] {}", p.display());
+            for r in rs {
+                rv.push((entry.clone(), r.clone(), self.validate_recipient(r)));
+            }
+        }
+        let mut recipients_file: Vec<_> = self.recipients_file.iter().collect();
+        recipients_file.sort();
+        for (p, f) in recipients_file {
+            let key = format!("recipients_file.\"{}\"", p.display());
+            rv.push((
+                format!("[recipients_file] {}", p.display()),
+                format!("recipients file {}", f.display()),
+                self.validate_recipients_file(f, &key),
+            ));
+        }
+        let mut recipients_dir: Vec<_> = self.recipients_dir.iter().collect();
+        recipients_dir.sort();
+        for (p, d) in recipients_dir {
+            let key = format!("recipients_dir.\"{}\"", p.display());
+            rv.push((
+                format!("[recipients_dir] {}", p.display()),
+                format!("key files {}", d.display()),
+                self.validate_recipients_dir(d, &key),
+            ));
+        }
+        for (idx, rule) in self.rules.iter().enumerate() {
+            let entry = format!("rules[{}] {}", idx + 1, rule.path.display());
+            for r in &rule.recipients {
+                rv.push((entry.clone(), r.clone(), self.validate_recipient(r)));
+            }
+            if let Some(f) = &rule.recipients_file {
+                let key = format!("rules[{}].recipients_file", idx + 1);
+                rv.push((
+                    entry.clone(),
+                    format!("recipients file {}", f.display()),
+                    self.validate_recipients_file(f, &key),
+                ));
+            }
+            if let Some(d) = &rule.recipients_dir {
+                let key = format!("rules[{}].recipients_dir", idx + 1);
+                rv.push((
+                    entry.clone(),
+                    format!("key files {}", d.display()),
+                    self.validate_recipients_dir(d, &key),
+                ));
+            }
+        }
         rv
     }
 
-    pub fn get_passphrase_command(&self, key: &str) -> Option<&String> {
-        self.passphrase.get(key)
+    fn validate_recipient(&self, recipient: &str) -> Result<Option<String>> {
+        let mut notes = vec![];
+        for member in self.expand_groups(&[recipient.to_string()])? {
+            let resolved = self.resolve_recipient(&member);
+            let (key, _) = age::split_label(&resolved);
+            if remote::is_keys_url(key) {
+                notes.push(format!("keys URL {}, not fetched", key));
+            } else {
+                age::validate_public_keys(&[&resolved])?;
+                self.check_recipient_types(&[resolved])?;
+            }
+        }
+        Ok((!notes.is_empty()).then(|| notes.join("; ")))
+    }
+
+    fn validate_recipients_file(&self, file: &Path, key: &str) -> Result<Option<String>> {
+        let file = env::expand(&file.to_string_lossy(), key)?;
+        let recipients = age::read_recipients_file(self.prefix.join(file))?;
+        age::validate_public_keys(&recipients)?;
+        self.check_recipient_types(&recipients)?;
+        Ok(None)
+    }
+
+    /// Like [`Self::read_recipients_dir`], noting how many key files are skipped
+    fn validate_recipients_dir(&self, dir: &Path, key: &str) -> Result<Option<String>> {
+        let (recipients, skipped) = self.read_recipients_dir(dir, key)?;
+        self.check_recipient_types(&recipients)?;
+        Ok((skipped > 0).then(|| format!("{} malformed key files skipped", skipped)))
+    }
+
+    /// Recipients of the key files in `dir`, a directory or a glob pattern relative to
+    /// the repository root, and the number of files skipped. A key file which can't be
+    /// read or holds no valid recipient is skipped with a warning, or fails with
+    /// `recipients.strict`.
+    fn read_recipients_dir(&self, dir: &Path, key: &str) -> Result<(Vec<String>, usize)> {
+        let dir = env::expand(&dir.to_string_lossy(), key)?;
+        let mut recipients = vec![];
+        let mut skipped = 0;
+        for file in self.key_files(&dir, key)? {
+            match read_key_file(&file) {
+                Ok(keys) => recipients.extend(keys),
+                Err(err) if self.recipients.strict => {
+                    return Err(err.context(format!("Malformed key file in {}", key)).into())
+                }
+                Err(err) => {
+                    log::warn!("Skipping malformed key file of {}: {:#}", key, err);
+                    skipped += 1;
+                }
+            }
+        }
+        Ok((recipients, skipped))
+    }
+
+    /// The files in the directory `dir`, except hidden ones, or those matching the
+    /// glob pattern `dir`, sorted by path
+    fn key_files(&self, dir: &str, key: &str) -> Result<Vec<PathBuf>> {
+        let mut files = if dir.contains(['*', '?', '[']) {
+            let pattern = format!(
+                "{}/{}",
+                Pattern::escape(&self.prefix.to_string_lossy()),
+                dir
+            );
+            glob::glob(&pattern)
+                .with_context(|| format!("Invalid pattern '{}' in {}", dir, key))?
+                .filter_map(|path| path.ok())
+                .filter(|path| path.is_file())
+                .collect::<Vec<_>>()
+        } else {
+            let path = self.prefix.join(dir);
+            let entries = fs::read_dir(&path).with_context(|| {
+                format!(
+                    "Couldn't read the key directory '{}' of {}",
+                    path.display(),
+                    key
+                )
+            })?;
+            let mut files = vec![];
+            for entry in entries {
+                let path = entry.context("Couldn't list the key directory")?.path();
+                let hidden = path
+                    .file_name()
+                    .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+                if path.is_file() && !hidden {
+                    files.push(path);
+                }
+            }
+            files
+        };
+        files.sort();
+        Ok(files)
+    }
+
+    /// The identity from `GIT_AGECRYPT_IDENTITY` and the one of the identity getter,
+    /// then the ones of the configuration files, followed by the `configured` ones. Glob
+    /// patterns are expanded into the identity files they match.
+    pub fn merge_identities(&self, configured: Vec<String>) -> Result<Vec<String>> {
+        let mut identities: Vec<String> = self.overrides.identity.iter().cloned().collect();
+        identities.extend(age::getter_identity());
+        for identity in &self.identities {
+            if age::is_inline_identity(Path::new(identity)) {
+                identities.push(identity.clone());
+            } else {
+                identities.push(env::expand(identity, "identities")?);
+            }
+        }
+        identities.extend(configured);
+        let mut expanded = vec![];
+        for identity in identities {
+            expanded.extend(expand_glob(identity)?);
+        }
+        Ok(expanded)
+    }
+
+    /// Every recipient configured for any path, with aliases and groups resolved and
+    /// the recipients of recipients files and key directories read. Entries which can't
+    /// be resolved are left out, [`Self::validate`] reports them. `None` if keys URLs
+    /// are configured, which aren't fetched for this.
+    pub fn all_recipients(&self) -> Option<Vec<String>> {
+        let mut keys: Vec<&Vec<String>> = self.config.values().collect();
+        let mut files: Vec<(String, &PathBuf)> = self
+            .recipients_file
+            .iter()
+            .map(|(p, f)| (format!("recipients_file.\"{}\"", p.display()), f))
+            .collect();
+        let mut dirs: Vec<(String, &PathBuf)> = self
+            .recipients_dir
+            .iter()
+            .map(|(p, d)| (format!("recipients_dir.\"{}\"", p.display()), d))
+            .collect();
+        for (idx, rule) in self.rules.iter().enumerate() {
+            keys.push(&rule.recipients);
+            if let Some(f) = &rule.recipients_file {
+                files.push((format!("rules[{}].recipients_file", idx + 1), f));
+            }
+            if let Some(d) = &rule.recipients_dir {
+                dirs.push((format!("rules[{}].recipients_dir", idx + 1), d));
+            }
+        }
+
+        if let Some(recipients) = &self.overrides.recipients {
+            (keys, files, dirs) = (vec![recipients], vec![], vec![]);
+        }
+
+        let mut recipients = vec![];
+        for keys in keys {
+            for recipient in self.expand_groups(keys).unwrap_or_default() {
+                let recipient = self.resolve_recipient(&recipient);
+                if remote::is_keys_url(age::split_label(&recipient).0) {
+                    return None;
+                }
+                recipients.push(recipient);
+            }
+        }
+        for (key, file) in files {
+            let read = env::expand(&file.to_string_lossy(), &key)
+                .and_then(|file| Ok(age::read_recipients_file(self.prefix.join(file))?));
+            recipients.extend(read.unwrap_or_default());
+        }
+        for (key, dir) in dirs {
+            recipients.extend(self.read_recipients_dir(dir, &key).map_or(vec![], |r| r.0));
+        }
+        Some(recipients)
+    }
+
+    /// Identity files listed in the configuration files
+    pub fn identities(&self) -> &[String] {
+        &self.identities
+    }
+
+    /// Passphrase getter `key` of the [passphrase] section
+    pub fn get_passphrase_getter(&self, key: &str) -> Result<Option<Getter>> {
+        self.passphrase
+            .getters
+            .get(key)
+            .map(|getter| getter.expanded(&format!("passphrase.{}", key)))
+            .transpose()
     }
 
     pub fn has_passphrase_key(&self, key: &str) -> bool {
-        self.passphrase.contains_key(key)
+        self.passphrase.getters.contains_key(key)
+    }
+
+    /// Identity getter `key` of the [identity] section
+    pub fn get_identity_getter(&self, key: &str) -> Result<Option<Getter>> {
+        self.identity
+            .getters
+            .get(key)
+            .map(|getter| getter.expanded(&format!("identity.{}", key)))
+            .transpose()
+    }
+
+    pub fn has_identity_key(&self, key: &str) -> bool {
+        self.identity.getters.contains_key(key)
+    }
+
+    pub fn get_passphrase_shell(&self) -> Result<Option<String>> {
+        self.passphrase
+            .shell
+            .as_deref()
+            .map(|shell| env::expand(shell, "passphrase.shell"))
+            .transpose()
+    }
+
+    /// How long getter commands may run, `None` if they aren't limited
+    pub fn passphrase_timeout(&self) -> Option<Duration> {
+        (self.passphrase.timeout_secs > 0)
+            .then(|| Duration::from_secs(self.passphrase.timeout_secs))
     }
 
-    fn resolve_recipient(&self, key: &str) -> String {
-        self.aliases.get(key).cloned().unwrap_or_else(|| key.to_string())
+    /// How long passphrases obtained by getters are cached, `None` if disabled
+    pub fn passphrase_cache_ttl(&self) -> Option<Duration> {
+        (self.passphrase_cache.ttl_secs > 0)
+            .then(|| Duration::from_secs(self.passphrase_cache.ttl_secs))
     }
 
-    fn resolve_recipients(&self, keys: &[String]) -> Vec<String> {
-        keys.iter().map(|k| self.resolve_recipient(k)).collect()
+    /// Whether identity files holding a secret key in the clear are rejected
+    pub fn require_encrypted_identities(&self) -> bool {
+        self.identity_files.require_encrypted
     }
 
+    /// Whether encrypting files which the configured identities can't decrypt fails
+    pub fn require_self_decrypt(&self) -> bool {
+        self.clean.require_self_decrypt
+    }
+
+    /// Whether checking out files which can't be decrypted fails
+    pub fn require_decrypt(&self) -> bool {
+        self.smudge.require_decrypt
+    }
+
+    /// Permissions of decrypted files from `smudge.file_mode`, if configured
+    pub fn smudge_file_mode(&self) -> Result<Option<u32>> {
+        self.smudge
+            .file_mode
+            .as_deref()
+            .map(parse_file_mode)
+            .transpose()
+    }
+
+    /// The label for the header of encrypted files from `output.comment`, if configured
+    pub fn comment(&self) -> Result<Option<&str>> {
+        let Some(comment) = &self.output.comment else {
+            return Ok(None);
+        };
+        age::validate_comment(comment)?;
+        Ok(Some(comment))
+    }
+
+    /// The compression of the plaintext from `output.compress`, if configured
+    pub fn compression(&self) -> Result<Option<age::Compression>> {
+        let Some(compress) = &self.output.compress else {
+            return Ok(None);
+        };
+        Ok(Some(compress.parse().context("Invalid output.compress")?))
+    }
+
+    /// The key of `output.deterministic`, `None` unless it is enabled. The key is only
+    /// needed to encrypt, so that failing to get it is left to encryption.
+    pub fn deterministic_key(&self) -> Option<Result<String>> {
+        if !self.output.deterministic {
+            return None;
+        }
+        let Some(key) = &self.output.deterministic_key else {
+            return Some(Err(anyhow!("output.deterministic_key isn't set").into()));
+        };
+        Some(env::expand(key, "output.deterministic_key"))
+    }
+
+    /// Envelope of `input.decode` around the stored age files, if any
+    pub fn envelope(&self) -> Option<Envelope> {
+        self.input.decode
+    }
+
+    /// Size limit of the cache of decrypted contents, if it is enabled
+    pub fn smudge_cache_max_bytes(&self) -> Option<u64> {
+        self.smudge.cache.then_some(self.smudge.cache_max_bytes)
+    }
+
+    /// Types of recipients from `recipients.allowed_types`, `None` if all are allowed
+    pub fn allowed_recipient_types(&self) -> Result<Option<Vec<age::RecipientType>>> {
+        let Some(types) = &self.recipients.allowed_types else {
+            return Ok(None);
+        };
+        let parsed = types
+            .iter()
+            .map(|kind| kind.parse())
+            .collect::<anyhow::Result<_>>();
+        Ok(Some(parsed.context("Invalid recipients.allowed_types")?))
+    }
+
+    /// Fails if any of `public_keys` is of a type `recipients.allowed_types` excludes
+    pub fn check_recipient_types(&self, public_keys: &[String]) -> Result<()> {
+        if let Some(allowed) = self.allowed_recipient_types()? {
+            age::check_recipient_types(public_keys, &allowed)
+                .context("Rejected by recipients.allowed_types in git-agecrypt.toml")?;
+        }
+        Ok(())
+    }
+
+    pub fn encoding(&self) -> age::Encoding {
+        if self.output.armor {
+            age::Encoding::Armored
+        } else {
+            age::Encoding::Binary
+        }
+    }
+
+    /// Resolves an alias, keeping the label of `entry`, or else the one of the alias
+    fn resolve_recipient(&self, entry: &str) -> String {
+        let (name, label) = age::split_label(entry);
+        let resolved = self.aliases.get(name).map_or(name, String::as_str);
+        let (key, alias_label) = age::split_label(resolved);
+        with_label(key.to_string(), label.or(alias_label))
+    }
+
+    /// Names `recipient` for people: by its label, or else by the alias of its key, or
+    /// else by the key itself
+    pub fn recipient_name(&self, recipient: &str) -> String {
+        let (key, label) = age::split_label(recipient);
+        if let Some(label) = label {
+            return label.to_string();
+        }
+        let mut aliases: Vec<_> = self
+            .aliases
+            .iter()
+            .filter(|(_, aliased)| age::split_label(aliased).0 == key)
+            .map(|(name, _)| name.as_str())
+            .collect();
+        aliases.sort();
+        aliases
+            .first()
+            .map_or_else(|| key.to_string(), |name| name.to_string())
+    }
+
+    /// Expands groups, aliases and keys URLs among `keys`, without duplicates.
+    /// Labels are kept, keys fetched from a URL get the label of the URL.
+    pub fn resolve_recipients(&self, keys: &[String]) -> Result<Vec<String>> {
+        let mut resolved = vec![];
+        for recipient in self
+            .expand_groups(keys)?
+            .iter()
+            .map(|k| self.resolve_recipient(k))
+        {
+            let (key, label) = age::split_label(&recipient);
+            if remote::is_keys_url(key) {
+                let ttl = Duration::from_secs(self.remote_keys.ttl_secs);
+                let fetched = remote::fetch_keys(key, self.cache_dir.as_deref(), ttl)?;
+                resolved.extend(fetched.into_iter().map(|k| with_label(k, label)));
+            } else {
+                resolved.push(recipient);
+            }
+        }
+        let mut seen = HashSet::new();
+        resolved.retain(|key| seen.insert(key.clone()));
+        Ok(resolved)
+    }
+
+    /// Replaces `@group` entries of `keys` with the members of the group, transitively
+    fn expand_groups(&self, keys: &[String]) -> Result<Vec<String>> {
+        let mut expanded = vec![];
+        self.expand_groups_into(keys, &mut vec![], &mut expanded)?;
+        Ok(expanded)
+    }
+
+    /// `within` holds the groups being expanded, to detect cycles
+    fn expand_groups_into(
+        &self,
+        keys: &[String],
+        within: &mut Vec<String>,
+        expanded: &mut Vec<String>,
+    ) -> Result<()> {
+        for key in keys {
+            let Some(name) = age::split_label(key).0.strip_prefix('@') else {
+                expanded.push(key.clone());
+                continue;
+            };
+            let members = self
+                .groups
+                .get(name)
+                .ok_or_else(|| anyhow!("Recipient group '{}' isn't defined in [groups]", name))?;
+            if within.iter().any(|group| group == name) {
+                return Err(anyhow!(
+                    "Recipient groups form a cycle: @{} -> @{}",
+                    within.join(" -> @"),
+                    name
+                )
+                .into());
+            }
+            within.push(name.to_string());
+            self.expand_groups_into(members, within, expanded)?;
+            within.pop();
+        }
+        Ok(())
+    }
+
+    /// Names the rules and entries selecting recipients which match none of `files`,
+    /// e.g. because of a typo in their pattern
+    pub fn unmatched_entries(&self, files: &[PathBuf]) -> Vec<String> {
+        let relpaths: Vec<_> = files
+            .iter()
+            .filter_map(|file| file.strip_prefix(&self.prefix).ok())
+            .collect();
+        let unmatched = |pattern: &Path| !relpaths.iter().any(|relpath| matches(pattern, relpath));
+
+        let mut entries = vec![];
+        for (idx, rule) in self.rules.iter().enumerate() {
+            if unmatched(&rule.path) {
+                entries.push(format!("rules[{}] {}", idx + 1, rule.path.display()));
+            }
+        }
+        let mut sections: Vec<_> = (self.config.keys().map(|p| ("config", p)))
+            .chain(self.recipients_file.keys().map(|p| ("recipients_file", p)))
+            .chain(self.recipients_dir.keys().map(|p| ("recipients_dir", p)))
+            .filter(|(_, pattern)| unmatched(pattern))
+            .collect();
+        sections.sort();
+        for (section, pattern) in sections {
+            entries.push(format!("[{}] {}", section, pattern.display()));
+        }
+        entries
+    }
+
+    /// Which parts of `path` are encrypted, set by the first rule matching it
+    pub fn encryption_scope(&self, path: &Path) -> Result<EncryptionScope> {
+        let relpath = path.strip_prefix(&self.prefix).with_context(|| {
+            format!(
+                "Not a path inside git repository, path={path:?}, repo={:?}",
+                self.prefix
+            )
+        })?;
+        Ok(self
+            .rules
+            .iter()
+            .find(|rule| self.rule_matches(rule, relpath))
+            .map_or(EncryptionScope::File, |rule| rule.encrypt))
+    }
+
+    /// Recipients of `path`, of the types `recipients.allowed_types` allows
     pub fn get_public_keys(&self, path: &Path) -> Result<Vec<String>> {
+        let public_keys = self.find_public_keys(path)?;
+        self.check_recipient_types(&public_keys)?;
+        Ok(public_keys)
+    }
+
+    fn find_public_keys(&self, path: &Path) -> Result<Vec<String>> {
         let relpath = path.strip_prefix(&self.prefix).with_context(|| {
             format!(
                 "Not a path inside git repository, path={path:?}, repo={:?}",
                 self.prefix
             )
         })?;
-        
-        // Try exact match first
-        if let Some(keys) = self.config.get(relpath) {
-            return Ok(self.resolve_recipients(keys));
+
+        if let Some(recipients) = &self.overrides.recipients {
+            log::info!(
+                "Using recipients from {} for '{}'",
+                RECIPIENTS_ENV,
+                relpath.display()
+            );
+            return self.resolve_recipients(recipients);
         }
-        
-        // Try folder prefix or glob pattern matching
-        for (pattern, keys) in &self.config {
-            let pattern_str = pattern.to_string_lossy();
-            let relpath_str = relpath.to_string_lossy();
-            
-            // Check if pattern is a directory prefix (e.g., "protected/" matches "protected/secret.md")
-            if relpath.starts_with(pattern) {
-                return Ok(self.resolve_recipients(keys));
+
+        if let Some((idx, rule)) = self
+            .rules
+            .iter()
+            .enumerate()
+            .find(|(_, rule)| self.rule_matches(rule, relpath))
+        {
+            log::info!("Using rule {} for '{}'", idx + 1, relpath.display());
+            let mut public_keys = self.resolve_recipients(&rule.recipients)?;
+            if let Some(recipients_file) = &rule.recipients_file {
+                let key = format!("rules[{}].recipients_file", idx + 1);
+                let recipients_file = env::expand(&recipients_file.to_string_lossy(), &key)?;
+                public_keys.extend(age::read_recipients_file(
+                    self.prefix.join(recipients_file),
+                )?);
             }
-            
-            // Check glob pattern match (e.g., "protected/*" or "**/*.md")
-            if let Ok(glob_pattern) = Pattern::new(&pattern_str) {
-                if glob_pattern.matches(&relpath_str) {
-                    return Ok(self.resolve_recipients(keys));
+            if let Some(recipients_dir) = &rule.recipients_dir {
+                let key = format!("rules[{}].recipients_dir", idx + 1);
+                public_keys.extend(self.read_recipients_dir(recipients_dir, &key)?.0);
+            }
+            if public_keys.is_empty() {
+                return Err(no_recipients(path, &format!("rules[{}]", idx + 1)));
+            }
+            return Ok(public_keys);
+        }
+
+        let keys = find_entry_with_pattern(&self.config, relpath);
+        let recipients_file = find_entry_with_pattern(&self.recipients_file, relpath);
+        let recipients_dir = find_entry_with_pattern(&self.recipients_dir, relpath);
+        if keys.is_none() && recipients_file.is_none() && recipients_dir.is_none() {
+            return Err(anyhow!(
+                "No public key can be found for '{}', no rule or [config] entry matches it",
+                path.display()
+            )
+            .into());
+        }
+
+        let mut public_keys = match keys {
+            Some((pattern, keys)) => {
+                log::info!(
+                    "Using [config] entry '{}' for '{}'",
+                    pattern.display(),
+                    relpath.display()
+                );
+                self.resolve_recipients(keys)?
+            }
+            None => vec![],
+        };
+        if let Some((pattern, recipients_file)) = recipients_file {
+            log::info!(
+                "Using [recipients_file] entry '{}' for '{}'",
+                pattern.display(),
+                relpath.display()
+            );
+            let recipients_file = env::expand(
+                &recipients_file.to_string_lossy(),
+                &format!("recipients_file.\"{}\"", pattern.display()),
+            )?;
+            public_keys.extend(age::read_recipients_file(
+                self.prefix.join(recipients_file),
+            )?);
+        }
+        if let Some((pattern, recipients_dir)) = recipients_dir {
+            log::info!(
+                "Using [recipients_dir] entry '{}' for '{}'",
+                pattern.display(),
+                relpath.display()
+            );
+            let key = format!("recipients_dir.\"{}\"", pattern.display());
+            public_keys.extend(self.read_recipients_dir(recipients_dir, &key)?.0);
+        }
+        if public_keys.is_empty() {
+            return Err(no_recipients(
+                path,
+                "[config], [recipients_file] and [recipients_dir]",
+            ));
+        }
+        Ok(public_keys)
+    }
+}
+
+/// Parses octal permissions like "0600", "600" or "0o600"
+fn parse_file_mode(mode: &str) -> Result<u32> {
+    let digits = mode.strip_prefix("0o").unwrap_or(mode);
+    match u32::from_str_radix(digits, 8) {
+        Ok(parsed) if !digits.is_empty() && parsed <= 0o7777 => Ok(parsed),
+        _ => Err(anyhow!(
+            "Invalid smudge.file_mode '{}', expected octal permissions like \"0600\"",
+            mode
+        )
+        .into()),
+    }
+}
+
+/// The recipients of a key file, which may hold comments like a recipients file
+fn read_key_file(file: &Path) -> anyhow::Result<Vec<String>> {
+    let recipients = age::read_recipients_file(file)?;
+    if recipients.is_empty() {
+        anyhow::bail!("Key file {:?} holds no recipient", file);
+    }
+    Ok(recipients)
+}
+
+/// Encrypting to nobody would make `path` undecryptable, so matching an empty entry fails
+fn no_recipients(path: &Path, section: &str) -> super::Error {
+    anyhow!(
+        "No recipients configured for '{}', the matching entry in {} of git-agecrypt.toml is empty",
+        path.display(),
+        section
+    )
+    .into()
+}
+
+/// `$XDG_CONFIG_HOME/git-agecrypt/config.toml`, defaulting to `~/.config`
+fn user_config_file() -> Option<PathBuf> {
+    Some(user_config_dir()?.join("config.toml"))
+}
+
+/// The repository's configuration file: `explicit` or the one in `GIT_AGECRYPT_CONFIG`,
+/// relative to the current directory, otherwise the one at the root of `workdir`, which
+/// is the git directory of bare repositories
+pub(crate) fn repo_config_file(workdir: &Path, explicit: Option<&Path>) -> Result<PathBuf> {
+    let explicit = explicit.map(PathBuf::from).or_else(|| {
+        std::env::var_os(CONFIG_ENV)
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from)
+    });
+    match explicit {
+        Some(path) => {
+            log::debug!("Using configuration file '{}'", path.display());
+            let cwd = std::env::current_dir().context("Cannot determine current directory")?;
+            Ok(cwd.join(path))
+        }
+        None => Ok(workdir.join(CONFIG_FILE)),
+    }
+}
+
+/// Sections of the configuration file which decide the recipients of files
+const RECIPIENT_SECTIONS: &[&str] = &[
+    "aliases",
+    "groups",
+    "recipients",
+    "config",
+    "recipients_file",
+    "recipients_dir",
+    "rules",
+];
+
+/// Whether the recipient sections differ between the configuration files `old` and
+/// `new`, e.g. two versions of the repository's file. Formatting and comments don't
+/// matter.
+pub(crate) fn recipient_sections_changed(old: &str, new: &str) -> Result<bool> {
+    let sections = |contents: &str| -> Result<Vec<Option<toml::Value>>> {
+        let mut table: toml::Table =
+            toml::from_str(contents).context("Couldn't parse configuration file")?;
+        Ok(RECIPIENT_SECTIONS
+            .iter()
+            .map(|section| table.remove(*section))
+            .collect())
+    };
+    Ok(sections(old)? != sections(new)?)
+}
+
+/// Directory of the per-user configuration, `$XDG_CONFIG_HOME/git-agecrypt`
+pub(crate) fn user_config_dir() -> Option<PathBuf> {
+    let dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(dir.join("git-agecrypt"))
+}
+
+/// Merges `overlay` into `base`: tables key by key, arrays are concatenated with the
+/// entries of `overlay` first, so that e.g. its rules are checked first, and other
+/// values are replaced.
+fn merge_values(base: &mut toml::Value, overlay: toml::Value) {
+    match (base, overlay) {
+        (toml::Value::Table(base), toml::Value::Table(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_values(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
                 }
             }
         }
-        
-        Err(anyhow!("No public key can be found for '{}'", path.display()).into())
+        (toml::Value::Array(base), toml::Value::Array(mut overlay)) => {
+            overlay.append(base);
+            *base = overlay;
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// Looks up the entry of `entries` applying to `relpath`, with its pattern.
+/// Exact matches are preferred over directory prefixes and globs.
+fn find_entry_with_pattern<'a, V>(
+    entries: &'a HashMap<PathBuf, V>,
+    relpath: &Path,
+) -> Option<(&'a PathBuf, &'a V)> {
+    // Try exact match first
+    if let Some(entry) = entries.get_key_value(relpath) {
+        return Some(entry);
+    }
+
+    // Try folder prefix or glob pattern matching
+    entries
+        .iter()
+        .find(|(pattern, _)| matches(pattern, relpath))
+}
+
+/// Whether `pattern` is a directory prefix of `relpath` or a glob matching it.
+fn matches(pattern: &Path, relpath: &Path) -> bool {
+    // Check if pattern is a directory prefix (e.g., "protected/" matches "protected/secret.md")
+    if relpath.starts_with(pattern) {
+        return true;
+    }
+
+    // Check glob pattern match (e.g., "protected/*" or "**/*.md")
+    Pattern::new(&pattern.to_string_lossy())
+        .map(|glob_pattern| glob_pattern.matches(&relpath.to_string_lossy()))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_fs::{prelude::*, TempDir};
+    use rstest::rstest;
+
+    use super::*;
+
+    const OPS: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIBTqOL6QsCymbYvOXkxghyHqxYg8kPtoNAg84+05YHDs";
+    const DEVS: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIHJhPwqcFkDwPw6rlyRZT8xsJDoLT+RxAigl8LZ/uLi/";
+
+    fn load(dir: &TempDir, contents: &str) -> Result<AppConfig> {
+        let file = dir.child("git-agecrypt.toml");
+        file.write_str(contents).unwrap();
+        AppConfig::load_merged(&[file.to_path_buf()], file.path(), dir.path())
+    }
+
+    fn rules() -> String {
+        format!(
+            r#"
+            [config]
+            "legacy" = ["{DEVS}"]
+
+            [[rules]]
+            path = "infra/*.env"
+            recipients = ["{OPS}"]
+
+            [[rules]]
+            path = "infra/app.env"
+            recipients = ["{DEVS}"]
+
+            [[rules]]
+            path = "app/secrets/*"
+            recipients_file = "devs.recipients"
+            "#
+        )
+    }
+
+    #[rstest]
+    #[case::first_rule("infra/prod.env", OPS)]
+    #[case::first_match_wins("infra/app.env", OPS)]
+    #[case::recipients_file("app/secrets/token", DEVS)]
+    #[case::falls_back_to_config("legacy/key", DEVS)]
+    fn test_rules_select_recipients(#[case] path: &str, #[case] expected: &str) -> Result<()> {
+        let dir = TempDir::new().unwrap();
+        dir.child("devs.recipients")
+            .write_str(&format!("# developers\n{DEVS}\n"))
+            .unwrap();
+        let cfg = load(&dir, &rules())?;
+
+        assert_eq!(cfg.get_public_keys(&dir.path().join(path))?, [expected]);
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::release(Some("release/1.2"), OPS)]
+    #[case::feature(Some("feature/login"), DEVS)]
+    #[case::detached(None, DEVS)]
+    fn test_rules_by_branch(#[case] branch: Option<&str>, #[case] expected: &str) -> Result<()> {
+        let dir = TempDir::new().unwrap();
+        let contents = format!(
+            r#"
+            [[rules]]
+            path = "secrets/*"
+            branch = "release/*"
+            recipients = ["{OPS}"]
+
+            [[rules]]
+            path = "secrets/*"
+            recipients = ["{DEVS}"]
+            "#
+        );
+        let cfg = load(&dir, &contents)?.with_branch(branch.map(String::from));
+
+        assert_eq!(
+            cfg.get_public_keys(&dir.path().join("secrets/db"))?,
+            [expected]
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_recipient_name() -> Result<()> {
+        let dir = TempDir::new().unwrap();
+        let cfg = load(&dir, &format!("[aliases]\nops = \"{OPS}\"\n"))?;
+
+        assert_eq!(cfg.recipient_name(&format!("{DEVS} # bob")), "bob");
+        assert_eq!(cfg.recipient_name(OPS), "ops");
+        assert_eq!(cfg.recipient_name(DEVS), DEVS);
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_all_recipients() -> Result<()> {
+        let dir = TempDir::new().unwrap();
+        let age_key = "age19p9nfga0xjpqeljnhhxk3hwug0wve772svmvy45995u6kfephcvqj7wps7";
+        dir.child("devs.recipients")
+            .write_str(&format!("{age_key}\n"))
+            .unwrap();
+        let cfg = load(&dir, &rules())?;
+
+        let mut recipients = cfg.all_recipients().expect("no keys URL");
+        recipients.sort();
+
+        assert_eq!(recipients, [age_key, OPS, DEVS, DEVS]);
+        let remote = load(
+            &dir,
+            "[config]\n'secrets' = ['https://github.com/alice.keys']\n",
+        )?;
+        assert_eq!(remote.all_recipients(), None);
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_no_matching_rule() -> Result<()> {
+        let dir = TempDir::new().unwrap();
+        let cfg = load(&dir, &rules())?;
+
+        let err = cfg
+            .get_public_keys(&dir.path().join("app/config.toml"))
+            .unwrap_err();
+
+        assert!(err.to_string().contains("app/config.toml"), "{}", err);
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_unmatched_entries() -> Result<()> {
+        let dir = TempDir::new().unwrap();
+        let cfg = load(&dir, &rules())?;
+        let files = [
+            dir.path().join("infra/prod.env"),
+            dir.path().join("legacy/key"),
+        ];
+
+        assert_eq!(
+            cfg.unmatched_entries(&files),
+            ["rules[2] infra/app.env", "rules[3] app/secrets/*"]
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_repo_config_file() -> Result<()> {
+        let dir = TempDir::new().unwrap();
+        let cwd = std::env::current_dir().unwrap();
+        let explicit = dir.path().join("config/agecrypt.toml");
+
+        assert_eq!(
+            repo_config_file(dir.path(), None)?,
+            dir.path().join("git-agecrypt.toml")
+        );
+        assert_eq!(repo_config_file(dir.path(), Some(&explicit))?, explicit);
+        assert_eq!(
+            repo_config_file(dir.path(), Some(Path::new("ci/agecrypt.toml")))?,
+            cwd.join("ci/agecrypt.toml")
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::marked_lines("infra/prod.env", EncryptionScope::MarkedLines)]
+    #[case::first_match_wins("infra/app.env", EncryptionScope::MarkedLines)]
+    #[case::default("app/secrets/token", EncryptionScope::File)]
+    #[case::no_rule("legacy/key", EncryptionScope::File)]
+    fn test_encryption_scope(#[case] path: &str, #[case] expected: EncryptionScope) -> Result<()> {
+        let dir = TempDir::new().unwrap();
+        let contents = rules().replacen(
+            "path = \"infra/*.env\"",
+            "path = \"infra/*.env\"\n            encrypt = \"marked-lines\"",
+            1,
+        );
+        let cfg = load(&dir, &contents)?;
+
+        assert_eq!(cfg.encryption_scope(&dir.path().join(path))?, expected);
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::without_recipients("[[rules]]\npath = \"infra/*\"\n")]
+    #[case::invalid_pattern("[[rules]]\npath = \"infra/[\"\nrecipients = [\"x\"]\n")]
+    fn test_invalid_rules_are_rejected(#[case] contents: &str) {
+        let dir = TempDir::new().unwrap();
+        let err = load(&dir, contents).err().expect("rule should be rejected");
+        assert!(err.to_string().contains("Rule 1"), "{}", err);
+    }
+
+    #[rstest]
+    fn test_validate() -> Result<()> {
+        let dir = TempDir::new().unwrap();
+        dir.child("broken.recipients")
+            .write_str(&format!("{OPS}\nnot-a-key\n"))
+            .unwrap();
+        let cfg = load(
+            &dir,
+            &format!(
+                r#"
+                [aliases]
+                alice = "https://github.com/alice.keys"
+
+                [config]
+                "secrets" = ["{OPS}", "alice", "age1invalid"]
+
+                [recipients_file]
+                "infra" = "broken.recipients"
+                "#
+            ),
+        )?;
+
+        let results: Vec<_> = cfg
+            .validate()
+            .into_iter()
+            .map(|(entry, recipient, result)| (entry, recipient, result.map_err(|e| e.to_string())))
+            .collect();
+
+        assert_eq!(results.len(), 4);
+        assert_eq!(results[0].1, OPS);
+        assert_eq!(results[0].2, Ok(None));
+        assert_eq!(results[1].1, "alice");
+        assert!(matches!(&results[1].2, Ok(Some(note)) if note.contains("not fetched")));
+        assert!(results[2].2.is_err());
+        assert_eq!(results[3].0, "[recipients_file] infra");
+        assert!(results[3]
+            .2
+            .as_ref()
+            .unwrap_err()
+            .contains("broken.recipients:2"));
+        Ok(())
+    }
+
+    fn with_env(mut cfg: AppConfig, vars: &[(&str, &str)]) -> AppConfig {
+        cfg.overrides = EnvOverrides::from_lookup(|name| {
+            vars.iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, v)| v.to_string())
+        });
+        cfg
+    }
+
+    #[rstest]
+    #[case::matching_rule("infra/prod.env")]
+    #[case::matching_nothing("app/config.toml")]
+    fn test_env_recipients_override_config(#[case] path: &str) -> Result<()> {
+        let dir = TempDir::new().unwrap();
+        let cfg = with_env(
+            load(&dir, &rules())?,
+            &[(RECIPIENTS_ENV, &format!(" {DEVS}, ,{OPS}"))],
+        );
+
+        assert_eq!(cfg.get_public_keys(&dir.path().join(path))?, [DEVS, OPS]);
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::unset(&[], &["configured.txt"])]
+    #[case::set(&[(IDENTITY_ENV, "/run/secrets/age.txt")], &["/run/secrets/age.txt", "configured.txt"])]
+    #[case::empty(&[(IDENTITY_ENV, " ")], &["configured.txt"])]
+    fn test_env_identity_is_merged_first(
+        #[case] vars: &[(&str, &str)],
+        #[case] expected: &[&str],
+    ) -> Result<()> {
+        let dir = TempDir::new().unwrap();
+        let cfg = with_env(load(&dir, "")?, vars);
+
+        assert_eq!(
+            cfg.merge_identities(vec!["configured.txt".to_string()])?,
+            expected
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_identity_globs_are_expanded() -> Result<()> {
+        let dir = TempDir::new().unwrap();
+        for name in ["b.key", "a.key", "c.txt", "sub/d.key"] {
+            dir.child("identities").child(name).touch().unwrap();
+        }
+        let identities = dir.child("identities");
+        let cfg = load(
+            &dir,
+            &format!("identities = ['{}/*.key']\n", identities.path().display()),
+        )?;
+        let missing = format!("{}/*.missing", identities.path().display());
+
+        assert_eq!(
+            cfg.merge_identities(vec![missing, "configured.txt".to_string()])?,
+            [
+                identities
+                    .child("a.key")
+                    .path()
+                    .to_string_lossy()
+                    .to_string(),
+                identities
+                    .child("b.key")
+                    .path()
+                    .to_string_lossy()
+                    .to_string(),
+                "configured.txt".to_string(),
+            ]
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_config_files_are_merged() -> Result<()> {
+        let dir = TempDir::new().unwrap();
+        let user = dir.child("user.toml");
+        user.write_str("identities = [\"user.txt\"]\n").unwrap();
+        let shared = dir.child("git-agecrypt.toml");
+        shared
+            .write_str(&format!(
+                r#"
+                identities = ["shared.txt"]
+
+                [passphrase]
+                sops = "sops -d shared.yaml"
+                pass = "pass show age"
+
+                [config]
+                "secrets" = ["{OPS}"]
+                "#
+            ))
+            .unwrap();
+        let local = dir.child(LOCAL_CONFIG_FILE);
+        local
+            .write_str(&format!(
+                r#"
+                identities = ["local.txt"]
+
+                [passphrase]
+                sops = "sops -d local.yaml"
+
+                [identity]
+                sops = "sops -d --extract '[\"age\"]' local.yaml"
+
+                [config]
+                "secrets" = ["{DEVS}"]
+                "#
+            ))
+            .unwrap();
+
+        let files = [
+            user.to_path_buf(),
+            shared.to_path_buf(),
+            local.to_path_buf(),
+        ];
+        let cfg = AppConfig::load_merged(&files, shared.path(), dir.path())?;
+
+        assert_eq!(
+            cfg.merge_identities(vec![])?,
+            ["local.txt", "shared.txt", "user.txt"]
+        );
+        let command = |command: &str| Some(Getter::Command(command.to_string()));
+        assert_eq!(
+            cfg.get_passphrase_getter("sops")?,
+            command("sops -d local.yaml")
+        );
+        assert_eq!(cfg.get_passphrase_getter("pass")?, command("pass show age"));
+        assert_eq!(
+            cfg.get_identity_getter("sops")?,
+            command(r#"sops -d --extract '["age"]' local.yaml"#)
+        );
+        assert!(!cfg.has_identity_key("pass"));
+        assert_eq!(
+            cfg.get_public_keys(&dir.path().join("secrets/db"))?,
+            [DEVS, OPS]
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_recipient_sections_changed() -> Result<()> {
+        let old = format!(
+            r#"
+            [output]
+            armor = true
+
+            [config]
+            "secrets" = ["{OPS}"]
+            "#
+        );
+        let reformatted = format!(
+            r#"
+            [config]
+            # the team
+            secrets = [ "{OPS}" ]
+            [output]
+            armor = true
+            "#
+        );
+        let other_output = format!("[config]\n\"secrets\" = [\"{OPS}\"]\n");
+        let added = format!("[config]\n\"secrets\" = [\"{OPS}\", \"{DEVS}\"]\n");
+
+        assert!(!recipient_sections_changed(&old, &reformatted)?);
+        assert!(!recipient_sections_changed(&old, &other_output)?);
+        assert!(recipient_sections_changed(&old, &added)?);
+        assert!(recipient_sections_changed("", &old)?);
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_missing_config_files_are_skipped() -> Result<()> {
+        let dir = TempDir::new().unwrap();
+        let files = [dir.path().join("git-agecrypt.toml")];
+
+        let cfg = AppConfig::load_merged(&files, &files[0], dir.path())?;
+
+        assert!(cfg.list().is_empty());
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::config("[config]\n\"secrets\" = []\n", "[config]")]
+    #[case::recipients_file("[recipients_file]\n\"secrets\" = \"empty.recipients\"\n", "[config]")]
+    #[case::rule(
+        "[[rules]]\npath = \"secrets/*\"\nrecipients_file = \"empty.recipients\"\n",
+        "rules[1]"
+    )]
+    fn test_empty_recipients_are_rejected(
+        #[case] contents: &str,
+        #[case] section: &str,
+    ) -> Result<()> {
+        let dir = TempDir::new().unwrap();
+        dir.child("empty.recipients")
+            .write_str("# nobody yet\n")
+            .unwrap();
+        let cfg = load(&dir, contents)?;
+
+        let err = cfg
+            .get_public_keys(&dir.path().join("secrets/db"))
+            .unwrap_err();
+
+        let err = err.to_string();
+        assert!(err.contains("No recipients configured"), "{}", err);
+        assert!(err.contains(section), "{}", err);
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::dir("keys/")]
+    #[case::glob("keys/*.pub")]
+    fn test_recipients_dir(#[case] keys: &str) -> Result<()> {
+        let dir = TempDir::new().unwrap();
+        dir.child("keys/ops.pub")
+            .write_str(&format!("{OPS}\n"))
+            .unwrap();
+        dir.child("keys/dev.pub")
+            .write_str(&format!("# bob\n{DEVS}\n"))
+            .unwrap();
+        dir.child("keys/broken.pub")
+            .write_str("not a key\n")
+            .unwrap();
+        dir.child("keys/.hidden").write_str("not a key\n").unwrap();
+        let contents = format!("[recipients_dir]\n\"secrets\" = \"{keys}\"\n");
+        let cfg = load(&dir, &contents)?;
+
+        let public_keys = cfg.get_public_keys(&dir.path().join("secrets/db"))?;
+
+        assert_eq!(public_keys, [DEVS, OPS]);
+        let strict = load(&dir, &format!("[recipients]\nstrict = true\n{contents}"))?;
+        let err = strict
+            .get_public_keys(&dir.path().join("secrets/db"))
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("broken.pub"), "{:#}", err);
+        Ok(())
+    }
+
+    fn groups() -> String {
+        format!(
+            r#"
+            [aliases]
+            bob = "{DEVS}"
+
+            [groups]
+            ops = ["{OPS}"]
+            developers = ["bob", "@ops"]
+            everyone = ["@developers", "@ops", "{OPS}"]
+            loop-a = ["@loop-b"]
+            loop-b = ["{OPS}", "@loop-a"]
+
+            [config]
+            "everyone" = ["@everyone"]
+            "loop" = ["@loop-a"]
+            "typo" = ["@devs"]
+            "#
+        )
+    }
+
+    #[rstest]
+    fn test_groups_are_expanded_and_deduplicated() -> Result<()> {
+        let dir = TempDir::new().unwrap();
+        let cfg = load(&dir, &groups())?;
+
+        let public_keys = cfg.get_public_keys(&dir.path().join("everyone/file"))?;
+
+        assert_eq!(public_keys, [DEVS, OPS]);
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_plugins() -> Result<()> {
+        let dir = TempDir::new().unwrap();
+        dir.child("tools/kms-shim")
+            .write_str("#!/bin/sh\n")
+            .unwrap();
+        let contents = |path: &str| {
+            format!(
+                r#"
+                [[plugins]]
+                name = "kms"
+                path = "{path}"
+                args = ["--region", "eu-west-1"]
+                "#
+            )
+        };
+
+        let cfg = load(&dir, &contents("tools/kms-shim"))?;
+        assert_eq!(
+            cfg.plugins()?,
+            [Plugin {
+                name: "kms".into(),
+                path: dir.path().join("tools/kms-shim"),
+                args: vec!["--region".into(), "eu-west-1".into()],
+            }]
+        );
+
+        let cfg = load(&dir, &contents("tools/age-plugin-kms"))?;
+        let err = cfg.plugins().unwrap_err().to_string();
+        assert!(
+            err.contains("Binary of plugin 'kms' doesn't exist"),
+            "{}",
+            err
+        );
+
+        let err = load(&dir, "[[plugins]]\nname = \"../kms\"\npath = \"kms\"").unwrap_err();
+        assert!(err.to_string().contains("invalid name '../kms'"), "{}", err);
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_allowed_recipient_types() -> Result<()> {
+        let dir = TempDir::new().unwrap();
+        let contents = |allowed: &str| {
+            format!(
+                r#"
+                [recipients]
+                allowed_types = {allowed}
+
+                [config]
+                "secrets" = ["{OPS}"]
+                "#
+            )
+        };
+        let file = dir.path().join("secrets/db");
+
+        let cfg = load(&dir, &contents(r#"["x25519", "ssh-ed25519"]"#))?;
+        assert_eq!(cfg.get_public_keys(&file)?, [OPS]);
+
+        let cfg = load(&dir, &contents(r#"["x25519"]"#))?;
+        let err = format!("{:#}", cfg.get_public_keys(&file).unwrap_err());
+        assert!(err.contains("recipients.allowed_types"), "{}", err);
+        assert!(err.contains("is of type ssh-ed25519"), "{}", err);
+        let (_, _, validated) = cfg.validate().remove(0);
+        assert!(validated.is_err());
+
+        assert!(load(&dir, &contents(r#"["ed25519"]"#)).is_err());
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::default("", false)]
+    #[case::required("[identity_files]\nrequire_encrypted = true", true)]
+    #[case::with_list(
+        "identities = ['key.age']\n[identity_files]\nrequire_encrypted = true",
+        true
+    )]
+    fn test_require_encrypted_identities(
+        #[case] contents: &str,
+        #[case] expected: bool,
+    ) -> Result<()> {
+        let dir = TempDir::new().unwrap();
+        assert_eq!(
+            load(&dir, contents)?.require_encrypted_identities(),
+            expected
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_deterministic_key() -> Result<()> {
+        let dir = TempDir::new().unwrap();
+
+        let cfg = load(&dir, "[output]\ndeterministic_key = \"unused\"")?;
+        assert!(cfg.deterministic_key().is_none());
+
+        let cfg = load(
+            &dir,
+            "[output]\ndeterministic = true\ndeterministic_key = \"$$ecret\"",
+        )?;
+        assert_eq!(
+            cfg.deterministic_key().transpose()?.as_deref(),
+            Some("$ecret")
+        );
+
+        let cfg = load(&dir, "[output]\ndeterministic = true")?;
+        let err = cfg.deterministic_key().unwrap().unwrap_err();
+        assert!(err.to_string().contains("deterministic_key"), "{}", err);
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_compression() -> Result<()> {
+        let dir = TempDir::new().unwrap();
+
+        assert_eq!(load(&dir, "")?.compression()?, None);
+        let cfg = load(&dir, "[output]\ncompress = \"zstd\"")?;
+        assert_eq!(cfg.compression()?, Some(age::Compression::Zstd));
+
+        let err = format!(
+            "{:#}",
+            load(&dir, "[output]\ncompress = \"lz4\"").err().unwrap()
+        );
+        assert!(err.contains("Invalid output.compress"), "{}", err);
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_getter_providers() -> Result<()> {
+        let dir = TempDir::new().unwrap();
+        let cfg = load(
+            &dir,
+            r#"
+            [passphrase]
+            timeout_secs = 10
+            pass = "pass show age"
+            ci = { provider = "env", var = "AGE_KEY_PASSPHRASE" }
+            vault = { provider = "env-json", var = "VAULT_SECRETS", field = "age" }
+
+            [identity]
+            k8s = { provider = "file", path = "/run/secrets/age-identity" }
+            "#,
+        )?;
+
+        assert_eq!(
+            cfg.get_passphrase_getter("pass")?,
+            Some(Getter::Command("pass show age".to_string()))
+        );
+        let provider = |provider| Some(Getter::Provider(provider));
+        let var = "AGE_KEY_PASSPHRASE".to_string();
+        assert_eq!(
+            cfg.get_passphrase_getter("ci")?,
+            provider(GetterProvider::Env { var })
+        );
+        assert_eq!(
+            cfg.get_passphrase_getter("vault")?,
+            provider(GetterProvider::EnvJson {
+                var: "VAULT_SECRETS".to_string(),
+                field: "age".to_string(),
+            })
+        );
+        let path = "/run/secrets/age-identity".to_string();
+        assert_eq!(
+            cfg.get_identity_getter("k8s")?,
+            provider(GetterProvider::File { path })
+        );
+        assert_eq!(cfg.passphrase_timeout(), Some(Duration::from_secs(10)));
+        let unknown = load(&dir, "[passphrase]\nci = { provider = \"vault\" }");
+        let err = format!("{:#}", unknown.err().unwrap());
+        assert!(err.contains("unknown getter provider 'vault', known are env, file, env-json"));
+        assert!(load(&dir, "[passphrase]\nci = { provider = \"env\" }").is_err());
+        assert!(load(&dir, "[passphrase]\nci = 42").is_err());
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::leading_zero("0600", Some(0o600))]
+    #[case::without_zero("640", Some(0o640))]
+    #[case::rust_style("0o600", Some(0o600))]
+    #[case::not_octal("0800", None)]
+    #[case::symbolic("u=rw", None)]
+    #[case::too_large("0100000", None)]
+    fn test_parse_file_mode(#[case] mode: &str, #[case] expected: Option<u32>) {
+        assert_eq!(parse_file_mode(mode).ok(), expected);
+    }
+
+    #[rstest]
+    fn test_recipient_labels() -> Result<()> {
+        let dir = TempDir::new().unwrap();
+        let mut cfg = load(
+            &dir,
+            &format!(
+                r#"
+                [aliases]
+                bob = "{DEVS} # bob"
+
+                [config]
+                "secrets" = ["{OPS} # ops team", {{ key = "bob", label = "bob's laptop" }}, "bob"]
+                "typo" = [{{ key = "age1typo", label = "carol" }}]
+                "#
+            ),
+        )?;
+
+        assert_eq!(
+            cfg.get_public_keys(&dir.path().join("secrets/db"))?,
+            [
+                format!("{OPS} # ops team"),
+                format!("{DEVS} # bob's laptop"),
+                format!("{DEVS} # bob"),
+            ]
+        );
+        let results = cfg.validate();
+        assert_eq!(results.len(), 4);
+        assert!(results[..3]
+            .iter()
+            .all(|(_, _, result)| matches!(result, Ok(None))));
+        assert_eq!(results[3].1, "age1typo # carol");
+        assert!(results[3].2.is_err());
+
+        cfg.remove(vec![OPS.to_string()], vec![])?;
+        assert_eq!(
+            cfg.get_public_keys(&dir.path().join("secrets/db"))?.len(),
+            2
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::undefined("typo/file", "Recipient group 'devs' isn't defined")]
+    #[case::cycle("loop/file", "@loop-a -> @loop-b -> @loop-a")]
+    fn test_invalid_groups(#[case] path: &str, #[case] message: &str) -> Result<()> {
+        let dir = TempDir::new().unwrap();
+        let cfg = load(&dir, &groups())?;
+
+        let err = cfg.get_public_keys(&dir.path().join(path)).unwrap_err();
+
+        assert!(err.to_string().contains(message), "{}", err);
+        Ok(())
     }
 }
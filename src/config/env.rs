@@ -0,0 +1,101 @@
+use std::env;
+
+use anyhow::anyhow;
+
+use super::Result;
+
+/// Expands `$VAR`, `${VAR}` and a leading `~` in the configuration value of `key`
+/// using the process environment. `$$` yields a literal `$`.
+pub(crate) fn expand(value: &str, key: &str) -> Result<String> {
+    expand_with(value, key, |name| env::var(name).ok())
+}
+
+fn expand_with(value: &str, key: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String> {
+    let var = |name: &str| {
+        lookup(name)
+            .ok_or_else(|| anyhow!("Environment variable {} used in '{}' is not set", name, key))
+    };
+
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+    if rest == "~" || rest.starts_with("~/") {
+        expanded.push_str(&var(HOME)?);
+        rest = &rest[1..];
+    }
+
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        if let Some(after) = after.strip_prefix('$') {
+            expanded.push('$');
+            rest = after;
+        } else if let Some(braced) = after.strip_prefix('{') {
+            let end = braced
+                .find('}')
+                .ok_or_else(|| anyhow!("Unterminated variable '${{{}' in '{}'", braced, key))?;
+            expanded.push_str(&var(&braced[..end])?);
+            rest = &braced[end + 1..];
+        } else {
+            let len = after
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(after.len());
+            if len == 0 || after.starts_with(|c: char| c.is_ascii_digit()) {
+                // Not a variable name, e.g. `$(...)` in commands
+                expanded.push('$');
+                rest = after;
+            } else {
+                expanded.push_str(&var(&after[..len])?);
+                rest = &after[len..];
+            }
+        }
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+#[cfg(windows)]
+const HOME: &str = "USERPROFILE";
+#[cfg(not(windows))]
+const HOME: &str = "HOME";
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "HOME" | "USERPROFILE" => Some("/home/alice".to_string()),
+            "KEYS" => Some("keys".to_string()),
+            "TEAM_1" => Some("ops".to_string()),
+            _ => None,
+        }
+    }
+
+    #[rstest]
+    #[case::plain("keys/team.recipients", "keys/team.recipients")]
+    #[case::bare("$KEYS/team.recipients", "keys/team.recipients")]
+    #[case::braced("${KEYS}/${TEAM_1}.recipients", "keys/ops.recipients")]
+    #[case::name_ends_at_punctuation("$KEYS.d/$TEAM_1", "keys.d/ops")]
+    #[case::home("~/.config/age/key.txt", "/home/alice/.config/age/key.txt")]
+    #[case::only_home("~", "/home/alice")]
+    #[case::tilde_elsewhere("keys/~backup", "keys/~backup")]
+    #[case::escaped("echo $$KEYS costs $$5", "echo $KEYS costs $5")]
+    #[case::not_a_variable("pass show $(whoami) $1", "pass show $(whoami) $1")]
+    fn test_expand(#[case] value: &str, #[case] expected: &str) -> Result<()> {
+        assert_eq!(expand_with(value, "test", lookup)?, expected);
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::bare("$MISSING/key.txt", "MISSING")]
+    #[case::braced("${MISSING}", "MISSING")]
+    #[case::unterminated("${KEYS", "Unterminated")]
+    fn test_expand_errors(#[case] value: &str, #[case] message: &str) {
+        let err = expand_with(value, "passphrase.linux", lookup).unwrap_err();
+        let err = err.to_string();
+        assert!(err.contains(message), "{}", err);
+        assert!(err.contains("passphrase.linux"), "{}", err);
+    }
+}
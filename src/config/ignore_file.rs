@@ -0,0 +1,96 @@
+//! `.gitagecryptignore`: files which the clean filter passes through unencrypted, even
+//! though `.gitattributes` applies it to them, e.g. public examples next to secrets.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+use super::Result;
+
+/// Name of the ignore file, at the root of the repository. It uses gitignore syntax.
+pub(crate) const IGNORE_FILE: &str = ".gitagecryptignore";
+
+/// Patterns of the ignore file of a repository
+pub(crate) struct IgnoreFile {
+    root: PathBuf,
+    patterns: Gitignore,
+}
+
+impl IgnoreFile {
+    /// Loads the ignore file of the repository at `workdir`, which ignores nothing if
+    /// it doesn't exist
+    pub(crate) fn load(workdir: &Path) -> Result<Self> {
+        let path = workdir.join(IGNORE_FILE);
+        let patterns = if path.is_file() {
+            let mut builder = GitignoreBuilder::new(workdir);
+            // Errors are returned instead of the builder
+            builder
+                .add(&path)
+                .map_or(Ok(()), Err)
+                .with_context(|| format!("Couldn't read {}", path.display()))?;
+            builder
+                .build()
+                .with_context(|| format!("Invalid pattern in {}", path.display()))?
+        } else {
+            Gitignore::empty()
+        };
+        Ok(Self {
+            root: workdir.to_path_buf(),
+            patterns,
+        })
+    }
+
+    /// Whether `path`, absolute or relative to the repository root, is ignored, also
+    /// when one of its directories is. Paths outside of the repository never are.
+    pub(crate) fn is_ignored(&self, path: &Path) -> bool {
+        let relpath = if path.is_absolute() {
+            match path.strip_prefix(&self.root) {
+                Ok(relpath) => relpath,
+                Err(_) => return false,
+            }
+        } else {
+            path
+        };
+        self.patterns
+            .matched_path_or_any_parents(relpath, false)
+            .is_ignore()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_fs::{prelude::*, TempDir};
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case::pattern("config/example.yaml", true)]
+    #[case::directory("examples/public/app.yaml", true)]
+    #[case::negated("config/secret.example.yaml", false)]
+    #[case::not_listed("config/prod.yaml", false)]
+    #[case::anchored("nested/examples/app.yaml", false)]
+    fn test_is_ignored(#[case] path: &str, #[case] ignored: bool) -> anyhow::Result<()> {
+        let dir = TempDir::new()?;
+        dir.child(IGNORE_FILE)
+            .write_str("# public examples\n*example.yaml\n!secret.example.yaml\n/examples/\n")?;
+
+        let ignore_file = IgnoreFile::load(dir.path())?;
+
+        assert_eq!(ignore_file.is_ignored(Path::new(path)), ignored);
+        assert_eq!(ignore_file.is_ignored(&dir.path().join(path)), ignored);
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_missing_ignore_file_ignores_nothing() -> anyhow::Result<()> {
+        let dir = TempDir::new()?;
+
+        let ignore_file = IgnoreFile::load(dir.path())?;
+
+        assert!(!ignore_file.is_ignored(Path::new("config/example.yaml")));
+        assert!(!ignore_file.is_ignored(Path::new("/elsewhere/example.yaml")));
+        Ok(())
+    }
+}
@@ -1,10 +1,17 @@
 mod age_identities;
 mod app;
+mod env;
 mod git;
+mod ignore_file;
+mod remote;
 
 pub(crate) use age_identities::{AgeIdentities, AgeIdentity};
-pub(crate) use app::AppConfig;
+pub(crate) use app::{
+    recipient_sections_changed, repo_config_file, user_config_dir, AppConfig, EncryptionScope,
+    Envelope, Getter, GetterProvider, Plugin,
+};
 pub(crate) use git::GitConfig;
+pub(crate) use ignore_file::{IgnoreFile, IGNORE_FILE};
 
 use thiserror::Error;
 
@@ -14,6 +21,12 @@ pub enum Error {
     AlreadyExists(String),
     #[error("{:?} doesn't exist.", .0)]
     NotExist(String),
+    /// The configuration files can't be loaded, e.g. they aren't valid TOML or have
+    /// invalid values
+    #[error(transparent)]
+    Load(anyhow::Error),
+    #[error(transparent)]
+    Age(#[from] crate::age::AgeError),
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
@@ -0,0 +1,151 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+/// Whether a recipient entry refers to published SSH keys, e.g. `https://github.com/alice.keys`
+pub(crate) fn is_keys_url(entry: &str) -> bool {
+    entry.starts_with("https://")
+}
+
+/// Fetches the SSH keys published at `url`.
+/// Results are cached in `cache_dir` and reused while younger than `ttl`. When the
+/// cache is stale and fetching fails, the stale copy is used so that offline
+/// operation keeps working after the first successful fetch.
+pub(crate) fn fetch_keys(
+    url: &str,
+    cache_dir: Option<&Path>,
+    ttl: Duration,
+) -> Result<Vec<String>> {
+    let cache_file = cache_dir.map(|dir| cache_path(dir, url));
+
+    if let Some(cache_file) = &cache_file {
+        if is_fresh(cache_file, ttl) {
+            log::debug!("Using cached keys; url={}, cache={:?}", url, cache_file);
+            return read_cache(cache_file);
+        }
+    }
+
+    match download(url) {
+        Ok(contents) => {
+            let keys = parse_keys(&contents, url);
+            if let Some(cache_file) = &cache_file {
+                store_cache(cache_file, &keys)
+                    .with_context(|| format!("Couldn't cache keys fetched from {}", url))?;
+            }
+            Ok(keys)
+        }
+        Err(err) => match &cache_file {
+            Some(cache_file) if cache_file.is_file() => {
+                log::warn!("{:#}, using cached keys from {:?}", err, cache_file);
+                read_cache(cache_file)
+            }
+            _ => Err(err),
+        },
+    }
+}
+
+fn cache_path(dir: &Path, url: &str) -> PathBuf {
+    let name = blake3::hash(url.as_bytes()).to_hex();
+    dir.join("keys").join(format!("{}.keys", name.as_str()))
+}
+
+fn is_fresh(cache_file: &Path, ttl: Duration) -> bool {
+    fs::metadata(cache_file)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .is_some_and(|age| age < ttl)
+}
+
+fn read_cache(cache_file: &Path) -> Result<Vec<String>> {
+    let contents = fs::read_to_string(cache_file)
+        .with_context(|| format!("Couldn't read cached keys {:?}", cache_file))?;
+    Ok(contents.lines().map(String::from).collect())
+}
+
+fn store_cache(cache_file: &Path, keys: &[String]) -> Result<()> {
+    if let Some(dir) = cache_file.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(cache_file, keys.join("\n"))?;
+    Ok(())
+}
+
+fn download(url: &str) -> Result<String> {
+    log::debug!("Fetching keys; url={}", url);
+    let output = Command::new("curl")
+        .args([
+            "--silent",
+            "--show-error",
+            "--fail",
+            "--location",
+            "--max-time",
+            "30",
+        ])
+        .arg(url)
+        .output()
+        .with_context(|| format!("Couldn't fetch recipients from {}, is curl installed?", url))?;
+
+    if !output.status.success() {
+        bail!(
+            "Couldn't fetch recipients from {}: {}",
+            url,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    String::from_utf8(output.stdout)
+        .map_err(|_| anyhow!("Recipients fetched from {} are not valid UTF-8", url))
+}
+
+/// Keeps the keys age can encrypt to, e.g. GitHub also publishes ECDSA keys
+fn parse_keys(contents: &str, source: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .filter(|line| {
+            let supported = line.parse::<::age::ssh::Recipient>().is_ok();
+            if !supported {
+                log::warn!("Ignoring unsupported key from {}: {}", source, line);
+            }
+            supported
+        })
+        .map(String::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_fs::TempDir;
+    use rstest::rstest;
+
+    use super::*;
+
+    const KEY: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIBTqOL6QsCymbYvOXkxghyHqxYg8kPtoNAg84+05YHDs";
+
+    #[rstest]
+    fn test_fresh_cache_is_used_without_fetching() -> Result<()> {
+        let dir = TempDir::new()?;
+        // Unresolvable, so this only passes if the cache is used
+        let url = "https://keys.invalid/alice.keys";
+        store_cache(&cache_path(dir.path(), url), &[KEY.to_string()])?;
+
+        let keys = fetch_keys(url, Some(dir.path()), Duration::from_secs(60))?;
+
+        assert_eq!(keys, [KEY]);
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_unsupported_keys_are_skipped() {
+        let contents = format!("ecdsa-sha2-nistp256 AAAAE2VjZHNhLXNoYTItbmlzdHAyNTY=\n{KEY}\n\n");
+        assert_eq!(parse_keys(&contents, "test"), [KEY]);
+    }
+}
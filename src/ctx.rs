@@ -7,6 +7,7 @@ use std::{
 use anyhow::{bail, Result};
 
 use crate::{
+    atomic,
     config::{AgeIdentities, AgeIdentity, AppConfig, Container, GitConfig},
     git,
 };
@@ -16,8 +17,24 @@ pub(crate) trait Context {
 
     fn repo(&self) -> &Self::Repo;
 
+    /// Replaces the sidecar atomically, so that a failure leaves the previous one intact
     fn store_sidecar(&self, for_path: &Path, extension: &str, content: &[u8]) -> Result<()>;
 
+    /// Stores the ciphertext of `for_path` along with the `hash` of its plaintext. The old
+    /// hash is removed first, so that an interrupted update never pairs it with other
+    /// ciphertext, which the clean filter would reuse.
+    fn store_sidecars(&self, for_path: &Path, hash: &[u8], encrypted: &[u8]) -> Result<()>;
+
+    /// Like [`Context::store_sidecars`], with the ciphertext written by `write` to a
+    /// temporary file, e.g. while it is encrypted. The sidecars are only replaced once it
+    /// succeeded, the file can be read back before.
+    fn store_sidecars_with(
+        &self,
+        for_path: &Path,
+        hash: &[u8],
+        write: impl FnOnce(&mut File) -> Result<()>,
+    ) -> Result<()>;
+
     fn load_sidecar(
         &self,
         for_path: &Path,
@@ -31,15 +48,19 @@ pub(crate) trait Context {
     fn age_identities(&self) -> Box<dyn Container<Item = AgeIdentity> + '_>;
 
     fn config(&self) -> Result<AppConfig>;
+
+    /// Only the repository's configuration file, for changing and saving it
+    fn config_file(&self) -> Result<AppConfig>;
 }
 
 struct ContextWrapper<R: git::Repository> {
     repo: R,
+    config_file: PathBuf,
 }
 
 impl<R: git::Repository> ContextWrapper<R> {
-    pub(crate) fn new(repo: R) -> Self {
-        Self { repo }
+    pub(crate) fn new(repo: R, config_file: PathBuf) -> Self {
+        Self { repo, config_file }
     }
     fn sidecar_directory(&self) -> PathBuf {
         self.repo.path().join("git-agecrypt")
@@ -65,9 +86,28 @@ impl<R: git::Repository> Context for ContextWrapper<R> {
     }
 
     fn store_sidecar(&self, for_path: &Path, extension: &str, content: &[u8]) -> Result<()> {
-        let sidecar_path = self.get_sidecar(for_path, extension)?;
-        File::create(sidecar_path)?.write_all(content)?;
-        Ok(())
+        atomic::write(&self.get_sidecar(for_path, extension)?, content)
+    }
+
+    fn store_sidecars(&self, for_path: &Path, hash: &[u8], encrypted: &[u8]) -> Result<()> {
+        self.store_sidecars_with(for_path, hash, |file| Ok(file.write_all(encrypted)?))
+    }
+
+    fn store_sidecars_with(
+        &self,
+        for_path: &Path,
+        hash: &[u8],
+        write: impl FnOnce(&mut File) -> Result<()>,
+    ) -> Result<()> {
+        let hash_sidecar = self.get_sidecar(for_path, "hash")?;
+        atomic::write_streamed(&self.get_sidecar(for_path, "age")?, false, |file| {
+            write(file)?;
+            match fs::remove_file(&hash_sidecar) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+                _ => Ok(()),
+            }
+        })?;
+        self.store_sidecar(for_path, "hash", hash)
     }
 
     fn load_sidecar(
@@ -113,13 +153,58 @@ impl<R: git::Repository> Context for ContextWrapper<R> {
     }
 
     fn config(&self) -> Result<AppConfig> {
-        Ok(AppConfig::load(
-            &PathBuf::from("git-agecrypt.toml"),
+        Ok(AppConfig::load(&self.config_file, self.repo.workdir())?
+            .with_cache_dir(self.sidecar_directory())
+            .with_branch(self.repo.current_branch()))
+    }
+
+    fn config_file(&self) -> Result<AppConfig> {
+        Ok(AppConfig::load_file(
+            &self.config_file,
             self.repo.workdir(),
         )?)
     }
 }
 
-pub(crate) fn new(repo: git::LibGit2Repository) -> impl Context<Repo = git::LibGit2Repository> {
-    ContextWrapper::new(repo)
+/// Context of `repo`, with its configuration read from `config_file`
+pub(crate) fn new(
+    repo: git::LibGit2Repository,
+    config_file: PathBuf,
+) -> impl Context<Repo = git::LibGit2Repository> {
+    ContextWrapper::new(repo, config_file)
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_fs::TempDir;
+    use duct::cmd;
+    use rstest::rstest;
+
+    use super::*;
+    use crate::git::Repository;
+
+    #[rstest]
+    fn test_store_sidecars_replaces_both() -> Result<()> {
+        let dir = TempDir::new()?;
+        cmd!("git", "init").dir(dir.path()).run()?;
+        let repo = git::LibGit2Repository::from_dir(dir.to_path_buf())?;
+        let ctx = new(repo, dir.join("git-agecrypt.toml"));
+        let file = ctx.repo().workdir().join("secret");
+
+        ctx.store_sidecars(&file, b"old hash", b"old ciphertext")?;
+        ctx.store_sidecars(&file, b"new hash", b"new ciphertext")?;
+
+        assert_eq!(
+            ctx.load_sidecar(&file, "hash")?.as_deref(),
+            Some(&b"new hash"[..])
+        );
+        assert_eq!(
+            ctx.load_sidecar(&file, "age")?.as_deref(),
+            Some(&b"new ciphertext"[..])
+        );
+        // No temporary files are left behind
+        let sidecars = fs::read_dir(ctx.repo().path().join("git-agecrypt"))?.count();
+        assert_eq!(sidecars, 2);
+        Ok(())
+    }
 }
@@ -0,0 +1,313 @@
+//! Deterministic age encryption for `output.deterministic`, yielding the same ciphertext
+//! for the same plaintext and recipients on every machine.
+//!
+//! age draws the file key, the payload nonce and the ephemeral keys of its X25519
+//! stanzas from the OS random number generator, and offers no way to supply them. Here
+//! they are derived from a configured key and the plaintext instead, and the file is
+//! written in the age v1 format, so that any age implementation decrypts it. Equal
+//! ciphertext reveals equal plaintext, which is why this is opt-in.
+//!
+//! The file key depends on all of the plaintext, so it is hashed before anything is
+//! encrypted and can't be streamed: the whole plaintext is kept in memory.
+
+use std::io::Write;
+
+use age_core::{
+    format::Stanza,
+    primitives::{aead_encrypt, hkdf},
+};
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine};
+use bech32::FromBase32;
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    ChaCha20Poly1305, Key as PayloadKey, Nonce,
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+/// Shortest configured key accepted, e.g. 32 random bytes in base64 are 44 characters
+pub(crate) const MIN_KEY_LEN: usize = 32;
+
+const HEADER_VERSION: &str = "age-encryption.org/v1";
+const X25519_LABEL: &[u8] = b"age-encryption.org/v1/X25519";
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Key from which everything random of an age file is derived, together with the
+/// plaintext
+pub(crate) struct Key(Zeroizing<[u8; 32]>);
+
+impl Key {
+    /// Derives the key from the configured secret, which has to be at least
+    /// [`MIN_KEY_LEN`] characters long
+    pub(crate) fn new(secret: &str) -> Result<Self> {
+        if secret.len() < MIN_KEY_LEN {
+            bail!(
+                "the key has to be at least {} characters long, e.g. from openssl rand -base64 32",
+                MIN_KEY_LEN
+            );
+        }
+        Ok(Self(Zeroizing::new(blake3::derive_key(
+            "git-agecrypt deterministic encryption v1 key",
+            secret.as_bytes(),
+        ))))
+    }
+
+    /// File key and payload nonce of `plaintext`
+    fn file_secrets(&self, plaintext: &[u8]) -> (Zeroizing<[u8; 16]>, [u8; 16]) {
+        let mut derived = Zeroizing::new([0; 32]);
+        blake3::Hasher::new_keyed(&self.0)
+            .update(plaintext)
+            .finalize_xof()
+            .fill(&mut derived[..]);
+        let mut file_key = Zeroizing::new([0; 16]);
+        file_key.copy_from_slice(&derived[..16]);
+        let mut nonce = [0; 16];
+        nonce.copy_from_slice(&derived[16..]);
+        (file_key, nonce)
+    }
+}
+
+/// Encrypts `plaintext` to the X25519 `recipients` (`age1...`) into `out` as a binary
+/// age file, adding the `extra` stanzas to the header, like that of the comment
+pub(crate) fn encrypt(
+    key: &Key,
+    recipients: &[String],
+    extra: &[Stanza],
+    plaintext: &[u8],
+    out: &mut impl Write,
+) -> Result<()> {
+    let (file_key, nonce) = key.file_secrets(plaintext);
+
+    let mut header = format!("{}\n", HEADER_VERSION);
+    for recipient in recipients {
+        let (epk, body) = wrap_x25519(&file_key, recipient)?;
+        header.push_str(&stanza(&Stanza {
+            tag: "X25519".to_string(),
+            args: vec![STANDARD_NO_PAD.encode(epk)],
+            body,
+        }));
+    }
+    for extra in extra {
+        header.push_str(&stanza(extra));
+    }
+    header.push_str("---");
+    let mut mac = Hmac::<Sha256>::new_from_slice(&hkdf(&[], b"header", &file_key[..]))
+        .expect("HMAC takes keys of any length");
+    mac.update(header.as_bytes());
+    header.push_str(&format!(
+        " {}\n",
+        STANDARD_NO_PAD.encode(mac.finalize().into_bytes())
+    ));
+
+    out.write_all(header.as_bytes())?;
+    out.write_all(&nonce)?;
+    let payload_key = Zeroizing::new(hkdf(&nonce, b"payload", &file_key[..]));
+    let cipher = ChaCha20Poly1305::new(PayloadKey::from_slice(&payload_key[..]));
+    let mut chunks: Vec<_> = plaintext.chunks(CHUNK_SIZE).collect();
+    if chunks.is_empty() {
+        chunks.push(&[]);
+    }
+    let last = chunks.len() - 1;
+    for (counter, chunk) in chunks.into_iter().enumerate() {
+        let mut chunk_nonce = [0; 12];
+        chunk_nonce[3..11].copy_from_slice(&(counter as u64).to_be_bytes());
+        chunk_nonce[11] = u8::from(counter == last);
+        let encrypted = cipher
+            .encrypt(Nonce::from_slice(&chunk_nonce), chunk)
+            .map_err(|_| anyhow!("Couldn't encrypt the payload"))?;
+        out.write_all(&encrypted)?;
+    }
+    Ok(())
+}
+
+/// Ephemeral public key and body of the X25519 stanza wrapping `file_key` for
+/// `recipient`. The ephemeral secret is derived from both, as the file key already
+/// is from the plaintext.
+fn wrap_x25519(file_key: &[u8; 16], recipient: &str) -> Result<([u8; 32], Vec<u8>)> {
+    let pk = x25519_public_key(recipient)?;
+    let ephemeral = StaticSecret::from(
+        *blake3::Hasher::new_derive_key(
+            "git-agecrypt deterministic encryption v1 X25519 ephemeral",
+        )
+        .update(file_key)
+        .update(pk.as_bytes())
+        .finalize()
+        .as_bytes(),
+    );
+    let epk = PublicKey::from(&ephemeral);
+    let shared = ephemeral.diffie_hellman(&pk);
+    if !shared.was_contributory() {
+        bail!(
+            "Recipient {} is a low order point, refusing to encrypt to it",
+            recipient
+        );
+    }
+    let mut salt = [0; 64];
+    salt[..32].copy_from_slice(epk.as_bytes());
+    salt[32..].copy_from_slice(pk.as_bytes());
+    let wrap_key = Zeroizing::new(hkdf(&salt, X25519_LABEL, shared.as_bytes()));
+    Ok((*epk.as_bytes(), aead_encrypt(&wrap_key, file_key)))
+}
+
+/// Parses an `age1...` recipient. It isn't named in errors, as it might be a mistyped
+/// secret key.
+fn x25519_public_key(recipient: &str) -> Result<PublicKey> {
+    let (hrp, data, _) = bech32::decode(recipient).context("Invalid X25519 recipient")?;
+    let bytes = Vec::<u8>::from_base32(&data)
+        .ok()
+        .filter(|_| hrp == "age")
+        .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+        .context("Invalid X25519 recipient, it isn't an age1... public key")?;
+    Ok(PublicKey::from(bytes))
+}
+
+/// A stanza of the header: its arguments and the body in base64 lines of 64 columns,
+/// the last one shorter, possibly empty
+fn stanza(stanza: &Stanza) -> String {
+    let mut encoded_stanza = format!("-> {}", stanza.tag);
+    for arg in &stanza.args {
+        encoded_stanza.push(' ');
+        encoded_stanza.push_str(arg);
+    }
+    encoded_stanza.push('\n');
+    let encoded = STANDARD_NO_PAD.encode(&stanza.body);
+    for line in encoded.as_bytes().chunks(64) {
+        encoded_stanza.push_str(std::str::from_utf8(line).expect("base64 is ASCII"));
+        encoded_stanza.push('\n');
+    }
+    if encoded.len() % 64 == 0 {
+        encoded_stanza.push('\n');
+    }
+    encoded_stanza
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use age::{secrecy::ExposeSecret, x25519};
+    use rstest::rstest;
+
+    use super::*;
+
+    fn key(secret: &str) -> Key {
+        Key::new(secret).unwrap()
+    }
+
+    fn decrypt(identity: &x25519::Identity, encrypted: &[u8]) -> Vec<u8> {
+        let decryptor = age::Decryptor::new(encrypted).unwrap();
+        let mut reader = decryptor
+            .decrypt(std::iter::once(identity as &dyn age::Identity))
+            .unwrap();
+        let mut decrypted = vec![];
+        reader.read_to_end(&mut decrypted).unwrap();
+        decrypted
+    }
+
+    #[rstest]
+    #[case::empty(0)]
+    #[case::single_chunk(1000)]
+    #[case::full_chunk(CHUNK_SIZE)]
+    #[case::chunks(2 * CHUNK_SIZE + 1)]
+    fn test_decrypts_with_age(#[case] len: usize) -> Result<()> {
+        let (alice, bob) = (x25519::Identity::generate(), x25519::Identity::generate());
+        let recipients = [alice.to_public().to_string(), bob.to_public().to_string()];
+        let plaintext: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        let mut encrypted = vec![];
+
+        encrypt(
+            &key(&"k".repeat(32)),
+            &recipients,
+            &[],
+            &plaintext,
+            &mut encrypted,
+        )?;
+
+        assert_eq!(decrypt(&alice, &encrypted), plaintext);
+        assert_eq!(decrypt(&bob, &encrypted), plaintext);
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_same_plaintext_same_ciphertext() -> Result<()> {
+        let identity = x25519::Identity::generate();
+        let recipients = [identity.to_public().to_string()];
+        let secret = "QmlsbHkgd2FzIGEgY2hhbXBpb24gb2YgYm93bGluZw==";
+        let comment = [Stanza {
+            tag: "git-agecrypt-comment".to_string(),
+            args: vec!["repo:infra".to_string()],
+            body: vec![],
+        }];
+        let encrypt_with = |secret: &str, plaintext: &[u8]| -> Result<Vec<u8>> {
+            let mut encrypted = vec![];
+            encrypt(
+                &key(secret),
+                &recipients,
+                &comment,
+                plaintext,
+                &mut encrypted,
+            )?;
+            Ok(encrypted)
+        };
+
+        let encrypted = encrypt_with(secret, b"DB_PASSWORD=hunter2")?;
+
+        assert_eq!(encrypted, encrypt_with(secret, b"DB_PASSWORD=hunter2")?);
+        assert_ne!(encrypted, encrypt_with(secret, b"DB_PASSWORD=hunter3")?);
+        assert_ne!(
+            encrypted,
+            encrypt_with(&"k".repeat(32), b"DB_PASSWORD=hunter2")?
+        );
+        assert_eq!(decrypt(&identity, &encrypted), b"DB_PASSWORD=hunter2");
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_invalid_keys() {
+        assert!(Key::new("too short").is_err());
+
+        let ssh = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIHsKLqeplhpW+uObz5dvMgjz1OxfM/XXUB+VHtZ6";
+        let err = encrypt(
+            &key(&"k".repeat(32)),
+            &[ssh.to_string()],
+            &[],
+            b"",
+            &mut vec![],
+        );
+        assert!(err.is_err());
+        let identity = x25519::Identity::generate();
+        let not_public = identity.to_string().expose_secret().to_lowercase();
+        let err = encrypt(
+            &key(&"k".repeat(32)),
+            &[not_public.clone()],
+            &[],
+            b"",
+            &mut vec![],
+        );
+        assert!(!format!("{:#}", err.unwrap_err()).contains(&not_public));
+    }
+
+    /// The ciphertext of the fixture was computed independently of this implementation
+    #[rstest]
+    fn test_known_answer() -> Result<()> {
+        let identity: x25519::Identity =
+            "AGE-SECRET-KEY-1QYPQXPQ9QCRSSZG2PVXQ6RS0ZQG3YYC5Z5TPWXQERGD3C8G7RUSQGPQYEE"
+                .parse()
+                .unwrap();
+        let recipient = "age1q73he0q5yzfu3d64msd3p6rvksnrwjk3d2598mgtmlqt9wrdr37q2vrn72";
+        let secret = "QmlsbHkgd2FzIGEgY2hhbXBpb24gb2YgYm93bGluZw==";
+        let plaintext = b"DB_PASSWORD=hunter2\n";
+        let expected = include_bytes!("../tests/fixtures/deterministic.age");
+        let (recipients, mut encrypted) = ([recipient.to_string()], vec![]);
+
+        encrypt(&key(secret), &recipients, &[], plaintext, &mut encrypted)?;
+
+        assert_eq!(identity.to_public().to_string(), recipient);
+        assert_eq!(encrypted, expected);
+        assert_eq!(decrypt(&identity, &encrypted), plaintext);
+        Ok(())
+    }
+}
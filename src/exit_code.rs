@@ -0,0 +1,142 @@
+//! Exit statuses telling apart the failures which scripts wrapping git-agecrypt handle
+//! differently, e.g. asking for a passphrase instead of reporting a broken
+//! configuration. They are documented in the README and stable: new classes of failures
+//! get new numbers, any other failure exits with 1.
+
+use std::{error::Error, process::ExitCode};
+
+use ::age::DecryptError;
+
+use crate::{age::AgeError, config};
+
+/// Failures with an exit status of their own
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Failure {
+    /// The configuration can't be loaded. clap exits with the same status when the
+    /// command line is invalid.
+    Config = 2,
+    /// The file is encrypted to none of the identities
+    NoMatchingIdentity = 3,
+    /// A passphrase is needed but missing, doesn't fit, or its getter failed
+    Passphrase = 4,
+    /// A recipient can't be parsed or isn't allowed
+    Recipient = 5,
+    /// Ctrl-C stopped encrypting or decrypting, like shells report SIGINT
+    Cancelled = 130,
+}
+
+/// Marks a failure of the passphrase or identity getter, keeping its message
+#[derive(Debug, thiserror::Error)]
+#[error(transparent)]
+pub(crate) struct GetterFailed(pub(crate) anyhow::Error);
+
+/// Exit status of the process failing with `err`
+pub(crate) fn of(err: &anyhow::Error) -> ExitCode {
+    ExitCode::from(failure_of(err).map_or(1, |failure| failure as u8))
+}
+
+/// The innermost failure in the chain of `err`, being the most specific one, e.g. the
+/// broken configuration of a failing passphrase getter
+fn failure_of(err: &anyhow::Error) -> Option<Failure> {
+    err.chain().filter_map(failure).last()
+}
+
+/// Failure of `err` itself, also inside of transparent variants, which leave their
+/// cause out of the chain
+fn failure(err: &(dyn Error + 'static)) -> Option<Failure> {
+    if let Some(GetterFailed(err)) = err.downcast_ref() {
+        return failure_of(err).or(Some(Failure::Passphrase));
+    }
+    if let Some(err) = err.downcast_ref::<config::Error>() {
+        return match err {
+            config::Error::Load(_) => Some(Failure::Config),
+            config::Error::Age(err) => failure(err),
+            config::Error::Other(err) => failure_of(err),
+            config::Error::AlreadyExists(_) | config::Error::NotExist(_) => None,
+        };
+    }
+    if let Some(err) = err.downcast_ref::<AgeError>() {
+        return match err {
+            AgeError::InvalidRecipient { .. }
+            | AgeError::IdentityAsRecipient { .. }
+            | AgeError::RecipientTypeNotAllowed { .. } => Some(Failure::Recipient),
+            AgeError::NoMatchingIdentity { .. } => Some(Failure::NoMatchingIdentity),
+            AgeError::PassphraseRequired(_) | AgeError::IncorrectPassphrase(_) => {
+                Some(Failure::Passphrase)
+            }
+            AgeError::Cancelled => Some(Failure::Cancelled),
+            AgeError::Decrypt(err) => failure(err),
+            AgeError::Other(err) => failure_of(err),
+            _ => None,
+        };
+    }
+    match err.downcast_ref::<DecryptError>() {
+        Some(DecryptError::NoMatchingKeys) => Some(Failure::NoMatchingIdentity),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::{anyhow, Context};
+    use rstest::rstest;
+
+    use super::*;
+
+    fn no_matching_identity() -> AgeError {
+        AgeError::NoMatchingIdentity {
+            identities: "key.txt".to_string(),
+        }
+    }
+
+    #[rstest]
+    #[case::other(anyhow!("Failed to write the file"), None)]
+    #[case::config(
+        config::Error::Load(anyhow!("Invalid output.compress")).into(),
+        Some(Failure::Config)
+    )]
+    #[case::no_matching_identity(
+        anyhow::Error::from(no_matching_identity()).context("Couldn't decrypt secret.txt"),
+        Some(Failure::NoMatchingIdentity)
+    )]
+    #[case::inside_config(
+        config::Error::Other(no_matching_identity().into()).into(),
+        Some(Failure::NoMatchingIdentity)
+    )]
+    #[case::no_matching_keys(
+        AgeError::Decrypt(DecryptError::NoMatchingKeys).into(),
+        Some(Failure::NoMatchingIdentity)
+    )]
+    #[case::passphrase_required(
+        AgeError::PassphraseRequired(Some("key.txt".to_string())).into(),
+        Some(Failure::Passphrase)
+    )]
+    #[case::incorrect_passphrase(
+        AgeError::IncorrectPassphrase(anyhow!("incorrect passphrase for SSH key")).into(),
+        Some(Failure::Passphrase)
+    )]
+    #[case::getter(
+        GetterFailed(anyhow!("Passphrase getter exited with 1")).into(),
+        Some(Failure::Passphrase)
+    )]
+    #[case::getter_config(
+        GetterFailed(config::Error::Load(anyhow!("Couldn't load configuration")).into()).into(),
+        Some(Failure::Config)
+    )]
+    #[case::cancelled(
+        anyhow::Error::from(AgeError::Cancelled).context("Couldn't decrypt big.tar"),
+        Some(Failure::Cancelled)
+    )]
+    #[case::recipient(
+        Err::<(), _>(AgeError::InvalidRecipient {
+            recipient: "age1typo".to_string(),
+            position: 1,
+        })
+        .context("Couldn't encrypt secret.txt")
+        .unwrap_err(),
+        Some(Failure::Recipient)
+    )]
+    fn test_failure_of(#[case] err: anyhow::Error, #[case] expected: Option<Failure>) {
+        assert_eq!(failure_of(&err), expected, "{:?}", err);
+    }
+}
@@ -31,13 +31,90 @@ impl From<io::Error> for Error {
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Outcome of a three-way merge of file contents
+pub(crate) struct MergedFile {
+    /// Merged content, with conflict markers around the conflicting parts
+    pub contents: Vec<u8>,
+    pub conflicts: usize,
+}
+
+/// Merges the changes from `base` to `other` into `current` with `git merge-file`.
+/// The inputs are briefly written to a private directory inside `scratch_dir`.
+pub(crate) fn merge_file(
+    base: &[u8],
+    current: &[u8],
+    other: &[u8],
+    scratch_dir: &Path,
+) -> Result<MergedFile> {
+    let dir = tempfile::Builder::new()
+        .prefix("merge")
+        .tempdir_in(scratch_dir)
+        .context("Couldn't create directory for merging")?;
+    let mut inputs = vec![];
+    for (name, contents) in [("current", current), ("base", base), ("other", other)] {
+        let path = dir.path().join(name);
+        std::fs::write(&path, contents)?;
+        inputs.push(path);
+    }
+
+    let output = process::Command::new("git")
+        .args([
+            "merge-file",
+            "--stdout",
+            "-L",
+            "ours",
+            "-L",
+            "base",
+            "-L",
+            "theirs",
+        ])
+        .args(&inputs)
+        .output()
+        .context("Couldn't run git merge-file")?;
+    // The exit code is the number of conflicts, negative on errors
+    match output.status.code() {
+        Some(conflicts @ 0..=127) => Ok(MergedFile {
+            contents: output.stdout,
+            conflicts: conflicts as usize,
+        }),
+        _ => Err(anyhow!(
+            "git merge-file failed; status='{}', stderr={:?}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        )
+        .into()),
+    }
+}
+
 pub(crate) trait Repository {
+    /// The root of the working tree, or the git directory of bare repositories
     fn workdir(&self) -> &Path;
 
     fn path(&self) -> &Path;
 
+    /// Whether the repository has no working tree, nor an index, e.g. a mirror
+    fn is_bare(&self) -> bool;
+
     fn get_file_contents(&self, path: &Path) -> Result<Vec<u8>>;
 
+    /// Contents of the file at `path` in the commit `rev`, like `HEAD~3` or a tag
+    fn get_revision_contents(&self, rev: &str, path: &Path) -> Result<Vec<u8>>;
+
+    /// Name of the checked out branch, like `release/1.2`, `None` on a detached HEAD
+    fn current_branch(&self) -> Option<String>;
+
+    /// Lists the files added or modified between the commit `since` and HEAD
+    fn list_changed_files(&self, since: &str) -> Result<Vec<PathBuf>>;
+
+    /// Lists tracked files which git runs through `filter`, only those matching any of
+    /// `pathspecs`, relative to the root of the working tree, unless there are none
+    fn list_filtered_files(&self, filter: &str, pathspecs: &[String]) -> Result<Vec<PathBuf>>;
+
+    fn get_staged_contents(&self, path: &Path) -> Result<Vec<u8>>;
+
+    /// Replaces the staged contents of a tracked file, keeping the rest of its index entry
+    fn stage_contents(&self, path: &Path, contents: &[u8]) -> Result<()>;
+
     fn add_config(&self, key: &str, value: &str) -> Result<()>;
 
     fn contains_config(&self, key: &str, value: &str) -> bool;
@@ -55,6 +132,7 @@ pub(crate) trait Repository {
 
 pub(crate) struct LibGit2Repository {
     inner: git2::Repository,
+    workdir: PathBuf,
 }
 
 impl LibGit2Repository {
@@ -65,46 +143,160 @@ impl LibGit2Repository {
     pub(crate) fn from_dir(path: PathBuf) -> Result<Self> {
         let inner = git2::Repository::discover(&path)
             .with_context(|| format!("'{}' Not a git repository", path.display()))?;
-        if inner.is_bare() {
-            return Err(anyhow!("Bare repositories are unsupported {}", path.display(),).into());
-        }
-        Ok(Self { inner })
+        // Bare repositories still run the filters, e.g. for `git cat-file --filters`
+        let workdir = inner
+            .workdir()
+            .unwrap_or_else(|| inner.path())
+            .to_path_buf();
+        Ok(Self { inner, workdir })
+    }
+
+    fn relative_path<'a>(&self, path: &'a Path) -> Result<&'a Path> {
+        Ok(path.strip_prefix(self.workdir()).with_context(|| {
+            format!(
+                "Path {} is outside of git repository {}",
+                path.display(),
+                self.workdir().display()
+            )
+        })?)
+    }
+
+    fn revision_tree(&self, rev: &str) -> Result<git2::Tree<'_>> {
+        Ok(self
+            .inner
+            .revparse_single(rev)
+            .and_then(|object| object.peel_to_tree())
+            .with_context(|| format!("'{}' isn't a commit of the repository", rev))?)
+    }
+
+    /// Contents of the file at `path` in `tree`, which is the one of `rev`
+    fn get_tree_contents(&self, tree: &git2::Tree, rev: &str, path: &Path) -> Result<Vec<u8>> {
+        let relpath = self.relative_path(path)?;
+        let entry = tree.get_path(relpath).map_err(|e| match e.code() {
+            git2::ErrorCode::NotFound => Error::NotExist(format!(
+                "Path {} is not found in {}",
+                relpath.display(),
+                rev,
+            )),
+            _ => Error::Other(e.into()),
+        })?;
+        let contents = entry.to_object(&self.inner)?;
+        let blob = contents.as_blob().ok_or_else(|| {
+            anyhow!(
+                "Path {} in {} is a {}, not a file",
+                relpath.display(),
+                rev,
+                contents.kind().map_or("git object", |kind| kind.str()),
+            )
+        })?;
+
+        Ok(blob.content().into())
+    }
+
+    fn get_index_entry(&self, index: &git2::Index, relpath: &Path) -> Result<git2::IndexEntry> {
+        index.get_path(relpath, 0).ok_or_else(|| {
+            Error::NotExist(format!("Path {} is not found in index", relpath.display()))
+        })
     }
 }
 
 impl Repository for LibGit2Repository {
     fn workdir(&self) -> &Path {
-        self.inner.workdir().unwrap() // None in case of bare repo
+        &self.workdir
     }
 
     fn path(&self) -> &Path {
         self.inner.path()
     }
 
+    fn is_bare(&self) -> bool {
+        self.inner.is_bare()
+    }
+
     fn get_file_contents(&self, path: &Path) -> Result<Vec<u8>> {
-        let relpath = path.strip_prefix(self.workdir()).with_context(|| {
-            format!(
-                "Path {} is outside of git repository {}",
-                path.display(),
-                self.workdir().display()
-            )
-        })?;
-        let entry = self
+        let tree = self
             .inner
             .head()
             .context("Couldn not determine repository head")?
-            .peel_to_tree()?
-            .get_path(relpath)
-            .map_err(|e| match e.code() {
-                git2::ErrorCode::NotFound => Error::NotExist(format!(
-                    "Path {} is not found in HEAD",
-                    relpath.display(),
-                )),
-                _ => Error::Other(e.into()),
-            })?;
-        let contents = entry.to_object(&self.inner)?;
+            .peel_to_tree()?;
+        self.get_tree_contents(&tree, "HEAD", path)
+    }
+
+    fn get_revision_contents(&self, rev: &str, path: &Path) -> Result<Vec<u8>> {
+        self.get_tree_contents(&self.revision_tree(rev)?, rev, path)
+    }
+
+    fn current_branch(&self) -> Option<String> {
+        // Also named before the first commit, when HEAD doesn't resolve yet
+        let head = self.inner.find_reference("HEAD").ok()?;
+        let target = head.symbolic_target()?.strip_prefix("refs/heads/")?;
+        Some(target.to_string())
+    }
+
+    fn list_changed_files(&self, since: &str) -> Result<Vec<PathBuf>> {
+        let old = self.revision_tree(since)?;
+        let head = self
+            .inner
+            .head()
+            .context("Couldn not determine repository head")?
+            .peel_to_tree()?;
+        let diff = self
+            .inner
+            .diff_tree_to_tree(Some(&old), Some(&head), None)?;
+        Ok(diff
+            .deltas()
+            .filter(|delta| delta.status() != git2::Delta::Deleted)
+            .filter_map(|delta| delta.new_file().path())
+            .map(|relpath| self.workdir().join(relpath))
+            .collect())
+    }
+
+    fn list_filtered_files(&self, filter: &str, pathspecs: &[String]) -> Result<Vec<PathBuf>> {
+        let pathspec = if pathspecs.is_empty() {
+            None
+        } else {
+            Some(git2::Pathspec::new(pathspecs)?)
+        };
+        let index = self.inner.index()?;
+        let mut files = vec![];
+        for entry in index.iter() {
+            // Conflicting entries have no single content to work on
+            if (entry.flags >> 12) & 0x3 != 0 {
+                continue;
+            }
+            let relpath = PathBuf::from(String::from_utf8_lossy(&entry.path).as_ref());
+            if let Some(pathspec) = &pathspec {
+                if !pathspec.matches_path(&relpath, git2::PathspecFlags::DEFAULT) {
+                    continue;
+                }
+            }
+            let attr =
+                self.inner
+                    .get_attr(&relpath, "filter", git2::AttrCheckFlags::FILE_THEN_INDEX)?;
+            if attr == Some(filter) {
+                files.push(self.workdir().join(relpath));
+            }
+        }
+        Ok(files)
+    }
+
+    fn get_staged_contents(&self, path: &Path) -> Result<Vec<u8>> {
+        let relpath = self.relative_path(path)?;
+        let index = self.inner.index()?;
+        let entry = self.get_index_entry(&index, relpath)?;
+        Ok(self.inner.find_blob(entry.id)?.content().into())
+    }
 
-        Ok(contents.as_blob().unwrap().content().into())
+    fn stage_contents(&self, path: &Path, contents: &[u8]) -> Result<()> {
+        let relpath = self.relative_path(path)?;
+        let mut index = self.inner.index()?;
+        let mut entry = self.get_index_entry(&index, relpath)?;
+        // The stat data still describes the working tree file, so git keeps treating it
+        // as unmodified
+        entry.id = self.inner.blob(contents)?;
+        index.add(&entry)?;
+        index.write()?;
+        Ok(())
     }
 
     fn add_config(&self, key: &str, value: &str) -> Result<()> {
@@ -244,11 +436,53 @@ mod tests {
         Ok(())
     }
 
+    #[fixture]
+    fn bare_repo(tempdir: TempDir) -> Repo {
+        let source = tempdir.child("source");
+        source
+            .child("secrets/token")
+            .write_str("committed")
+            .unwrap();
+        cmd!("git", "init", "source")
+            .dir(tempdir.path())
+            .run()
+            .unwrap();
+        cmd!("git", "add", ".").dir(source.path()).run().unwrap();
+        cmd!(
+            "git",
+            "-c",
+            "user.name=A U Thor",
+            "-c",
+            "user.email=author@example.com",
+            "commit",
+            "-m",
+            "adding file"
+        )
+        .dir(source.path())
+        .run()
+        .unwrap();
+        cmd!("git", "clone", "--bare", "source", "bare.git")
+            .dir(tempdir.path())
+            .run()
+            .unwrap();
+        let repo = LibGit2Repository::from_dir(tempdir.join("bare.git")).unwrap();
+        Repo {
+            inner: repo,
+            dir: tempdir,
+        }
+    }
+
     #[rstest]
-    fn test_bare_repo_is_error(tempdir: TempDir) -> Result<()> {
-        cmd!("git", "init", "--bare").dir(tempdir.path()).run()?;
-        let repo = LibGit2Repository::from_dir(tempdir.path().to_path_buf()).err();
-        assert_matches!(repo, Some(Error::Other(_)));
+    fn test_bare_repo_can_be_loaded(bare_repo: Repo) -> Result<()> {
+        let git_dir = bare_repo.dir.join("bare.git").canonicalize()?;
+
+        assert!(bare_repo.is_bare());
+        assert_eq!(bare_repo.workdir().canonicalize()?, git_dir);
+        assert_eq!(bare_repo.path().canonicalize()?, git_dir);
+        assert_eq!(
+            bare_repo.get_file_contents(&bare_repo.workdir().join("secrets/token"))?,
+            b"committed"
+        );
         Ok(())
     }
 
@@ -286,6 +520,156 @@ mod tests {
             .get_file_contents(git_repo.dir.path().parent().unwrap())
             .is_err());
 
+        let err = git_repo
+            .get_file_contents(&git_repo.dir.join("subdir"))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Path subdir in HEAD is a tree, not a file");
+
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_changed_files(git_repo: Repo) -> Result<()> {
+        let commit = |message: &str| {
+            cmd!("git", "add", "-A").dir(git_repo.dir.path()).run()?;
+            cmd!(
+                "git",
+                "-c",
+                "user.name=A U Thor",
+                "-c",
+                "user.email=author@example.com",
+                "commit",
+                "-m",
+                message
+            )
+            .dir(git_repo.dir.path())
+            .run()
+        };
+        git_repo.dir.child("secrets/kept").write_str("kept")?;
+        git_repo.dir.child("secrets/changed").write_str("old")?;
+        git_repo.dir.child("secrets/removed").write_str("removed")?;
+        commit("first")?;
+        git_repo.dir.child("secrets/changed").write_str("new")?;
+        git_repo.dir.child("secrets/added").write_str("added")?;
+        std::fs::remove_file(git_repo.dir.join("secrets/removed"))?;
+        commit("second")?;
+
+        assert_eq!(
+            git_repo.list_changed_files("HEAD~1")?,
+            [
+                git_repo.dir.join("secrets/added"),
+                git_repo.dir.join("secrets/changed")
+            ]
+        );
+        let changed = git_repo.dir.join("secrets/changed");
+        assert_eq!(git_repo.get_revision_contents("HEAD~1", &changed)?, b"old");
+        assert_matches!(
+            git_repo.get_revision_contents("HEAD~1", &git_repo.dir.join("secrets/added")),
+            Err(Error::NotExist(_))
+        );
+        assert_matches!(
+            git_repo.list_changed_files("no-such-ref"),
+            Err(Error::Other(_))
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_current_branch(git_repo: Repo) -> Result<()> {
+        let dir = git_repo.dir.path();
+        cmd!("git", "checkout", "-q", "-b", "release/1.2")
+            .dir(dir)
+            .run()?;
+        assert_eq!(git_repo.current_branch().as_deref(), Some("release/1.2"));
+
+        git_repo.dir.child("README").write_str("plain")?;
+        cmd!("git", "add", ".").dir(dir).run()?;
+        cmd!(
+            "git",
+            "-c",
+            "user.name=A U Thor",
+            "-c",
+            "user.email=author@example.com",
+            "commit",
+            "-m",
+            "adding file"
+        )
+        .dir(dir)
+        .run()?;
+        cmd!("git", "checkout", "-q", "--detach").dir(dir).run()?;
+
+        assert_eq!(git_repo.current_branch(), None);
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_filtered_files_in_pathspecs(git_repo: Repo) -> Result<()> {
+        git_repo
+            .dir
+            .child(".gitattributes")
+            .write_str("secrets/** filter=git-agecrypt\n")?;
+        for file in [
+            "secrets/prod/db",
+            "secrets/prod/api.env",
+            "secrets/dev/db",
+            "README",
+        ] {
+            git_repo.dir.child(file).write_str("encrypted")?;
+        }
+        cmd!("git", "add", ".").dir(git_repo.dir.path()).run()?;
+        let list = |pathspecs: &[&str]| {
+            let pathspecs: Vec<_> = pathspecs.iter().map(|p| p.to_string()).collect();
+            let mut files = git_repo.list_filtered_files("git-agecrypt", &pathspecs)?;
+            files.sort();
+            Ok::<_, Error>(files)
+        };
+        let (prod_api, prod_db, dev_db) = (
+            git_repo.dir.join("secrets/prod/api.env"),
+            git_repo.dir.join("secrets/prod/db"),
+            git_repo.dir.join("secrets/dev/db"),
+        );
+
+        assert_eq!(
+            list(&["secrets/prod"])?,
+            [prod_api.clone(), prod_db.clone()]
+        );
+        assert_eq!(
+            list(&["secrets/*/db", "README"])?,
+            [dev_db.clone(), prod_db]
+        );
+        assert_eq!(list(&["secrets/prod/*.env"])?, [prod_api]);
+        assert_eq!(list(&["prod"])?, Vec::<PathBuf>::new());
+        assert_eq!(list(&[])?.len(), 3);
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_filtered_files_can_be_restaged(git_repo: Repo) -> Result<()> {
+        git_repo
+            .dir
+            .child(".gitattributes")
+            .write_str("secrets/** filter=git-agecrypt\n")?;
+        git_repo.dir.child("secrets/token").write_str("encrypted")?;
+        git_repo.dir.child("README").write_str("plain")?;
+        cmd!("git", "add", ".").dir(git_repo.dir.path()).run()?;
+
+        let token = git_repo.dir.join("secrets/token");
+        assert_eq!(
+            git_repo.list_filtered_files("git-agecrypt", &[])?,
+            [token.clone()]
+        );
+
+        git_repo.stage_contents(&token, b"rekeyed")?;
+
+        assert_eq!(git_repo.get_staged_contents(&token)?, b"rekeyed");
+        let staged = cmd!("git", "show", ":secrets/token")
+            .dir(git_repo.dir.path())
+            .read()?;
+        assert_eq!(staged, "rekeyed");
+        assert_matches!(
+            git_repo.get_staged_contents(&git_repo.dir.join("missing")),
+            Err(Error::NotExist(_))
+        );
         Ok(())
     }
 
@@ -0,0 +1,276 @@
+//! Sources of the passphrase and identity of a getter key. Commands run by a shell are
+//! what getters have always been; the other built-in providers read the secret without
+//! a program in between, e.g. from a variable a CI system or secrets manager injects.
+//! Native providers, like one asking a secrets manager's API, implement [`KeyProvider`]
+//! and become a variant of [`Provider`], leaving the filters untouched.
+
+use std::{env, fs, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use zeroize::Zeroizing;
+
+use crate::{
+    config::{AppConfig, Getter, GetterProvider},
+    run_getter, GetterSource, Shell, SystemRunner,
+};
+
+/// Hands out the secrets of a getter key
+pub(crate) trait KeyProvider {
+    /// The passphrase for encrypted identity files and passphrase-encrypted files
+    fn passphrase(&self) -> Result<Zeroizing<String>>;
+
+    /// Contents of an identity file, checked by [`crate::age::set_identity`]. By default
+    /// the same secret as the passphrase, as the section of the getter tells which one
+    /// it is. Providers hand out the text rather than parsed identities, as it joins the
+    /// configured identities like an inline one, which are named and whose recipients
+    /// are derived by their contents.
+    fn identity(&self) -> Result<Zeroizing<String>> {
+        self.passphrase()
+    }
+
+    /// What the passphrase is cached by, `None` for providers which are cheap to ask
+    /// again and aren't cached
+    fn cache_key(&self) -> Option<&str> {
+        None
+    }
+}
+
+/// The built-in providers
+pub(crate) enum Provider {
+    Command(CommandProvider),
+    Env(EnvProvider),
+    File(FileProvider),
+    EnvJson(EnvJsonProvider),
+}
+
+impl Provider {
+    /// The provider of `getter`, running commands with the shell and timeout of the
+    /// [passphrase] section. `source` is what selected the getter, named in errors.
+    pub(crate) fn new(getter: Getter, cfg: &AppConfig, source: GetterSource) -> Result<Self> {
+        Ok(match getter {
+            Getter::Command(command) => Self::Command(CommandProvider {
+                shell: Shell::from_config(cfg.get_passphrase_shell()?.as_deref())?,
+                command,
+                source,
+                timeout: cfg.passphrase_timeout(),
+            }),
+            Getter::Provider(GetterProvider::Env { var }) => Self::Env(EnvProvider { var }),
+            Getter::Provider(GetterProvider::File { path }) => Self::File(FileProvider { path }),
+            Getter::Provider(GetterProvider::EnvJson { var, field }) => {
+                Self::EnvJson(EnvJsonProvider { var, field })
+            }
+        })
+    }
+
+    fn inner(&self) -> &dyn KeyProvider {
+        match self {
+            Self::Command(provider) => provider,
+            Self::Env(provider) => provider,
+            Self::File(provider) => provider,
+            Self::EnvJson(provider) => provider,
+        }
+    }
+}
+
+impl KeyProvider for Provider {
+    fn passphrase(&self) -> Result<Zeroizing<String>> {
+        self.inner().passphrase()
+    }
+
+    fn identity(&self) -> Result<Zeroizing<String>> {
+        self.inner().identity()
+    }
+
+    fn cache_key(&self) -> Option<&str> {
+        self.inner().cache_key()
+    }
+}
+
+/// Prints the secret from a command run by the configured shell
+pub(crate) struct CommandProvider {
+    shell: Shell,
+    command: String,
+    source: GetterSource,
+    timeout: Option<Duration>,
+}
+
+impl KeyProvider for CommandProvider {
+    fn passphrase(&self) -> Result<Zeroizing<String>> {
+        log::debug!(
+            "Executing getter command with {}: {}",
+            self.shell,
+            self.command
+        );
+        run_getter(
+            &SystemRunner,
+            &self.shell,
+            &self.command,
+            self.source,
+            self.timeout,
+        )
+    }
+
+    fn cache_key(&self) -> Option<&str> {
+        Some(&self.command)
+    }
+}
+
+/// Reads the secret from the environment variable `var`
+pub(crate) struct EnvProvider {
+    var: String,
+}
+
+impl EnvProvider {
+    /// The secret in `value`, what the variable is set to
+    fn secret(&self, value: Result<String, env::VarError>) -> Result<Zeroizing<String>> {
+        let value = value
+            .map(Zeroizing::new)
+            .with_context(|| format!("Environment variable {} isn't set", self.var))?;
+        trimmed(&value, || format!("Environment variable {}", self.var))
+    }
+}
+
+impl KeyProvider for EnvProvider {
+    fn passphrase(&self) -> Result<Zeroizing<String>> {
+        self.secret(env::var(&self.var))
+    }
+}
+
+/// Reads the secret from the file at `path`
+pub(crate) struct FileProvider {
+    path: String,
+}
+
+impl KeyProvider for FileProvider {
+    fn passphrase(&self) -> Result<Zeroizing<String>> {
+        let contents = fs::read_to_string(&self.path)
+            .map(Zeroizing::new)
+            .with_context(|| format!("Couldn't read the secret file {}", self.path))?;
+        trimmed(&contents, || format!("Secret file {}", self.path))
+    }
+}
+
+/// Reads the secret from the string `field` of the JSON object in the environment
+/// variable `var`, a reference for providers parsing what a secrets manager hands out
+pub(crate) struct EnvJsonProvider {
+    var: String,
+    field: String,
+}
+
+impl KeyProvider for EnvJsonProvider {
+    fn passphrase(&self) -> Result<Zeroizing<String>> {
+        let json = env::var(&self.var)
+            .map(Zeroizing::new)
+            .with_context(|| format!("Environment variable {} isn't set", self.var))?;
+        let value = json_field(&json, &self.field)
+            .with_context(|| format!("Environment variable {} holds no secret", self.var))?;
+        trimmed(&value, || format!("Field '{}' of {}", self.field, self.var))
+    }
+}
+
+/// The string `field` of the JSON object `json`
+fn json_field(json: &str, field: &str) -> Result<Zeroizing<String>> {
+    let mut object: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_str(json).context("It isn't a JSON object")?;
+    match object.remove(field) {
+        Some(serde_json::Value::String(value)) => Ok(Zeroizing::new(value)),
+        Some(_) => bail!("Field '{}' isn't a string", field),
+        None => bail!("Field '{}' is missing", field),
+    }
+}
+
+/// `secret` without surrounding whitespace, like the output of getter commands.
+/// `what` names its source when it is empty.
+fn trimmed(secret: &str, what: impl FnOnce() -> String) -> Result<Zeroizing<String>> {
+    let secret = secret.trim();
+    if secret.is_empty() {
+        bail!("{} is empty", what());
+    }
+    Ok(Zeroizing::new(secret.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_fs::{prelude::*, TempDir};
+    use assert_matches::assert_matches;
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case::string(r#"{"age": "hunter2", "db": "swordfish"}"#, Some("hunter2"))]
+    #[case::missing(r#"{"db": "swordfish"}"#, None)]
+    #[case::not_string(r#"{"age": 42}"#, None)]
+    #[case::not_object(r#"["hunter2"]"#, None)]
+    fn test_json_field(#[case] json: &str, #[case] expected: Option<&str>) {
+        assert_eq!(
+            json_field(json, "age").ok().as_deref().map(String::as_str),
+            expected
+        );
+    }
+
+    #[rstest]
+    fn test_file_provider() -> Result<()> {
+        let dir = TempDir::new()?;
+        let file = dir.child("secret");
+        file.write_str("hunter2\r\n")?;
+        let provider = |path: &str| FileProvider {
+            path: path.to_string(),
+        };
+
+        let secret = provider(&file.path().to_string_lossy()).passphrase()?;
+        assert_eq!(secret.as_str(), "hunter2");
+        assert_eq!(provider("").cache_key(), None);
+        file.write_str("\n")?;
+        let err = provider(&file.path().to_string_lossy())
+            .passphrase()
+            .unwrap_err();
+        assert!(err.to_string().ends_with("is empty"), "{}", err);
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_env_provider() -> Result<()> {
+        let provider = EnvProvider {
+            var: "AGE_SECRET".to_string(),
+        };
+
+        let secret = provider.secret(Ok(" hunter2\n".to_string()))?;
+        assert_eq!(secret.as_str(), "hunter2");
+        assert_eq!(provider.cache_key(), None);
+        let err = provider.secret(Err(env::VarError::NotPresent)).unwrap_err();
+        assert!(err.to_string().ends_with("isn't set"), "{}", err);
+        let err = provider.secret(Ok("\n".to_string())).unwrap_err();
+        assert!(err.to_string().ends_with("is empty"), "{}", err);
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_provider_new() -> Result<()> {
+        let dir = TempDir::new()?;
+        let file = dir.child("git-agecrypt.toml");
+        file.write_str("[passphrase]\nshell = \"bash -c\"\ntimeout_secs = 5\n")?;
+        let cfg = AppConfig::load(file.path(), dir.path())?;
+        let new = |getter| Provider::new(getter, &cfg, GetterSource::Arg);
+        let var = || "AGE_SECRETS".to_string();
+
+        let command = new(Getter::Command("pass show age".to_string()))?;
+        assert_matches!(
+            &command,
+            Provider::Command(c) if c.shell.program == "bash"
+                && c.timeout == Some(Duration::from_secs(5))
+        );
+        assert_eq!(command.cache_key(), Some("pass show age"));
+        let env = new(Getter::Provider(GetterProvider::Env { var: var() }))?;
+        assert_matches!(&env, Provider::Env(p) if p.var == "AGE_SECRETS");
+        assert_eq!(env.cache_key(), None);
+        let path = "/run/secrets/age".to_string();
+        let file = new(Getter::Provider(GetterProvider::File { path }))?;
+        assert_matches!(&file, Provider::File(p) if p.path == "/run/secrets/age");
+        let (var, field) = (var(), "age".to_string());
+        let json = new(Getter::Provider(GetterProvider::EnvJson { var, field }))?;
+        assert_matches!(&json, Provider::EnvJson(p) if p.field == "age");
+        assert_eq!(json.cache_key(), None);
+        Ok(())
+    }
+}
@@ -0,0 +1,28 @@
+//! Encryption core of git-agecrypt, for tools building on the same age handling.
+//!
+//! ```
+//! use age::secrecy::ExposeSecret;
+//! use git_agecrypt::{decrypt, encrypt, DecryptOutcome, Encoding};
+//!
+//! let identity = age::x25519::Identity::generate();
+//! let recipient = identity.to_public().to_string();
+//! let encrypted = encrypt(&[recipient], &mut &b"secret"[..], Encoding::Armored)?;
+//!
+//! // Identities are paths of identity files, or given inline
+//! let identities = [identity.to_string().expose_secret().to_string()];
+//! let decrypted = decrypt(&identities, &mut &encrypted[..])?;
+//! assert_eq!(decrypted, DecryptOutcome::Decrypted(b"secret".to_vec()));
+//! # Ok::<(), git_agecrypt::AgeError>(())
+//! ```
+
+// All of it is used by the binary, which is built on this crate
+#[doc(hidden)]
+pub mod age;
+mod deterministic;
+mod tty;
+
+pub use self::age::{
+    comment_of, decrypt, decrypt_streaming, decrypt_with, encrypt, encrypt_streaming, encrypt_with,
+    recipients_of, validate_identity, validate_public_keys, AgeError, DecryptOutcome, Encoding,
+    RecipientStanzaInfo, Streaming,
+};
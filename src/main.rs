@@ -1,27 +1,119 @@
-mod age;
+mod atomic;
 mod cli;
 mod config;
 mod ctx;
+mod exit_code;
 mod git;
+mod key_provider;
+mod parallel;
+mod partial;
+mod passphrase_cache;
+mod plugins;
+mod progress;
+mod smudge_cache;
 
-use std::path::PathBuf;
-use std::process::Command;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::process::{Child, Command, ExitCode, Output, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use anyhow::{bail, Context, Result};
 use cli::run;
 use config::AppConfig;
 use git::Repository;
+use git_agecrypt::age;
+use key_provider::{KeyProvider, Provider};
+use zeroize::Zeroizing;
 
-fn main() -> Result<()> {
-    env_logger::init();
+fn main() -> ExitCode {
+    match run_main() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            // Reported like an error returned from main, but with the status of its class
+            eprintln!("Error: {:?}", err);
+            exit_code::of(&err)
+        }
+    }
+}
+
+fn run_main() -> Result<()> {
     let args = cli::parse_args();
+    init_logger(args.log_level());
+    age::set_terminal_hook(progress::suspend);
+    if let Some(file) = &args.passphrase_file {
+        age::set_passphrase_file(file);
+    }
+    if let cli::Commands::Completions { shell } = args.command {
+        // Works outside of repositories, e.g. when installing the completions
+        cli::write_completions(shell, &mut io::stdout());
+        return Ok(());
+    }
+    if let cli::Commands::Standalone(_) = args.command {
+        // Works outside of repositories, using the configuration of the current one if any
+        let ctx = match git::LibGit2Repository::from_current_dir() {
+            Ok(repo) => {
+                let config_file =
+                    config::repo_config_file(repo.workdir(), args.config_file.as_deref())?;
+                resolve_passphrase(&args, &repo, &config_file).map_err(exit_code::GetterFailed)?;
+                setup_age(&repo, &config_file)?;
+                Some(ctx::new(repo, config_file))
+            }
+            Err(_) => None,
+        };
+        return cli::run_standalone(args, ctx);
+    }
     let repo = git::LibGit2Repository::from_current_dir()?;
+    let config_file = config::repo_config_file(repo.workdir(), args.config_file.as_deref())?;
+    if cli::passes_through(&args.command) {
+        // Passing contents through needs neither passphrases nor plugins
+        return run(args, ctx::new(repo, config_file), Ok(None));
+    }
 
     // Handle passphrase getter before running commands
-    resolve_passphrase(&args, &repo)?;
+    let getter = resolve_passphrase(&args, &repo, &config_file);
+    let setup = setup_age(&repo, &config_file);
 
-    let ctx = ctx::new(repo);
-    run(args, ctx)
+    let ctx = ctx::new(repo, config_file);
+    if let cli::Commands::Public(cli::PublicCommands::Doctor) = args.command {
+        // Reports a failing getter along with the other problems
+        return run(args, ctx, getter);
+    }
+    let key = getter.map_err(exit_code::GetterFailed)?;
+    setup?;
+    run(args, ctx, Ok(key))
+}
+
+/// Makes the plugin binaries configured in `[[plugins]]` available to age, sets the
+/// comment of `output.comment`, the compression of `output.compress` and the key of
+/// `output.deterministic` for encrypted files, and enforces
+/// `identity_files.require_encrypted`
+fn setup_age(repo: &impl Repository, config_file: &Path) -> Result<()> {
+    let cfg = AppConfig::load(config_file, repo.workdir())?;
+    if let Some(comment) = cfg.comment()? {
+        age::set_comment(comment)?;
+    }
+    if cfg.require_encrypted_identities() {
+        age::set_require_encrypted_identities();
+    }
+    if let Some(compression) = cfg.compression()? {
+        age::set_compression(compression);
+    }
+    if let Some(key) = cfg.deterministic_key() {
+        age::set_deterministic_key(key.map_err(anyhow::Error::from));
+    }
+    plugins::install(
+        &cfg.plugins()?,
+        &repo.path().join("git-agecrypt").join("plugins"),
+    )
+}
+
+/// Logs at `level`, or as configured by RUST_LOG when it is set, which takes precedence
+fn init_logger(level: log::LevelFilter) {
+    env_logger::Builder::new()
+        .filter_level(level)
+        .parse_env(env_logger::Env::default())
+        .init();
 }
 
 const AGE_PASSPHRASE_GETTER_ENV: &str = "AGE_PASSPHRASE_GETTER";
@@ -39,15 +131,46 @@ impl std::fmt::Display for GetterSource {
         match self {
             GetterSource::Arg => write!(f, "-g argument"),
             GetterSource::EnvVar => write!(f, "{} env var", AGE_PASSPHRASE_GETTER_ENV),
-            GetterSource::ImplicitSops => write!(f, "implicit sops key in [passphrase] section"),
+            GetterSource::ImplicitSops => {
+                write!(f, "implicit sops key in [passphrase] or [identity] section")
+            }
         }
     }
 }
 
-fn resolve_passphrase(args: &cli::Args, repo: &impl Repository) -> Result<()> {
-    // Load config to check [passphrase] section
-    let cfg = AppConfig::load(&PathBuf::from("git-agecrypt.toml"), repo.workdir())?;
+/// Runs the passphrase getter and the identity getter of the key in use, if any,
+/// returning the key
+fn resolve_passphrase(
+    args: &cli::Args,
+    repo: &impl Repository,
+    config_file: &Path,
+) -> Result<Option<String>> {
+    // Load config to check [passphrase] and [identity] sections
+    let cfg = AppConfig::load(config_file, repo.workdir())?;
+    let Some((key, source)) = getter_key(args, &cfg) else {
+        return Ok(None);
+    };
+
+    let identity_getter = cfg.get_identity_getter(&key)?;
+    let passphrase_getter = cfg.get_passphrase_getter(&key)?;
+    if identity_getter.is_none() && passphrase_getter.is_none() {
+        bail!(
+            "Passphrase getter '{}' not found in [passphrase] or [identity] section of git-agecrypt.toml (triggered by {})",
+            key,
+            source
+        );
+    }
+    if let Some(getter) = identity_getter {
+        resolve_identity(&key, &Provider::new(getter, &cfg, source)?)?;
+    }
+    if let Some(getter) = passphrase_getter {
+        resolve_getter_passphrase(&cfg, repo, &key, &Provider::new(getter, &cfg, source)?)?;
+    }
+    Ok(Some(key))
+}
 
+/// The key of the getters to run, with what selected it
+fn getter_key(args: &cli::Args, cfg: &AppConfig) -> Option<(String, GetterSource)> {
     // Determine which key to use (priority order):
     // 1. Explicit -g <key> argument (highest priority)
     // 2. AGE_PASSPHRASE_GETTER env var:
@@ -55,87 +178,316 @@ fn resolve_passphrase(args: &cli::Args, repo: &impl Repository) -> Result<()> {
     //    - if empty: suppress sops check (return early)
     //    - if non-empty: use its value as getter key
     // 3. Implicit "sops" key if present in config (lowest priority)
-    let (getter_key, source): (Option<String>, Option<GetterSource>) = if let Some(ref key) = args.passphrase_getter {
-        // -g argument takes highest priority
-        (Some(key.clone()), Some(GetterSource::Arg))
-    } else {
-        // Check AGE_PASSPHRASE_GETTER env var
-        match std::env::var(AGE_PASSPHRASE_GETTER_ENV) {
-            Ok(env_value) => {
-                if env_value.is_empty() {
-                    // Empty value = suppress sops, do nothing
-                    log::debug!("{} is set but empty, suppressing default sops getter", AGE_PASSPHRASE_GETTER_ENV);
-                    return Ok(());
-                } else {
-                    // Non-empty value = use as getter key
-                    log::debug!("Using getter key from {}: {}", AGE_PASSPHRASE_GETTER_ENV, env_value);
-                    (Some(env_value), Some(GetterSource::EnvVar))
+    let (getter_key, source): (Option<String>, Option<GetterSource>) =
+        if let Some(ref key) = args.passphrase_getter {
+            // -g argument takes highest priority
+            (Some(key.clone()), Some(GetterSource::Arg))
+        } else {
+            // Check AGE_PASSPHRASE_GETTER env var
+            match std::env::var(AGE_PASSPHRASE_GETTER_ENV) {
+                Ok(env_value) => {
+                    if env_value.is_empty() {
+                        // Empty value = suppress sops, do nothing
+                        log::debug!(
+                            "{} is set but empty, suppressing default sops getter",
+                            AGE_PASSPHRASE_GETTER_ENV
+                        );
+                        return None;
+                    } else {
+                        // Non-empty value = use as getter key
+                        log::debug!(
+                            "Using getter key from {}: {}",
+                            AGE_PASSPHRASE_GETTER_ENV,
+                            env_value
+                        );
+                        (Some(env_value), Some(GetterSource::EnvVar))
+                    }
                 }
-            }
-            Err(_) => {
-                // Env var not set, fall through to sops check
-                if cfg.has_passphrase_key("sops") {
-                    (Some("sops".to_string()), Some(GetterSource::ImplicitSops))
-                } else {
-                    (None, None)
+                Err(_) => {
+                    // Env var not set, fall through to sops check
+                    if cfg.has_passphrase_key("sops") || cfg.has_identity_key("sops") {
+                        (Some("sops".to_string()), Some(GetterSource::ImplicitSops))
+                    } else {
+                        (None, None)
+                    }
                 }
             }
+        };
+
+    getter_key.zip(source)
+}
+
+/// Asks the identity getter `key` for the identity, which is used like an identity file.
+/// It is never cached, unlike passphrases.
+fn resolve_identity(key: &str, provider: &impl KeyProvider) -> Result<()> {
+    log::debug!("Getting the identity from identity getter '{}'", key);
+    let identity = provider
+        .identity()
+        .with_context(|| format!("Identity getter '{}' failed", key))?;
+    age::set_identity(&identity)
+        .with_context(|| format!("Identity getter '{}' printed no usable identity", key))?;
+    log::debug!("Using identity from identity getter '{}'", key);
+    Ok(())
+}
+
+/// Asks the passphrase getter `key` for the passphrase, or reuses its cached one
+fn resolve_getter_passphrase(
+    cfg: &AppConfig,
+    repo: &impl Repository,
+    key: &str,
+    provider: &impl KeyProvider,
+) -> Result<()> {
+    let passphrase = getter_passphrase(cfg, repo, key, provider)?;
+    // Handed to the age module directly rather than through AGE_PASSPHRASE, so that it
+    // isn't copied around the environment of this and all child processes
+    log::debug!("Using passphrase from passphrase getter '{}'", key);
+    age::set_passphrase(&passphrase);
+    Ok(())
+}
+
+/// The passphrase of the getter `key`, cached for providers with a cache key
+fn getter_passphrase(
+    cfg: &AppConfig,
+    repo: &impl Repository,
+    key: &str,
+    provider: &impl KeyProvider,
+) -> Result<Zeroizing<String>> {
+    let cache = cfg.passphrase_cache_ttl().zip(provider.cache_key());
+    let cache_dir = repo.path().join("git-agecrypt");
+    if let Some((ttl, cache_key)) = cache {
+        if let Some(passphrase) = passphrase_cache::load(&cache_dir, cache_key, ttl) {
+            log::debug!("Using cached passphrase from passphrase getter '{}'", key);
+            return Ok(passphrase);
         }
-    };
+    }
 
-    let (Some(key), Some(source)) = (getter_key, source) else {
-        return Ok(());
-    };
+    log::debug!("Getting the passphrase from passphrase getter '{}'", key);
+    let passphrase = provider.passphrase()?;
+    if let Some((_, cache_key)) = cache {
+        if let Err(err) = passphrase_cache::store(&cache_dir, cache_key, &passphrase) {
+            log::warn!("{:#}", err);
+        }
+    }
+    Ok(passphrase)
+}
 
-    let command = cfg.get_passphrase_command(&key).ok_or_else(|| {
-        anyhow::anyhow!(
-            "Passphrase getter '{}' not found in [passphrase] section of git-agecrypt.toml (triggered by {})",
-            key,
-            source
-        )
-    })?;
+/// Interpreter running passphrase commands, to support pipes and complex commands
+#[derive(Debug, PartialEq, Eq)]
+struct Shell {
+    program: String,
+    args: Vec<String>,
+}
+
+impl Shell {
+    /// Uses the configured interpreter, e.g. `pwsh -c`, or the platform default
+    fn from_config(shell: Option<&str>) -> Result<Self> {
+        let Some(shell) = shell else {
+            return Ok(Self::default_for(cfg!(windows)));
+        };
+        let mut words = shell.split_whitespace().map(String::from);
+        let program = words
+            .next()
+            .context("Passphrase shell configured in [passphrase] section is empty")?;
+        Ok(Self {
+            program,
+            args: words.collect(),
+        })
+    }
+
+    fn default_for(windows: bool) -> Self {
+        let (program, arg) = if windows { ("cmd", "/C") } else { ("sh", "-c") };
+        Self {
+            program: program.to_string(),
+            args: vec![arg.to_string()],
+        }
+    }
+}
+
+impl std::fmt::Display for Shell {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.program)?;
+        for arg in &self.args {
+            write!(f, " {}", arg)?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs external programs, replaceable so that tests don't depend on a real shell
+trait CommandRunner {
+    /// Returns `None` if the program didn't exit within `timeout`
+    fn run(
+        &self,
+        program: &str,
+        args: &[&str],
+        timeout: Option<Duration>,
+    ) -> io::Result<Option<Output>>;
+}
+
+struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn run(
+        &self,
+        program: &str,
+        args: &[&str],
+        timeout: Option<Duration>,
+    ) -> io::Result<Option<Output>> {
+        let mut command = Command::new(program);
+        command
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        // Its own process group, so that programs started by the shell are killed with it
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut command, 0);
+        let mut child = command.spawn()?;
+
+        // Read while waiting, a full pipe would block the child. Stderr is shown as it
+        // arrives, as getters may print instructions like "touch your YubiKey" there.
+        let stdout = read_in_background(child.stdout.take(), None);
+        let stderr = read_in_background(child.stderr.take(), Some(Box::new(io::stderr())));
+
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                kill(&mut child);
+                // Reaps the child, so that it doesn't linger as a zombie
+                child.wait()?;
+                return Ok(None);
+            }
+            thread::sleep(Duration::from_millis(20));
+        };
+        Ok(Some(Output {
+            status,
+            stdout: join_reader(stdout)?,
+            stderr: join_reader(stderr)?,
+        }))
+    }
+}
+
+/// Collects the contents of `pipe`, also copying them to `forward` as they are read
+fn read_in_background(
+    pipe: Option<impl Read + Send + 'static>,
+    mut forward: Option<Box<dyn Write + Send>>,
+) -> thread::JoinHandle<io::Result<Vec<u8>>> {
+    thread::spawn(move || {
+        let mut contents = vec![];
+        let Some(mut pipe) = pipe else {
+            return Ok(contents);
+        };
+        let mut buf = [0; 4096];
+        loop {
+            let read = match pipe.read(&mut buf) {
+                Ok(0) => return Ok(contents),
+                Ok(read) => read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            if let Some(out) = &mut forward {
+                // Showing it is best effort, it is still captured for error messages
+                let _ = out.write_all(&buf[..read]).and_then(|()| out.flush());
+            }
+            contents.extend_from_slice(&buf[..read]);
+        }
+    })
+}
+
+fn join_reader(reader: thread::JoinHandle<io::Result<Vec<u8>>>) -> io::Result<Vec<u8>> {
+    reader.join().unwrap_or_else(|_| {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "Reading output failed",
+        ))
+    })
+}
+
+/// Kills `child` along with its process group
+#[cfg(unix)]
+fn kill(child: &mut Child) {
+    // SAFETY: kill has no memory preconditions. The child was spawned with
+    // `process_group(0)` and isn't reaped yet, so its pid is still the id of its own group
+    // and the negated pid signals only that group.
+    unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+}
+
+#[cfg(not(unix))]
+fn kill(child: &mut Child) {
+    let _ = child.kill();
+}
 
-    log::debug!("Executing passphrase command for key '{}': {}", key, command);
+/// Runs the passphrase `command` with `shell` and returns its trimmed output.
+/// The command is killed if it runs longer than `timeout`.
+fn run_getter(
+    runner: &impl CommandRunner,
+    shell: &Shell,
+    command: &str,
+    source: GetterSource,
+    timeout: Option<Duration>,
+) -> Result<Zeroizing<String>> {
+    let mut args: Vec<&str> = shell.args.iter().map(String::as_str).collect();
+    args.push(command);
 
-    // Execute command using shell to support pipes and complex commands
-    let output = Command::new("sh")
-        .arg("-c")
-        .arg(command)
-        .output()
+    let output = runner
+        .run(&shell.program, &args, timeout)
         .with_context(|| {
             format!(
-                "Failed to execute passphrase command (triggered by {})\nCommand: {}",
-                source,
-                command
-            )
+            "Failed to execute passphrase command (triggered by {})\nInterpreter: {}\nCommand: {}",
+            source,
+            shell,
+            command
+        )
         })?;
+    let Some(output) = output else {
+        bail!(
+            "Passphrase command timed out after {}s (triggered by {})\nInterpreter: {}\nCommand: {}",
+            timeout.unwrap_or_default().as_secs(),
+            source,
+            shell,
+            command
+        );
+    };
 
     if !output.status.success() {
         bail!(
-            "Passphrase command failed (triggered by {})\nCommand: {}\nExit code: {}\nstderr: {}",
+            "Passphrase command failed (triggered by {})\nInterpreter: {}\nCommand: {}\nExit code: {}\nstderr: {}",
             source,
+            shell,
             command,
             output.status,
             String::from_utf8_lossy(&output.stderr)
         );
     }
 
-    // Parse and sanitize passphrase
-    let passphrase = String::from_utf8(output.stdout)
+    if !output.stderr.is_empty() {
+        log::debug!(
+            "Passphrase command succeeded; stderr={}",
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        );
+    }
+
+    // Parse and sanitize passphrase, only from stdout, wiping the command output once done
+    let stdout = Zeroizing::new(output.stdout);
+    let passphrase = std::str::from_utf8(&stdout)
         .with_context(|| {
             format!(
-                "Passphrase command output is not valid UTF-8 (triggered by {})\nCommand: {}",
+                "Passphrase command output is not valid UTF-8 (triggered by {})\nInterpreter: {}\nCommand: {}",
                 source,
+                shell,
                 command
             )
         })?
-        .trim()
-        .to_string();
+        .trim();
 
     if passphrase.is_empty() {
         bail!(
-            "Passphrase command returned empty output (triggered by {})\nCommand: {}",
+            "Passphrase command returned empty output (triggered by {})\nInterpreter: {}\nCommand: {}",
             source,
+            shell,
             command
         );
     }
@@ -143,8 +495,218 @@ fn resolve_passphrase(args: &cli::Args, repo: &impl Repository) -> Result<()> {
     // TODO: Additional passphrase sanitization if needed
     // For now we just trim whitespace which is the most common issue
 
-    log::debug!("Setting AGE_PASSPHRASE from passphrase getter '{}'", key);
-    std::env::set_var("AGE_PASSPHRASE", passphrase);
+    Ok(Zeroizing::new(passphrase.to_owned()))
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::{Cell, RefCell},
+        process::ExitStatus,
+    };
+
+    use rstest::rstest;
+
+    use super::*;
+
+    /// Records the invoked program instead of running it
+    struct StubRunner {
+        status: i32,
+        stdout: &'static [u8],
+        invoked: RefCell<Vec<String>>,
+    }
+
+    impl StubRunner {
+        fn new(status: i32, stdout: &'static [u8]) -> Self {
+            Self {
+                status,
+                stdout,
+                invoked: RefCell::new(vec![]),
+            }
+        }
+    }
+
+    impl CommandRunner for StubRunner {
+        fn run(
+            &self,
+            program: &str,
+            args: &[&str],
+            _timeout: Option<Duration>,
+        ) -> io::Result<Option<Output>> {
+            let mut invoked = self.invoked.borrow_mut();
+            invoked.push(program.to_string());
+            invoked.extend(args.iter().map(|a| a.to_string()));
+            Ok(Some(Output {
+                status: exit_status(self.status),
+                stdout: self.stdout.to_vec(),
+                stderr: b"stub error".to_vec(),
+            }))
+        }
+    }
+
+    /// Never finishes in time
+    struct HangingRunner;
+
+    impl CommandRunner for HangingRunner {
+        fn run(
+            &self,
+            _program: &str,
+            _args: &[&str],
+            _timeout: Option<Duration>,
+        ) -> io::Result<Option<Output>> {
+            Ok(None)
+        }
+    }
+
+    #[cfg(unix)]
+    fn exit_status(code: i32) -> ExitStatus {
+        use std::os::unix::process::ExitStatusExt;
+        // Wait status with the exit code in the high byte
+        ExitStatus::from_raw(code << 8)
+    }
+
+    #[cfg(windows)]
+    fn exit_status(code: i32) -> ExitStatus {
+        use std::os::windows::process::ExitStatusExt;
+        ExitStatus::from_raw(code as u32)
+    }
+
+    #[rstest]
+    #[case::unix(false, "sh", "-c")]
+    #[case::windows(true, "cmd", "/C")]
+    fn test_default_shell(#[case] windows: bool, #[case] program: &str, #[case] arg: &str) {
+        assert_eq!(
+            Shell::default_for(windows),
+            Shell {
+                program: program.to_string(),
+                args: vec![arg.to_string()],
+            }
+        );
+    }
+
+    #[rstest]
+    fn test_configured_shell_runs_command() -> Result<()> {
+        let runner = StubRunner::new(0, b"  hunter2\r\n");
+        let shell = Shell::from_config(Some("pwsh -NoProfile -c"))?;
+
+        let passphrase = run_getter(&runner, &shell, "Get-Secret age", GetterSource::Arg, None)?;
+
+        assert_eq!(passphrase.as_str(), "hunter2");
+        assert_eq!(
+            *runner.invoked.borrow(),
+            ["pwsh", "-NoProfile", "-c", "Get-Secret age"]
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_empty_shell_is_rejected() {
+        assert!(Shell::from_config(Some("  ")).is_err());
+    }
+
+    #[rstest]
+    #[case::failed(1, b"")]
+    #[case::not_utf8(0, b"\xff\xfe")]
+    fn test_errors_name_interpreter(#[case] status: i32, #[case] stdout: &'static [u8]) {
+        let runner = StubRunner::new(status, stdout);
+        let shell = Shell::from_config(Some("pwsh -c")).unwrap();
+
+        let err =
+            run_getter(&runner, &shell, "Get-Secret age", GetterSource::Arg, None).unwrap_err();
+
+        assert!(err.to_string().contains("Interpreter: pwsh -c"), "{}", err);
+    }
+
+    #[rstest]
+    fn test_timed_out_getter_is_named() {
+        let shell = Shell::default_for(false);
+        let timeout = Some(Duration::from_secs(30));
+
+        let source = GetterSource::EnvVar;
+        let err = run_getter(&HangingRunner, &shell, "pass show age", source, timeout)
+            .unwrap_err()
+            .to_string();
+
+        assert!(
+            err.contains("Passphrase command timed out after 30s"),
+            "{}",
+            err
+        );
+        assert!(err.contains(AGE_PASSPHRASE_GETTER_ENV), "{}", err);
+        assert!(err.contains("Command: pass show age"), "{}", err);
+    }
+
+    #[cfg(unix)]
+    #[rstest]
+    fn test_stderr_is_kept_apart_from_stdout() -> Result<()> {
+        let command = "echo 'touch your YubiKey' >&2; echo hunter2";
+
+        let output = SystemRunner
+            .run("sh", &["-c", command], None)?
+            .expect("no timeout");
+
+        assert_eq!(output.stdout, b"hunter2\n");
+        assert_eq!(output.stderr, b"touch your YubiKey\n");
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[rstest]
+    fn test_slow_command_is_killed() -> Result<()> {
+        let started = Instant::now();
+
+        let timeout = Some(Duration::from_millis(100));
+        let output = SystemRunner.run("sh", &["-c", "sleep 10"], timeout)?;
+
+        assert!(output.is_none());
+        assert!(started.elapsed() < Duration::from_secs(5));
+        Ok(())
+    }
+
+    /// Hands out a fixed passphrase, counting how often it is asked for
+    struct CountingProvider {
+        cache_key: Option<&'static str>,
+        asked: Cell<usize>,
+    }
+
+    impl KeyProvider for CountingProvider {
+        fn passphrase(&self) -> Result<Zeroizing<String>> {
+            self.asked.set(self.asked.get() + 1);
+            Ok(Zeroizing::new("hunter2".to_string()))
+        }
+
+        fn cache_key(&self) -> Option<&str> {
+            self.cache_key
+        }
+    }
+
+    #[cfg(unix)]
+    #[rstest]
+    #[case::cached(Some("pass show age"), 1)]
+    #[case::uncached(None, 2)]
+    fn test_getter_passphrase_cache(
+        #[case] cache_key: Option<&'static str>,
+        #[case] asked: usize,
+    ) -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        duct::cmd!("git", "init").dir(dir.path()).run()?;
+        let repo = git::LibGit2Repository::from_dir(dir.to_path_buf())?;
+        let config_file = dir.join("git-agecrypt.toml");
+        std::fs::write(&config_file, "[passphrase_cache]\nttl_secs = 60\n")?;
+        let cfg = AppConfig::load(&config_file, repo.workdir())?;
+        let provider = CountingProvider {
+            cache_key,
+            asked: Cell::new(0),
+        };
+
+        for _ in 0..2 {
+            let passphrase = getter_passphrase(&cfg, &repo, "ci", &provider)?;
+            assert_eq!(passphrase.as_str(), "hunter2");
+        }
+
+        assert_eq!(provider.asked.get(), asked);
+        let cache = repo.path().join("git-agecrypt").join("passphrase");
+        assert_eq!(cache.exists(), cache_key.is_some());
+        Ok(())
+    }
 }
@@ -0,0 +1,107 @@
+//! Running CPU bound work, like encrypting many files, on several threads.
+
+use std::{num::NonZeroUsize, sync::Mutex, thread};
+
+use crate::progress::Progress;
+
+/// Number of jobs to use when not specified, one per available CPU
+pub(crate) fn default_jobs() -> NonZeroUsize {
+    thread::available_parallelism().unwrap_or(NonZeroUsize::MIN)
+}
+
+/// Applies `f` to all `items` using up to `jobs` threads, counting them in `progress`,
+/// which is finished once all are done.
+/// Results are in the order of `items`, as if they were processed one by one.
+pub(crate) fn map<T, R, F>(jobs: NonZeroUsize, items: Vec<T>, progress: Progress, f: F) -> Vec<R>
+where
+    T: Send,
+    R: Send,
+    F: Fn(T) -> R + Sync,
+{
+    let f = |item: T| {
+        let result = f(item);
+        progress.inc();
+        result
+    };
+    let jobs = jobs.get().min(items.len());
+    if jobs <= 1 {
+        return items.into_iter().map(f).collect();
+    }
+
+    let len = items.len();
+    let queue = Mutex::new(items.into_iter().enumerate());
+    let results = Mutex::new(Vec::with_capacity(len));
+    thread::scope(|scope| {
+        for _ in 0..jobs {
+            scope.spawn(|| loop {
+                // Only hold the lock to take the next item, not while working on it
+                let next = queue.lock().unwrap().next();
+                let Some((i, item)) = next else {
+                    break;
+                };
+                let result = f(item);
+                results.lock().unwrap().push((i, result));
+            });
+        }
+    });
+
+    let mut results = results.into_inner().unwrap();
+    results.sort_unstable_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use age::secrecy::ExposeSecret;
+    use anyhow::Result;
+    use assert_fs::{prelude::*, TempDir};
+    use rstest::rstest;
+
+    use super::*;
+    use crate::age as agecrypt;
+
+    #[rstest]
+    #[case::serial(1)]
+    #[case::two(2)]
+    #[case::more_jobs_than_items(64)]
+    fn test_results_match_serial_path(#[case] jobs: usize) -> Result<()> {
+        let dir = TempDir::new()?;
+        let identity = age::x25519::Identity::generate();
+        let identity_file = dir.child("identity.txt");
+        identity_file.write_str(identity.to_string().expose_secret())?;
+        let recipients = [identity.to_public().to_string()];
+
+        let encrypted = (0..20)
+            .map(|i| {
+                let plaintext = format!("secret number {}", i);
+                agecrypt::encrypt(
+                    &recipients,
+                    &mut plaintext.as_bytes(),
+                    agecrypt::Encoding::Binary,
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let decrypt = |encrypted: &Vec<u8>| {
+            agecrypt::decrypt(&[identity_file.path()], &mut &encrypted[..]).unwrap()
+        };
+
+        let serial: Vec<_> = encrypted.iter().map(decrypt).collect();
+        let parallel = map(
+            NonZeroUsize::new(jobs).unwrap(),
+            encrypted.iter().collect(),
+            Progress::hidden(),
+            decrypt,
+        );
+
+        assert_eq!(parallel, serial);
+        assert_eq!(parallel[7].as_deref(), Some(&b"secret number 7"[..]));
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_no_items() {
+        let jobs = NonZeroUsize::new(4).unwrap();
+        let results: Vec<u8> = map(jobs, vec![], Progress::hidden(), |i: u8| i);
+        assert!(results.is_empty());
+    }
+}
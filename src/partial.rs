@@ -0,0 +1,190 @@
+//! Encryption of single lines, for files which are only partly secret.
+//!
+//! Lines ending with the `# agecrypt` marker are encrypted, the rest of the file stays
+//! plaintext, so that it keeps its readable structure and diffs normally. An encrypted
+//! line keeps its indentation and marker, its content is replaced by the base64 encoded
+//! age ciphertext:
+//!
+//! ```text
+//!   password: hunter2 # agecrypt
+//!   ENC[age:YWdlLWVuY3J5cHRpb24ub3JnL3YxCi0+IFgyNTUxOSBa...] # agecrypt
+//! ```
+//!
+//! The content is everything between the indentation and the marker, including the
+//! whitespace before the marker, so that decrypting restores the line exactly.
+
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+/// Tags the lines to encrypt, at their end
+pub(crate) const MARKER: &str = "# agecrypt";
+
+const PREFIX: &str = "ENC[age:";
+const SUFFIX: &str = "] ";
+
+/// A line ending with the marker
+struct MarkedLine<'a> {
+    indent: &'a str,
+    content: &'a str,
+    /// The marker, with any trailing whitespace and the line ending
+    marker: &'a str,
+}
+
+impl<'a> MarkedLine<'a> {
+    fn parse(line: &'a str) -> Option<Self> {
+        let end = line.trim_end().strip_suffix(MARKER)?.len();
+        let (before, marker) = line.split_at(end);
+        let content = before.trim_start();
+        if content.trim().is_empty() {
+            // A marker on its own is a comment, e.g. explaining the scheme
+            return None;
+        }
+        Some(Self {
+            indent: &before[..before.len() - content.len()],
+            content,
+            marker,
+        })
+    }
+
+    /// The ciphertext of an encrypted line, `None` for lines to encrypt
+    fn ciphertext(&self) -> Option<Result<Vec<u8>>> {
+        let encoded = self.content.strip_prefix(PREFIX)?.strip_suffix(SUFFIX)?;
+        Some(
+            STANDARD
+                .decode(encoded)
+                .context("Encrypted line isn't valid base64"),
+        )
+    }
+}
+
+/// Encrypts the content of the marked lines of `contents` with `encrypt`.
+/// Lines which are already encrypted are kept as they are.
+pub(crate) fn encrypt_lines(
+    contents: &str,
+    mut encrypt: impl FnMut(&str) -> Result<Vec<u8>>,
+) -> Result<String> {
+    let mut encrypted = String::with_capacity(contents.len());
+    for (idx, line) in contents.split_inclusive('\n').enumerate() {
+        match MarkedLine::parse(line) {
+            Some(marked) if marked.ciphertext().is_none() => {
+                let ciphertext = encrypt(marked.content)
+                    .with_context(|| format!("Couldn't encrypt line {}", idx + 1))?;
+                encrypted.push_str(marked.indent);
+                encrypted.push_str(PREFIX);
+                encrypted.push_str(&STANDARD.encode(ciphertext));
+                encrypted.push_str(SUFFIX);
+                encrypted.push_str(marked.marker);
+            }
+            _ => encrypted.push_str(line),
+        }
+    }
+    Ok(encrypted)
+}
+
+/// Decrypts the encrypted lines of `contents` with `decrypt`
+pub(crate) fn decrypt_lines(
+    contents: &str,
+    mut decrypt: impl FnMut(&[u8]) -> Result<Vec<u8>>,
+) -> Result<String> {
+    let mut decrypted = String::with_capacity(contents.len());
+    for (idx, line) in contents.split_inclusive('\n').enumerate() {
+        let Some(marked) = MarkedLine::parse(line) else {
+            decrypted.push_str(line);
+            continue;
+        };
+        let Some(ciphertext) = marked.ciphertext() else {
+            decrypted.push_str(line);
+            continue;
+        };
+        let content = ciphertext
+            .and_then(|ciphertext| decrypt(&ciphertext))
+            .and_then(|content| Ok(String::from_utf8(content)?))
+            .with_context(|| format!("Couldn't decrypt line {}", idx + 1))?;
+        decrypted.push_str(marked.indent);
+        decrypted.push_str(&content);
+        decrypted.push_str(marked.marker);
+    }
+    Ok(decrypted)
+}
+
+/// The ciphertexts of the encrypted lines of `contents`, skipping invalid ones
+pub(crate) fn ciphertexts(contents: &str) -> Vec<Vec<u8>> {
+    contents
+        .split_inclusive('\n')
+        .filter_map(MarkedLine::parse)
+        .filter_map(|marked| marked.ciphertext()?.ok())
+        .collect()
+}
+
+/// Whether `contents` has lines encrypted by [`encrypt_lines`]
+pub(crate) fn has_encrypted_lines(contents: &[u8]) -> bool {
+    std::str::from_utf8(contents).is_ok_and(|contents| {
+        contents
+            .split_inclusive('\n')
+            .filter_map(MarkedLine::parse)
+            .any(|marked| marked.ciphertext().is_some())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    /// Reversible stand-in for age, so that the results are predictable
+    fn reverse(content: &[u8]) -> Result<Vec<u8>> {
+        Ok(content.iter().rev().copied().collect())
+    }
+
+    #[rstest]
+    #[case::crlf("  password: hunter2 # agecrypt  \r\n")]
+    #[case::no_newline("token=abc\t# agecrypt")]
+    #[case::flush_marker("key: value# agecrypt\n")]
+    fn test_lines_round_trip(#[case] line: &str) -> Result<()> {
+        let contents = format!("name: app\n{line}");
+
+        let encrypted = encrypt_lines(&contents, |content| reverse(content.as_bytes()))?;
+        let decrypted = decrypt_lines(&encrypted, reverse)?;
+
+        assert!(encrypted.starts_with("name: app\n"), "{}", encrypted);
+        assert!(
+            !encrypted.contains("password") && !encrypted.contains("abc"),
+            "{}",
+            encrypted
+        );
+        assert!(has_encrypted_lines(encrypted.as_bytes()));
+        assert_eq!(decrypted, contents);
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_only_marked_lines_are_encrypted() -> Result<()> {
+        let contents = "# agecrypt\nplain: yes\nsecret: no # agecrypt\n# agecrypt is used below\n";
+
+        let encrypted = encrypt_lines(contents, |_| Ok(b"ciphertext".to_vec()))?;
+
+        assert_eq!(
+            encrypted,
+            format!(
+                "# agecrypt\nplain: yes\n{}{}{}# agecrypt\n# agecrypt is used below\n",
+                PREFIX,
+                STANDARD.encode("ciphertext"),
+                SUFFIX
+            )
+        );
+        assert_eq!(ciphertexts(&encrypted), [b"ciphertext".to_vec()]);
+        assert!(!has_encrypted_lines(contents.as_bytes()));
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_encrypted_lines_are_kept() -> Result<()> {
+        let contents = encrypt_lines("secret: 1 # agecrypt\n", |_| Ok(b"ciphertext".to_vec()))?;
+
+        let again = encrypt_lines(&contents, |_| panic!("encrypted twice"))?;
+
+        assert_eq!(again, contents);
+        Ok(())
+    }
+}
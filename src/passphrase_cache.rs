@@ -0,0 +1,161 @@
+//! Short-lived cache for passphrases obtained by a passphrase getter.
+//!
+//! Git starts the filters once per file, so without it the getter command runs
+//! for every file of a checkout. The passphrase is stored in plain text in a
+//! file only readable by the current user, inside the git directory, and is
+//! considered stale after the configured TTL.
+
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use zeroize::Zeroizing;
+
+/// Returns the passphrase cached for `command` if it was stored less than `ttl` ago.
+/// Stale entries and entries readable by other users are removed.
+pub(crate) fn load(dir: &Path, command: &str, ttl: Duration) -> Option<Zeroizing<String>> {
+    let file = cache_path(dir, command);
+    let metadata = fs::metadata(&file).ok()?;
+
+    let fresh = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .is_some_and(|age| age < ttl);
+    if !fresh || !is_private(&metadata) {
+        log::debug!(
+            "Discarding cached passphrase; cache={:?}, fresh={}",
+            file,
+            fresh
+        );
+        let _ = fs::remove_file(&file);
+        return None;
+    }
+
+    let contents = Zeroizing::new(fs::read(&file).ok()?);
+    let passphrase = std::str::from_utf8(&contents).ok()?;
+    Some(Zeroizing::new(passphrase.to_owned()))
+}
+
+/// Caches `passphrase` for `command` in a file only accessible by the current user
+pub(crate) fn store(dir: &Path, command: &str, passphrase: &str) -> Result<()> {
+    let file = cache_path(dir, command);
+    let parent = file.parent().expect("cache file has a parent directory");
+    create_private_dir(parent)
+        .with_context(|| format!("Couldn't create passphrase cache directory {:?}", parent))?;
+
+    // Written next to the final file and renamed, so that concurrent filters never
+    // read a partially written passphrase
+    let tmp = file.with_extension(format!("tmp{}", std::process::id()));
+    let written = create_private_file(&tmp).and_then(|mut f| {
+        f.write_all(passphrase.as_bytes())?;
+        Ok(())
+    });
+    if let Err(err) = written.and_then(|_| Ok(fs::rename(&tmp, &file)?)) {
+        let _ = fs::remove_file(&tmp);
+        return Err(err).with_context(|| format!("Couldn't cache passphrase in {:?}", file));
+    }
+    Ok(())
+}
+
+fn cache_path(dir: &Path, command: &str) -> PathBuf {
+    let name = blake3::hash(command.as_bytes()).to_hex();
+    dir.join("passphrase").join(name.as_str())
+}
+
+#[cfg(unix)]
+pub(crate) fn is_private(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    // SAFETY: getuid has no preconditions and never fails
+    (metadata.mode() & 0o077) == 0 && metadata.uid() == unsafe { libc::getuid() }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn is_private(_metadata: &fs::Metadata) -> bool {
+    false
+}
+
+#[cfg(unix)]
+pub(crate) fn create_private_dir(dir: &Path) -> Result<()> {
+    use std::os::unix::fs::DirBuilderExt;
+
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)?;
+    Ok(())
+}
+
+#[cfg(unix)]
+pub(crate) fn create_private_file(path: &Path) -> Result<fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    Ok(fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn create_private_dir(_dir: &Path) -> Result<()> {
+    anyhow::bail!("Caching is only supported on unix")
+}
+
+#[cfg(not(unix))]
+pub(crate) fn create_private_file(_path: &Path) -> Result<fs::File> {
+    anyhow::bail!("Caching is only supported on unix")
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_fs::TempDir;
+    use rstest::rstest;
+
+    use super::*;
+
+    const COMMAND: &str = "pass show age/identity";
+
+    #[rstest]
+    fn test_fresh_passphrase_is_reused() -> Result<()> {
+        let dir = TempDir::new()?;
+        store(dir.path(), COMMAND, "hunter2")?;
+
+        let cached = load(dir.path(), COMMAND, Duration::from_secs(60));
+
+        assert_eq!(cached.as_deref().map(String::as_str), Some("hunter2"));
+        assert!(load(dir.path(), "other command", Duration::from_secs(60)).is_none());
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_stale_passphrase_is_removed() -> Result<()> {
+        let dir = TempDir::new()?;
+        store(dir.path(), COMMAND, "hunter2")?;
+
+        assert!(load(dir.path(), COMMAND, Duration::ZERO).is_none());
+        assert!(!cache_path(dir.path(), COMMAND).exists());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[rstest]
+    fn test_cache_is_only_accessible_by_owner() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new()?;
+        store(dir.path(), COMMAND, "hunter2")?;
+        let file = cache_path(dir.path(), COMMAND);
+
+        assert_eq!(fs::metadata(&file)?.permissions().mode() & 0o777, 0o600);
+
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o644))?;
+        assert!(load(dir.path(), COMMAND, Duration::from_secs(60)).is_none());
+        Ok(())
+    }
+}
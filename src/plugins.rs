@@ -0,0 +1,146 @@
+//! Plugin binaries configured in `[[plugins]]`, made available to age.
+//!
+//! age runs the plugin `name` as the binary `age-plugin-name` found in PATH, for both
+//! recipients and identities. A directory with such a binary for every configured
+//! plugin is put first in PATH: on unix, it holds shims which run the configured binary
+//! with its arguments, so that neither the name nor the location of the binary matter.
+
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+use crate::config::Plugin;
+
+/// Makes `plugins` available to age for the rest of the process, with their shims
+/// in `shim_dir`
+pub(crate) fn install(plugins: &[Plugin], shim_dir: &Path) -> Result<()> {
+    if plugins.is_empty() {
+        return Ok(());
+    }
+    let mut dirs = vec![];
+    for plugin in plugins {
+        let dir = plugin_dir(plugin, shim_dir)?;
+        if !dirs.contains(&dir) {
+            dirs.push(dir);
+        }
+    }
+    log::debug!("Adding plugin directories to PATH; dirs={:?}", dirs);
+    let path = env::var_os("PATH").unwrap_or_default();
+    dirs.extend(env::split_paths(&path));
+    env::set_var(
+        "PATH",
+        env::join_paths(dirs).context("Couldn't add plugins to PATH")?,
+    );
+    Ok(())
+}
+
+fn binary_name(plugin: &Plugin) -> String {
+    format!("age-plugin-{}", plugin.name)
+}
+
+/// Directory in which age finds the binary of `plugin` under its expected name
+#[cfg(unix)]
+fn plugin_dir(plugin: &Plugin, shim_dir: &Path) -> Result<PathBuf> {
+    write_shim(&shim_dir.join(binary_name(plugin)), &shim(plugin))?;
+    Ok(shim_dir.to_path_buf())
+}
+
+/// Directory in which age finds the binary of `plugin` under its expected name.
+/// Without shims, the binary has to be named like that and take no arguments.
+#[cfg(not(unix))]
+fn plugin_dir(plugin: &Plugin, _shim_dir: &Path) -> Result<PathBuf> {
+    let name = binary_name(plugin);
+    let stem = plugin.path.file_stem().and_then(|stem| stem.to_str());
+    if stem != Some(name.as_str()) || !plugin.args.is_empty() {
+        anyhow::bail!(
+            "Plugin '{}' needs a binary named {} without args on this platform, got {}",
+            plugin.name,
+            name,
+            plugin.path.display()
+        );
+    }
+    Ok(plugin.path.parent().unwrap_or(Path::new(".")).to_path_buf())
+}
+
+/// Script running the binary of `plugin` with its configured arguments and those of age
+#[cfg(unix)]
+fn shim(plugin: &Plugin) -> String {
+    let quote = |arg: &str| format!("'{}'", arg.replace('\'', r"'\''"));
+    let mut command = vec![quote(&plugin.path.to_string_lossy())];
+    command.extend(plugin.args.iter().map(|arg| quote(arg)));
+    format!("#!/bin/sh\nexec {} \"$@\"\n", command.join(" "))
+}
+
+/// Writes the executable `shim`, unless it is up to date. It is renamed into place, as
+/// other git-agecrypt processes may run it concurrently.
+#[cfg(unix)]
+fn write_shim(path: &Path, shim: &str) -> Result<()> {
+    use std::{
+        fs::{self, OpenOptions},
+        io::Write,
+        os::unix::fs::OpenOptionsExt,
+    };
+
+    if fs::read_to_string(path).is_ok_and(|existing| existing == shim) {
+        return Ok(());
+    }
+    let dir = path.parent().unwrap_or(Path::new("."));
+    fs::create_dir_all(dir).with_context(|| format!("Couldn't create {:?}", dir))?;
+    let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+    let written = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o755)
+        .open(&tmp)
+        .and_then(|mut file| file.write_all(shim.as_bytes()))
+        .and_then(|()| fs::rename(&tmp, path));
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    written.with_context(|| format!("Couldn't write the plugin shim {:?}", path))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{fs, os::unix::fs::PermissionsExt, process::Command};
+
+    use assert_fs::{prelude::*, TempDir};
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    fn test_shim_runs_binary_with_args() -> Result<()> {
+        let dir = TempDir::new()?;
+        let binary = dir.child("tools/kms plugin");
+        binary.write_str("#!/bin/sh\nprintf '%s|' \"$@\"\n")?;
+        fs::set_permissions(binary.path(), fs::Permissions::from_mode(0o755))?;
+        let plugin = Plugin {
+            name: "kms".into(),
+            path: binary.to_path_buf(),
+            args: vec!["--region".into(), "it's eu".into()],
+        };
+
+        let shim_dir = plugin_dir(&plugin, &dir.path().join("shims"))?;
+        let shim = shim_dir.join("age-plugin-kms");
+        let output = Command::new(&shim)
+            .arg("--age-plugin=recipient-v1")
+            .output()?;
+
+        assert_eq!(
+            String::from_utf8(output.stdout)?,
+            "--region|it's eu|--age-plugin=recipient-v1|"
+        );
+        assert_eq!(fs::metadata(&shim)?.permissions().mode() & 0o777, 0o755);
+        assert_eq!(
+            fs::read_dir(&shim_dir)?.count(),
+            1,
+            "temporary file is left"
+        );
+        Ok(())
+    }
+}
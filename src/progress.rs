@@ -0,0 +1,88 @@
+//! Progress bars of commands walking all files handled by the filter.
+//!
+//! They are only drawn on a terminal, and hidden while the user is asked for something,
+//! e.g. the passphrase of an identity, which may happen in the middle of a walk.
+
+use std::{
+    io::{self, IsTerminal},
+    sync::{Mutex, MutexGuard},
+};
+
+use indicatif::{ProgressBar, ProgressStyle};
+
+/// The bar being drawn, to hide it during interactions
+static ACTIVE: Mutex<Option<ProgressBar>> = Mutex::new(None);
+
+/// Progress of a walk over files, drawn on stderr until dropped
+pub(crate) struct Progress {
+    bar: Option<ProgressBar>,
+}
+
+impl Progress {
+    /// Shows the progress of working on `len` files if `enabled` and stderr is a terminal
+    pub(crate) fn new(len: usize, message: &'static str, enabled: bool) -> Self {
+        let template = "{msg} [{bar:40}] {pos}/{len} files, ETA {eta}";
+        Self::with_template(len as u64, template, message, enabled)
+    }
+
+    /// Shows the progress of reading `len` bytes of a single file, like [`Self::new`]
+    pub(crate) fn bytes(len: u64, message: &'static str, enabled: bool) -> Self {
+        let template = "{msg} [{bar:40}] {bytes}/{total_bytes}, ETA {eta}";
+        Self::with_template(len, template, message, enabled)
+    }
+
+    fn with_template(len: u64, template: &str, message: &'static str, enabled: bool) -> Self {
+        if !enabled || !io::stderr().is_terminal() {
+            return Self::hidden();
+        }
+        let style = ProgressStyle::with_template(template)
+            .expect("Progress template is valid")
+            .progress_chars("=> ");
+        let bar = ProgressBar::new(len)
+            .with_style(style)
+            .with_message(message);
+        *active() = Some(bar.clone());
+        Self { bar: Some(bar) }
+    }
+
+    pub(crate) fn hidden() -> Self {
+        Self { bar: None }
+    }
+
+    /// Counts a file as done
+    pub(crate) fn inc(&self) {
+        if let Some(bar) = &self.bar {
+            bar.inc(1);
+        }
+    }
+
+    /// Sets the bytes done of a bar of [`Self::bytes`]
+    pub(crate) fn set_position(&self, done: u64) {
+        if let Some(bar) = &self.bar {
+            bar.set_position(done);
+        }
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        if let Some(bar) = self.bar.take() {
+            bar.finish_and_clear();
+            *active() = None;
+        }
+    }
+}
+
+fn active() -> MutexGuard<'static, Option<ProgressBar>> {
+    ACTIVE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Hides the bar being drawn while `interaction` runs, see [`crate::age::set_terminal_hook`]
+pub(crate) fn suspend(interaction: &mut dyn FnMut()) {
+    // Not holding the lock, so that the interaction may finish a bar
+    let bar = active().clone();
+    match bar {
+        Some(bar) => bar.suspend(interaction),
+        None => interaction(),
+    }
+}
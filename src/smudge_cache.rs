@@ -0,0 +1,204 @@
+//! Cache of decrypted file contents, to speed up repeated checkouts.
+//!
+//! Git runs the smudge filter for every file which changes on checkout, even when
+//! its ciphertext is the same as on an earlier checkout, e.g. when switching back and
+//! forth between branches. Entries are keyed by the hash of the ciphertext, the
+//! recipients configured for the file and the identities in use, so changing either
+//! of them makes old entries unreachable. The plaintext is stored unencrypted, in
+//! files only readable by the current user inside the git directory, like the
+//! decrypted working tree. Least recently used entries are removed when the cache
+//! grows beyond its size limit.
+
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result};
+
+use crate::passphrase_cache::{create_private_dir, create_private_file, is_private};
+
+/// Key of the cache entry of `encrypted` when decrypting it with `identities` for a
+/// file encrypted to `public_keys`
+pub(crate) fn key(
+    encrypted: &[u8],
+    public_keys: &[impl AsRef<str>],
+    identities: &[impl AsRef<str>],
+) -> blake3::Hash {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(encrypted);
+    // An empty item separates the lists, so that moving an item between them changes the key
+    let items = public_keys
+        .iter()
+        .map(AsRef::as_ref)
+        .chain([""])
+        .chain(identities.iter().map(AsRef::as_ref));
+    for item in items {
+        hasher.update(&[0]);
+        hasher.update(item.as_bytes());
+    }
+    hasher.finalize()
+}
+
+/// Returns the cached plaintext for `key`, marking the entry as recently used.
+/// Entries readable by other users are removed.
+pub(crate) fn load(dir: &Path, key: &blake3::Hash) -> Option<Vec<u8>> {
+    let file = cache_path(dir, key);
+    let metadata = fs::metadata(&file).ok()?;
+    if !is_private(&metadata) {
+        log::debug!(
+            "Discarding cached plaintext readable by others; cache={:?}",
+            file
+        );
+        let _ = fs::remove_file(&file);
+        return None;
+    }
+
+    let contents = fs::read(&file).ok()?;
+    let touched = fs::File::options()
+        .write(true)
+        .open(&file)
+        .and_then(|f| f.set_modified(SystemTime::now()));
+    if let Err(err) = touched {
+        log::debug!(
+            "Couldn't mark cached plaintext as used; cache={:?}, error={}",
+            file,
+            err
+        );
+    }
+    Some(contents)
+}
+
+/// Caches `plaintext` for `key`, then removes the least recently used entries until
+/// the cache takes at most `max_bytes`
+pub(crate) fn store(
+    dir: &Path,
+    key: &blake3::Hash,
+    plaintext: &[u8],
+    max_bytes: u64,
+) -> Result<()> {
+    if plaintext.len() as u64 > max_bytes {
+        log::debug!("File is larger than the smudge cache, not caching it");
+        return Ok(());
+    }
+    let file = cache_path(dir, key);
+    let parent = file.parent().expect("cache file has a parent directory");
+    create_private_dir(parent)
+        .with_context(|| format!("Couldn't create smudge cache directory {:?}", parent))?;
+
+    // Written next to the final file and renamed, so that concurrent filters never
+    // read a partially written entry
+    let tmp = file.with_extension(format!("tmp{}", std::process::id()));
+    let written = create_private_file(&tmp).and_then(|mut f| {
+        f.write_all(plaintext)?;
+        Ok(())
+    });
+    if let Err(err) = written.and_then(|_| Ok(fs::rename(&tmp, &file)?)) {
+        let _ = fs::remove_file(&tmp);
+        return Err(err).with_context(|| format!("Couldn't cache plaintext in {:?}", file));
+    }
+    prune(parent, max_bytes)
+}
+
+/// Removes the least recently used entries until the rest takes at most `max_bytes`
+fn prune(dir: &Path, max_bytes: u64) -> Result<()> {
+    let mut entries = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        // Files being written by other filters have an extension
+        if metadata.is_file() && entry.path().extension().is_none() {
+            let used = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            entries.push((used, metadata.len(), entry.path()));
+        }
+    }
+    entries.sort();
+
+    let mut total: u64 = entries.iter().map(|(_, len, _)| len).sum();
+    for (_, len, path) in entries {
+        if total <= max_bytes {
+            break;
+        }
+        log::debug!(
+            "Removing least recently used cached plaintext; cache={:?}",
+            path
+        );
+        fs::remove_file(&path)?;
+        total -= len;
+    }
+    Ok(())
+}
+
+fn cache_path(dir: &Path, key: &blake3::Hash) -> PathBuf {
+    dir.join("smudge").join(key.to_hex().as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_fs::TempDir;
+    use rstest::rstest;
+
+    use super::*;
+
+    const KEYS: [&str; 1] = ["age1ql3z7hjy54pw3hyww5ayyfg7zqgvc7w3j2elw8zmrj2kg5sfn9aqmcac8p"];
+    const IDENTITIES: [&str; 1] = ["/home/user/.age/identity.txt"];
+
+    #[rstest]
+    fn test_cached_plaintext_is_reused() -> Result<()> {
+        let dir = TempDir::new()?;
+        let key = key(b"ciphertext", &KEYS, &IDENTITIES);
+        store(dir.path(), &key, b"plaintext", 1024)?;
+
+        assert_eq!(load(dir.path(), &key).as_deref(), Some(&b"plaintext"[..]));
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_key_covers_recipients_and_identities() {
+        let no_keys: [&str; 0] = [];
+        let original = key(b"ciphertext", &KEYS, &IDENTITIES);
+
+        assert_ne!(original, key(b"other ciphertext", &KEYS, &IDENTITIES));
+        assert_ne!(original, key(b"ciphertext", &no_keys, &IDENTITIES));
+        assert_ne!(original, key(b"ciphertext", &KEYS, &no_keys));
+        assert_ne!(
+            key(b"ciphertext", &KEYS, &no_keys),
+            key(b"ciphertext", &no_keys, &KEYS)
+        );
+    }
+
+    #[rstest]
+    fn test_least_recently_used_entries_are_removed() -> Result<()> {
+        let dir = TempDir::new()?;
+        let keys: Vec<_> = ["a", "b", "c"]
+            .iter()
+            .map(|name| key(name.as_bytes(), &KEYS, &IDENTITIES))
+            .collect();
+        store(dir.path(), &keys[0], &[0; 40], 100)?;
+        store(dir.path(), &keys[1], &[1; 40], 100)?;
+        let old = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1);
+        fs::File::options()
+            .write(true)
+            .open(cache_path(dir.path(), &keys[1]))?
+            .set_modified(old)?;
+
+        store(dir.path(), &keys[2], &[2; 40], 100)?;
+
+        assert!(load(dir.path(), &keys[0]).is_some());
+        assert!(load(dir.path(), &keys[1]).is_none());
+        assert!(load(dir.path(), &keys[2]).is_some());
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_files_larger_than_the_cache_are_not_cached() -> Result<()> {
+        let dir = TempDir::new()?;
+        let key = key(b"ciphertext", &KEYS, &IDENTITIES);
+        store(dir.path(), &key, &[0; 101], 100)?;
+
+        assert!(load(dir.path(), &key).is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,143 @@
+//! Interaction with the user through the controlling terminal.
+//!
+//! Git runs filters with piped stdio, so these talk to `/dev/tty` directly.
+//! Without a controlling terminal (e.g. in CI) nothing is shown and nothing is
+//! read, so callers never block.
+//! Interactions are serialized, so that prompts from parallel jobs don't interleave.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    sync::{Mutex, MutexGuard, OnceLock},
+};
+
+use age::secrecy::SecretString;
+#[cfg(unix)]
+use zeroize::Zeroizing;
+
+static TERMINAL: Mutex<()> = Mutex::new(());
+
+/// Runs around every interaction, see [`set_hook`]
+static HOOK: OnceLock<fn(&mut dyn FnMut())> = OnceLock::new();
+
+fn lock() -> MutexGuard<'static, ()> {
+    // Nothing is protected but the order of interactions, so poisoning doesn't matter
+    TERMINAL.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Makes every interaction run inside of `hook`, e.g. to hide a progress bar meanwhile.
+/// Only the first hook is kept.
+pub(crate) fn set_hook(hook: fn(&mut dyn FnMut())) {
+    if HOOK.set(hook).is_err() {
+        log::debug!("Terminal hook was already set, ignoring the new one");
+    }
+}
+
+/// Runs `interaction` while holding the terminal, inside of the hook if there is one
+fn interact<R>(interaction: impl FnOnce() -> R) -> R {
+    let _lock = lock();
+    let Some(hook) = HOOK.get() else {
+        return interaction();
+    };
+    let (mut interaction, mut result) = (Some(interaction), None);
+    hook(&mut || result = interaction.take().map(|interaction| interaction()));
+    result.expect("Terminal hook didn't run the interaction")
+}
+
+fn open() -> Option<File> {
+    if cfg!(unix) {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/tty")
+            .ok()
+    } else {
+        None
+    }
+}
+
+/// Shows `message` on the terminal, returns `false` if there is none.
+pub(crate) fn message(message: &str) -> bool {
+    interact(|| match open() {
+        Some(mut tty) => writeln!(tty, "{}", message).is_ok(),
+        None => false,
+    })
+}
+
+/// Shows `prompt` and reads a line of input without the line ending.
+pub(crate) fn read_line(prompt: &str) -> Option<String> {
+    interact(|| {
+        let mut tty = open()?;
+        write!(tty, "{}", prompt).ok()?;
+        tty.flush().ok()?;
+
+        let mut line = String::new();
+        BufReader::new(tty).read_line(&mut line).ok()?;
+        Some(line.trim_end_matches(['\r', '\n']).to_string())
+    })
+}
+
+/// Shows `prompt` and reads a line of input without echoing it.
+/// Returns `None` if echo cannot be turned off rather than revealing the input.
+#[cfg(unix)]
+pub(crate) fn read_secret(prompt: &str) -> Option<SecretString> {
+    interact(|| {
+        let mut tty = open()?;
+        write!(tty, "{}", prompt).ok()?;
+        tty.flush().ok()?;
+
+        let _echo_off = EchoOff::new(&tty)?;
+        let mut line = Zeroizing::new(String::new());
+        BufReader::new(&tty).read_line(&mut line).ok()?;
+        Some(SecretString::from(line.trim_end_matches(['\r', '\n'])))
+    })
+}
+
+#[cfg(not(unix))]
+pub(crate) fn read_secret(_prompt: &str) -> Option<SecretString> {
+    None
+}
+
+/// Disables echoing on a terminal until dropped
+#[cfg(unix)]
+struct EchoOff {
+    fd: std::os::unix::io::RawFd,
+    original: libc::termios,
+}
+
+#[cfg(unix)]
+impl EchoOff {
+    fn new(tty: &File) -> Option<Self> {
+        use std::os::unix::io::AsRawFd;
+
+        let fd = tty.as_raw_fd();
+        let mut original = std::mem::MaybeUninit::<libc::termios>::uninit();
+        // SAFETY: `fd` is an open terminal and `original` is only read once initialized
+        let original = unsafe {
+            if libc::tcgetattr(fd, original.as_mut_ptr()) != 0 {
+                return None;
+            }
+            original.assume_init()
+        };
+
+        let mut silent = original;
+        silent.c_lflag &= !libc::ECHO;
+        // Still echo the newline so that further output starts on its own line
+        silent.c_lflag |= libc::ECHONL;
+        // SAFETY: `silent` is a valid termios obtained from the same terminal
+        if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &silent) } != 0 {
+            return None;
+        }
+        Some(Self { fd, original })
+    }
+}
+
+#[cfg(unix)]
+impl Drop for EchoOff {
+    fn drop(&mut self) {
+        // SAFETY: restores the settings read from the same, still open, terminal
+        unsafe {
+            libc::tcsetattr(self.fd, libc::TCSANOW, &self.original);
+        }
+    }
+}